@@ -0,0 +1,870 @@
+use crate::mp4_parser::EditListEntry;
+use std::io::{self, ErrorKind};
+
+/// How the size of a box is stored in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderForm {
+    /// 32-bit size, switched to a largesize header when the box outgrows 4 GB
+    #[default]
+    Compact,
+    /// size = 1 followed by a 64-bit largesize
+    Large,
+    /// size = 0: the box extends to the end of its parent (or the file)
+    ToEnd,
+}
+
+/// One box of an MP4 file with its children
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoxNode {
+    pub box_type: [u8; 4],
+    pub header: HeaderForm,
+    pub body: BoxBody,
+}
+
+/// Content of a box
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BoxBody {
+    /// Plain container (moov, trak, mdia, ...)
+    Container(Vec<BoxNode>),
+    /// Full box whose payload is child boxes (ISO meta)
+    FullContainer {
+        version: u8,
+        flags: u32,
+        children: Vec<BoxNode>,
+    },
+    /// Full box with a typed payload
+    Full {
+        version: u8,
+        flags: u32,
+        content: FullBox,
+    },
+    /// Payload kept as is (unknown boxes, mdat, stsd, boxes that did not parse cleanly)
+    Raw(Vec<u8>),
+}
+
+/// Typed payload of the common full boxes. Time fields are 64-bit when the box version is 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FullBox {
+    Mvhd(Mvhd),
+    Tkhd(Tkhd),
+    Mdhd(Mdhd),
+    Hdlr(Hdlr),
+    Elst(Vec<EditListEntry>),
+    /// (sample_count, sample_delta)
+    Stts(Vec<(u32, u32)>),
+    /// (sample_count, sample_offset), the offset is unsigned in version 0
+    Ctts(Vec<(u32, i32)>),
+    /// 1-based sample numbers
+    Stss(Vec<u32>),
+    Stsc(Vec<StscEntry>),
+    Stsz(Stsz),
+    Stco(Vec<u32>),
+    Co64(Vec<u64>),
+    Mehd {
+        fragment_duration: u64,
+    },
+    Trex(Trex),
+    Mfhd {
+        sequence_number: u32,
+    },
+    Tfhd(Tfhd),
+    Tfdt {
+        base_media_decode_time: u64,
+    },
+    Trun(Trun),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mvhd {
+    pub creation_time: u64,
+    pub modification_time: u64,
+    pub timescale: u32,
+    pub duration: u64,
+    pub rate: u32,   // 16.16
+    pub volume: u16, // 8.8
+    pub reserved: [u8; 10],
+    pub matrix: [u32; 9],
+    pub pre_defined: [u8; 24],
+    pub next_track_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tkhd {
+    pub creation_time: u64,
+    pub modification_time: u64,
+    pub track_id: u32,
+    pub reserved1: u32,
+    pub duration: u64, // movie timescale
+    pub reserved2: [u8; 8],
+    pub layer: u16,
+    pub alternate_group: u16,
+    pub volume: u16, // 8.8
+    pub reserved3: u16,
+    pub matrix: [u32; 9],
+    pub width: u32,  // 16.16
+    pub height: u32, // 16.16
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mdhd {
+    pub creation_time: u64,
+    pub modification_time: u64,
+    pub timescale: u32,
+    pub duration: u64,
+    pub language: u16, // packed ISO 639-2/T
+    pub pre_defined: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hdlr {
+    pub pre_defined: [u8; 4], // QuickTime: component type (mhlr, dhlr)
+    pub handler_type: [u8; 4],
+    pub reserved: [u8; 12],
+    pub name: Vec<u8>, // null-terminated (ISO) or Pascal (QuickTime) string, as stored
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StscEntry {
+    pub first_chunk: u32,
+    pub samples_per_chunk: u32,
+    pub sample_description_index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stsz {
+    /// Size of every sample, 0 when the sizes are listed in `entry_sizes`
+    pub sample_size: u32,
+    pub sample_count: u32,
+    pub entry_sizes: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trex {
+    pub track_id: u32,
+    pub default_sample_description_index: u32,
+    pub default_sample_duration: u32,
+    pub default_sample_size: u32,
+    pub default_sample_flags: u32,
+}
+
+/// Track fragment header. The box flags decide which optional fields are written; a field
+/// whose flag is set but that is `None` is written as 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tfhd {
+    pub track_id: u32,
+    pub base_data_offset: Option<u64>,         // 0x000001
+    pub sample_description_index: Option<u32>, // 0x000002
+    pub default_sample_duration: Option<u32>,  // 0x000008
+    pub default_sample_size: Option<u32>,      // 0x000010
+    pub default_sample_flags: Option<u32>,     // 0x000020
+}
+
+/// Track fragment run. As in `Tfhd`, the box flags decide which fields are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trun {
+    pub data_offset: Option<i32>,        // 0x000001
+    pub first_sample_flags: Option<u32>, // 0x000004
+    pub samples: Vec<TrunSample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TrunSample {
+    pub duration: Option<u32>,           // 0x000100
+    pub size: Option<u32>,               // 0x000200
+    pub flags: Option<u32>,              // 0x000400
+    pub composition_offset: Option<i32>, // 0x000800, unsigned in version 0
+}
+
+const CONTAINERS: [&[u8; 4]; 15] = [
+    b"moov", b"trak", b"edts", b"mdia", b"minf", b"dinf", b"stbl", b"mvex", b"moof", b"traf",
+    b"mfra", b"udta", b"tref", b"ilst", b"sinf",
+];
+
+/// Parses `data` into a box tree. Every box is kept: boxes that are not understood, or whose
+/// typed form would not serialize to the same bytes, are stored raw, so `write_box_tree`
+/// reproduces `data` exactly.
+pub fn parse_box_tree(data: &[u8]) -> io::Result<Vec<BoxNode>> {
+    parse_boxes(data, None)
+}
+
+/// Serializes a box tree. Sizes are recomputed, so edited boxes may change length.
+pub fn write_box_tree(nodes: &[BoxNode]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for node in nodes {
+        node.write(&mut output);
+    }
+    output
+}
+
+impl BoxNode {
+    pub fn new(box_type: &[u8; 4], body: BoxBody) -> Self {
+        BoxNode {
+            box_type: *box_type,
+            header: HeaderForm::Compact,
+            body,
+        }
+    }
+
+    /// Child boxes of a container, empty for other boxes
+    pub fn children(&self) -> &[BoxNode] {
+        match &self.body {
+            BoxBody::Container(children) | BoxBody::FullContainer { children, .. } => children,
+            _ => &[],
+        }
+    }
+
+    /// Child boxes of a container, `None` for other boxes
+    pub fn children_mut(&mut self) -> Option<&mut Vec<BoxNode>> {
+        match &mut self.body {
+            BoxBody::Container(children) | BoxBody::FullContainer { children, .. } => {
+                Some(children)
+            }
+            _ => None,
+        }
+    }
+
+    /// First descendant at `path` (e.g. `[b"mdia", b"minf", b"stbl"]`)
+    pub fn find(&self, path: &[&[u8; 4]]) -> Option<&BoxNode> {
+        let (first, rest) = path.split_first()?;
+        let child = self.children().iter().find(|c| &c.box_type == *first)?;
+        if rest.is_empty() {
+            Some(child)
+        } else {
+            child.find(rest)
+        }
+    }
+
+    /// Mutable version of `find`
+    pub fn find_mut(&mut self, path: &[&[u8; 4]]) -> Option<&mut BoxNode> {
+        let (first, rest) = path.split_first()?;
+        let child = self
+            .children_mut()?
+            .iter_mut()
+            .find(|c| &c.box_type == *first)?;
+        if rest.is_empty() {
+            Some(child)
+        } else {
+            child.find_mut(rest)
+        }
+    }
+
+    /// Typed payload of a full box
+    pub fn full_box(&self) -> Option<&FullBox> {
+        match &self.body {
+            BoxBody::Full { content, .. } => Some(content),
+            _ => None,
+        }
+    }
+
+    /// Mutable typed payload of a full box
+    pub fn full_box_mut(&mut self) -> Option<&mut FullBox> {
+        match &mut self.body {
+            BoxBody::Full { content, .. } => Some(content),
+            _ => None,
+        }
+    }
+
+    /// Size of the serialized box, header included
+    pub fn size(&self) -> u64 {
+        let body_size = match &self.body {
+            BoxBody::Container(children) => children.iter().map(BoxNode::size).sum(),
+            BoxBody::FullContainer { children, .. } => {
+                4 + children.iter().map(BoxNode::size).sum::<u64>()
+            }
+            BoxBody::Full { .. } => self.body.to_bytes().len() as u64,
+            BoxBody::Raw(data) => data.len() as u64,
+        };
+        self.header_size(body_size) + body_size
+    }
+
+    /// Size of the box header for a body of `body_size` bytes
+    pub fn header_size(&self, body_size: u64) -> u64 {
+        match self.header {
+            HeaderForm::ToEnd => 8,
+            HeaderForm::Compact if body_size + 8 <= u32::MAX as u64 => 8,
+            _ => 16,
+        }
+    }
+
+    /// Appends the serialized box to `output`
+    pub fn write(&self, output: &mut Vec<u8>) {
+        let body = self.body.to_bytes();
+        let size = 8 + body.len() as u64;
+        match self.header {
+            HeaderForm::ToEnd => {
+                output.extend_from_slice(&0u32.to_be_bytes());
+                output.extend_from_slice(&self.box_type);
+            }
+            HeaderForm::Compact if size <= u32::MAX as u64 => {
+                output.extend_from_slice(&(size as u32).to_be_bytes());
+                output.extend_from_slice(&self.box_type);
+            }
+            _ => {
+                output.extend_from_slice(&1u32.to_be_bytes());
+                output.extend_from_slice(&self.box_type);
+                output.extend_from_slice(&(size + 8).to_be_bytes());
+            }
+        }
+        output.extend_from_slice(&body);
+    }
+}
+
+impl BoxBody {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            BoxBody::Container(children) => write_box_tree(children),
+            BoxBody::FullContainer {
+                version,
+                flags,
+                children,
+            } => {
+                let mut output = full_box_header(*version, *flags);
+                output.extend_from_slice(&write_box_tree(children));
+                output
+            }
+            BoxBody::Full {
+                version,
+                flags,
+                content,
+            } => {
+                let mut output = full_box_header(*version, *flags);
+                content.write(*version, *flags, &mut output);
+                output
+            }
+            BoxBody::Raw(data) => data.clone(),
+        }
+    }
+}
+
+fn full_box_header(version: u8, flags: u32) -> Vec<u8> {
+    let mut header = flags.to_be_bytes();
+    header[0] = version;
+    header.to_vec()
+}
+
+fn parse_boxes(data: &[u8], parent: Option<&[u8; 4]>) -> io::Result<Vec<BoxNode>> {
+    let mut nodes = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        if data.len() - offset < 8 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Trailing bytes after the last box",
+            ));
+        }
+        let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as u64;
+        let box_type: [u8; 4] = data[offset + 4..offset + 8].try_into().unwrap();
+
+        let (header, header_size, size) = match size {
+            0 => (HeaderForm::ToEnd, 8, (data.len() - offset) as u64),
+            1 => {
+                let large = data.get(offset + 8..offset + 16).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Truncated largesize header")
+                })?;
+                let size = u64::from_be_bytes(large.try_into().unwrap());
+                (HeaderForm::Large, 16, size)
+            }
+            _ => (HeaderForm::Compact, 8, size),
+        };
+        if size < header_size as u64 || size > (data.len() - offset) as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Invalid size {} for box '{}'",
+                    size,
+                    String::from_utf8_lossy(&box_type)
+                ),
+            ));
+        }
+
+        let body = &data[offset + header_size..offset + size as usize];
+        nodes.push(BoxNode {
+            box_type,
+            header,
+            body: parse_body(&box_type, body, parent),
+        });
+        offset += size as usize;
+    }
+
+    Ok(nodes)
+}
+
+fn parse_body(box_type: &[u8; 4], body: &[u8], parent: Option<&[u8; 4]>) -> BoxBody {
+    // ilst items (©nam, covr, ----, ...) hold data/mean/name boxes
+    if CONTAINERS.contains(&box_type) || parent == Some(b"ilst") {
+        return match parse_boxes(body, Some(box_type)) {
+            Ok(children) => BoxBody::Container(children),
+            Err(_) => BoxBody::Raw(body.to_vec()),
+        };
+    }
+
+    if body.len() < 4 {
+        return BoxBody::Raw(body.to_vec());
+    }
+    let version = body[0];
+    let flags = u32::from_be_bytes([0, body[1], body[2], body[3]]);
+
+    if box_type == b"meta" {
+        // QuickTime meta has no version/flags and starts with hdlr
+        let parsed = if body.get(4..8) == Some(b"hdlr") {
+            parse_boxes(body, Some(box_type)).map(BoxBody::Container)
+        } else {
+            parse_boxes(&body[4..], Some(box_type)).map(|children| BoxBody::FullContainer {
+                version,
+                flags,
+                children,
+            })
+        };
+        return parsed.unwrap_or_else(|_| BoxBody::Raw(body.to_vec()));
+    }
+
+    match FullBox::parse(box_type, version, flags, &body[4..]) {
+        Some(content) => {
+            let parsed = BoxBody::Full {
+                version,
+                flags,
+                content,
+            };
+            // Anything the typed form would not reproduce (trailing bytes, odd flag
+            // combinations) stays raw
+            if parsed.to_bytes() == body {
+                parsed
+            } else {
+                BoxBody::Raw(body.to_vec())
+            }
+        }
+        None => BoxBody::Raw(body.to_vec()),
+    }
+}
+
+/// Big-endian reader over a box payload
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let value = self
+            .data
+            .get(self.offset..self.offset + N)?
+            .try_into()
+            .ok()?;
+        self.offset += N;
+        Some(value)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_be_bytes)
+    }
+
+    /// 64-bit in version 1, 32-bit otherwise
+    fn time(&mut self, version: u8) -> Option<u64> {
+        if version == 1 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn matrix(&mut self) -> Option<[u32; 9]> {
+        let mut matrix = [0u32; 9];
+        for value in &mut matrix {
+            *value = self.u32()?;
+        }
+        Some(matrix)
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.data[self.offset..].to_vec();
+        self.offset = self.data.len();
+        rest
+    }
+
+    /// Reads `count` entries, refusing counts the remaining data cannot hold
+    fn entries<T>(
+        &mut self,
+        entry_size: usize,
+        mut read: impl FnMut(&mut Self) -> Option<T>,
+    ) -> Option<Vec<T>> {
+        let count = self.u32()? as usize;
+        if count > (self.data.len() - self.offset) / entry_size.max(1) {
+            return None;
+        }
+        (0..count).map(|_| read(self)).collect()
+    }
+
+    /// Optional field present when `flag` is set in `flags`
+    fn optional_u32(&mut self, flags: u32, flag: u32) -> Option<Option<u32>> {
+        if flags & flag != 0 {
+            self.u32().map(Some)
+        } else {
+            Some(None)
+        }
+    }
+}
+
+fn push_time(output: &mut Vec<u8>, version: u8, value: u64) {
+    if version == 1 {
+        output.extend_from_slice(&value.to_be_bytes());
+    } else {
+        output.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+}
+
+fn push_optional_u32(output: &mut Vec<u8>, flags: u32, flag: u32, value: Option<u32>) {
+    if flags & flag != 0 {
+        output.extend_from_slice(&value.unwrap_or(0).to_be_bytes());
+    }
+}
+
+impl FullBox {
+    fn parse(box_type: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Option<Self> {
+        let r = &mut Reader {
+            data: payload,
+            offset: 0,
+        };
+        let content = match box_type {
+            b"mvhd" => FullBox::Mvhd(Mvhd {
+                creation_time: r.time(version)?,
+                modification_time: r.time(version)?,
+                timescale: r.u32()?,
+                duration: r.time(version)?,
+                rate: r.u32()?,
+                volume: r.u16()?,
+                reserved: r.bytes()?,
+                matrix: r.matrix()?,
+                pre_defined: r.bytes()?,
+                next_track_id: r.u32()?,
+            }),
+            b"tkhd" => FullBox::Tkhd(Tkhd {
+                creation_time: r.time(version)?,
+                modification_time: r.time(version)?,
+                track_id: r.u32()?,
+                reserved1: r.u32()?,
+                duration: r.time(version)?,
+                reserved2: r.bytes()?,
+                layer: r.u16()?,
+                alternate_group: r.u16()?,
+                volume: r.u16()?,
+                reserved3: r.u16()?,
+                matrix: r.matrix()?,
+                width: r.u32()?,
+                height: r.u32()?,
+            }),
+            b"mdhd" => FullBox::Mdhd(Mdhd {
+                creation_time: r.time(version)?,
+                modification_time: r.time(version)?,
+                timescale: r.u32()?,
+                duration: r.time(version)?,
+                language: r.u16()?,
+                pre_defined: r.u16()?,
+            }),
+            b"hdlr" => FullBox::Hdlr(Hdlr {
+                pre_defined: r.bytes()?,
+                handler_type: r.bytes()?,
+                reserved: r.bytes()?,
+                name: r.rest(),
+            }),
+            b"elst" => {
+                let entry_size = if version == 1 { 20 } else { 12 };
+                FullBox::Elst(r.entries(entry_size, |r| {
+                    let segment_duration = r.time(version)?;
+                    let media_time = if version == 1 {
+                        r.u64()? as i64
+                    } else {
+                        r.u32()? as i32 as i64
+                    };
+                    Some(EditListEntry {
+                        segment_duration,
+                        media_time,
+                        media_rate: r.u32()?,
+                    })
+                })?)
+            }
+            b"stts" => FullBox::Stts(r.entries(8, |r| Some((r.u32()?, r.u32()?)))?),
+            b"ctts" => FullBox::Ctts(r.entries(8, |r| Some((r.u32()?, r.u32()? as i32)))?),
+            b"stss" => FullBox::Stss(r.entries(4, |r| r.u32())?),
+            b"stsc" => FullBox::Stsc(r.entries(12, |r| {
+                Some(StscEntry {
+                    first_chunk: r.u32()?,
+                    samples_per_chunk: r.u32()?,
+                    sample_description_index: r.u32()?,
+                })
+            })?),
+            b"stsz" => {
+                let sample_size = r.u32()?;
+                if sample_size == 0 {
+                    let entry_sizes = r.entries(4, |r| r.u32())?;
+                    FullBox::Stsz(Stsz {
+                        sample_size,
+                        sample_count: entry_sizes.len() as u32,
+                        entry_sizes,
+                    })
+                } else {
+                    FullBox::Stsz(Stsz {
+                        sample_size,
+                        sample_count: r.u32()?,
+                        entry_sizes: Vec::new(),
+                    })
+                }
+            }
+            b"stco" => FullBox::Stco(r.entries(4, |r| r.u32())?),
+            b"co64" => FullBox::Co64(r.entries(8, |r| r.u64())?),
+            b"mehd" => FullBox::Mehd {
+                fragment_duration: r.time(version)?,
+            },
+            b"trex" => FullBox::Trex(Trex {
+                track_id: r.u32()?,
+                default_sample_description_index: r.u32()?,
+                default_sample_duration: r.u32()?,
+                default_sample_size: r.u32()?,
+                default_sample_flags: r.u32()?,
+            }),
+            b"mfhd" => FullBox::Mfhd {
+                sequence_number: r.u32()?,
+            },
+            b"tfhd" => FullBox::Tfhd(Tfhd {
+                track_id: r.u32()?,
+                base_data_offset: if flags & 0x000001 != 0 {
+                    Some(r.u64()?)
+                } else {
+                    None
+                },
+                sample_description_index: r.optional_u32(flags, 0x000002)?,
+                default_sample_duration: r.optional_u32(flags, 0x000008)?,
+                default_sample_size: r.optional_u32(flags, 0x000010)?,
+                default_sample_flags: r.optional_u32(flags, 0x000020)?,
+            }),
+            b"tfdt" => FullBox::Tfdt {
+                base_media_decode_time: r.time(version)?,
+            },
+            b"trun" => {
+                let sample_count = r.u32()? as usize;
+                let data_offset = r.optional_u32(flags, 0x000001)?.map(|v| v as i32);
+                let first_sample_flags = r.optional_u32(flags, 0x000004)?;
+                let sample_size = 4 * (flags & 0x000F00).count_ones() as usize;
+                if sample_count * sample_size > payload.len() - r.offset {
+                    return None;
+                }
+                let samples = (0..sample_count)
+                    .map(|_| {
+                        Some(TrunSample {
+                            duration: r.optional_u32(flags, 0x000100)?,
+                            size: r.optional_u32(flags, 0x000200)?,
+                            flags: r.optional_u32(flags, 0x000400)?,
+                            composition_offset: r.optional_u32(flags, 0x000800)?.map(|v| v as i32),
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                FullBox::Trun(Trun {
+                    data_offset,
+                    first_sample_flags,
+                    samples,
+                })
+            }
+            _ => return None,
+        };
+        Some(content)
+    }
+
+    fn write(&self, version: u8, flags: u32, output: &mut Vec<u8>) {
+        match self {
+            FullBox::Mvhd(mvhd) => {
+                push_time(output, version, mvhd.creation_time);
+                push_time(output, version, mvhd.modification_time);
+                output.extend_from_slice(&mvhd.timescale.to_be_bytes());
+                push_time(output, version, mvhd.duration);
+                output.extend_from_slice(&mvhd.rate.to_be_bytes());
+                output.extend_from_slice(&mvhd.volume.to_be_bytes());
+                output.extend_from_slice(&mvhd.reserved);
+                for value in mvhd.matrix {
+                    output.extend_from_slice(&value.to_be_bytes());
+                }
+                output.extend_from_slice(&mvhd.pre_defined);
+                output.extend_from_slice(&mvhd.next_track_id.to_be_bytes());
+            }
+            FullBox::Tkhd(tkhd) => {
+                push_time(output, version, tkhd.creation_time);
+                push_time(output, version, tkhd.modification_time);
+                output.extend_from_slice(&tkhd.track_id.to_be_bytes());
+                output.extend_from_slice(&tkhd.reserved1.to_be_bytes());
+                push_time(output, version, tkhd.duration);
+                output.extend_from_slice(&tkhd.reserved2);
+                output.extend_from_slice(&tkhd.layer.to_be_bytes());
+                output.extend_from_slice(&tkhd.alternate_group.to_be_bytes());
+                output.extend_from_slice(&tkhd.volume.to_be_bytes());
+                output.extend_from_slice(&tkhd.reserved3.to_be_bytes());
+                for value in tkhd.matrix {
+                    output.extend_from_slice(&value.to_be_bytes());
+                }
+                output.extend_from_slice(&tkhd.width.to_be_bytes());
+                output.extend_from_slice(&tkhd.height.to_be_bytes());
+            }
+            FullBox::Mdhd(mdhd) => {
+                push_time(output, version, mdhd.creation_time);
+                push_time(output, version, mdhd.modification_time);
+                output.extend_from_slice(&mdhd.timescale.to_be_bytes());
+                push_time(output, version, mdhd.duration);
+                output.extend_from_slice(&mdhd.language.to_be_bytes());
+                output.extend_from_slice(&mdhd.pre_defined.to_be_bytes());
+            }
+            FullBox::Hdlr(hdlr) => {
+                output.extend_from_slice(&hdlr.pre_defined);
+                output.extend_from_slice(&hdlr.handler_type);
+                output.extend_from_slice(&hdlr.reserved);
+                output.extend_from_slice(&hdlr.name);
+            }
+            FullBox::Elst(entries) => {
+                output.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for entry in entries {
+                    push_time(output, version, entry.segment_duration);
+                    if version == 1 {
+                        output.extend_from_slice(&entry.media_time.to_be_bytes());
+                    } else {
+                        output.extend_from_slice(&(entry.media_time as i32).to_be_bytes());
+                    }
+                    output.extend_from_slice(&entry.media_rate.to_be_bytes());
+                }
+            }
+            FullBox::Stts(entries) => {
+                output.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (count, delta) in entries {
+                    output.extend_from_slice(&count.to_be_bytes());
+                    output.extend_from_slice(&delta.to_be_bytes());
+                }
+            }
+            FullBox::Ctts(entries) => {
+                output.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (count, offset) in entries {
+                    output.extend_from_slice(&count.to_be_bytes());
+                    output.extend_from_slice(&offset.to_be_bytes());
+                }
+            }
+            FullBox::Stss(values) | FullBox::Stco(values) => {
+                output.extend_from_slice(&(values.len() as u32).to_be_bytes());
+                for value in values {
+                    output.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            FullBox::Stsc(entries) => {
+                output.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for entry in entries {
+                    output.extend_from_slice(&entry.first_chunk.to_be_bytes());
+                    output.extend_from_slice(&entry.samples_per_chunk.to_be_bytes());
+                    output.extend_from_slice(&entry.sample_description_index.to_be_bytes());
+                }
+            }
+            FullBox::Stsz(stsz) => {
+                output.extend_from_slice(&stsz.sample_size.to_be_bytes());
+                if stsz.sample_size == 0 {
+                    output.extend_from_slice(&(stsz.entry_sizes.len() as u32).to_be_bytes());
+                    for size in &stsz.entry_sizes {
+                        output.extend_from_slice(&size.to_be_bytes());
+                    }
+                } else {
+                    output.extend_from_slice(&stsz.sample_count.to_be_bytes());
+                }
+            }
+            FullBox::Co64(offsets) => {
+                output.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+                for offset in offsets {
+                    output.extend_from_slice(&offset.to_be_bytes());
+                }
+            }
+            FullBox::Mehd { fragment_duration } => push_time(output, version, *fragment_duration),
+            FullBox::Trex(trex) => {
+                output.extend_from_slice(&trex.track_id.to_be_bytes());
+                output.extend_from_slice(&trex.default_sample_description_index.to_be_bytes());
+                output.extend_from_slice(&trex.default_sample_duration.to_be_bytes());
+                output.extend_from_slice(&trex.default_sample_size.to_be_bytes());
+                output.extend_from_slice(&trex.default_sample_flags.to_be_bytes());
+            }
+            FullBox::Mfhd { sequence_number } => {
+                output.extend_from_slice(&sequence_number.to_be_bytes())
+            }
+            FullBox::Tfhd(tfhd) => {
+                output.extend_from_slice(&tfhd.track_id.to_be_bytes());
+                if flags & 0x000001 != 0 {
+                    output.extend_from_slice(&tfhd.base_data_offset.unwrap_or(0).to_be_bytes());
+                }
+                push_optional_u32(output, flags, 0x000002, tfhd.sample_description_index);
+                push_optional_u32(output, flags, 0x000008, tfhd.default_sample_duration);
+                push_optional_u32(output, flags, 0x000010, tfhd.default_sample_size);
+                push_optional_u32(output, flags, 0x000020, tfhd.default_sample_flags);
+            }
+            FullBox::Tfdt {
+                base_media_decode_time,
+            } => push_time(output, version, *base_media_decode_time),
+            FullBox::Trun(trun) => {
+                output.extend_from_slice(&(trun.samples.len() as u32).to_be_bytes());
+                push_optional_u32(output, flags, 0x000001, trun.data_offset.map(|v| v as u32));
+                push_optional_u32(output, flags, 0x000004, trun.first_sample_flags);
+                for sample in &trun.samples {
+                    push_optional_u32(output, flags, 0x000100, sample.duration);
+                    push_optional_u32(output, flags, 0x000200, sample.size);
+                    push_optional_u32(output, flags, 0x000400, sample.flags);
+                    push_optional_u32(
+                        output,
+                        flags,
+                        0x000800,
+                        sample.composition_offset.map(|v| v as u32),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_writer::test_support::tiny_writer;
+
+    #[test]
+    fn test_box_tree_round_trip_and_edit() {
+        let mut writer = tiny_writer(3);
+        writer.set_tags(crate::metadata::Mp4Tags {
+            title: Some("Tree".to_string()),
+            ..Default::default()
+        });
+        let mut data = writer.finish_with_faststart().unwrap().into_inner();
+        // Unknown box with a largesize header
+        data.extend_from_slice(&[0, 0, 0, 1, b'j', b'u', b'n', b'k', 0, 0, 0, 0, 0, 0, 0, 18]);
+        data.extend_from_slice(&[0xAB, 0xCD]);
+
+        let mut tree = parse_box_tree(&data).unwrap();
+        assert_eq!(write_box_tree(&tree), data);
+        assert_eq!(tree[3].header, HeaderForm::Large);
+
+        let moov = tree.iter_mut().find(|b| &b.box_type == b"moov").unwrap();
+        assert!(moov
+            .find(&[b"udta", b"meta", b"ilst", b"\xA9nam", b"data"])
+            .is_some());
+        let stts = moov
+            .find_mut(&[b"trak", b"mdia", b"minf", b"stbl", b"stts"])
+            .unwrap();
+        match stts.full_box_mut() {
+            Some(FullBox::Stts(entries)) => {
+                assert_eq!(entries, &[(3, 3000)]);
+                entries[0] = (2, 3000);
+                entries.push((1, 1500));
+            }
+            other => panic!("unexpected stts {:?}", other),
+        }
+
+        // The edited tree parses back to the same tree
+        let edited = write_box_tree(&tree);
+        assert_eq!(edited.len(), data.len() + 8);
+        assert_eq!(parse_box_tree(&edited).unwrap(), tree);
+    }
+}
@@ -0,0 +1,556 @@
+use crate::mp4_writer::write_full_box;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+
+/// Timescale of the chapter text track (milliseconds)
+pub(crate) const CHAPTER_TIMESCALE: u32 = 1000;
+
+// Nero chpl stores chapter starts in 100 ns units
+const CHPL_UNITS_PER_MS: u64 = 10_000;
+
+// `encd` atom appended to each text sample, declaring UTF-8
+const TEXT_ENCODING_UTF8: [u8; 12] = [0, 0, 0, 12, b'e', b'n', b'c', b'd', 0, 0, 1, 0];
+
+/// Chapter marker on the output timeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    /// Start time in milliseconds from the start of the movie
+    pub start_ms: u64,
+    pub title: String,
+}
+
+impl Chapter {
+    pub fn new(start_ms: u64, title: &str) -> Self {
+        Chapter {
+            start_ms,
+            title: title.to_string(),
+        }
+    }
+}
+
+/// Parses a chapter list, either an ffmetadata file (`;FFMETADATA1` with `[CHAPTER]`
+/// sections) or JSON. JSON is an array of `{"start": ..., "title": ...}` objects, or an
+/// object holding it under `"chapters"` (as printed by `ffprobe -show_chapters`). A JSON
+/// `start` is in seconds, unless a `time_base` is given (ffprobe), then it counts time base
+/// units; `start_time` (seconds) and `start_ms` are read too.
+/// Chapters are returned sorted by start time.
+pub fn parse_chapters(text: &str) -> io::Result<Vec<Chapter>> {
+    let text = text.trim_start_matches('\u{FEFF}');
+    let mut chapters = if text.trim_start().starts_with(";FFMETADATA") {
+        parse_ffmetadata(text)?
+    } else {
+        parse_json_chapters(text)?
+    };
+    chapters.sort_by_key(|chapter| chapter.start_ms);
+    Ok(chapters)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// `[CHAPTER]` sections of an ffmetadata file (TIMEBASE, START and title keys)
+fn parse_ffmetadata(text: &str) -> io::Result<Vec<Chapter>> {
+    struct Section {
+        timebase: (u64, u64),
+        start: Option<u64>,
+        title: String,
+    }
+
+    fn finish(section: Option<Section>, chapters: &mut Vec<Chapter>) -> io::Result<()> {
+        if let Some(section) = section {
+            let start = section
+                .start
+                .ok_or_else(|| invalid_data("[CHAPTER] without START".to_string()))?;
+            let (num, den) = section.timebase;
+            chapters.push(Chapter {
+                start_ms: (start as u128 * num as u128 * 1000 / den as u128) as u64,
+                title: section.title,
+            });
+        }
+        Ok(())
+    }
+
+    let mut chapters = Vec::new();
+    let mut section: Option<Section> = None;
+
+    for line in unescape_lines(text) {
+        if line.starts_with(';') || line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            finish(section.take(), &mut chapters)?;
+            if line == "[CHAPTER]" {
+                // ffmpeg's default time base when TIMEBASE is missing
+                section = Some(Section {
+                    timebase: (1, 1_000_000_000),
+                    start: None,
+                    title: String::new(),
+                });
+            }
+            continue;
+        }
+
+        let Some(section) = section.as_mut() else {
+            continue; // global metadata
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            "TIMEBASE" => {
+                section.timebase = parse_timebase(value)
+                    .ok_or_else(|| invalid_data(format!("Invalid TIMEBASE '{}'", value)))?;
+            }
+            "START" => {
+                section.start = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid_data(format!("Invalid START '{}'", value)))?,
+                );
+            }
+            "title" => section.title = value.to_string(),
+            _ => {}
+        }
+    }
+    finish(section, &mut chapters)?;
+
+    Ok(chapters)
+}
+
+/// Parses a "num/den" time base
+fn parse_timebase(text: &str) -> Option<(u64, u64)> {
+    text.split_once('/')
+        .and_then(|(num, den)| Some((num.trim().parse().ok()?, den.trim().parse().ok()?)))
+        .filter(|&(_, den)| den != 0)
+}
+
+/// Joins lines continued with a trailing backslash and removes the `\` escapes of
+/// `=`, `;`, `#` and `\`
+fn unescape_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => current.push(escaped),
+                None => break,
+            },
+            '\r' => {}
+            '\n' => lines.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    lines.push(current);
+
+    lines
+}
+
+fn parse_json_chapters(text: &str) -> io::Result<Vec<Chapter>> {
+    let mut parser = JsonParser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+    let value = parser.parse_document()?;
+
+    let list = match &value {
+        Json::Array(list) => list,
+        Json::Object(object) => match object.get("chapters") {
+            Some(Json::Array(list)) => list,
+            _ => return Err(invalid_data("Expected a \"chapters\" array".to_string())),
+        },
+        _ => {
+            return Err(invalid_data(
+                "Expected a JSON array of chapters".to_string(),
+            ))
+        }
+    };
+
+    list.iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let Json::Object(object) = entry else {
+                return Err(invalid_data(format!("Chapter {} is not an object", index)));
+            };
+
+            let start_ms = if let Some(start_ms) = object.get("start_ms") {
+                match start_ms {
+                    Json::Number(ms) if *ms >= 0.0 => ms.round() as u64,
+                    _ => return Err(invalid_start(index)),
+                }
+            } else if let (Some(start), Some(time_base)) =
+                (object.get("start"), object.get("time_base"))
+            {
+                // ffprobe: integer start in time_base units, next to a start_time in seconds
+                let Json::String(time_base) = time_base else {
+                    return Err(invalid_start(index));
+                };
+                let (num, den) = parse_timebase(time_base).ok_or_else(|| invalid_start(index))?;
+                match start {
+                    Json::Number(units) if *units >= 0.0 && units.fract() == 0.0 => {
+                        (*units as u128 * num as u128 * 1000 / den as u128) as u64
+                    }
+                    _ => return Err(invalid_start(index)),
+                }
+            } else {
+                let start = object
+                    .get("start_time")
+                    .or_else(|| object.get("start"))
+                    .ok_or_else(|| invalid_data(format!("Chapter {} has no start", index)))?;
+                match start {
+                    Json::Number(seconds) if *seconds >= 0.0 => (seconds * 1000.0).round() as u64,
+                    Json::String(time) => parse_time(time).ok_or_else(|| invalid_start(index))?,
+                    _ => return Err(invalid_start(index)),
+                }
+            };
+
+            let title = match (object.get("title"), object.get("tags")) {
+                (Some(Json::String(title)), _) => title.clone(),
+                (None, Some(Json::Object(tags))) => match tags.get("title") {
+                    Some(Json::String(title)) => title.clone(),
+                    _ => String::new(),
+                },
+                _ => String::new(),
+            };
+
+            Ok(Chapter { start_ms, title })
+        })
+        .collect()
+}
+
+fn invalid_start(index: usize) -> io::Error {
+    invalid_data(format!("Chapter {} has an invalid start time", index))
+}
+
+/// Parses "SS.sss", "MM:SS.sss" or "HH:MM:SS.sss" into milliseconds
+pub fn parse_time(text: &str) -> Option<u64> {
+    let mut seconds = 0.0;
+    for part in text.trim().split(':') {
+        let value: f64 = part.parse().ok()?;
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Some((seconds * 1000.0).round() as u64)
+}
+
+/// Parsed JSON value (only what chapter lists need)
+enum Json {
+    /// true, false or null
+    Literal,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn parse_document(&mut self) -> io::Result<Json> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos < self.chars.len() {
+            return Err(self.error("Trailing characters after JSON value"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, message: &str) -> io::Error {
+        invalid_data(format!("{} at character {}", message, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> io::Result<char> {
+        let c = self
+            .chars
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error("Unexpected end of JSON"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect_word(&mut self, word: &str) -> io::Result<Json> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(self.error("Invalid literal"));
+            }
+        }
+        Ok(Json::Literal)
+    }
+
+    fn parse_value(&mut self) -> io::Result<Json> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(Json::String(self.parse_string()?)),
+            Some('t') => self.expect_word("true"),
+            Some('f') => self.expect_word("false"),
+            Some('n') => self.expect_word("null"),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.parse_number(),
+            _ => Err(self.error("Expected a JSON value")),
+        }
+    }
+
+    fn parse_object(&mut self) -> io::Result<Json> {
+        self.pos += 1; // {
+        let mut object = BTreeMap::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(object));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.chars.get(self.pos) != Some(&'"') {
+                return Err(self.error("Expected an object key"));
+            }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            if self.next()? != ':' {
+                return Err(self.error("Expected ':'"));
+            }
+            let value = self.parse_value()?;
+            object.insert(key, value);
+
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(Json::Object(object)),
+                _ => return Err(self.error("Expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> io::Result<Json> {
+        self.pos += 1; // [
+        let mut list = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&']') {
+            self.pos += 1;
+            return Ok(Json::Array(list));
+        }
+
+        loop {
+            list.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(Json::Array(list)),
+                _ => return Err(self.error("Expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> io::Result<String> {
+        self.pos += 1; // opening quote
+        let mut text = String::new();
+
+        loop {
+            match self.next()? {
+                '"' => return Ok(text),
+                '\\' => {
+                    let c = match self.next()? {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        'b' => '\u{8}',
+                        'f' => '\u{C}',
+                        'u' => self.parse_unicode_escape()?,
+                        c @ ('"' | '\\' | '/') => c,
+                        _ => return Err(self.error("Invalid escape")),
+                    };
+                    text.push(c);
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    /// `\uXXXX`, combining UTF-16 surrogate pairs
+    fn parse_unicode_escape(&mut self) -> io::Result<char> {
+        let high = self.parse_hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.next()? != '\\' || self.next()? != 'u' {
+                return Err(self.error("Unpaired surrogate"));
+            }
+            let low = self.parse_hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("Unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    fn parse_hex4(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self
+                .next()?
+                .to_digit(16)
+                .ok_or_else(|| self.error("Invalid unicode escape"))?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> io::Result<Json> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("Invalid number"))
+    }
+}
+
+/// Chapters that fit in a movie of `duration_ms`, each with its duration up to the next
+/// chapter (or the end of the movie). Chapters sharing a start time keep the last title.
+pub(crate) fn chapter_durations(chapters: &[Chapter], duration_ms: u64) -> Vec<(&Chapter, u32)> {
+    let visible: Vec<&Chapter> = chapters
+        .iter()
+        .filter(|chapter| chapter.start_ms < duration_ms)
+        .collect();
+
+    visible
+        .iter()
+        .enumerate()
+        .filter_map(|(index, chapter)| {
+            let end = visible
+                .get(index + 1)
+                .map_or(duration_ms, |next| next.start_ms);
+            (end > chapter.start_ms).then(|| (*chapter, (end - chapter.start_ms) as u32))
+        })
+        .collect()
+}
+
+/// QuickTime text sample: 16-bit length, the UTF-8 title and an `encd` atom
+pub(crate) fn build_text_sample(title: &str) -> Vec<u8> {
+    let title = &title.as_bytes()[..title.len().min(u16::MAX as usize)];
+    let mut sample = Vec::with_capacity(2 + title.len() + TEXT_ENCODING_UTF8.len());
+    sample.extend_from_slice(&(title.len() as u16).to_be_bytes());
+    sample.extend_from_slice(title);
+    sample.extend_from_slice(&TEXT_ENCODING_UTF8);
+    sample
+}
+
+/// Title stored in a QuickTime text sample
+pub(crate) fn parse_text_sample(sample: &[u8]) -> String {
+    let len = sample
+        .get(..2)
+        .map_or(0, |len| u16::from_be_bytes([len[0], len[1]]) as usize);
+    let text = sample.get(2..2 + len).unwrap_or_default();
+    String::from_utf8_lossy(text).into_owned()
+}
+
+/// Nero `chpl` box (version 1): start times in 100 ns units and titles of up to 255 bytes
+pub(crate) fn build_chpl(chapters: &[Chapter]) -> Vec<u8> {
+    let chapters = &chapters[..chapters.len().min(u8::MAX as usize)];
+
+    let mut chpl = Vec::new();
+    chpl.extend_from_slice(&0u32.to_be_bytes()); // reserved
+    chpl.push(chapters.len() as u8);
+    for chapter in chapters {
+        let mut title_len = chapter.title.len().min(u8::MAX as usize);
+        while !chapter.title.is_char_boundary(title_len) {
+            title_len -= 1;
+        }
+        chpl.extend_from_slice(&(chapter.start_ms * CHPL_UNITS_PER_MS).to_be_bytes());
+        chpl.push(title_len as u8);
+        chpl.extend_from_slice(&chapter.title.as_bytes()[..title_len]);
+    }
+
+    let mut result = Vec::new();
+    write_full_box(&mut result, b"chpl", 1, 0, &chpl);
+    result
+}
+
+/// Reads the content of a `chpl` box (version 0 has no reserved field)
+pub(crate) fn parse_chpl(data: &[u8]) -> io::Result<Vec<Chapter>> {
+    let truncated = || invalid_data("Truncated chpl box".to_string());
+
+    let version = *data.first().ok_or_else(truncated)?;
+    let mut pos = if version == 1 { 8 } else { 4 };
+    let count = *data.get(pos).ok_or_else(truncated)?;
+    pos += 1;
+
+    let mut chapters = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let start = data.get(pos..pos + 8).ok_or_else(truncated)?;
+        let start = u64::from_be_bytes(start.try_into().unwrap_or_default());
+        let title_len = *data.get(pos + 8).ok_or_else(truncated)? as usize;
+        let title = data
+            .get(pos + 9..pos + 9 + title_len)
+            .ok_or_else(truncated)?;
+        chapters.push(Chapter {
+            start_ms: start / CHPL_UNITS_PER_MS,
+            title: String::from_utf8_lossy(title).into_owned(),
+        });
+        pos += 9 + title_len;
+    }
+
+    Ok(chapters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chapters() {
+        let ffmetadata = ";FFMETADATA1\ntitle=Session\n\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=90500\nEND=120000\ntitle=Q\\=A\n\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=90500\ntitle=Opening\n";
+        let expected = vec![Chapter::new(0, "Opening"), Chapter::new(90500, "Q=A")];
+        assert_eq!(parse_chapters(ffmetadata).unwrap(), expected);
+
+        let json =
+            r#"[{"start": 90.5, "title": "Q=A"}, {"start": "00:00:00", "title": "Opening"}]"#;
+        assert_eq!(parse_chapters(json).unwrap(), expected);
+
+        let ffprobe = r#"{"chapters": [
+            {"id": 0, "time_base": "1/1000", "start": 0, "start_time": "0.000000",
+             "end": 90500, "end_time": "90.500000", "tags": {"title": "Opening"}},
+            {"id": 1, "time_base": "1/90000", "start": 8145000, "start_time": "90.500000",
+             "end": 10800000, "end_time": "120.000000", "tags": {"title": "Q=A"}}]}"#;
+        assert_eq!(parse_chapters(ffprobe).unwrap(), expected);
+
+        // Integer start without start_time is still read in time_base units
+        let ffprobe = r#"{"chapters": [{"id": 1, "time_base": "1/1000", "start": 90500, "tags": {"title": "Q=A"}},
+            {"id": 0, "time_base": "1/1000", "start": 0, "tags": {"title": "Opening"}}]}"#;
+        assert_eq!(parse_chapters(ffprobe).unwrap(), expected);
+
+        assert!(parse_chapters(r#"[{"title": "no start"}]"#).is_err());
+
+        let chpl = build_chpl(&expected);
+        assert_eq!(parse_chpl(&chpl[8..]).unwrap(), expected);
+        assert_eq!(
+            chapter_durations(&expected, 100_000)
+                .iter()
+                .map(|(_, duration)| *duration)
+                .collect::<Vec<_>>(),
+            vec![90500, 9500]
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use crate::cut::{add_output_track, rescale_signed, source_mp4, unscale};
+use crate::mp4_parser::{samples_in_decode_order, TrackSamples};
+use crate::mp4_writer::{ConvertOptions, StreamingMp4Writer, TIMESCALE};
+use crate::ts_writer::{read_source_tracks, scale, StreamCodec};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, Write};
+
+/// Concatenates TS and MP4 inputs into one MP4 with the default options
+pub fn concat_media(inputs: &[&[u8]]) -> io::Result<Vec<u8>> {
+    let sink = write_concatenated_mp4(
+        inputs.iter().map(|data| Ok(*data)),
+        Cursor::new(Vec::new()),
+        &ConvertOptions::default(),
+    )?;
+    Ok(sink.into_inner())
+}
+
+/// Concatenates TS and MP4 (regular or fragmented) inputs into one continuous MP4 in `sink`
+///
+/// Every input must carry the same tracks (the first H.264 and the first AAC track are used)
+/// with identical SPS/PPS and AAC configuration. Inputs are taken from `inputs` one at a time:
+/// each is converted, appended and dropped before the next one is read, so an incompatible
+/// input fails the concatenation after the inputs before it were written.
+///
+/// Each input starts where the longest track of the previous one ended. A track that ends
+/// earlier has the gap absorbed in the duration of its last sample before the join (e.g. video
+/// ending 100ms before the audio gets a last frame 100ms longer), overlapping samples are
+/// shortened, and audio frames fully covered by the previous input are dropped. The output
+/// starts at 0.
+///
+/// `options.faststart`, `format`, `brands`, `tags`, `chapters` and `transform` are applied.
+/// Samples are interleaved in decode order and the AAC priming comes from the edit lists of
+/// the inputs (TS inputs use the default), so `interleave_ms` and `aac_priming_samples` do not
+/// apply. Fragmented output and `preserve_timestamps` are not supported.
+pub fn write_concatenated_mp4<W, I, D>(
+    inputs: I,
+    sink: W,
+    options: &ConvertOptions,
+) -> io::Result<W>
+where
+    W: Read + Write + Seek,
+    I: IntoIterator<Item = io::Result<D>>,
+    D: AsRef<[u8]>,
+{
+    if options.fragment.is_some() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Concatenated output cannot be fragmented",
+        ));
+    }
+    if options.preserve_timestamps {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Concatenated output always starts at 0, source timestamps cannot be preserved",
+        ));
+    }
+
+    let brands = options
+        .brands
+        .clone()
+        .unwrap_or_else(|| options.format.default_brands());
+    let mut writer = StreamingMp4Writer::with_format(sink, options.format, &brands)?;
+    writer.set_tags(options.tags.clone());
+    writer.set_chapters(options.chapters.clone());
+
+    // Track layout and edits come from the first input
+    let mut codecs: Vec<StreamCodec> = Vec::new();
+    let mut outputs: Vec<OutputTrack> = Vec::new();
+    let mut start = 0i64; // output time of the current input (90kHz)
+    let mut count = 0;
+    for input in inputs {
+        let input = input?;
+        let source = source_mp4(input.as_ref())?;
+        let part = InputTracks::read(&source)?;
+        count += 1;
+        if count == 1 {
+            codecs = part.codecs.clone();
+            outputs = part.add_output_tracks(&mut writer, options);
+        } else {
+            part.check_compatible(&codecs, count)?;
+        }
+
+        let (origin, end) = part.time_span();
+
+        // Output media time of the first sample of each track, so that the input is presented
+        // from `start` on with the track offsets inside the input kept
+        let bases: Vec<i64> = part.tables.iter().map(first_dts).collect();
+        let offsets: Vec<i64> = part
+            .tables
+            .iter()
+            .zip(&part.shifts)
+            .zip(&bases)
+            .zip(&outputs)
+            .map(|(((table, &shift), &base), output)| {
+                let base_time = shift + scale(base, table.timescale);
+                rescale_signed(
+                    start - origin + base_time - output.delay,
+                    TIMESCALE,
+                    output.timescale,
+                ) + output.media_start
+            })
+            .collect();
+
+        for sample in samples_in_decode_order(&part.tables) {
+            let track = part
+                .tables
+                .iter()
+                .position(|table| table.track_id == sample.track_id)
+                .expect("sample of a source track");
+            let output = &mut outputs[track];
+            let source_timescale = part.tables[track].timescale;
+            let to_output = |time: i64| {
+                offsets[track]
+                    + rescale_signed(time - bases[track], source_timescale, output.timescale)
+            };
+
+            let dts = to_output(sample.dts as i64);
+            let end = to_output((sample.dts + sample.duration as u64) as i64);
+            if output.is_audio && output.pending.as_ref().is_some_and(|p| end <= p.end) {
+                continue;
+            }
+
+            let data = source
+                .get(sample.offset as usize..(sample.offset + sample.size as u64) as usize)
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Sample lies outside the file")
+                })?;
+            output.push(
+                &mut writer,
+                PendingSample {
+                    data: data.to_vec(),
+                    dts,
+                    cts: to_output(sample.cts()),
+                    end,
+                    is_sync: sample.is_sync,
+                },
+            )?;
+        }
+
+        start += end - origin;
+    }
+    if count == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "No input given"));
+    }
+
+    for output in &mut outputs {
+        output.flush(&mut writer)?;
+    }
+    if options.faststart {
+        writer.finish_with_faststart()
+    } else {
+        writer.finish()
+    }
+}
+
+/// Tracks of one input that are concatenated, video first
+struct InputTracks {
+    tables: Vec<TrackSamples>,
+    codecs: Vec<StreamCodec>,
+    shifts: Vec<i64>, // 90kHz
+}
+
+impl InputTracks {
+    fn read(mp4_data: &[u8]) -> io::Result<Self> {
+        let mut tracks = read_source_tracks(mp4_data)?;
+        let video = tracks
+            .iter()
+            .position(|track| matches!(track.codec, StreamCodec::H264 { .. }))
+            .map(|index| tracks.remove(index));
+        let audio = tracks
+            .iter()
+            .position(|track| matches!(track.codec, StreamCodec::Aac(_)))
+            .map(|index| tracks.remove(index));
+
+        let mut part = InputTracks {
+            tables: Vec::new(),
+            codecs: Vec::new(),
+            shifts: Vec::new(),
+        };
+        for track in video.into_iter().chain(audio) {
+            part.tables.push(track.samples);
+            part.codecs.push(track.codec);
+            part.shifts.push(track.shift);
+        }
+        Ok(part)
+    }
+
+    /// Fails unless this input (number `number`, 1-based) can continue the tracks of the first
+    /// input, which have `codecs`
+    fn check_compatible(&self, codecs: &[StreamCodec], number: usize) -> io::Result<()> {
+        let kinds = |codecs: &[StreamCodec]| -> Vec<bool> {
+            codecs
+                .iter()
+                .map(|codec| matches!(codec, StreamCodec::H264 { .. }))
+                .collect()
+        };
+        if kinds(codecs) != kinds(&self.codecs) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Input {} does not have the same video/audio tracks as input 1",
+                    number
+                ),
+            ));
+        }
+        for (codec, other_codec) in codecs.iter().zip(&self.codecs) {
+            if codec != other_codec {
+                let kind = match codec {
+                    StreamCodec::H264 { .. } => "H.264 SPS/PPS",
+                    StreamCodec::Aac(_) => "AAC configuration",
+                };
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Input {} has a different {} than input 1", number, kind),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds one output track per track of this input, with the edits that present the input
+    /// from 0 on
+    fn add_output_tracks<W: Write + Seek>(
+        &self,
+        writer: &mut StreamingMp4Writer<W>,
+        options: &ConvertOptions,
+    ) -> Vec<OutputTrack> {
+        let (origin, _) = self.time_span();
+        let mut outputs = Vec::with_capacity(self.tables.len());
+        for ((table, codec), &shift) in self.tables.iter().zip(&self.codecs).zip(&self.shifts) {
+            let (index, timescale) = add_output_track(writer, codec);
+            if let (StreamCodec::H264 { .. }, Some(transform)) = (codec, options.transform) {
+                writer.set_transform(index, transform);
+            }
+            let base = first_dts(table);
+            let delay = (shift + scale(base, table.timescale) - origin).max(0);
+            let media_start = rescale_signed(
+                unscale(origin - shift, table.timescale) - base,
+                table.timescale,
+                timescale,
+            )
+            .max(0);
+            writer.set_edit(index, delay as u64, media_start as u64);
+            outputs.push(OutputTrack {
+                index,
+                timescale,
+                is_audio: matches!(codec, StreamCodec::Aac(_)),
+                delay,
+                media_start,
+                pending: None,
+            });
+        }
+        outputs
+    }
+
+    /// First and last presented time over all tracks (90kHz). Samples before 0 are hidden by
+    /// the source edit list (e.g. AAC priming) and do not count.
+    fn time_span(&self) -> (i64, i64) {
+        let mut span: Option<(i64, i64)> = None;
+        for (table, &shift) in self.tables.iter().zip(&self.shifts) {
+            for sample in table.samples() {
+                let time = shift + scale(sample.cts(), table.timescale);
+                let end = time + scale(sample.duration as i64, table.timescale);
+                span = Some(span.map_or((time, end), |(first, last)| {
+                    (first.min(time), last.max(end))
+                }));
+            }
+        }
+        span.map_or((0, 0), |(first, last)| (first.max(0), last))
+    }
+}
+
+/// Output track of the concatenation, holding back its last sample until the next one fixes
+/// its duration
+struct OutputTrack {
+    index: usize,
+    timescale: u32,
+    is_audio: bool,
+    delay: i64,       // 90kHz
+    media_start: i64, // output timescale
+    pending: Option<PendingSample>,
+}
+
+/// Sample times in the output timescale
+struct PendingSample {
+    data: Vec<u8>,
+    dts: i64,
+    cts: i64,
+    end: i64, // dts + source duration
+    is_sync: bool,
+}
+
+impl OutputTrack {
+    fn push<W: Write + Seek>(
+        &mut self,
+        writer: &mut StreamingMp4Writer<W>,
+        mut sample: PendingSample,
+    ) -> io::Result<()> {
+        if let Some(previous) = self.pending.take() {
+            // Decode times must increase, even across an overlapping join
+            sample.dts = sample.dts.max(previous.dts + 1);
+            self.write(writer, previous, sample.dts)?;
+        }
+        self.pending = Some(sample);
+        Ok(())
+    }
+
+    fn flush<W: Write + Seek>(&mut self, writer: &mut StreamingMp4Writer<W>) -> io::Result<()> {
+        match self.pending.take() {
+            Some(sample) => {
+                let end = sample.end.max(sample.dts + 1);
+                self.write(writer, sample, end)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn write<W: Write + Seek>(
+        &self,
+        writer: &mut StreamingMp4Writer<W>,
+        sample: PendingSample,
+        next_dts: i64,
+    ) -> io::Result<()> {
+        writer.write_sample(
+            self.index,
+            &sample.data,
+            (next_dts - sample.dts) as u32,
+            (sample.cts - sample.dts) as i32,
+            sample.is_sync,
+        )
+    }
+}
+
+fn first_dts(table: &TrackSamples) -> i64 {
+    table.samples().next().map_or(0, |sample| sample.dts as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::read_mp4_tracks;
+    use crate::mp4_writer::test_support;
+    use crate::ts_parser::AacConfig;
+
+    /// 10 frames with a single keyframe
+    fn sample_mp4(audio_config: AacConfig, first_byte: u8) -> Vec<u8> {
+        test_support::sample_mp4(10, 10, audio_config, first_byte)
+    }
+
+    #[test]
+    fn test_concat_continues_timeline() {
+        // 10 video frames (300ms) and 10 audio frames (400ms) per input
+        let first = sample_mp4(AacConfig::default(), 0);
+        let second = sample_mp4(AacConfig::default(), 100);
+        let output = concat_media(&[&first, &second]).unwrap();
+
+        let tracks = read_mp4_tracks(&output).unwrap();
+        let video: Vec<_> = tracks[0].samples().collect();
+        assert_eq!(video.len(), 20);
+        // The second input starts after the longer audio track of the first one
+        assert_eq!(video[9].duration, 9000);
+        assert_eq!(video[10].dts, 36000);
+        assert!(video[10].is_sync);
+        assert_eq!(output[video[10].offset as usize + 5], 100);
+
+        let audio: Vec<_> = tracks[1].samples().collect();
+        assert_eq!(audio.len(), 20);
+        assert_eq!(audio[10].dts, 19200);
+        assert_eq!(output[audio[10].offset as usize + 1], 100);
+
+        let mono = AacConfig {
+            channels: 1,
+            ..AacConfig::default()
+        };
+        let third = sample_mp4(mono, 0);
+        assert!(concat_media(&[&first, &third]).is_err());
+
+        let options = ConvertOptions {
+            preserve_timestamps: true,
+            ..ConvertOptions::default()
+        };
+        assert!(write_concatenated_mp4([Ok(&first)], Cursor::new(Vec::new()), &options).is_err());
+    }
+}
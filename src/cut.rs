@@ -0,0 +1,575 @@
+use crate::mp4_parser::{samples_in_decode_order, SampleInfo, TrackSamples};
+use crate::mp4_writer::{StreamingMp4Writer, TIMESCALE};
+use crate::ts_parser::parse_sps;
+use crate::ts_writer::{read_source_tracks, regular_mp4, scale, StreamCodec};
+use std::borrow::Cow;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, Write};
+
+/// Time range of a cut
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CutOptions {
+    /// Start of the range in milliseconds from the first presented sample of the input
+    pub start_ms: u64,
+    /// End of the range in milliseconds (None = to the end of the input)
+    pub end_ms: Option<u64>,
+    /// Start playback exactly at `start_ms` through an edit list instead of at the
+    /// keyframe before it (the keyframe is still kept so the first frames can be decoded)
+    pub accurate: bool,
+}
+
+/// Cuts a time range out of a TS or MP4 (regular or fragmented) file without re-encoding
+///
+/// Video starts at the keyframe at or before the start time and keeps every frame presented
+/// before the end time. Audio frames overlapping the video range are kept, with the edit list
+/// hiding the part of the first frame that lies before the start. The output MP4 starts at 0.
+/// Only the first H.264 track and the AAC tracks are kept.
+pub fn cut_media(data: &[u8], options: &CutOptions) -> io::Result<Vec<u8>> {
+    if options.end_ms.is_some_and(|end| end <= options.start_ms) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "End time must be after the start time",
+        ));
+    }
+
+    let mp4_data = source_mp4(data)?;
+    cut_regular_mp4(&mp4_data, options)
+}
+
+/// A TS or MP4 input as a regular MP4: fragmented MP4 is defragmented, and TS is converted
+/// with its timestamps reset so times count from the start of the input
+pub(crate) fn source_mp4(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let is_mp4 =
+        data.len() >= 8 && matches!(&data[4..8], b"ftyp" | b"styp" | b"moov" | b"free" | b"wide");
+    if is_mp4 {
+        regular_mp4(data)
+    } else {
+        Ok(Cow::Owned(crate::convert_ts_to_mp4(data)?))
+    }
+}
+
+/// Limits of the parts written by `split_media`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitOptions {
+    /// Longest part in milliseconds
+    pub max_duration_ms: Option<u64>,
+    /// Largest part file in bytes
+    pub max_size: Option<u64>,
+}
+
+/// Splits a TS or MP4 file into standalone MP4 parts without re-encoding
+///
+/// Parts start at video keyframes (at audio frames for audio-only input): each part ends at
+/// the last keyframe that keeps it within `max_duration_ms` and `max_size`. A part only
+/// exceeds a limit when a single GOP already does. Every part starts at 0, and audio frames
+/// crossing a boundary are kept in both parts, trimmed by the edit list of the second one.
+///
+/// Each part is handed to `write_part` with its number as soon as it is written, so only one
+/// part is held in memory. Returns the number of parts.
+pub fn split_media<F>(data: &[u8], options: &SplitOptions, mut write_part: F) -> io::Result<usize>
+where
+    F: FnMut(usize, &[u8]) -> io::Result<()>,
+{
+    if options.max_duration_ms.is_none() && options.max_size.is_none() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "A maximum part duration or size is needed",
+        ));
+    }
+    if options.max_duration_ms == Some(0) || options.max_size == Some(0) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Part duration and size limits must be positive",
+        ));
+    }
+
+    let mp4_data = source_mp4(data)?;
+    let media = SourceMedia::read(&mp4_data)?;
+
+    // Parts start at sync samples of the video track, or of the first track without video
+    let lead = media.video.unwrap_or(0);
+    let lead_times = &media.times[lead];
+    let starts: Vec<u32> = media.samples[lead]
+        .iter()
+        .filter(|sample| sample.is_sync)
+        .map(|sample| sample.index)
+        .collect();
+    if starts.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "No video keyframe found",
+        ));
+    }
+    let start_time = |part: usize| lead_times[starts[part] as usize];
+    let end_time = |next: usize| {
+        starts
+            .get(next)
+            .map_or(i64::MAX, |&index| lead_times[index as usize])
+    };
+
+    // Sample sizes by presentation time, for estimating the size of a part before writing it
+    let mut sizes: Vec<(i64, u64)> = media
+        .samples
+        .iter()
+        .zip(&media.times)
+        .flat_map(|(samples, times)| {
+            samples
+                .iter()
+                .map(|sample| (times[sample.index as usize], sample.size as u64))
+        })
+        .collect();
+    sizes.sort_unstable();
+    let mut total = 0;
+    let prefix: Vec<u64> = std::iter::once(0)
+        .chain(sizes.iter().map(|&(_, size)| {
+            total += size + SAMPLE_OVERHEAD;
+            total
+        }))
+        .collect();
+    let estimate = |from: i64, to: i64| {
+        let first = sizes.partition_point(|&(time, _)| time < from);
+        let last = sizes.partition_point(|&(time, _)| time < to);
+        prefix[last] - prefix[first] + PART_OVERHEAD
+    };
+
+    let last_time = lead_times.iter().copied().max().unwrap_or(0);
+    let mut part = Vec::new();
+    let mut count = 0;
+    let mut current = 0;
+    while current < starts.len() {
+        let from = start_time(current);
+        let mut next = starts.len();
+        if let Some(duration) = options.max_duration_ms {
+            let limit = from + ms_to_ticks(duration);
+            if last_time >= limit {
+                // Keyframes are presented in decode order, so the last one within the limit
+                // is found by bisection
+                let within = starts[current + 1..]
+                    .partition_point(|&index| lead_times[index as usize] <= limit);
+                next = current + within.max(1);
+            }
+        }
+        if let Some(max_size) = options.max_size {
+            while next > current + 1 && estimate(from, end_time(next)) > max_size {
+                next -= 1;
+            }
+        }
+
+        // Drop keyframe intervals until the written part fits
+        loop {
+            let video_range = media.video.map(|_| {
+                let end = starts
+                    .get(next)
+                    .copied()
+                    .unwrap_or(media.samples[lead].len() as u32);
+                (starts[current], end)
+            });
+            part.clear();
+            let sink = Cursor::new(std::mem::take(&mut part));
+            let sink = media.write_range(&mp4_data, from, end_time(next), video_range, sink)?;
+            part = sink.into_inner();
+            let too_large = options.max_size.is_some_and(|max| part.len() as u64 > max);
+            if !too_large || next == current + 1 {
+                break;
+            }
+            next -= 1;
+        }
+        write_part(count, &part)?;
+        count += 1;
+        current = next;
+    }
+    Ok(count)
+}
+
+/// Bytes of sample tables per sample and of the file and track headers of a part, kept low so
+/// the estimate does not end parts early (parts that turn out too large are shortened)
+const SAMPLE_OVERHEAD: u64 = 8;
+const PART_OVERHEAD: u64 = 1024;
+
+/// H.264 and AAC tracks of a regular MP4 with the presentation time of every sample
+struct SourceMedia {
+    tables: Vec<TrackSamples>,
+    samples: Vec<Vec<SampleInfo>>, // in decode order
+    order: Vec<(usize, u32)>,      // track and sample index of every sample, in decode order
+    positions: Vec<Vec<usize>>,    // position of every sample of a track in `order`
+    codecs: Vec<StreamCodec>,
+    shifts: Vec<i64>,     // 90kHz
+    times: Vec<Vec<i64>>, // 90kHz, in decode order
+    origin: i64,          // first presented time
+    video: Option<usize>, // first H.264 track
+}
+
+/// Kept samples and edit of one track
+struct TrackCut {
+    first: u32,       // index of the first kept sample
+    end: u32,         // index after the last kept sample
+    delay: u64,       // empty edit in front of the track (90kHz)
+    media_start: i64, // presented media time of the cut start, in the source timescale
+}
+
+impl SourceMedia {
+    fn read(mp4_data: &[u8]) -> io::Result<Self> {
+        let mut media = SourceMedia {
+            tables: Vec::new(),
+            samples: Vec::new(),
+            order: Vec::new(),
+            positions: Vec::new(),
+            codecs: Vec::new(),
+            shifts: Vec::new(),
+            times: Vec::new(),
+            origin: 0,
+            video: None,
+        };
+        for track in read_source_tracks(mp4_data)? {
+            let samples: Vec<SampleInfo> = track.samples.samples().collect();
+            let times = samples
+                .iter()
+                .map(|sample| track.shift + scale(sample.cts(), track.samples.timescale))
+                .collect();
+            if media.video.is_none() && matches!(track.codec, StreamCodec::H264 { .. }) {
+                media.video = Some(media.tables.len());
+            }
+            media.positions.push(vec![0; samples.len()]);
+            media.samples.push(samples);
+            media.tables.push(track.samples);
+            media.codecs.push(track.codec);
+            media.shifts.push(track.shift);
+            media.times.push(times);
+        }
+        // Samples before 0 are hidden by the source edit list (e.g. AAC priming)
+        media.origin = media
+            .times
+            .iter()
+            .flatten()
+            .copied()
+            .min()
+            .unwrap_or(0)
+            .max(0);
+
+        // The decode order of all tracks is merged once; parts write a window of it
+        for sample in samples_in_decode_order(&media.tables) {
+            let track = media
+                .tables
+                .iter()
+                .position(|table| table.track_id == sample.track_id)
+                .expect("sample of a source track");
+            media.positions[track][sample.index as usize] = media.order.len();
+            media.order.push((track, sample.index));
+        }
+        Ok(media)
+    }
+
+    /// Writes the samples presented from `zero` to `end` (90kHz) as an MP4 starting at 0.
+    /// `video_range` is the decode-order range of the video track; audio is cut to match.
+    fn write_range<W: Read + Write + Seek>(
+        &self,
+        mp4_data: &[u8],
+        zero: i64,
+        end: i64,
+        video_range: Option<(u32, u32)>,
+        sink: W,
+    ) -> io::Result<W> {
+        let mut cuts = Vec::with_capacity(self.tables.len());
+        for (index, table) in self.tables.iter().enumerate() {
+            let range = match self.codecs[index] {
+                _ if Some(index) == self.video => video_range,
+                StreamCodec::H264 { .. } => None, // other video tracks
+                StreamCodec::Aac(_) => audio_range(
+                    &self.samples[index],
+                    table.timescale,
+                    &self.times[index],
+                    zero,
+                    end,
+                ),
+            };
+            let shift = self.shifts[index];
+            cuts.push(range.map(|(first, end)| {
+                let base = self.samples[index][first as usize].dts;
+                let base_time = shift + scale(base as i64, table.timescale);
+                TrackCut {
+                    first,
+                    end,
+                    delay: (base_time - zero).max(0) as u64,
+                    media_start: unscale(zero - shift, table.timescale) - base as i64,
+                }
+            }));
+        }
+
+        let mut writer = StreamingMp4Writer::new(sink)?;
+        let mut outputs = Vec::with_capacity(self.tables.len());
+        for ((table, codec), cut) in self.tables.iter().zip(&self.codecs).zip(&cuts) {
+            let Some(cut) = cut else {
+                outputs.push(None);
+                continue;
+            };
+            let (output, timescale) = add_output_track(&mut writer, codec);
+            let media_start = rescale_signed(cut.media_start, table.timescale, timescale);
+            writer.set_edit(output, cut.delay, media_start.max(0) as u64);
+            outputs.push(Some((output, timescale)));
+        }
+
+        // Only the window of the merged decode order holding the kept samples is walked
+        let kept = || {
+            cuts.iter()
+                .enumerate()
+                .filter_map(|(index, cut)| Some((index, cut.as_ref()?)))
+        };
+        let from = kept()
+            .map(|(index, cut)| self.positions[index][cut.first as usize])
+            .min()
+            .unwrap_or(0);
+        let to = kept()
+            .map(|(index, cut)| self.positions[index][cut.end as usize - 1] + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut bases = vec![None; self.tables.len()];
+        for &(index, sample_index) in &self.order[from..to] {
+            let sample = &self.samples[index][sample_index as usize];
+            let (Some(cut), Some((output, timescale))) = (&cuts[index], outputs[index]) else {
+                continue;
+            };
+            if sample.index < cut.first || sample.index >= cut.end {
+                continue;
+            }
+
+            // Decode times are rescaled from the first kept sample so rounding does not accumulate
+            let source_timescale = self.tables[index].timescale;
+            let base = *bases[index].get_or_insert(sample.dts) as i64;
+            let to_output = |time: i64| rescale_signed(time - base, source_timescale, timescale);
+            let dts = to_output(sample.dts as i64);
+            let duration = to_output((sample.dts + sample.duration as u64) as i64) - dts;
+            let composition_offset = to_output(sample.cts()) - dts;
+
+            let data = mp4_data
+                .get(sample.offset as usize..(sample.offset + sample.size as u64) as usize)
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Sample lies outside the file")
+                })?;
+            writer.write_sample(
+                output,
+                data,
+                duration as u32,
+                composition_offset as i32,
+                sample.is_sync,
+            )?;
+        }
+
+        writer.finish_with_faststart()
+    }
+}
+
+fn cut_regular_mp4(mp4_data: &[u8], options: &CutOptions) -> io::Result<Vec<u8>> {
+    let media = SourceMedia::read(mp4_data)?;
+    let start = media.origin + ms_to_ticks(options.start_ms);
+    let end = options
+        .end_ms
+        .map_or(i64::MAX, |end| media.origin + ms_to_ticks(end));
+    if media.times.iter().flatten().all(|&time| time < start) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Start time is past the end of the input",
+        ));
+    }
+
+    // The video track decides where the cut actually starts
+    let Some(video) = media.video else {
+        let sink = media.write_range(mp4_data, start, end, None, Cursor::new(Vec::new()))?;
+        return Ok(sink.into_inner());
+    };
+    let times = &media.times[video];
+    let (keyframe, last) = video_range(&media.tables[video], times, start, end)?;
+    let keyframe_time = times[keyframe as usize];
+    let zero = if options.accurate {
+        start.max(keyframe_time)
+    } else {
+        keyframe_time
+    };
+    let range = Some((keyframe, last));
+    let sink = media.write_range(mp4_data, zero, end, range, Cursor::new(Vec::new()))?;
+    Ok(sink.into_inner())
+}
+
+/// Decode-order range of the video samples to keep: from the last keyframe presented at or
+/// before `start` (or the first keyframe) through the last sample presented before `end`
+fn video_range(
+    table: &TrackSamples,
+    times: &[i64],
+    start: i64,
+    end: i64,
+) -> io::Result<(u32, u32)> {
+    let sync: Vec<usize> = table
+        .samples()
+        .filter(|sample| sample.is_sync)
+        .map(|sample| sample.index as usize)
+        .collect();
+    let keyframe = sync
+        .iter()
+        .rev()
+        .find(|&&index| times[index] <= start)
+        .or(sync.first())
+        .copied()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "No video keyframe found"))?;
+
+    let last = (keyframe..times.len())
+        .rev()
+        .find(|&index| times[index] < end)
+        .ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "No video frames in the cut range")
+        })?;
+    Ok((keyframe as u32, last as u32 + 1))
+}
+
+/// Range of the audio frames overlapping `zero..end`, None if there are none
+///
+/// Audio frames are presented in decode order, so both ends are found by bisection.
+fn audio_range(
+    samples: &[SampleInfo],
+    timescale: u32,
+    times: &[i64],
+    zero: i64,
+    end: i64,
+) -> Option<(u32, u32)> {
+    let first = samples.partition_point(|sample| {
+        times[sample.index as usize] + scale(sample.duration as i64, timescale) <= zero
+    });
+    let end = times.partition_point(|&time| time < end);
+    (first < end).then_some((first as u32, end as u32))
+}
+
+/// Adds a track for `codec` to `writer`, returning its index and timescale
+pub(crate) fn add_output_track<W: Write + Seek>(
+    writer: &mut StreamingMp4Writer<W>,
+    codec: &StreamCodec,
+) -> (usize, u32) {
+    match codec {
+        StreamCodec::H264 { sps, pps } => {
+            let sps = sps.first().map(Vec::as_slice).unwrap_or_default();
+            let pps = pps.first().map(Vec::as_slice).unwrap_or_default();
+            let (width, height) = parse_sps(sps).map_or((0, 0), |info| (info.width, info.height));
+            (writer.add_video_track(width, height, sps, pps), TIMESCALE)
+        }
+        StreamCodec::Aac(config) => (writer.add_audio_track(*config), config.sample_rate()),
+    }
+}
+
+/// Converts milliseconds to 90kHz ticks
+fn ms_to_ticks(ms: u64) -> i64 {
+    ms as i64 * (TIMESCALE / 1000) as i64
+}
+
+/// Converts a 90kHz time to `timescale`
+pub(crate) fn unscale(value: i64, timescale: u32) -> i64 {
+    rescale_signed(value, TIMESCALE, timescale)
+}
+
+pub(crate) fn rescale_signed(value: i64, from: u32, to: u32) -> i64 {
+    if from == 0 || from == to {
+        return value;
+    }
+    (value as i128 * to as i128 / from as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::{parse_container_box, parse_mp4, read_mp4_tracks, read_trak_edits};
+    use crate::mp4_writer::test_support;
+    use crate::ts_parser::AacConfig;
+
+    /// `frames` frames of 3000 ticks with a keyframe every 5, 48kHz audio in 1920-sample frames
+    fn sample_mp4(frames: u8) -> Vec<u8> {
+        test_support::sample_mp4(frames, 5, AacConfig::default(), 0)
+    }
+
+    #[test]
+    fn test_cut_snaps_to_keyframe_and_trims_audio() {
+        let mp4 = sample_mp4(10);
+
+        // Cut starts at 200ms (frame 6) and ends at 300ms (frame 9)
+        let mut options = CutOptions {
+            start_ms: 200,
+            end_ms: Some(300),
+            accurate: false,
+        };
+        let edits = |data: &[u8]| -> Vec<i64> {
+            let moov = parse_mp4(data).unwrap().moov.unwrap();
+            parse_container_box(&moov.data)
+                .unwrap()
+                .iter()
+                .filter(|b| &b.box_type == b"trak")
+                .map(|trak| {
+                    let edits = read_trak_edits(&trak.data).unwrap().unwrap_or_default();
+                    edits.last().map_or(0, |edit| edit.media_time)
+                })
+                .collect()
+        };
+
+        let cut = cut_media(&mp4, &options).unwrap();
+        let tracks = read_mp4_tracks(&cut).unwrap();
+        let video_samples: Vec<_> = tracks[0].samples().collect();
+        assert_eq!(video_samples.len(), 4);
+        assert!(video_samples[0].is_sync);
+        assert_eq!(cut[video_samples[0].offset as usize + 5], 5);
+        assert_eq!(video_samples[0].dts, 0);
+        // Audio frames 4..8 cover 160ms..320ms; the first 320 samples are hidden
+        let audio_samples: Vec<_> = tracks[1].samples().collect();
+        assert_eq!(audio_samples.len(), 4);
+        assert_eq!(cut[audio_samples[0].offset as usize + 1], 4);
+        assert_eq!(edits(&cut), vec![0, 320]);
+
+        // Frame-accurate start keeps the keyframe but presents from 200ms
+        options.accurate = true;
+        let cut = cut_media(&mp4, &options).unwrap();
+        let tracks = read_mp4_tracks(&cut).unwrap();
+        assert_eq!(tracks[0].sample_count(), 4);
+        // 200ms falls on the start of audio frame 5
+        assert_eq!(tracks[1].sample_count(), 3);
+        assert_eq!(edits(&cut), vec![3000, 0]);
+
+        options.end_ms = Some(100);
+        assert!(cut_media(&mp4, &options).is_err());
+    }
+
+    #[test]
+    fn test_split_at_keyframes() {
+        let mp4 = sample_mp4(20);
+
+        // Keyframes at 0, 166ms, 333ms and 500ms: 400ms parts end at the 333ms keyframe
+        let options = SplitOptions {
+            max_duration_ms: Some(400),
+            max_size: None,
+        };
+        let split = |options: &SplitOptions| {
+            let mut parts = Vec::new();
+            let count = split_media(&mp4, options, |number, part| {
+                assert_eq!(number, parts.len());
+                parts.push(part.to_vec());
+                Ok(())
+            })?;
+            assert_eq!(count, parts.len());
+            io::Result::Ok(parts)
+        };
+        let parts = split(&options).unwrap();
+        assert_eq!(parts.len(), 2);
+        for (part, first_frame) in parts.iter().zip([0, 10]) {
+            let tracks = read_mp4_tracks(part).unwrap();
+            let video: Vec<_> = tracks[0].samples().collect();
+            assert_eq!(video.len(), 10);
+            assert!(video[0].is_sync);
+            assert_eq!(video[0].dts, 0);
+            assert_eq!(part[video[0].offset as usize + 5], first_frame);
+        }
+
+        // A size limit below two GOPs gives one part per GOP, each within the limit
+        let max_size = parts[0].len() as u64 - 1;
+        let options = SplitOptions {
+            max_duration_ms: None,
+            max_size: Some(max_size),
+        };
+        let parts = split(&options).unwrap();
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|part| part.len() as u64 <= max_size));
+
+        assert!(split(&SplitOptions::default()).is_err());
+    }
+}
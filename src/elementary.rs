@@ -0,0 +1,515 @@
+use crate::mp4_writer::{
+    convert_annexb_to_avcc, is_sync_sample, rescale, DEFAULT_INTERLEAVE_MS, TIMESCALE,
+};
+use crate::muxer::{Mp4Writer, Mp4WriterOptions, Sample, TrackConfig};
+use crate::probe::StreamKind;
+use crate::ts_parser::{
+    extract_aac_frames, extract_h264_params, parse_adts_config, parse_ts_packets, MediaData,
+};
+use crate::ts_writer::{annexb_access_unit, read_source_tracks, regular_mp4, StreamCodec};
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::str::FromStr;
+
+const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// Video frame rate as a fraction (e.g. 30000/1001)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl FromStr for FrameRate {
+    type Err = io::Error;
+
+    /// Parses "25", "30000/1001" or a decimal rate; 23.976, 29.97 and 59.94 map to the
+    /// NTSC fractions
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid frame rate '{}'", s),
+            )
+        };
+
+        let rate = if let Some((num, den)) = s.split_once('/') {
+            FrameRate {
+                num: num.trim().parse().map_err(|_| invalid())?,
+                den: den.trim().parse().map_err(|_| invalid())?,
+            }
+        } else {
+            match s.trim() {
+                "23.976" | "23.98" => FrameRate {
+                    num: 24000,
+                    den: 1001,
+                },
+                "29.97" => FrameRate {
+                    num: 30000,
+                    den: 1001,
+                },
+                "59.94" => FrameRate {
+                    num: 60000,
+                    den: 1001,
+                },
+                other => {
+                    let value: f64 = other.parse().map_err(|_| invalid())?;
+                    if value.fract() == 0.0 {
+                        FrameRate {
+                            num: value as u32,
+                            den: 1,
+                        }
+                    } else {
+                        FrameRate {
+                            num: (value * 1000.0).round() as u32,
+                            den: 1000,
+                        }
+                    }
+                }
+            }
+        };
+
+        if rate.num == 0 || rate.den == 0 {
+            return Err(invalid());
+        }
+        Ok(rate)
+    }
+}
+
+/// How the frames of a raw H.264 stream are placed in time
+#[derive(Debug, Clone, PartialEq)]
+pub enum VideoTiming {
+    /// Constant frame rate, frames are presented in the order they are stored
+    FrameRate(FrameRate),
+    /// Presentation time in milliseconds of each frame, in stream (decode) order
+    Timestamps(Vec<f64>),
+}
+
+impl VideoTiming {
+    /// Reads a timing file with one presentation time in milliseconds per line, in stream
+    /// order. Empty lines and `#` comments (such as the mkvmerge "timestamp format v2"
+    /// header) are skipped.
+    pub fn parse_timing_file(text: &str) -> io::Result<Self> {
+        let mut timestamps = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let value: f64 = line.parse().map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid timestamp on line {}: '{}'", index + 1, line),
+                )
+            })?;
+            timestamps.push(value);
+        }
+        Ok(VideoTiming::Timestamps(timestamps))
+    }
+}
+
+/// Decode and presentation times of every frame, with the timescale they are in
+struct FrameTimes {
+    timescale: u32,
+    dts: Vec<u64>,
+    pts: Vec<u64>,
+    last_duration: u32,
+}
+
+fn frame_times(timing: &VideoTiming, frame_count: usize) -> io::Result<FrameTimes> {
+    match timing {
+        VideoTiming::FrameRate(rate) => {
+            // Scale small rates up so the track timescale stays close to 90kHz (25 -> 90000/3600)
+            let factor = (TIMESCALE / rate.num).max(1);
+            let duration = rate.den as u64 * factor as u64;
+            let times: Vec<u64> = (0..frame_count as u64).map(|i| i * duration).collect();
+            Ok(FrameTimes {
+                timescale: rate.num * factor,
+                dts: times.clone(),
+                pts: times,
+                last_duration: duration as u32,
+            })
+        }
+        VideoTiming::Timestamps(timestamps) => {
+            if timestamps.len() < frame_count {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Timing file has {} timestamps for {} frames",
+                        timestamps.len(),
+                        frame_count
+                    ),
+                ));
+            }
+
+            let pts: Vec<i64> = timestamps[..frame_count]
+                .iter()
+                .map(|ms| (ms * TIMESCALE as f64 / 1000.0).round() as i64)
+                .collect();
+
+            // Decode times are the presentation times in display order, delayed just enough
+            // that no frame is decoded after it is presented
+            let mut dts = pts.clone();
+            dts.sort_unstable();
+            let reorder_delay = dts
+                .iter()
+                .zip(&pts)
+                .map(|(dts, pts)| dts - pts)
+                .max()
+                .unwrap_or(0)
+                .max(0);
+            let base = dts
+                .first()
+                .map_or(0, |first| (first - reorder_delay).min(0));
+
+            let last_duration = match dts.as_slice() {
+                [.., previous, last] if last > previous => (last - previous) as u32,
+                _ => 3000,
+            };
+            Ok(FrameTimes {
+                timescale: TIMESCALE,
+                dts: dts
+                    .iter()
+                    .map(|dts| (dts - reorder_delay - base) as u64)
+                    .collect(),
+                pts: pts.iter().map(|pts| (pts - base) as u64).collect(),
+                last_duration,
+            })
+        }
+    }
+}
+
+/// Splits an Annex-B H.264 stream into access units (H.264 7.4.1.2.3): a new access unit
+/// starts at an AUD, SEI, SPS or PPS, or at a slice with first_mb_in_slice = 0, once the
+/// current one holds a slice
+fn split_access_units(stream: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut unit_start: Option<usize> = None;
+    let mut has_slice = false;
+    let mut i = 0;
+
+    while i + 3 < stream.len() {
+        let start_code_len = if stream[i..].starts_with(&[0, 0, 0, 1]) {
+            4
+        } else if stream[i..].starts_with(&[0, 0, 1]) {
+            3
+        } else {
+            i += 1;
+            continue;
+        };
+
+        let nal_start = i + start_code_len;
+        if nal_start >= stream.len() {
+            break;
+        }
+        let nal_type = stream[nal_start] & 0x1F;
+        let is_slice = nal_type == 1 || nal_type == 5;
+        // first_mb_in_slice is ue(v) coded, so 0 is a single leading 1 bit
+        let first_slice = is_slice && stream.get(nal_start + 1).is_some_and(|b| b & 0x80 != 0);
+
+        let starts_unit = matches!(nal_type, 6..=9 | 14..=18) || first_slice;
+        if has_slice && starts_unit {
+            if let Some(start) = unit_start {
+                units.push(&stream[start..i]);
+            }
+            unit_start = None;
+            has_slice = false;
+        }
+        unit_start.get_or_insert(i);
+        has_slice |= is_slice;
+
+        i = nal_start + 1;
+    }
+
+    if let Some(start) = unit_start {
+        units.push(&stream[start..]);
+    }
+
+    units
+}
+
+/// Muxes a raw Annex-B H.264 stream and an optional ADTS AAC stream into an MP4 written to
+/// `sink`. Video frames are timed by `timing`; audio frames follow each other from time 0.
+/// Chunks are interleaved every `DEFAULT_INTERLEAVE_MS` of decode time.
+pub fn mux_elementary_streams<W: Read + Write + Seek>(
+    video: &[u8],
+    audio: Option<&[u8]>,
+    timing: &VideoTiming,
+    sink: W,
+    options: &Mp4WriterOptions,
+) -> io::Result<W> {
+    let mut params = MediaData::new();
+    extract_h264_params(&mut params, video);
+    let (Some(sps), Some(pps)) = (&params.sps, &params.pps) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "No SPS/PPS found in the H.264 stream",
+        ));
+    };
+
+    let units = split_access_units(video);
+    if units.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "No frames found in the H.264 stream",
+        ));
+    }
+    let times = frame_times(timing, units.len())?;
+
+    let mut writer = Mp4Writer::new(sink, options.clone())?;
+    let video_track = writer.add_track(
+        TrackConfig::h264(params.width, params.height, sps, pps).with_timescale(times.timescale),
+    )?;
+
+    let (audio_frames, audio_track, audio_rate) = match audio {
+        Some(audio) => {
+            let config = parse_adts_config(audio).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "No ADTS header found in the AAC stream",
+                )
+            })?;
+            let (frames, _) = extract_aac_frames(audio);
+            let track = writer.add_track(TrackConfig::aac(config))?;
+            (frames, Some(track), config.sample_rate())
+        }
+        None => (Vec::new(), None, 1),
+    };
+
+    let interleave = DEFAULT_INTERLEAVE_MS as u64 * TIMESCALE as u64 / 1000;
+    let mut window_end = interleave;
+    let mut video_index = 0;
+    let mut audio_index = 0;
+
+    while video_index < units.len() || audio_index < audio_frames.len() {
+        while video_index < units.len()
+            && rescale(times.dts[video_index], times.timescale, TIMESCALE) < window_end
+        {
+            let data = convert_annexb_to_avcc(units[video_index]);
+            if !data.is_empty() {
+                let duration = times
+                    .dts
+                    .get(video_index + 1)
+                    .map_or(times.last_duration, |next| {
+                        (next - times.dts[video_index]) as u32
+                    });
+                let sample = Sample {
+                    data: &data,
+                    dts: times.dts[video_index],
+                    cts: times.pts[video_index],
+                    duration,
+                    is_sync: is_sync_sample(&data),
+                };
+                writer.write_sample(video_track, &sample)?;
+            }
+            video_index += 1;
+        }
+
+        if let Some(audio_track) = audio_track {
+            while audio_index < audio_frames.len()
+                && rescale(
+                    audio_index as u64 * AAC_FRAME_SAMPLES,
+                    audio_rate,
+                    TIMESCALE,
+                ) < window_end
+            {
+                let dts = audio_index as u64 * AAC_FRAME_SAMPLES;
+                let sample = Sample {
+                    data: &audio_frames[audio_index],
+                    dts,
+                    cts: dts,
+                    duration: AAC_FRAME_SAMPLES as u32,
+                    is_sync: true,
+                };
+                writer.write_sample(audio_track, &sample)?;
+                audio_index += 1;
+            }
+        }
+
+        window_end += interleave;
+    }
+
+    writer.finish()
+}
+
+/// Raw elementary stream demuxed from a TS or MP4 file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementaryStream {
+    /// MP4 track ID, or PID for TS input
+    pub id: u32,
+    pub kind: StreamKind,
+    /// H.264 Annex B (video) or AAC with ADTS headers (audio)
+    pub data: Vec<u8>,
+}
+
+impl ElementaryStream {
+    /// Usual file extension of the stream: h264 or aac
+    pub fn extension(&self) -> &'static str {
+        match self.kind {
+            StreamKind::Video => "h264",
+            _ => "aac",
+        }
+    }
+}
+
+/// Demuxes the H.264 and AAC streams of a TS or MP4 (regular or fragmented) file
+///
+/// MP4 samples are converted to Annex B with an access unit delimiter and the SPS/PPS from
+/// `avcC` in front of every keyframe, and AAC frames get ADTS headers rebuilt from the `esds`
+/// AudioSpecificConfig. TS video is returned as carried in the PES packets. Tracks with other
+/// codecs are skipped; TS input yields at most one video and one audio stream.
+pub fn extract_elementary_streams(data: &[u8]) -> io::Result<Vec<ElementaryStream>> {
+    let is_mp4 =
+        data.len() >= 8 && matches!(&data[4..8], b"ftyp" | b"styp" | b"moov" | b"free" | b"wide");
+    if !is_mp4 {
+        let media_data = parse_ts_packets(data)?;
+        let mut streams = Vec::new();
+        if !media_data.video_stream.is_empty() {
+            streams.push(ElementaryStream {
+                id: media_data.video_pid.unwrap_or_default() as u32,
+                kind: StreamKind::Video,
+                data: media_data.video_stream,
+            });
+        }
+        if let (Some(config), false) = (media_data.audio_config, media_data.audio_frames.is_empty())
+        {
+            let mut audio = Vec::new();
+            for frame in &media_data.audio_frames {
+                audio.extend_from_slice(&config.adts_header(frame.len()));
+                audio.extend_from_slice(frame);
+            }
+            streams.push(ElementaryStream {
+                id: media_data.audio_pid.unwrap_or_default() as u32,
+                kind: StreamKind::Audio,
+                data: audio,
+            });
+        }
+        return Ok(streams);
+    }
+
+    let mp4_data = regular_mp4(data)?;
+    let mut streams = Vec::new();
+    for track in read_source_tracks(&mp4_data)? {
+        let mut output = Vec::new();
+        for sample in track.samples.samples() {
+            let data = mp4_data
+                .get(sample.offset as usize..(sample.offset + sample.size as u64) as usize)
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Sample lies outside the file")
+                })?;
+            match &track.codec {
+                StreamCodec::H264 { sps, pps } => {
+                    output.extend(annexb_access_unit(data, sample.is_sync, sps, pps)?)
+                }
+                StreamCodec::Aac(config) => {
+                    output.extend_from_slice(&config.adts_header(data.len()));
+                    output.extend_from_slice(data);
+                }
+            }
+        }
+        streams.push(ElementaryStream {
+            id: track.samples.track_id,
+            kind: match track.codec {
+                StreamCodec::H264 { .. } => StreamKind::Video,
+                StreamCodec::Aac(_) => StreamKind::Audio,
+            },
+            data: output,
+        });
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_access_units_without_aud() {
+        let stream = [
+            0, 0, 0, 1, 0x67, 0x42, // SPS
+            0, 0, 0, 1, 0x68, 0xCE, // PPS
+            0, 0, 1, 0x65, 0x88, 0x11, // IDR, first_mb_in_slice = 0
+            0, 0, 1, 0x65, 0x20, 0x22, // second slice of the same frame
+            0, 0, 1, 0x41, 0x9A, 0x33, // next frame
+            0, 0, 0, 1, 0x06, 0x05, // SEI starts the third frame
+            0, 0, 1, 0x41, 0x9B, 0x44,
+        ];
+
+        let units = split_access_units(&stream);
+        assert_eq!(units.len(), 3);
+        assert_eq!(units[0].len(), 24);
+        assert_eq!(units[1], &[0, 0, 1, 0x41, 0x9A, 0x33]);
+        assert_eq!(units[2][4], 0x06);
+    }
+
+    #[test]
+    fn test_frame_times_from_timestamps() {
+        assert_eq!(
+            "29.97".parse::<FrameRate>().unwrap(),
+            FrameRate {
+                num: 30000,
+                den: 1001
+            }
+        );
+        let times = frame_times(&VideoTiming::FrameRate("25".parse().unwrap()), 2).unwrap();
+        assert_eq!((times.timescale, times.dts[1]), (90000, 3600));
+
+        // I P B B in stream order, presented as I B B P
+        let timing =
+            VideoTiming::parse_timing_file("# timestamp format v2\n0\n100\n33.333\n66.667\n")
+                .unwrap();
+        let times = frame_times(&timing, 4).unwrap();
+        assert_eq!(times.pts, vec![3000, 12000, 6000, 9000]);
+        assert_eq!(times.dts, vec![0, 3000, 6000, 9000]);
+        assert!(frame_times(&timing, 5).is_err());
+    }
+
+    #[test]
+    fn test_extract_round_trip() {
+        let sps = [
+            0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00,
+            0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+        ];
+        let pps = [0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+        let config = crate::ts_parser::AacConfig::default();
+
+        // AUD, SPS and PPS before the keyframe, AUD before every other frame
+        let mut video = Vec::new();
+        let mut audio = Vec::new();
+        for i in 0..4u8 {
+            video.extend_from_slice(&[0, 0, 0, 1, 0x09, 0xF0]);
+            if i == 0 {
+                for parameter_set in [&sps[..], &pps[..]] {
+                    video.extend_from_slice(&[0, 0, 0, 1]);
+                    video.extend_from_slice(parameter_set);
+                }
+            }
+            video.extend_from_slice(&[0, 0, 0, 1, if i == 0 { 0x65 } else { 0x41 }, 0x88, i]);
+            audio.extend_from_slice(&config.adts_header(3));
+            audio.extend_from_slice(&[0x21, i, 0x40]);
+        }
+
+        let timing = VideoTiming::FrameRate("30".parse().unwrap());
+        let mp4 = mux_elementary_streams(
+            &video,
+            Some(&audio),
+            &timing,
+            std::io::Cursor::new(Vec::new()),
+            &Mp4WriterOptions::default(),
+        )
+        .unwrap()
+        .into_inner();
+
+        let streams = extract_elementary_streams(&mp4).unwrap();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(
+            (streams[0].kind, streams[0].extension()),
+            (StreamKind::Video, "h264")
+        );
+        assert_eq!(streams[0].data, video);
+        assert_eq!(
+            (streams[1].kind, streams[1].extension()),
+            (StreamKind::Audio, "aac")
+        );
+        assert_eq!(streams[1].data, audio);
+    }
+}
@@ -0,0 +1,613 @@
+use crate::chapters::Chapter;
+use crate::metadata::{build_udta, Mp4Tags};
+use crate::mp4_writer::{
+    box_header, build_ftyp, build_mvhd, build_trak, rescale, shift_forward, write_box,
+    write_full_box, DisplayTransform, FileBrands, OutputFormat, SampleWriter, TrackMedia,
+    TrackState, TIMESCALE,
+};
+use crate::ts_parser::AacConfig;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Default target duration of one fragment
+pub const DEFAULT_FRAGMENT_DURATION_MS: u32 = 2000;
+
+// trun sample flags
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000; // sample_depends_on = 2 (does not depend on others)
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000; // sample_depends_on = 1, sample_is_non_sync_sample
+
+/// Options for fragmented MP4 output
+#[derive(Debug, Clone)]
+pub struct FragmentOptions {
+    /// A fragment is closed at the first keyframe after this much media
+    pub target_duration_ms: u32,
+    /// Index all fragments with a segment index (sidx) placed after the init segment
+    pub sidx: bool,
+    /// Append a movie fragment random access box (mfra) for seeking
+    pub mfra: bool,
+}
+
+impl Default for FragmentOptions {
+    fn default() -> Self {
+        FragmentOptions {
+            target_duration_ms: DEFAULT_FRAGMENT_DURATION_MS,
+            sidx: false,
+            mfra: false,
+        }
+    }
+}
+
+struct PendingSample {
+    data: Vec<u8>,
+    duration: u32,
+    composition_offset: i32,
+    is_sync: bool,
+}
+
+struct FragmentTrack {
+    state: TrackState,
+    pending: Vec<PendingSample>,
+    decode_time: u64,                   // decode time of the first pending sample
+    random_access: Vec<(u64, u64, u8)>, // (decode time, moof offset, traf number) for tfra
+}
+
+/// A written moof + mdat pair, as referenced from sidx
+struct FragmentRef {
+    size: u64,
+    duration: u64,                   // in the reference track timescale
+    earliest_presentation_time: u64, // in the reference track timescale
+    starts_with_sap: bool,
+}
+
+/// Fragmented MP4 writer: an init segment (ftyp + moov with mvex) followed by moof/mdat pairs.
+///
+/// Only the samples of the fragment being built are kept in memory. A fragment is closed at
+/// the first keyframe of the reference track (the first video track) once the target duration
+/// has been collected. Offsets are counted from the first byte written to `sink`.
+pub struct FragmentedMp4Writer<W: Write> {
+    sink: W,
+    options: FragmentOptions,
+    tracks: Vec<FragmentTrack>,
+    position: u64,
+    init_size: Option<u64>,
+    sequence_number: u32,
+    fragments: Vec<FragmentRef>,
+    brands: FileBrands,
+    tags: Mp4Tags,
+    chapters: Vec<Chapter>,
+}
+
+impl<W: Write> FragmentedMp4Writer<W> {
+    pub fn new(sink: W, options: FragmentOptions) -> Self {
+        FragmentedMp4Writer {
+            sink,
+            options,
+            tracks: Vec::new(),
+            position: 0,
+            init_size: None,
+            sequence_number: 1,
+            fragments: Vec::new(),
+            brands: FileBrands {
+                major_brand: *b"iso6",
+                minor_version: 0,
+                compatible_brands: vec![*b"iso6", *b"isom", *b"mp41"],
+            },
+            tags: Mp4Tags::default(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Adds an H.264 video track and returns its index.
+    /// All tracks must be added before the first sample is written.
+    pub fn add_video_track(&mut self, width: u16, height: u16, sps: &[u8], pps: &[u8]) -> usize {
+        self.add_track(TrackMedia::Video {
+            width,
+            height,
+            sps: sps.to_vec(),
+            pps: pps.to_vec(),
+        })
+    }
+
+    /// Adds an AAC audio track using the sample rate of `config` as its timescale
+    /// and returns its index
+    pub fn add_audio_track(&mut self, config: AacConfig) -> usize {
+        self.add_track(TrackMedia::Audio { config })
+    }
+
+    fn add_track(&mut self, media: TrackMedia) -> usize {
+        let track_id = self.tracks.len() as u32 + 1;
+        self.tracks.push(FragmentTrack {
+            state: TrackState::new(track_id, media),
+            pending: Vec::new(),
+            decode_time: 0,
+            random_access: Vec::new(),
+        });
+        self.tracks.len() - 1
+    }
+
+    /// Presents `track` from media time `media_start` (track timescale) after an
+    /// empty edit of `delay` (movie timescale).
+    pub fn set_edit(&mut self, track: usize, delay: u64, media_start: u64) {
+        self.tracks[track].state.delay = delay;
+        self.tracks[track].state.media_start = media_start;
+    }
+
+    /// Sets the rotation and flip written to the tkhd matrix of a video track
+    pub fn set_transform(&mut self, track: usize, transform: DisplayTransform) {
+        self.tracks[track].state.transform = transform;
+    }
+
+    /// Sets the ftyp brands of the init segment (iso6, isom and mp41 by default)
+    pub fn set_brands(&mut self, brands: FileBrands) {
+        self.brands = brands;
+    }
+
+    /// Sets the metadata written to moov/udta in the init segment
+    pub fn set_tags(&mut self, tags: Mp4Tags) {
+        self.tags = tags;
+    }
+
+    /// Sets the chapters written as a chpl box in the init segment (the duration is not known
+    /// yet, so there is no chapter text track)
+    pub fn set_chapters(&mut self, chapters: Vec<Chapter>) {
+        self.chapters = chapters;
+    }
+
+    /// Starts the decode timeline of `track` at `offset` (movie timescale), carried by the
+    /// first tfdt in the track timescale
+    pub fn set_start_offset(&mut self, track: usize, offset: u64) {
+        let timescale = self.tracks[track].state.timescale;
+        self.set_base_decode_time(track, rescale(offset, TIMESCALE, timescale));
+    }
+
+    /// Starts the decode timeline of `track` at `decode_time` (track timescale)
+    pub(crate) fn set_base_decode_time(&mut self, track: usize, decode_time: u64) {
+        self.tracks[track].decode_time = decode_time;
+    }
+
+    pub(crate) fn track_mut(&mut self, track: usize) -> &mut TrackState {
+        &mut self.tracks[track].state
+    }
+
+    /// Queues one sample for the current fragment, closing the fragment first when `data`
+    /// is a keyframe of the reference track and the target duration has been reached.
+    /// `duration` and `composition_offset` are in the track timescale.
+    pub fn write_sample(
+        &mut self,
+        track: usize,
+        data: &[u8],
+        duration: u32,
+        composition_offset: i32,
+        is_sync: bool,
+    ) -> io::Result<()> {
+        if track >= self.tracks.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown track index {}", track),
+            ));
+        }
+
+        if track == self.reference_track() && is_sync {
+            let timescale = self.tracks[track].state.timescale as u64;
+            let target = self.options.target_duration_ms as u64 * timescale / 1000;
+            let collected: u64 = self.tracks[track]
+                .pending
+                .iter()
+                .map(|s| s.duration as u64)
+                .sum();
+
+            if collected > 0 && collected >= target {
+                let cut = self.tracks[track].decode_time + collected;
+                self.write_fragment(Some(cut))?;
+            }
+        }
+
+        self.tracks[track].pending.push(PendingSample {
+            data: data.to_vec(),
+            duration,
+            composition_offset,
+            is_sync,
+        });
+        Ok(())
+    }
+
+    /// Writes the remaining samples and the optional mfra box.
+    /// A sidx needs a seekable sink, see `finish_with_sidx`.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.options.sidx {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "sidx requires a seekable sink, use finish_with_sidx",
+            ));
+        }
+
+        self.write_fragment(None)?;
+        if self.options.mfra {
+            self.write_mfra(0)?;
+        }
+        self.sink.flush()?;
+
+        Ok(self.sink)
+    }
+
+    /// Fragments are cut on the keyframes of the first video track (or the first track)
+    fn reference_track(&self) -> usize {
+        self.tracks
+            .iter()
+            .position(|t| matches!(t.state.media, TrackMedia::Video { .. }))
+            .unwrap_or(0)
+    }
+
+    fn write_init_segment(&mut self) -> io::Result<()> {
+        if self.init_size.is_some() {
+            return Ok(());
+        }
+
+        let mut init = Vec::new();
+
+        init.extend_from_slice(&build_ftyp(&self.brands));
+
+        // moov with empty sample tables and one trex per track
+        let mut moov = Vec::new();
+        moov.extend_from_slice(&build_mvhd(0, self.tracks.len() as u32 + 1));
+        for track in &self.tracks {
+            moov.extend_from_slice(&build_trak(&track.state, 0, OutputFormat::Mp4));
+        }
+
+        let mut mvex = Vec::new();
+        for track in &self.tracks {
+            let mut trex = Vec::new();
+            trex.extend_from_slice(&track.state.track_id.to_be_bytes());
+            trex.extend_from_slice(&1u32.to_be_bytes()); // default sample description index
+            trex.extend_from_slice(&0u32.to_be_bytes()); // default sample duration
+            trex.extend_from_slice(&0u32.to_be_bytes()); // default sample size
+            trex.extend_from_slice(&0u32.to_be_bytes()); // default sample flags
+            write_full_box(&mut mvex, b"trex", 0, 0, &trex);
+        }
+        write_box(&mut moov, b"mvex", &mvex);
+        moov.extend_from_slice(&build_udta(&self.tags, &self.chapters));
+        write_box(&mut init, b"moov", &moov);
+
+        self.sink.write_all(&init)?;
+        self.position += init.len() as u64;
+        self.init_size = Some(self.position);
+        Ok(())
+    }
+
+    /// Writes one moof + mdat pair holding the pending samples that start before `cut`
+    /// (a decode time in the reference track timescale), or all pending samples when `cut`
+    /// is None
+    fn write_fragment(&mut self, cut: Option<u64>) -> io::Result<()> {
+        self.write_init_segment()?;
+
+        let reference_timescale = self.tracks[self.reference_track()].state.timescale as u128;
+
+        let counts: Vec<usize> = self
+            .tracks
+            .iter()
+            .map(|track| match cut {
+                None => track.pending.len(),
+                Some(cut) => {
+                    // Compared across timescales: decode_time / timescale < cut / reference
+                    let cut = cut as u128 * track.state.timescale as u128;
+                    let mut decode_time = track.decode_time;
+                    track
+                        .pending
+                        .iter()
+                        .take_while(|sample| {
+                            let starts_before = decode_time as u128 * reference_timescale < cut;
+                            decode_time += sample.duration as u64;
+                            starts_before
+                        })
+                        .count()
+                }
+            })
+            .collect();
+
+        if counts.iter().all(|&count| count == 0) {
+            return Ok(());
+        }
+
+        let payload_size: u64 = self
+            .tracks
+            .iter()
+            .zip(&counts)
+            .flat_map(|(track, &count)| &track.pending[..count])
+            .map(|sample| sample.data.len() as u64)
+            .sum();
+        let mdat_header = box_header(b"mdat", payload_size);
+        let mdat_header_size = mdat_header.len() as u64;
+
+        // The trun data offsets depend on the moof size, which does not depend on their values
+        let moof_size = self.build_moof(&counts, 0)?.len() as u64;
+        let moof = self.build_moof(&counts, moof_size + mdat_header_size)?;
+
+        self.sink.write_all(&moof)?;
+        self.sink.write_all(&mdat_header)?;
+
+        let moof_offset = self.position;
+        let reference = self.reference_track();
+        let mut traf_number = 0u8;
+        let mut fragment = FragmentRef {
+            size: moof.len() as u64 + mdat_header_size + payload_size,
+            duration: 0,
+            earliest_presentation_time: 0,
+            starts_with_sap: false,
+        };
+
+        for (index, (track, &count)) in self.tracks.iter_mut().zip(&counts).enumerate() {
+            if count == 0 {
+                continue;
+            }
+            traf_number = traf_number.saturating_add(1);
+
+            let first = &track.pending[0];
+            if first.is_sync {
+                track
+                    .random_access
+                    .push((track.decode_time, moof_offset, traf_number));
+            }
+
+            let duration: u64 = track.pending[..count]
+                .iter()
+                .map(|s| s.duration as u64)
+                .sum();
+            if index == reference {
+                fragment.duration = duration;
+                fragment.earliest_presentation_time =
+                    (track.decode_time as i64 + first.composition_offset as i64).max(0) as u64;
+                fragment.starts_with_sap = first.is_sync;
+            }
+
+            for sample in track.pending.drain(..count) {
+                self.sink.write_all(&sample.data)?;
+            }
+            track.decode_time += duration;
+        }
+
+        self.position += fragment.size;
+        self.fragments.push(fragment);
+        self.sequence_number += 1;
+        Ok(())
+    }
+
+    /// Builds moof for the first `counts[i]` pending samples of each track.
+    /// `data_start` is the distance from the start of moof to the first sample in mdat.
+    fn build_moof(&self, counts: &[usize], data_start: u64) -> io::Result<Vec<u8>> {
+        let mut moof = Vec::new();
+        write_full_box(
+            &mut moof,
+            b"mfhd",
+            0,
+            0,
+            &self.sequence_number.to_be_bytes(),
+        );
+
+        let mut data_offset = data_start;
+        for (track, &count) in self.tracks.iter().zip(counts) {
+            if count == 0 {
+                continue;
+            }
+            let samples = &track.pending[..count];
+
+            let mut traf = Vec::new();
+
+            // tfhd - sample data offsets are relative to the start of moof
+            write_full_box(
+                &mut traf,
+                b"tfhd",
+                0,
+                0x020000, // default-base-is-moof
+                &track.state.track_id.to_be_bytes(),
+            );
+
+            // tfdt
+            write_full_box(&mut traf, b"tfdt", 1, 0, &track.decode_time.to_be_bytes());
+
+            // trun - duration, size, flags and (if needed) composition offset per sample
+            let has_composition = samples.iter().any(|s| s.composition_offset != 0);
+            let version = if samples.iter().any(|s| s.composition_offset < 0) {
+                1
+            } else {
+                0
+            };
+            let mut flags = 0x000001 | 0x000100 | 0x000200 | 0x000400;
+            if has_composition {
+                flags |= 0x000800;
+            }
+
+            let offset = i32::try_from(data_offset)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Fragment exceeds 2 GB"))?;
+
+            let mut trun = Vec::new();
+            trun.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&offset.to_be_bytes());
+            for sample in samples {
+                let sample_flags = if sample.is_sync {
+                    SYNC_SAMPLE_FLAGS
+                } else {
+                    NON_SYNC_SAMPLE_FLAGS
+                };
+                trun.extend_from_slice(&sample.duration.to_be_bytes());
+                trun.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                trun.extend_from_slice(&sample_flags.to_be_bytes());
+                if has_composition {
+                    trun.extend_from_slice(&sample.composition_offset.to_be_bytes());
+                }
+                data_offset += sample.data.len() as u64;
+            }
+            write_full_box(&mut traf, b"trun", version, flags, &trun);
+
+            write_box(&mut moof, b"traf", &traf);
+        }
+
+        let mut result = Vec::new();
+        write_box(&mut result, b"moof", &moof);
+        Ok(result)
+    }
+
+    /// Appends mfra with one tfra per track; `offset_shift` is added to every moof offset
+    fn write_mfra(&mut self, offset_shift: u64) -> io::Result<()> {
+        let mut mfra = Vec::new();
+
+        for track in &self.tracks {
+            let mut tfra = Vec::new();
+            tfra.extend_from_slice(&track.state.track_id.to_be_bytes());
+            tfra.extend_from_slice(&0u32.to_be_bytes()); // 1-byte traf/trun/sample numbers
+            tfra.extend_from_slice(&(track.random_access.len() as u32).to_be_bytes());
+            for &(time, moof_offset, traf_number) in &track.random_access {
+                tfra.extend_from_slice(&time.to_be_bytes());
+                tfra.extend_from_slice(&(moof_offset + offset_shift).to_be_bytes());
+                tfra.push(traf_number);
+                tfra.push(1); // trun number
+                tfra.push(1); // sample number
+            }
+            write_full_box(&mut mfra, b"tfra", 1, 0, &tfra);
+        }
+
+        // mfro carries the size of the whole mfra box
+        let mfra_size = 8 + mfra.len() as u32 + 16;
+        write_full_box(&mut mfra, b"mfro", 0, 0, &mfra_size.to_be_bytes());
+
+        let mut result = Vec::new();
+        write_box(&mut result, b"mfra", &mfra);
+        self.sink.write_all(&result)?;
+        self.position += result.len() as u64;
+        Ok(())
+    }
+
+    fn build_sidx(&self) -> Vec<u8> {
+        let reference = &self.tracks[self.reference_track()].state;
+
+        let mut sidx = Vec::new();
+        sidx.extend_from_slice(&reference.track_id.to_be_bytes());
+        sidx.extend_from_slice(&reference.timescale.to_be_bytes());
+        let earliest = self
+            .fragments
+            .first()
+            .map(|f| f.earliest_presentation_time)
+            .unwrap_or(0);
+        sidx.extend_from_slice(&earliest.to_be_bytes());
+        sidx.extend_from_slice(&0u64.to_be_bytes()); // first offset (fragments follow sidx)
+        sidx.extend_from_slice(&0u16.to_be_bytes()); // reserved
+        sidx.extend_from_slice(&(self.fragments.len() as u16).to_be_bytes());
+        for fragment in &self.fragments {
+            // reference_type = 0 (media), referenced_size
+            sidx.extend_from_slice(&(fragment.size as u32 & 0x7FFF_FFFF).to_be_bytes());
+            sidx.extend_from_slice(&(fragment.duration as u32).to_be_bytes());
+            // starts_with_SAP, SAP_type = 1, SAP_delta_time = 0
+            let sap = if fragment.starts_with_sap {
+                0x9000_0000u32
+            } else {
+                0
+            };
+            sidx.extend_from_slice(&sap.to_be_bytes());
+        }
+
+        let mut result = Vec::new();
+        write_full_box(&mut result, b"sidx", 1, 0, &sidx);
+        result
+    }
+}
+
+impl<W: Read + Write + Seek> FragmentedMp4Writer<W> {
+    /// Writes the remaining samples, then inserts a sidx indexing every fragment right
+    /// after the init segment (moving the fragments in place) and appends mfra if requested
+    pub fn finish_with_sidx(mut self) -> io::Result<W> {
+        self.write_fragment(None)?;
+
+        if self.fragments.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Too many fragments for a single sidx",
+            ));
+        }
+
+        let end = self.sink.stream_position()?;
+        let start = end - self.position;
+        let init_size = self.init_size.unwrap_or(0);
+
+        let sidx = self.build_sidx();
+        shift_forward(&mut self.sink, start + init_size, end, sidx.len() as u64)?;
+        self.sink.seek(SeekFrom::Start(start + init_size))?;
+        self.sink.write_all(&sidx)?;
+        self.sink.seek(SeekFrom::Start(end + sidx.len() as u64))?;
+        self.position += sidx.len() as u64;
+
+        if self.options.mfra {
+            self.write_mfra(sidx.len() as u64)?;
+        }
+        self.sink.flush()?;
+
+        Ok(self.sink)
+    }
+}
+
+impl<W: Write> SampleWriter for FragmentedMp4Writer<W> {
+    fn add_video_track(&mut self, width: u16, height: u16, sps: &[u8], pps: &[u8]) -> usize {
+        FragmentedMp4Writer::add_video_track(self, width, height, sps, pps)
+    }
+
+    fn add_audio_track(&mut self, config: AacConfig) -> usize {
+        FragmentedMp4Writer::add_audio_track(self, config)
+    }
+
+    fn set_edit(&mut self, track: usize, delay: u64, media_start: u64) {
+        FragmentedMp4Writer::set_edit(self, track, delay, media_start)
+    }
+
+    fn set_transform(&mut self, track: usize, transform: DisplayTransform) {
+        FragmentedMp4Writer::set_transform(self, track, transform)
+    }
+
+    fn set_start_offset(&mut self, track: usize, offset: u64) {
+        FragmentedMp4Writer::set_start_offset(self, track, offset)
+    }
+
+    fn write_sample(
+        &mut self,
+        track: usize,
+        data: &[u8],
+        duration: u32,
+        composition_offset: i32,
+        is_sync: bool,
+    ) -> io::Result<()> {
+        FragmentedMp4Writer::write_sample(self, track, data, duration, composition_offset, is_sync)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::{defragment_mp4, find_box_path, parse_mp4, parse_stsz};
+    use crate::mp4_writer::test_support::{tiny_video_track, write_tiny_frames};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_fragments_cut_at_keyframes() {
+        let options = FragmentOptions {
+            target_duration_ms: 100,
+            sidx: true,
+            mfra: true,
+        };
+        let mut writer = FragmentedMp4Writer::new(Cursor::new(Vec::new()), options);
+        let video = tiny_video_track(&mut writer);
+
+        // Keyframe every 4th frame (133ms at 30fps) -> 3 fragments
+        write_tiny_frames(&mut writer, video, 12, 4);
+
+        let data = writer.finish_with_sidx().unwrap().into_inner();
+        let mp4 = parse_mp4(&data).unwrap();
+        let moofs = mp4.boxes.iter().filter(|b| &b.box_type == b"moof").count();
+        assert_eq!(moofs, 3);
+        assert!(mp4.boxes.iter().any(|b| &b.box_type == b"sidx"));
+        assert!(mp4.boxes.iter().any(|b| &b.box_type == b"mfra"));
+
+        // Defragmenting gives back every sample
+        let regular = defragment_mp4(&data).unwrap();
+        let moov = parse_mp4(&regular).unwrap().moov.unwrap();
+        let stsz = find_box_path(&moov.data, &[b"trak", b"mdia", b"minf", b"stbl", b"stsz"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(parse_stsz(&stsz.data).unwrap().len(), 12);
+    }
+}
@@ -0,0 +1,682 @@
+use crate::box_tree::{parse_box_tree, BoxBody, BoxNode, FullBox};
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+
+/// Lists longer than this are shortened in text output unless `InspectOptions::full` is set
+const TEXT_LIST_LIMIT: usize = 10;
+
+/// Output format of `inspect_mp4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InspectFormat {
+    /// Indented tree with one box per line
+    #[default]
+    Text,
+    /// JSON array of boxes with `type`, `offset`, `size`, decoded fields and `children`
+    Json,
+}
+
+impl FromStr for InspectFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(InspectFormat::Text),
+            "json" => Ok(InspectFormat::Json),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown inspect format '{}' (expected text or json)", s),
+            )),
+        }
+    }
+}
+
+/// Options for `inspect_mp4`
+#[derive(Debug, Clone, Default)]
+pub struct InspectOptions {
+    pub format: InspectFormat,
+    /// Only show boxes whose path ends with this slash-separated path ("trak/mdia/mdhd", "trun"),
+    /// each with its children. `*` matches any box type.
+    pub path: Option<String>,
+    /// Print every table entry in text output instead of the first few
+    pub full: bool,
+}
+
+/// Decoded field value
+enum Value {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<Value>),
+    Record(Vec<(&'static str, Value)>),
+}
+
+/// A box with its position, decoded fields and children
+struct BoxReport {
+    path: String,
+    box_type: String,
+    offset: u64,
+    size: u64,
+    version: Option<(u8, u32)>,
+    fields: Vec<(&'static str, Value)>,
+    children: Vec<BoxReport>,
+}
+
+/// Describes the box hierarchy of an MP4 file with the decoded fields of the common boxes
+pub fn inspect_mp4(data: &[u8], options: &InspectOptions) -> io::Result<String> {
+    let tree = parse_box_tree(data)?;
+    let mut reports = describe_boxes(&tree, 0, "");
+
+    if let Some(path) = &options.path {
+        let filter: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if filter.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Empty box path"));
+        }
+        let mut matches = Vec::new();
+        collect_matches(reports, &filter, &mut matches);
+        reports = matches;
+    }
+
+    let mut output = String::new();
+    match options.format {
+        InspectFormat::Text => {
+            for report in &reports {
+                write_text(&mut output, report, 0, options.path.is_some(), options.full);
+            }
+        }
+        InspectFormat::Json => {
+            write_json_list(&mut output, &reports, 0, write_json_box);
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
+
+fn describe_boxes(nodes: &[BoxNode], mut offset: u64, parent_path: &str) -> Vec<BoxReport> {
+    let mut reports = Vec::new();
+    for node in nodes {
+        let size = node.size();
+        let box_type = String::from_utf8_lossy(&node.box_type).into_owned();
+        let path = if parent_path.is_empty() {
+            box_type.clone()
+        } else {
+            format!("{}/{}", parent_path, box_type)
+        };
+
+        // Children always come last in the box
+        let children = node.children();
+        let children_size: u64 = children.iter().map(BoxNode::size).sum();
+        let children = describe_boxes(children, offset + size - children_size, &path);
+
+        let (version, fields) = match &node.body {
+            BoxBody::Full {
+                version,
+                flags,
+                content,
+            } => (Some((*version, *flags)), describe_full_box(content, *flags)),
+            BoxBody::FullContainer { version, flags, .. } => (Some((*version, *flags)), Vec::new()),
+            BoxBody::Raw(data) => (None, describe_raw_box(&node.box_type, data)),
+            BoxBody::Container(_) => (None, Vec::new()),
+        };
+
+        reports.push(BoxReport {
+            path,
+            box_type,
+            offset,
+            size,
+            version,
+            fields,
+            children,
+        });
+        offset += size;
+    }
+    reports
+}
+
+fn collect_matches(reports: Vec<BoxReport>, filter: &[&str], matches: &mut Vec<BoxReport>) {
+    for mut report in reports {
+        let segments: Vec<&str> = report.path.split('/').collect();
+        let matched = segments.len() >= filter.len()
+            && segments[segments.len() - filter.len()..]
+                .iter()
+                .zip(filter)
+                .all(|(segment, pattern)| *pattern == "*" || segment == pattern);
+
+        if matched {
+            matches.push(report);
+        } else {
+            let children = std::mem::take(&mut report.children);
+            collect_matches(children, filter, matches);
+        }
+    }
+}
+
+fn uint_list<T: Into<u64> + Copy>(values: &[T]) -> Value {
+    Value::List(values.iter().map(|&v| Value::UInt(v.into())).collect())
+}
+
+fn fixed_16_16(value: u32) -> Value {
+    Value::Float(value as f64 / 65536.0)
+}
+
+fn fixed_8_8(value: u16) -> Value {
+    Value::Float(value as f64 / 256.0)
+}
+
+fn flags_value(flags: u32) -> Value {
+    Value::Text(format!("0x{:08X}", flags))
+}
+
+fn fourcc(code: &[u8]) -> Value {
+    Value::Text(String::from_utf8_lossy(code).into_owned())
+}
+
+/// Packed ISO 639-2/T code of mdhd (three 5-bit letters)
+fn language(code: u16) -> Value {
+    let letters = [(code >> 10) & 0x1F, (code >> 5) & 0x1F, code & 0x1F];
+    Value::Text(letters.iter().map(|&c| (c as u8 + 0x60) as char).collect())
+}
+
+fn describe_full_box(content: &FullBox, flags: u32) -> Vec<(&'static str, Value)> {
+    match content {
+        FullBox::Mvhd(mvhd) => vec![
+            ("creation_time", Value::UInt(mvhd.creation_time)),
+            ("modification_time", Value::UInt(mvhd.modification_time)),
+            ("timescale", Value::UInt(mvhd.timescale as u64)),
+            ("duration", Value::UInt(mvhd.duration)),
+            ("rate", fixed_16_16(mvhd.rate)),
+            ("volume", fixed_8_8(mvhd.volume)),
+            ("matrix", uint_list(&mvhd.matrix)),
+            ("next_track_id", Value::UInt(mvhd.next_track_id as u64)),
+        ],
+        FullBox::Tkhd(tkhd) => vec![
+            ("creation_time", Value::UInt(tkhd.creation_time)),
+            ("modification_time", Value::UInt(tkhd.modification_time)),
+            ("track_id", Value::UInt(tkhd.track_id as u64)),
+            ("duration", Value::UInt(tkhd.duration)),
+            ("layer", Value::Int(tkhd.layer as i16 as i64)),
+            ("alternate_group", Value::UInt(tkhd.alternate_group as u64)),
+            ("volume", fixed_8_8(tkhd.volume)),
+            (
+                "matrix",
+                Value::List(
+                    tkhd.matrix
+                        .iter()
+                        .map(|&v| Value::Int(v as i32 as i64))
+                        .collect(),
+                ),
+            ),
+            ("width", fixed_16_16(tkhd.width)),
+            ("height", fixed_16_16(tkhd.height)),
+        ],
+        FullBox::Mdhd(mdhd) => vec![
+            ("creation_time", Value::UInt(mdhd.creation_time)),
+            ("modification_time", Value::UInt(mdhd.modification_time)),
+            ("timescale", Value::UInt(mdhd.timescale as u64)),
+            ("duration", Value::UInt(mdhd.duration)),
+            ("language", language(mdhd.language)),
+        ],
+        FullBox::Hdlr(hdlr) => {
+            // ISO names are null-terminated, QuickTime names are Pascal strings
+            let name = match hdlr.name.first() {
+                Some(&len) if len as usize == hdlr.name.len() - 1 && len > 0 => &hdlr.name[1..],
+                _ => &hdlr.name[..],
+            };
+            let name = String::from_utf8_lossy(name);
+            let mut fields = Vec::new();
+            // QuickTime keeps the component type (mhlr/dhlr) here
+            if hdlr.pre_defined != [0; 4] {
+                fields.push(("component_type", fourcc(&hdlr.pre_defined)));
+            }
+            fields.push(("handler_type", fourcc(&hdlr.handler_type)));
+            fields.push(("name", Value::Text(name.trim_end_matches('\0').to_string())));
+            fields
+        }
+        FullBox::Elst(entries) => vec![(
+            "entries",
+            Value::List(
+                entries
+                    .iter()
+                    .map(|entry| {
+                        Value::Record(vec![
+                            ("segment_duration", Value::UInt(entry.segment_duration)),
+                            ("media_time", Value::Int(entry.media_time)),
+                            ("media_rate", fixed_16_16(entry.media_rate)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )],
+        FullBox::Stts(entries) => vec![
+            (
+                "sample_count",
+                Value::UInt(entries.iter().map(|&(count, _)| count as u64).sum()),
+            ),
+            (
+                "duration",
+                Value::UInt(
+                    entries
+                        .iter()
+                        .map(|&(count, delta)| count as u64 * delta as u64)
+                        .sum(),
+                ),
+            ),
+            (
+                "entries",
+                Value::List(
+                    entries
+                        .iter()
+                        .map(|&(count, delta)| {
+                            Value::Record(vec![
+                                ("count", Value::UInt(count as u64)),
+                                ("delta", Value::UInt(delta as u64)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ],
+        FullBox::Ctts(entries) => vec![(
+            "entries",
+            Value::List(
+                entries
+                    .iter()
+                    .map(|&(count, offset)| {
+                        Value::Record(vec![
+                            ("count", Value::UInt(count as u64)),
+                            ("offset", Value::Int(offset as i64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )],
+        FullBox::Stss(samples) => vec![("samples", uint_list(samples))],
+        FullBox::Stsc(entries) => vec![(
+            "entries",
+            Value::List(
+                entries
+                    .iter()
+                    .map(|entry| {
+                        Value::Record(vec![
+                            ("first_chunk", Value::UInt(entry.first_chunk as u64)),
+                            (
+                                "samples_per_chunk",
+                                Value::UInt(entry.samples_per_chunk as u64),
+                            ),
+                            (
+                                "sample_description_index",
+                                Value::UInt(entry.sample_description_index as u64),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )],
+        FullBox::Stsz(stsz) => vec![
+            ("sample_size", Value::UInt(stsz.sample_size as u64)),
+            ("sample_count", Value::UInt(stsz.sample_count as u64)),
+            ("sizes", uint_list(&stsz.entry_sizes)),
+        ],
+        FullBox::Stco(offsets) => vec![("offsets", uint_list(offsets))],
+        FullBox::Co64(offsets) => vec![("offsets", uint_list(offsets))],
+        FullBox::Mehd { fragment_duration } => {
+            vec![("fragment_duration", Value::UInt(*fragment_duration))]
+        }
+        FullBox::Trex(trex) => vec![
+            ("track_id", Value::UInt(trex.track_id as u64)),
+            (
+                "default_sample_description_index",
+                Value::UInt(trex.default_sample_description_index as u64),
+            ),
+            (
+                "default_sample_duration",
+                Value::UInt(trex.default_sample_duration as u64),
+            ),
+            (
+                "default_sample_size",
+                Value::UInt(trex.default_sample_size as u64),
+            ),
+            (
+                "default_sample_flags",
+                flags_value(trex.default_sample_flags),
+            ),
+        ],
+        FullBox::Mfhd { sequence_number } => {
+            vec![("sequence_number", Value::UInt(*sequence_number as u64))]
+        }
+        FullBox::Tfhd(tfhd) => {
+            let mut fields = vec![("track_id", Value::UInt(tfhd.track_id as u64))];
+            if let Some(offset) = tfhd.base_data_offset {
+                fields.push(("base_data_offset", Value::UInt(offset)));
+            }
+            if let Some(index) = tfhd.sample_description_index {
+                fields.push(("sample_description_index", Value::UInt(index as u64)));
+            }
+            if let Some(duration) = tfhd.default_sample_duration {
+                fields.push(("default_sample_duration", Value::UInt(duration as u64)));
+            }
+            if let Some(size) = tfhd.default_sample_size {
+                fields.push(("default_sample_size", Value::UInt(size as u64)));
+            }
+            if let Some(sample_flags) = tfhd.default_sample_flags {
+                fields.push(("default_sample_flags", flags_value(sample_flags)));
+            }
+            if flags & 0x020000 != 0 {
+                fields.push(("default_base_is_moof", Value::UInt(1)));
+            }
+            fields
+        }
+        FullBox::Tfdt {
+            base_media_decode_time,
+        } => vec![(
+            "base_media_decode_time",
+            Value::UInt(*base_media_decode_time),
+        )],
+        FullBox::Trun(trun) => {
+            let mut fields = vec![("sample_count", Value::UInt(trun.samples.len() as u64))];
+            if let Some(offset) = trun.data_offset {
+                fields.push(("data_offset", Value::Int(offset as i64)));
+            }
+            if let Some(sample_flags) = trun.first_sample_flags {
+                fields.push(("first_sample_flags", flags_value(sample_flags)));
+            }
+            let samples = trun
+                .samples
+                .iter()
+                .map(|sample| {
+                    let mut record = Vec::new();
+                    if let Some(duration) = sample.duration {
+                        record.push(("duration", Value::UInt(duration as u64)));
+                    }
+                    if let Some(size) = sample.size {
+                        record.push(("size", Value::UInt(size as u64)));
+                    }
+                    if let Some(sample_flags) = sample.flags {
+                        record.push(("flags", flags_value(sample_flags)));
+                    }
+                    if let Some(offset) = sample.composition_offset {
+                        record.push(("composition_offset", Value::Int(offset as i64)));
+                    }
+                    Value::Record(record)
+                })
+                .collect();
+            fields.push(("samples", Value::List(samples)));
+            fields
+        }
+    }
+}
+
+/// Fields of boxes the tree keeps raw: ftyp brands and stsd sample entries
+fn describe_raw_box(box_type: &[u8; 4], data: &[u8]) -> Vec<(&'static str, Value)> {
+    match box_type {
+        b"ftyp" | b"styp" if data.len() >= 8 => vec![
+            ("major_brand", fourcc(&data[..4])),
+            (
+                "minor_version",
+                Value::UInt(u32::from_be_bytes(data[4..8].try_into().unwrap()) as u64),
+            ),
+            (
+                "compatible_brands",
+                Value::List(data[8..].chunks_exact(4).map(fourcc).collect()),
+            ),
+        ],
+        b"stsd" if data.len() >= 8 => {
+            let mut entries = Vec::new();
+            let mut offset = 8;
+            while offset + 8 <= data.len() {
+                let size =
+                    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+                if size < 8 || offset + size > data.len() {
+                    break;
+                }
+                let format = &data[offset + 4..offset + 8];
+                let entry = &data[offset + 8..offset + size];
+                let read_u16 = |at: usize| {
+                    entry
+                        .get(at..at + 2)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]) as u64)
+                };
+
+                let mut record = vec![("format", fourcc(format))];
+                match format {
+                    b"avc1" | b"avc3" | b"hvc1" | b"hev1" | b"mp4v" => {
+                        if let (Some(width), Some(height)) = (read_u16(24), read_u16(26)) {
+                            record.push(("width", Value::UInt(width)));
+                            record.push(("height", Value::UInt(height)));
+                        }
+                    }
+                    b"mp4a" | b"ac-3" | b"ec-3" | b"Opus" => {
+                        if let (Some(channels), Some(rate)) = (read_u16(16), read_u16(24)) {
+                            record.push(("channel_count", Value::UInt(channels)));
+                            record.push(("sample_rate", Value::UInt(rate)));
+                        }
+                    }
+                    _ => {}
+                }
+                entries.push(Value::Record(record));
+                offset += size;
+            }
+            vec![("entries", Value::List(entries))]
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn write_text(output: &mut String, report: &BoxReport, depth: usize, show_path: bool, full: bool) {
+    let name = if show_path && depth == 0 {
+        &report.path
+    } else {
+        &report.box_type
+    };
+    let _ = write!(
+        output,
+        "{}{} @{} size={}",
+        "  ".repeat(depth),
+        name,
+        report.offset,
+        report.size
+    );
+    if let Some((version, flags)) = report.version {
+        let _ = write!(output, " version={} flags=0x{:06X}", version, flags);
+    }
+    for (key, value) in &report.fields {
+        output.push(' ');
+        output.push_str(key);
+        output.push('=');
+        write_text_value(output, value, full);
+    }
+    output.push('\n');
+
+    for child in &report.children {
+        write_text(output, child, depth + 1, show_path, full);
+    }
+}
+
+fn write_text_value(output: &mut String, value: &Value, full: bool) {
+    match value {
+        Value::UInt(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Int(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Float(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Text(v) => output.push_str(v),
+        Value::List(values) => {
+            let shown = if full {
+                values.len()
+            } else {
+                values.len().min(TEXT_LIST_LIMIT)
+            };
+            output.push('[');
+            for (i, value) in values[..shown].iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                write_text_value(output, value, full);
+            }
+            if shown < values.len() {
+                let _ = write!(output, ", ... {} more", values.len() - shown);
+            }
+            output.push(']');
+        }
+        Value::Record(fields) => {
+            output.push('(');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    output.push(' ');
+                }
+                output.push_str(key);
+                output.push('=');
+                write_text_value(output, value, full);
+            }
+            output.push(')');
+        }
+    }
+}
+
+fn write_json_box(output: &mut String, report: &BoxReport, depth: usize) {
+    let indent = "  ".repeat(depth + 1);
+    output.push_str("{\n");
+    let _ = write!(output, "{}\"type\": ", indent);
+    write_json_string(output, &report.box_type);
+    let _ = write!(output, ",\n{}\"path\": ", indent);
+    write_json_string(output, &report.path);
+    let _ = write!(
+        output,
+        ",\n{}\"offset\": {},\n{}\"size\": {}",
+        indent, report.offset, indent, report.size
+    );
+    if let Some((version, flags)) = report.version {
+        let _ = write!(
+            output,
+            ",\n{}\"version\": {},\n{}\"flags\": {}",
+            indent, version, indent, flags
+        );
+    }
+    for (key, value) in &report.fields {
+        let _ = write!(output, ",\n{}\"{}\": ", indent, key);
+        write_json_value(output, value);
+    }
+    if !report.children.is_empty() {
+        let _ = write!(output, ",\n{}\"children\": ", indent);
+        write_json_list(output, &report.children, depth + 1, write_json_box);
+    }
+    let _ = write!(output, "\n{}}}", "  ".repeat(depth));
+}
+
+fn write_json_list<T>(
+    output: &mut String,
+    items: &[T],
+    depth: usize,
+    write_item: fn(&mut String, &T, usize),
+) {
+    if items.is_empty() {
+        output.push_str("[]");
+        return;
+    }
+    let indent = "  ".repeat(depth + 1);
+    output.push_str("[\n");
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            output.push_str(",\n");
+        }
+        output.push_str(&indent);
+        write_item(output, item, depth + 1);
+    }
+    let _ = write!(output, "\n{}]", "  ".repeat(depth));
+}
+
+/// Field values are written on one line; table entries can be long
+fn write_json_value(output: &mut String, value: &Value) {
+    match value {
+        Value::UInt(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Int(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Float(v) => {
+            let _ = write!(output, "{}", v);
+        }
+        Value::Text(v) => write_json_string(output, v),
+        Value::List(values) => {
+            output.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                write_json_value(output, value);
+            }
+            output.push(']');
+        }
+        Value::Record(fields) => {
+            output.push('{');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                let _ = write!(output, "\"{}\": ", key);
+                write_json_value(output, value);
+            }
+            output.push('}');
+        }
+    }
+}
+
+pub(crate) fn write_json_string(output: &mut String, value: &str) {
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_writer::test_support::tiny_mp4;
+
+    #[test]
+    fn test_inspect_path_filter() {
+        let data = tiny_mp4(12);
+
+        let text = inspect_mp4(&data, &InspectOptions::default()).unwrap();
+        assert!(text.starts_with("ftyp @0 size=32 major_brand=isom minor_version=512"));
+        assert!(text.contains("\n      mdhd @"));
+
+        // Text output shortens long tables unless asked not to
+        let options = InspectOptions {
+            path: Some("stbl/stsz".to_string()),
+            ..Default::default()
+        };
+        let text = inspect_mp4(&data, &options).unwrap();
+        assert!(text.starts_with("moov/trak/mdia/minf/stbl/stsz @"));
+        assert!(text.contains("sample_count=12 sizes=[6, 6, 6, 6, 6, 6, 6, 6, 6, 6, ... 2 more]"));
+
+        let options = InspectOptions {
+            format: InspectFormat::Json,
+            path: Some("trak/*/mdhd".to_string()),
+            full: false,
+        };
+        let json = inspect_mp4(&data, &options).unwrap();
+        assert!(json.contains("\"type\": \"mdhd\""));
+        assert!(json.contains("\"timescale\": 90000"));
+        assert!(json.contains("\"duration\": 36000"));
+        assert!(json.contains("\"language\": \"und\""));
+        assert!(!json.contains("\"stsz\""));
+    }
+}
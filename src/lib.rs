@@ -1,20 +1,91 @@
-use std::io;
+use mp4_writer::ReadBack;
+use std::io::{self, Cursor, Read, Seek, Write};
 
+mod box_tree;
+mod chapters;
+mod concat;
+mod cut;
+mod elementary;
 mod fmp4_processor;
+mod fmp4_writer;
+mod inspect;
+mod metadata;
 mod mp4_parser;
 mod mp4_writer;
+mod muxer;
+mod probe;
+mod remux;
 mod thumbnail;
 mod ts_parser;
+mod ts_writer;
 
 // Re-export thumbnail functions
 pub use thumbnail::{extract_thumbnail_from_mp4, extract_thumbnail_from_ts};
 
 // Re-export MP4 parser functions
-pub use mp4_parser::{defragment_mp4, reset_mp4_timestamps};
+pub use mp4_parser::{
+    defragment_mp4, defragment_mp4_interleaved, read_mp4_chapters, read_mp4_tags, read_mp4_tracks,
+    read_mp4_transform, read_track_samples, reset_mp4_timestamps, samples_in_decode_order,
+    write_mp4_brands, write_mp4_chapters, write_mp4_tags, write_mp4_transform, DecodeOrderSamples,
+    EditListEntry, MediaType, SampleInfo, SampleIter, TrackSamples,
+};
+
+// Re-export the box tree model
+pub use box_tree::{
+    parse_box_tree, write_box_tree, BoxBody, BoxNode, FullBox, Hdlr, HeaderForm, Mdhd, Mvhd,
+    StscEntry, Stsz, Tfhd, Tkhd, Trex, Trun, TrunSample,
+};
+
+// Re-export box inspection
+pub use inspect::{inspect_mp4, InspectFormat, InspectOptions};
+
+// Re-export stream probing
+pub use probe::{probe, ContainerKind, ProbeReport, ProgramInfo, StreamInfo, StreamKind};
 
 // Re-export fMP4 processor
 pub use fmp4_processor::FragmentedMP4Processor;
 
+// Re-export TS parser types
+pub use ts_parser::AacConfig;
+
+// Re-export trimming and splitting
+pub use cut::{cut_media, split_media, CutOptions, SplitOptions};
+
+// Re-export concatenation
+pub use concat::{concat_media, write_concatenated_mp4};
+
+// Re-export track selection
+pub use remux::{
+    select_mp4_tracks, write_selected_mp4_tracks, TrackFilter, TrackMatch, TrackSelection,
+};
+
+// Re-export MP4 to TS remuxing
+pub use ts_writer::{convert_mp4_to_ts, write_mp4_as_ts};
+
+// Re-export streaming MP4 writer
+pub use mp4_writer::{
+    AudioRepairReport, ConversionReport, ConvertOptions, DisplayTransform, FileBrands,
+    OutputFormat, Rotation, StreamingMp4Writer, TimestampMode, DEFAULT_AAC_PRIMING_SAMPLES,
+    DEFAULT_INTERLEAVE_MS,
+};
+
+// Re-export fragmented MP4 writer
+pub use fmp4_writer::{FragmentOptions, FragmentedMp4Writer, DEFAULT_FRAGMENT_DURATION_MS};
+
+// Re-export the general-purpose muxer
+pub use muxer::{Mp4Writer, Mp4WriterOptions, Sample, TrackCodec, TrackConfig};
+
+// Re-export metadata tags
+pub use metadata::{CoverArt, CoverFormat, FreeformTag, Mp4Tags, DEFAULT_FREEFORM_MEAN};
+
+// Re-export chapters
+pub use chapters::{parse_chapters, parse_time, Chapter};
+
+// Re-export elementary stream muxing and demuxing
+pub use elementary::{
+    extract_elementary_streams, mux_elementary_streams, ElementaryStream, FrameRate, VideoTiming,
+};
+
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
@@ -121,21 +192,50 @@ pub fn init_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
+/// Convert TS to MP4 with timestamps starting from 0
 pub fn convert_ts_to_mp4(ts_data: &[u8]) -> io::Result<Vec<u8>> {
-    convert_ts_to_mp4_with_options(ts_data, false)
+    convert_ts_to_mp4_with_options(ts_data, true)
 }
 
+/// Convert TS to MP4; with `reset_timestamps = false` the earliest source PTS is kept as the
+/// start time through an empty edit
 pub fn convert_ts_to_mp4_with_options(
     ts_data: &[u8],
     reset_timestamps: bool,
 ) -> io::Result<Vec<u8>> {
-    // Parse TS packets
-    let media_data = ts_parser::parse_ts_packets(ts_data)?;
+    let options = ConvertOptions {
+        preserve_timestamps: !reset_timestamps,
+        ..ConvertOptions::default()
+    };
+    let sink = Cursor::new(Vec::new());
+    let (sink, _) =
+        mp4_writer::write_mp4_with_options(ts_data, sink, &options, Some(ReadBack::new()))?;
+    Ok(sink.into_inner())
+}
 
-    // Create MP4 container
-    let mp4_data = mp4_writer::create_mp4_with_options(media_data, reset_timestamps)?;
+/// Convert the TS read from `source` (e.g. a `BufReader<File>`) to MP4 and stream the result
+/// into `sink` (e.g. a `File`).
+/// Packets are converted as they are read and sample data is written to mdat right away, so
+/// memory use does not grow with the input; with `options.faststart` the moov box is moved to
+/// the front of the file at the end.
+/// The report tells whether timestamps were reset or the source start time was preserved.
+pub fn convert_ts_to_mp4_writer<R: Read, W: Read + Write + Seek>(
+    source: R,
+    sink: W,
+    options: &ConvertOptions,
+) -> io::Result<(W, ConversionReport)> {
+    mp4_writer::write_mp4_with_options(source, sink, options, Some(ReadBack::new()))
+}
 
-    Ok(mp4_data)
+/// Like `convert_ts_to_mp4_writer`, for a sink that cannot be read back (e.g. a file opened
+/// write-only). `options.faststart` and a fragmented sidx must be off, since both passes read
+/// the written file; the moov box stays at the end.
+pub fn convert_ts_to_mp4_write_only<R: Read, W: Write + Seek>(
+    source: R,
+    sink: W,
+    options: &ConvertOptions,
+) -> io::Result<(W, ConversionReport)> {
+    mp4_writer::write_mp4_with_options(source, sink, options, None)
 }
 
 /// Convert MP4 with timestamp reset (equivalent to CLI: ts2mp4 convert --reset-timestamps)
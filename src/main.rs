@@ -1,7 +1,8 @@
-use clap::{Parser, Subcommand};
-use std::fs;
-use std::io::{self, ErrorKind};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "ts2mp4")]
@@ -16,9 +17,9 @@ struct Cli {
 enum Commands {
     /// Convert TS or MP4 file (with optional timestamp reset)
     Convert {
-        /// Input file path (TS or MP4)
-        #[arg(short, long)]
-        input: PathBuf,
+        /// Input file path (TS or MP4); several inputs are concatenated into one MP4
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
 
         /// Output MP4 file path
         #[arg(short, long)]
@@ -27,6 +28,210 @@ enum Commands {
         /// Reset timestamps to start from 0 (like ffmpeg -avoid_negative_ts make_zero)
         #[arg(short, long, default_value_t = false)]
         reset_timestamps: bool,
+
+        /// Keep the source start time of TS input through an empty edit instead of starting from 0
+        #[arg(long, default_value_t = false, conflicts_with = "reset_timestamps")]
+        preserve_timestamps: bool,
+
+        /// Leave the moov box at the end of the file (skip the faststart pass)
+        #[arg(long, default_value_t = false)]
+        no_faststart: bool,
+
+        /// Interleave audio and video in chunks of this many milliseconds (0 = one chunk per track)
+        /// [default: 500]
+        #[arg(long)]
+        interleave_ms: Option<u32>,
+
+        /// Write fragmented MP4 (init segment followed by moof/mdat fragments)
+        #[arg(long, default_value_t = false)]
+        fragmented: bool,
+
+        /// Target fragment duration in milliseconds (fragments are cut at keyframes)
+        #[arg(long, default_value_t = ts2mp4::DEFAULT_FRAGMENT_DURATION_MS)]
+        fragment_duration_ms: u32,
+
+        /// Write a segment index (sidx) for fragmented output
+        #[arg(long, default_value_t = false, requires = "fragmented")]
+        sidx: bool,
+
+        /// Append a movie fragment random access box (mfra) for fragmented output
+        #[arg(long, default_value_t = false, requires = "fragmented")]
+        mfra: bool,
+
+        /// AAC encoder priming samples hidden at the start of the audio track (0 = keep all)
+        /// [default: 1024]
+        #[arg(long)]
+        aac_priming: Option<u32>,
+
+        #[command(flatten)]
+        tags: TagArgs,
+
+        /// Chapter list (JSON or ffmetadata), written as a QuickTime chapter track and a Nero chpl box
+        #[arg(long, value_name = "PATH")]
+        chapters: Option<PathBuf>,
+
+        #[command(flatten)]
+        container: ContainerArgs,
+
+        /// Rotate the video clockwise on display (0, 90, 180 or 270), without re-encoding
+        #[arg(long, value_name = "DEGREES")]
+        rotate: Option<ts2mp4::Rotation>,
+
+        /// Mirror the video on display (applied before the rotation)
+        #[arg(long, value_enum)]
+        flip: Option<Flip>,
+
+        #[command(flatten)]
+        tracks: TrackArgs,
+    },
+    /// Mux raw H.264 (Annex-B) and AAC (ADTS) elementary streams into MP4
+    Mux {
+        /// Input H.264 Annex-B file
+        #[arg(long)]
+        video: PathBuf,
+
+        /// Input AAC file with ADTS headers
+        #[arg(long)]
+        audio: Option<PathBuf>,
+
+        /// Output MP4 file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Video frame rate (e.g. 25, 29.97 or 30000/1001)
+        #[arg(long, required_unless_present = "timing", conflicts_with = "timing")]
+        fps: Option<ts2mp4::FrameRate>,
+
+        /// Timing file with one presentation time in milliseconds per frame, in stream order
+        #[arg(long)]
+        timing: Option<PathBuf>,
+
+        /// Leave the moov box at the end of the file (skip the faststart pass)
+        #[arg(long, default_value_t = false)]
+        no_faststart: bool,
+
+        /// Write fragmented MP4 (init segment followed by moof/mdat fragments)
+        #[arg(long, default_value_t = false)]
+        fragmented: bool,
+
+        /// Target fragment duration in milliseconds (fragments are cut at keyframes)
+        #[arg(long, default_value_t = ts2mp4::DEFAULT_FRAGMENT_DURATION_MS)]
+        fragment_duration_ms: u32,
+
+        #[command(flatten)]
+        container: ContainerArgs,
+    },
+    /// Write iTunes-style metadata tags into an MP4 file (existing tags are kept)
+    Tag {
+        /// Input MP4 file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output MP4 file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        tags: TagArgs,
+    },
+    /// Keep, drop or reorder the tracks of an MP4 file without re-encoding
+    Remux {
+        /// Input MP4 file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output MP4 file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        tracks: TrackArgs,
+    },
+    /// Cut a time range out of a TS or MP4 file into MP4, starting at a keyframe
+    Cut {
+        /// Input file path (TS or MP4)
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output MP4 file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Start time (seconds, MM:SS.mmm or HH:MM:SS.mmm)
+        #[arg(long, default_value = "0", value_parser = parse_time_ms)]
+        start: u64,
+
+        /// End time (defaults to the end of the input)
+        #[arg(long, value_parser = parse_time_ms)]
+        end: Option<u64>,
+
+        /// Start exactly at --start with an edit list instead of at the keyframe before it
+        #[arg(long, default_value_t = false)]
+        accurate: bool,
+    },
+    /// Split a TS or MP4 file at keyframes into MP4 parts of limited duration or size
+    Split {
+        /// Input file path (TS or MP4)
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output path template with a part number placeholder (e.g. out_%03d.mp4, numbered from 0)
+        #[arg(short, long)]
+        output: String,
+
+        /// Longest part (seconds, MM:SS.mmm or HH:MM:SS.mmm)
+        #[arg(long, value_parser = parse_time_ms, required_unless_present = "size")]
+        duration: Option<u64>,
+
+        /// Largest part file in bytes, with an optional K/M/G (1000) or Ki/Mi/Gi (1024) suffix
+        #[arg(long, value_parser = parse_size)]
+        size: Option<u64>,
+    },
+    /// Write the H.264 and AAC streams of a TS or MP4 file as raw .h264 (Annex B) and .aac (ADTS)
+    Extract {
+        /// Input file path (TS or MP4)
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output path for the first video stream
+        #[arg(long)]
+        video: Option<PathBuf>,
+
+        /// Output path for the first audio stream
+        #[arg(long)]
+        audio: Option<PathBuf>,
+
+        /// Output path for the stream with this MP4 track ID or TS PID (repeatable)
+        #[arg(long = "track", value_name = "ID=PATH", value_parser = parse_track_path)]
+        tracks: Vec<(u32, PathBuf)>,
+    },
+    /// Report programs, streams, codecs, duration and bitrate of a TS, M2TS or MP4 file
+    Probe {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Print the report as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Print the box hierarchy of an MP4 file with decoded header and sample table fields
+    Inspect {
+        /// Input MP4 file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output format: text or json
+        #[arg(long, default_value = "text")]
+        format: ts2mp4::InspectFormat,
+
+        /// Only show boxes whose path ends with this path (e.g. trak/mdia/mdhd, trun, moov/*/tkhd)
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Print every table entry in text output
+        #[arg(long, default_value_t = false)]
+        full: bool,
     },
     /// Extract thumbnail from TS file
     ThumbnailTs {
@@ -50,6 +255,181 @@ enum Commands {
     },
 }
 
+/// Metadata tag options shared by convert and tag
+#[derive(Args)]
+struct TagArgs {
+    /// Title (©nam)
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Date or year (©day)
+    #[arg(long)]
+    date: Option<String>,
+
+    /// Description (desc)
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Comment (©cmt)
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Cover art image (JPEG or PNG)
+    #[arg(long)]
+    cover: Option<PathBuf>,
+
+    /// Freeform tag in the com.apple.iTunes namespace (repeatable)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    freeform: Vec<(String, String)>,
+}
+
+impl TagArgs {
+    fn into_tags(self) -> io::Result<ts2mp4::Mp4Tags> {
+        let cover = match self.cover {
+            Some(path) => Some(ts2mp4::CoverArt::from_image(fs::read(path)?)?),
+            None => None,
+        };
+        Ok(ts2mp4::Mp4Tags {
+            title: self.title,
+            date: self.date,
+            description: self.description,
+            comment: self.comment,
+            cover,
+            freeform: self
+                .freeform
+                .iter()
+                .map(|(key, value)| ts2mp4::FreeformTag::new(key, value))
+                .collect(),
+        })
+    }
+}
+
+/// Track selection options shared by convert and remux
+#[derive(Args)]
+struct TrackArgs {
+    /// Video tracks to keep: all, none, or 1-based positions and language codes (e.g. "1" or "eng,kor")
+    #[arg(long, value_name = "TRACKS")]
+    video: Option<ts2mp4::TrackFilter>,
+
+    /// Audio tracks to keep: all, none, or 1-based positions and language codes (e.g. "2" or "eng")
+    #[arg(long, value_name = "TRACKS")]
+    audio: Option<ts2mp4::TrackFilter>,
+
+    /// Keep only these track IDs, in this order (repeatable or comma-separated)
+    #[arg(
+        long = "track",
+        value_name = "ID",
+        value_delimiter = ',',
+        conflicts_with_all = ["video", "audio"]
+    )]
+    track_ids: Vec<u32>,
+}
+
+impl TrackArgs {
+    fn into_selection(self) -> ts2mp4::TrackSelection {
+        ts2mp4::TrackSelection {
+            video: self.video.unwrap_or_default(),
+            audio: self.audio.unwrap_or_default(),
+            track_ids: self.track_ids,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+/// Display transform for the --rotate and --flip options, `None` if neither is given
+fn display_transform(
+    rotate: Option<ts2mp4::Rotation>,
+    flip: Option<Flip>,
+) -> Option<ts2mp4::DisplayTransform> {
+    if rotate.is_none() && flip.is_none() {
+        return None;
+    }
+
+    let degrees = rotate.unwrap_or_default().degrees() as i32;
+    // A vertical flip is a horizontal flip turned upside down
+    let (degrees, flip_horizontal) = match flip {
+        None => (degrees, false),
+        Some(Flip::Horizontal) => (degrees, true),
+        Some(Flip::Vertical) => (degrees + 180, true),
+    };
+    Some(ts2mp4::DisplayTransform::new(
+        ts2mp4::Rotation::from_degrees(degrees).unwrap_or_default(),
+        flip_horizontal,
+    ))
+}
+
+/// Container options shared by convert and mux
+#[derive(Args)]
+struct ContainerArgs {
+    /// Container format: mp4 or mov (default: mov for a .mov output path, mp4 otherwise;
+    /// MP4 input keeps its brands unless --brands is given)
+    #[arg(long)]
+    format: Option<ts2mp4::OutputFormat>,
+
+    /// ftyp brands as a comma-separated list, major brand first (e.g. "mp42,isom" or "M4V,M4A,mp42")
+    #[arg(long, value_name = "LIST")]
+    brands: Option<ts2mp4::FileBrands>,
+}
+
+impl ContainerArgs {
+    fn format_for(&self, output: &Path) -> ts2mp4::OutputFormat {
+        self.format.unwrap_or_else(|| {
+            let is_mov = output
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("mov"));
+            if is_mov {
+                ts2mp4::OutputFormat::Mov
+            } else {
+                ts2mp4::OutputFormat::Mp4
+            }
+        })
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", s)),
+    }
+}
+
+fn parse_time_ms(s: &str) -> Result<u64, String> {
+    ts2mp4::parse_time(s).ok_or_else(|| format!("expected seconds or HH:MM:SS.mmm, got '{}'", s))
+}
+
+fn parse_track_path(s: &str) -> Result<(u32, PathBuf), String> {
+    let (id, path) = parse_key_value(s)?;
+    let id = id
+        .parse()
+        .map_err(|_| format!("expected a numeric track ID, got '{}'", id))?;
+    Ok((id, PathBuf::from(path)))
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let text = s.trim().trim_end_matches(['B', 'b']);
+    let (number, multiplier) = [
+        ("Ki", 1u64 << 10),
+        ("Mi", 1 << 20),
+        ("Gi", 1 << 30),
+        ("K", 1_000),
+        ("k", 1_000),
+        ("M", 1_000_000),
+        ("G", 1_000_000_000),
+    ]
+    .iter()
+    .find_map(|&(suffix, multiplier)| Some((text.strip_suffix(suffix)?, multiplier)))
+    .unwrap_or((text, 1));
+    match number.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok((value * multiplier as f64) as u64),
+        _ => Err(format!("expected a size such as 500M or 2Gi, got '{}'", s)),
+    }
+}
+
 fn main() -> io::Result<()> {
     let cli = Cli::parse();
 
@@ -58,7 +438,151 @@ fn main() -> io::Result<()> {
             input,
             output,
             reset_timestamps,
-        } => convert_command(&input, &output, reset_timestamps),
+            preserve_timestamps,
+            no_faststart,
+            interleave_ms,
+            fragmented,
+            fragment_duration_ms,
+            sidx,
+            mfra,
+            aac_priming,
+            tags,
+            chapters,
+            container,
+            rotate,
+            flip,
+            tracks,
+        } => {
+            let chapters = match chapters {
+                Some(path) => ts2mp4::parse_chapters(&fs::read_to_string(path)?)?,
+                None => Vec::new(),
+            };
+            if input.len() > 1
+                && (preserve_timestamps || interleave_ms.is_some() || aac_priming.is_some())
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "--preserve-timestamps, --interleave-ms and --aac-priming are not available when concatenating several inputs",
+                ));
+            }
+            let flags = ConvertFlags {
+                format: container.format.is_some(),
+                aac_priming: aac_priming.is_some(),
+                reset_timestamps,
+            };
+            let options = ts2mp4::ConvertOptions {
+                preserve_timestamps,
+                faststart: !no_faststart,
+                interleave_ms: interleave_ms.unwrap_or(ts2mp4::DEFAULT_INTERLEAVE_MS),
+                fragment: fragmented.then_some(ts2mp4::FragmentOptions {
+                    target_duration_ms: fragment_duration_ms,
+                    sidx,
+                    mfra,
+                }),
+                aac_priming_samples: aac_priming.unwrap_or(ts2mp4::DEFAULT_AAC_PRIMING_SAMPLES),
+                tags: tags.into_tags()?,
+                chapters,
+                format: container.format_for(&output),
+                brands: container.brands,
+                transform: display_transform(rotate, flip),
+            };
+            let tracks = tracks.into_selection();
+            match input.as_slice() {
+                [input] => convert_command(input, &output, &options, &flags, &tracks),
+                inputs => concat_command(inputs, &output, &options, &tracks),
+            }
+        }
+        Commands::Mux {
+            video,
+            audio,
+            output,
+            fps,
+            timing,
+            no_faststart,
+            fragmented,
+            fragment_duration_ms,
+            container,
+        } => {
+            let timing = match (fps, timing) {
+                (Some(fps), _) => ts2mp4::VideoTiming::FrameRate(fps),
+                (None, Some(path)) => {
+                    ts2mp4::VideoTiming::parse_timing_file(&fs::read_to_string(path)?)?
+                }
+                (None, None) => unreachable!("clap requires --fps or --timing"),
+            };
+            let options = ts2mp4::Mp4WriterOptions {
+                faststart: !no_faststart,
+                fragment: fragmented.then_some(ts2mp4::FragmentOptions {
+                    target_duration_ms: fragment_duration_ms,
+                    ..Default::default()
+                }),
+                format: container.format_for(&output),
+                brands: container.brands,
+                ..Default::default()
+            };
+            mux_command(&video, audio.as_ref(), &output, &timing, &options)
+        }
+        Commands::Tag {
+            input,
+            output,
+            tags,
+        } => tag_command(&input, &output, &tags.into_tags()?),
+        Commands::Remux {
+            input,
+            output,
+            tracks,
+        } => remux_command(&input, &output, &tracks.into_selection()),
+        Commands::Cut {
+            input,
+            output,
+            start,
+            end,
+            accurate,
+        } => {
+            let options = ts2mp4::CutOptions {
+                start_ms: start,
+                end_ms: end,
+                accurate,
+            };
+            cut_command(&input, &output, &options)
+        }
+        Commands::Split {
+            input,
+            output,
+            duration,
+            size,
+        } => {
+            let options = ts2mp4::SplitOptions {
+                max_duration_ms: duration,
+                max_size: size,
+            };
+            split_command(&input, &output, &options)
+        }
+        Commands::Extract {
+            input,
+            video,
+            audio,
+            tracks,
+        } => extract_command(&input, video, audio, tracks),
+        Commands::Probe { input, json } => {
+            let report = ts2mp4::probe(&fs::read(input)?)?;
+            if json {
+                print!("{}", report.to_json());
+            } else {
+                print!("{}", report);
+            }
+            Ok(())
+        }
+        Commands::Inspect {
+            input,
+            format,
+            path,
+            full,
+        } => {
+            let options = ts2mp4::InspectOptions { format, path, full };
+            print!("{}", ts2mp4::inspect_mp4(&fs::read(input)?, &options)?);
+            Ok(())
+        }
         Commands::ThumbnailTs { input, output } => extract_thumbnail_ts(&input, &output),
         Commands::ThumbnailMp4 { input, output } => extract_thumbnail_mp4(&input, &output),
     }
@@ -78,33 +602,208 @@ fn detect_file_type(data: &[u8]) -> FileType {
     FileType::Unknown
 }
 
+/// First bytes of `path`, enough for `detect_file_type`
+fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    fs::File::open(path)?.take(188).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Writes `output` through a temporary file next to it that is renamed into place once `write`
+/// succeeds, so a failed conversion never leaves a truncated output behind
+fn write_output<T>(output: &Path, write: impl FnOnce(&Path) -> io::Result<T>) -> io::Result<T> {
+    let file_name = output
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Output path has no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".part");
+    let temp = output.with_file_name(temp_name);
+
+    let result = write(&temp).and_then(|value| fs::rename(&temp, output).map(|_| value));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Opens `path` for reading, writing and seeking, replacing an existing file
+fn create_seekable(path: &Path) -> io::Result<fs::File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
 enum FileType {
     Ts,
     Mp4,
     Unknown,
 }
 
-fn convert_command(input: &PathBuf, output: &PathBuf, reset_timestamps: bool) -> io::Result<()> {
+/// Convert flags as given on the command line, for the checks that depend on the input type
+struct ConvertFlags {
+    /// --format was given instead of being taken from the output extension
+    format: bool,
+    /// --aac-priming was given
+    aac_priming: bool,
+    /// Reset the timestamps of MP4 input to start from 0 (TS input always starts from 0
+    /// unless --preserve-timestamps is given)
+    reset_timestamps: bool,
+}
+
+/// Fails when TS-only conversion settings are given for an input that is remuxed, not converted
+fn check_ts_only_options(
+    options: &ts2mp4::ConvertOptions,
+    flags: &ConvertFlags,
+    output_kind: &str,
+) -> io::Result<()> {
+    if options.fragment.is_some()
+        || options.preserve_timestamps
+        || !options.faststart
+        || flags.aac_priming
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "--fragmented, --preserve-timestamps, --no-faststart and --aac-priming are not available for {}",
+                output_kind
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn convert_command(
+    input: &PathBuf,
+    output: &Path,
+    options: &ts2mp4::ConvertOptions,
+    flags: &ConvertFlags,
+    tracks: &ts2mp4::TrackSelection,
+) -> io::Result<()> {
     eprintln!("Input: {}", input.display());
     eprintln!("Output: {}", output.display());
-    if reset_timestamps {
+    if flags.reset_timestamps {
         eprintln!("Timestamp reset: enabled");
     }
+    if !tracks.is_all() && options.fragment.is_some() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Track selection is not available for fragmented output",
+        ));
+    }
 
-    let input_data = fs::read(input)?;
-    let file_type = detect_file_type(&input_data);
+    let file_type = detect_file_type(&read_header(input)?);
+
+    let ts_output = output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ts"));
+    if ts_output {
+        if !matches!(file_type, FileType::Mp4) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "TS output needs an MP4 input",
+            ));
+        }
+        if !options.tags.is_empty()
+            || !options.chapters.is_empty()
+            || options.brands.is_some()
+            || options.transform.is_some()
+            || options.format == ts2mp4::OutputFormat::Mov
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Tags, chapters, brands, rotation and MOV output are not available for TS output",
+            ));
+        }
+        check_ts_only_options(options, flags, "TS output")?;
+
+        eprintln!("Detected: MP4 format");
+        let input_data = fs::read(input)?;
+        let input_data = if tracks.is_all() {
+            input_data
+        } else {
+            eprintln!("Selecting tracks...");
+            ts2mp4::select_mp4_tracks(&input_data, tracks)?
+        };
+        eprintln!("Remuxing to MPEG-TS...");
+        write_output(output, |path| {
+            let file = io::BufWriter::new(fs::File::create(path)?);
+            ts2mp4::write_mp4_as_ts(&input_data, file)?.flush()
+        })?;
+        eprintln!("Conversion complete!");
+        return Ok(());
+    }
 
     let mp4_data = match file_type {
         FileType::Ts => {
             eprintln!("Detected: MPEG-TS format");
-            ts2mp4::convert_ts_to_mp4_with_options(&input_data, reset_timestamps)?
+            if options.fragment.is_some() {
+                eprintln!("Writing fragmented MP4...");
+            }
+            // Stream from the input file straight into the output file
+            let source = io::BufReader::new(fs::File::open(input)?);
+            let (_, report) = write_output(output, |path| {
+                write_selected_tracks(path, tracks, |file| {
+                    ts2mp4::convert_ts_to_mp4_writer(source, file, options)
+                })
+            })?;
+            match report.timestamps {
+                ts2mp4::TimestampMode::Reset => eprintln!("Timestamps: reset to start from 0"),
+                ts2mp4::TimestampMode::Preserved { start_offset } => eprintln!(
+                    "Timestamps: preserved (starts at {:.3}s)",
+                    start_offset as f64 / 90000.0
+                ),
+            }
+            let audio = &report.audio;
+            if *audio != ts2mp4::AudioRepairReport::default() {
+                eprintln!(
+                    "Audio repairs: {} silent frames inserted, {} overlapping frames dropped, {} durations adjusted, {} discontinuities left",
+                    audio.silent_frames,
+                    audio.dropped_frames,
+                    audio.adjusted_durations,
+                    audio.discontinuities
+                );
+            }
+            if audio.late_frames > 0 {
+                eprintln!(
+                    "Audio: {} frames left out, the audio stream started too long after the video",
+                    audio.late_frames
+                );
+            }
+            eprintln!("Conversion complete!");
+            return Ok(());
         }
         FileType::Mp4 => {
             eprintln!("Detected: MP4 format");
-            if reset_timestamps {
+            let input_data = fs::read(input)?;
+            if let Some(transform) = ts2mp4::read_mp4_transform(&input_data)? {
+                if !transform.is_identity() {
+                    eprintln!(
+                        "Display transform: rotated {} degrees{}",
+                        transform.rotation.degrees(),
+                        if transform.flip_horizontal {
+                            ", flipped horizontally"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            check_ts_only_options(options, flags, "MP4 input")?;
+            // A .mov output path alone keeps the brands of the source
+            if flags.format && options.format == ts2mp4::OutputFormat::Mov {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "MOV output is only supported for TS input",
+                ));
+            }
+            let data = if flags.reset_timestamps {
                 eprintln!("Converting Fragmented MP4 to regular MP4...");
                 // Fragmented MP4를 일반 MP4로 변환 시도
-                match ts2mp4::defragment_mp4(&input_data) {
+                match ts2mp4::defragment_mp4_interleaved(&input_data, options.interleave_ms) {
                     Ok(data) => {
                         eprintln!("Defragmentation successful");
                         data
@@ -116,8 +815,49 @@ fn convert_command(input: &PathBuf, output: &PathBuf, reset_timestamps: bool) ->
                     }
                 }
             } else {
-                eprintln!("No conversion needed, copying MP4 file...");
                 input_data.clone()
+            };
+
+            if options.tags.is_empty()
+                && options.chapters.is_empty()
+                && options.brands.is_none()
+                && options.transform.is_none()
+                && !flags.reset_timestamps
+                && tracks.is_all()
+            {
+                eprintln!("No conversion needed, copying MP4 file...");
+            }
+            let data = if options.tags.is_empty() {
+                data
+            } else {
+                eprintln!("Writing metadata tags...");
+                ts2mp4::write_mp4_tags(&data, &options.tags)?
+            };
+            let data = if options.chapters.is_empty() {
+                data
+            } else {
+                eprintln!("Writing {} chapters...", options.chapters.len());
+                ts2mp4::write_mp4_chapters(&data, &options.chapters)?
+            };
+            let data = match options.transform {
+                Some(transform) => {
+                    eprintln!("Writing display transform...");
+                    ts2mp4::write_mp4_transform(&data, transform)?
+                }
+                None => data,
+            };
+            let data = match &options.brands {
+                Some(brands) => {
+                    eprintln!("Writing ftyp brands...");
+                    ts2mp4::write_mp4_brands(&data, brands)?
+                }
+                None => data,
+            };
+            if tracks.is_all() {
+                data
+            } else {
+                eprintln!("Selecting tracks...");
+                ts2mp4::select_mp4_tracks(&data, tracks)?
             }
         }
         FileType::Unknown => {
@@ -128,11 +868,282 @@ fn convert_command(input: &PathBuf, output: &PathBuf, reset_timestamps: bool) ->
         }
     };
 
-    fs::write(output, mp4_data)?;
+    write_output(output, |path| fs::write(path, &mp4_data))?;
     eprintln!("Conversion complete!");
     Ok(())
 }
 
+fn concat_command(
+    inputs: &[PathBuf],
+    output: &Path,
+    options: &ts2mp4::ConvertOptions,
+    tracks: &ts2mp4::TrackSelection,
+) -> io::Result<()> {
+    for input in inputs {
+        eprintln!("Input: {}", input.display());
+    }
+    eprintln!("Output: {}", output.display());
+
+    let is_ts_output = output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ts"));
+    if is_ts_output {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Several inputs can only be concatenated into MP4",
+        ));
+    }
+
+    eprintln!("Concatenating {} inputs...", inputs.len());
+    write_output(output, |path| {
+        // Each input is read only when it is appended
+        let inputs = inputs.iter().map(fs::read);
+        write_selected_tracks(path, tracks, |file| {
+            ts2mp4::write_concatenated_mp4(inputs, file, options).map(drop)
+        })
+    })?;
+
+    eprintln!("Conversion complete!");
+    Ok(())
+}
+
+fn mux_command(
+    video: &PathBuf,
+    audio: Option<&PathBuf>,
+    output: &Path,
+    timing: &ts2mp4::VideoTiming,
+    options: &ts2mp4::Mp4WriterOptions,
+) -> io::Result<()> {
+    eprintln!("Video: {}", video.display());
+    if let Some(audio) = audio {
+        eprintln!("Audio: {}", audio.display());
+    }
+    eprintln!("Output: {}", output.display());
+
+    let video_data = fs::read(video)?;
+    let audio_data = audio.map(fs::read).transpose()?;
+
+    write_output(output, |path| {
+        let file = create_seekable(path)?;
+        ts2mp4::mux_elementary_streams(&video_data, audio_data.as_deref(), timing, file, options)
+            .map(drop)
+    })?;
+
+    eprintln!("Mux complete!");
+    Ok(())
+}
+
+fn tag_command(input: &PathBuf, output: &Path, tags: &ts2mp4::Mp4Tags) -> io::Result<()> {
+    eprintln!("Input: {}", input.display());
+    eprintln!("Output: {}", output.display());
+
+    let input_data = fs::read(input)?;
+    let mp4_data = ts2mp4::write_mp4_tags(&input_data, tags)?;
+    // The input is fully read, so tagging a file in place (-i and -o the same) is safe
+    write_output(output, |path| fs::write(path, &mp4_data))?;
+
+    let tags = ts2mp4::read_mp4_tags(&mp4_data)?;
+    let text_tags = [
+        ("Title", &tags.title),
+        ("Date", &tags.date),
+        ("Description", &tags.description),
+        ("Comment", &tags.comment),
+    ];
+    for (label, value) in text_tags {
+        if let Some(value) = value {
+            eprintln!("{}: {}", label, value);
+        }
+    }
+    if let Some(cover) = &tags.cover {
+        eprintln!("Cover: {:?}, {} bytes", cover.format, cover.data.len());
+    }
+    for tag in &tags.freeform {
+        eprintln!("{}:{}: {}", tag.mean, tag.name, tag.value);
+    }
+
+    eprintln!("Tagging complete!");
+    Ok(())
+}
+
+fn remux_command(
+    input: &PathBuf,
+    output: &Path,
+    tracks: &ts2mp4::TrackSelection,
+) -> io::Result<()> {
+    eprintln!("Input: {}", input.display());
+    eprintln!("Output: {}", output.display());
+
+    let input_data = fs::read(input)?;
+    let mp4_data = ts2mp4::select_mp4_tracks(&input_data, tracks)?;
+    write_output(output, |path| fs::write(path, &mp4_data))?;
+
+    for track in ts2mp4::read_mp4_tracks(&mp4_data)? {
+        eprintln!(
+            "Track {}: {:?}, {} samples",
+            track.track_id,
+            track.media_type,
+            track.sample_count()
+        );
+    }
+
+    eprintln!("Remux complete!");
+    Ok(())
+}
+
+/// Writes an MP4 to `path` with `write`, keeping only the selected tracks. With a selection the
+/// full MP4 goes to a scratch file next to `path` first and the kept tracks are copied from it.
+fn write_selected_tracks<T>(
+    path: &Path,
+    tracks: &ts2mp4::TrackSelection,
+    write: impl FnOnce(fs::File) -> io::Result<T>,
+) -> io::Result<T> {
+    if tracks.is_all() {
+        return write(create_seekable(path)?);
+    }
+    let scratch = path.with_extension("full");
+    let result = write(create_seekable(&scratch)?).and_then(|value| {
+        eprintln!("Selecting tracks...");
+        let source = io::BufReader::new(fs::File::open(&scratch)?);
+        let sink = io::BufWriter::new(fs::File::create(path)?);
+        ts2mp4::write_selected_mp4_tracks(source, sink, tracks)?.flush()?;
+        Ok(value)
+    });
+    let _ = fs::remove_file(&scratch);
+    result
+}
+
+fn cut_command(input: &PathBuf, output: &Path, options: &ts2mp4::CutOptions) -> io::Result<()> {
+    eprintln!("Input: {}", input.display());
+    eprintln!("Output: {}", output.display());
+    match options.end_ms {
+        Some(end) => eprintln!("Range: {}ms - {}ms", options.start_ms, end),
+        None => eprintln!("Range: {}ms - end", options.start_ms),
+    }
+
+    let input_data = fs::read(input)?;
+    let mp4_data = ts2mp4::cut_media(&input_data, options)?;
+    write_output(output, |path| fs::write(path, &mp4_data))?;
+
+    eprintln!("Cut complete!");
+    Ok(())
+}
+
+/// Writes the selected elementary streams, or every stream next to the input when none is selected
+fn extract_command(
+    input: &PathBuf,
+    video: Option<PathBuf>,
+    audio: Option<PathBuf>,
+    tracks: Vec<(u32, PathBuf)>,
+) -> io::Result<()> {
+    eprintln!("Input: {}", input.display());
+
+    let input_data = fs::read(input)?;
+    let streams = ts2mp4::extract_elementary_streams(&input_data)?;
+    let first_of = |kind: ts2mp4::StreamKind| streams.iter().find(|stream| stream.kind == kind);
+
+    let mut outputs = Vec::new();
+    for (kind, path) in [
+        (ts2mp4::StreamKind::Video, video),
+        (ts2mp4::StreamKind::Audio, audio),
+    ] {
+        if let Some(path) = path {
+            let stream = first_of(kind).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("No {} stream found", kind.name()),
+                )
+            })?;
+            outputs.push((stream, path));
+        }
+    }
+    for (id, path) in tracks {
+        let stream = streams
+            .iter()
+            .find(|stream| stream.id == id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("No H.264 or AAC stream with ID {}", id),
+                )
+            })?;
+        outputs.push((stream, path));
+    }
+    if outputs.is_empty() {
+        let stem = input.with_extension("");
+        for stream in &streams {
+            let path = PathBuf::from(format!(
+                "{}_{}.{}",
+                stem.display(),
+                stream.id,
+                stream.extension()
+            ));
+            outputs.push((stream, path));
+        }
+    }
+
+    for (stream, path) in outputs {
+        write_output(&path, |temp| fs::write(temp, &stream.data))?;
+        eprintln!(
+            "Stream {} ({}): {} ({} bytes)",
+            stream.id,
+            stream.kind.name(),
+            path.display(),
+            stream.data.len()
+        );
+    }
+
+    eprintln!("Extract complete!");
+    Ok(())
+}
+
+fn split_command(
+    input: &PathBuf,
+    template: &str,
+    options: &ts2mp4::SplitOptions,
+) -> io::Result<()> {
+    eprintln!("Input: {}", input.display());
+    // Checked before the input is read
+    part_path(template, 0)?;
+
+    let input_data = fs::read(input)?;
+    ts2mp4::split_media(&input_data, options, |number, part| {
+        let path = part_path(template, number)?;
+        write_output(&path, |temp| fs::write(temp, part))?;
+        eprintln!("Part {}: {} ({} bytes)", number, path.display(), part.len());
+        Ok(())
+    })?;
+
+    eprintln!("Split complete!");
+    Ok(())
+}
+
+/// Output path of part `number` from a template with a `%d` or `%0Nd` placeholder
+fn part_path(template: &str, number: usize) -> io::Result<PathBuf> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "Output template needs one %d or %0Nd placeholder (e.g. out_%03d.mp4)",
+        )
+    };
+    let (prefix, rest) = template.split_once('%').ok_or_else(invalid)?;
+    let (spec, suffix) = rest.split_once('d').ok_or_else(invalid)?;
+    if suffix.contains('%') {
+        return Err(invalid());
+    }
+    let width = match spec {
+        "" => 0,
+        _ if spec.starts_with('0') => spec.parse::<usize>().map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    Ok(PathBuf::from(format!(
+        "{}{:0width$}{}",
+        prefix,
+        number,
+        suffix,
+        width = width
+    )))
+}
+
 fn extract_thumbnail_ts(input: &PathBuf, output: &PathBuf) -> io::Result<()> {
     eprintln!("Extracting thumbnail from TS: {}", input.display());
 
@@ -0,0 +1,198 @@
+use crate::chapters::{build_chpl, Chapter};
+use crate::mp4_writer::{write_box, write_full_box};
+use std::io::{self, ErrorKind};
+
+/// Mean (namespace) used for freeform tags unless another one is given
+pub const DEFAULT_FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+// ilst item types
+pub(crate) const TITLE: [u8; 4] = [0xA9, b'n', b'a', b'm'];
+pub(crate) const DATE: [u8; 4] = [0xA9, b'd', b'a', b'y'];
+pub(crate) const DESCRIPTION: [u8; 4] = *b"desc";
+pub(crate) const COMMENT: [u8; 4] = [0xA9, b'c', b'm', b't'];
+pub(crate) const COVER: [u8; 4] = *b"covr";
+pub(crate) const FREEFORM: [u8; 4] = *b"----";
+
+// Well-known data types of the `data` atom
+pub(crate) const DATA_TYPE_UTF8: u32 = 1;
+pub(crate) const DATA_TYPE_JPEG: u32 = 13;
+pub(crate) const DATA_TYPE_PNG: u32 = 14;
+
+/// Image format of the cover art
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverFormat {
+    Jpeg,
+    Png,
+}
+
+/// Cover art stored in the `covr` item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverArt {
+    pub format: CoverFormat,
+    pub data: Vec<u8>,
+}
+
+impl CoverArt {
+    /// Wraps a JPEG or PNG image, detected from its signature
+    pub fn from_image(data: Vec<u8>) -> io::Result<Self> {
+        let format = if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            CoverFormat::Jpeg
+        } else if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+            CoverFormat::Png
+        } else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Cover art must be a JPEG or PNG image",
+            ));
+        };
+        Ok(CoverArt { format, data })
+    }
+}
+
+/// Freeform (`----`) tag identified by its mean and name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeformTag {
+    pub mean: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl FreeformTag {
+    /// Tag in the `com.apple.iTunes` namespace
+    pub fn new(name: &str, value: &str) -> Self {
+        FreeformTag {
+            mean: DEFAULT_FREEFORM_MEAN.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// iTunes-style metadata stored in `moov/udta/meta/ilst`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mp4Tags {
+    /// ©nam
+    pub title: Option<String>,
+    /// ©day, usually a year or an ISO 8601 date
+    pub date: Option<String>,
+    /// desc
+    pub description: Option<String>,
+    /// ©cmt
+    pub comment: Option<String>,
+    /// covr
+    pub cover: Option<CoverArt>,
+    /// ---- items
+    pub freeform: Vec<FreeformTag>,
+}
+
+impl Mp4Tags {
+    pub fn is_empty(&self) -> bool {
+        *self == Mp4Tags::default()
+    }
+
+    /// Whether writing these tags replaces an existing ilst item of `item_type`
+    /// (freeform items are matched by mean and name)
+    pub(crate) fn replaces(&self, item_type: &[u8; 4], freeform_key: Option<(&str, &str)>) -> bool {
+        match *item_type {
+            TITLE => self.title.is_some(),
+            DATE => self.date.is_some(),
+            DESCRIPTION => self.description.is_some(),
+            COMMENT => self.comment.is_some(),
+            COVER => self.cover.is_some(),
+            FREEFORM => freeform_key.is_some_and(|(mean, name)| {
+                self.freeform
+                    .iter()
+                    .any(|tag| tag.mean == mean && tag.name == name)
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// Builds the ilst items for the tags that are set
+pub(crate) fn build_ilst_items(tags: &Mp4Tags) -> Vec<u8> {
+    let mut items = Vec::new();
+
+    let text_items = [
+        (TITLE, &tags.title),
+        (DATE, &tags.date),
+        (DESCRIPTION, &tags.description),
+        (COMMENT, &tags.comment),
+    ];
+    for (item_type, value) in text_items {
+        if let Some(value) = value {
+            let data = build_data_atom(DATA_TYPE_UTF8, value.as_bytes());
+            write_box(&mut items, &item_type, &data);
+        }
+    }
+
+    if let Some(cover) = &tags.cover {
+        let data_type = match cover.format {
+            CoverFormat::Jpeg => DATA_TYPE_JPEG,
+            CoverFormat::Png => DATA_TYPE_PNG,
+        };
+        write_box(&mut items, &COVER, &build_data_atom(data_type, &cover.data));
+    }
+
+    for tag in &tags.freeform {
+        let mut item = Vec::new();
+        write_full_box(&mut item, b"mean", 0, 0, tag.mean.as_bytes());
+        write_full_box(&mut item, b"name", 0, 0, tag.name.as_bytes());
+        item.extend_from_slice(&build_data_atom(DATA_TYPE_UTF8, tag.value.as_bytes()));
+        write_box(&mut items, &FREEFORM, &item);
+    }
+
+    items
+}
+
+/// `data` atom: type indicator, locale (0) and the value
+fn build_data_atom(data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + value.len());
+    data.extend_from_slice(&data_type.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes()); // locale
+    data.extend_from_slice(value);
+
+    let mut result = Vec::new();
+    write_box(&mut result, b"data", &data);
+    result
+}
+
+/// Builds the content of a `meta` full box (version + flags, hdlr and ilst)
+pub(crate) fn build_meta_content(ilst_items: &[u8]) -> Vec<u8> {
+    let mut meta = vec![0u8; 4]; // version + flags
+
+    // hdlr: metadata handler 'mdir', manufacturer 'appl'
+    let mut hdlr = Vec::new();
+    hdlr.extend_from_slice(&0u32.to_be_bytes()); // pre-defined
+    hdlr.extend_from_slice(b"mdir");
+    hdlr.extend_from_slice(b"appl");
+    hdlr.extend_from_slice(&[0u8; 8]); // reserved
+    hdlr.push(0x00); // name
+    write_full_box(&mut meta, b"hdlr", 0, 0, &hdlr);
+
+    write_box(&mut meta, b"ilst", ilst_items);
+    meta
+}
+
+/// Builds `udta` holding the tags and the Nero chapter list, or nothing if neither is set
+pub(crate) fn build_udta(tags: &Mp4Tags, chapters: &[Chapter]) -> Vec<u8> {
+    if tags.is_empty() && chapters.is_empty() {
+        return Vec::new();
+    }
+
+    let mut udta = Vec::new();
+    if !tags.is_empty() {
+        write_box(
+            &mut udta,
+            b"meta",
+            &build_meta_content(&build_ilst_items(tags)),
+        );
+    }
+    if !chapters.is_empty() {
+        udta.extend_from_slice(&build_chpl(chapters));
+    }
+
+    let mut result = Vec::new();
+    write_box(&mut result, b"udta", &udta);
+    result
+}
@@ -1,6 +1,18 @@
 #![allow(dead_code)]
 
+use crate::box_tree::{
+    parse_box_tree, write_box_tree, BoxBody, BoxNode, FullBox, Mdhd, Mvhd, Tkhd,
+};
+use crate::chapters::{build_chpl, parse_chpl, parse_text_sample, Chapter, CHAPTER_TIMESCALE};
+use crate::metadata::{
+    self, build_ilst_items, build_meta_content, CoverArt, CoverFormat, FreeformTag, Mp4Tags,
+};
+use crate::mp4_writer::{
+    box_header, build_chapter_trak, build_chapter_tref, build_ftyp, DisplayTransform, FileBrands,
+    DEFAULT_INTERLEAVE_MS,
+};
 use std::io::{self, ErrorKind};
+use std::ops::Range;
 
 /// MP4 박스 (Box/Atom) 구조
 #[derive(Debug, Clone)]
@@ -12,7 +24,7 @@ pub struct Mp4Box {
 }
 
 /// mdat 박스 정보 (큰 데이터는 복사하지 않음)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MdatBox {
     pub offset: usize,
     pub size: u64,
@@ -31,7 +43,7 @@ pub struct Mp4File {
 }
 
 /// 박스 정보 (타입별로 다르게 저장)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BoxInfo {
     Small(Mp4Box), // ftyp, moov, styp, moof, emsg 등
     Mdat(MdatBox), // 큰 mdat는 오프셋만
@@ -424,6 +436,41 @@ pub fn parse_ctts(data: &[u8]) -> io::Result<Vec<CttsEntry>> {
     Ok(entries)
 }
 
+/// elst (Edit List Box) 엔트리
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditListEntry {
+    pub segment_duration: u64, // movie timescale
+    pub media_time: i64,       // media timescale, -1 = empty edit
+    pub media_rate: u32,       // 16.16 고정소수점
+}
+
+/// elst 박스 파싱
+pub fn parse_elst(data: &[u8]) -> io::Result<Vec<EditListEntry>> {
+    let mut reader = Mp4Reader::new(data);
+    let (version, _flags) = read_full_box_header(data)?;
+    reader.seek(4);
+
+    let entry_count = reader.read_u32()?;
+    let mut entries = Vec::with_capacity(entry_count as usize);
+
+    for _ in 0..entry_count {
+        let (segment_duration, media_time) = if version == 1 {
+            (reader.read_u64()?, reader.read_u64()? as i64)
+        } else {
+            // version 0: 32비트, media_time은 signed
+            (reader.read_u32()? as u64, reader.read_u32()? as i32 as i64)
+        };
+
+        entries.push(EditListEntry {
+            segment_duration,
+            media_time,
+            media_rate: reader.read_u32()?,
+        });
+    }
+
+    Ok(entries)
+}
+
 /// 모든 트랙의 타임스탬프 정보 추출
 pub fn extract_all_timestamps(mp4: &Mp4File) -> io::Result<Vec<TimestampInfo>> {
     let mut timestamps = Vec::new();
@@ -448,12 +495,24 @@ pub fn extract_all_timestamps(mp4: &Mp4File) -> io::Result<Vec<TimestampInfo>> {
 }
 
 /// 샘플 정보
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SampleInfo {
+    pub track_id: u32,
+    pub index: u32, // 트랙 내 0부터 시작하는 샘플 번호
     pub size: u32,
-    pub offset: u64,
+    pub offset: u64, // 파일 내 절대 오프셋
+    pub dts: u64,    // media timescale
     pub duration: u32,
     pub composition_offset: i32,
+    pub is_sync: bool,
+    pub sample_description_index: u32,
+}
+
+impl SampleInfo {
+    /// 표시 시각 (DTS + ctts 오프셋, media timescale)
+    pub fn cts(&self) -> i64 {
+        self.dts as i64 + self.composition_offset as i64
+    }
 }
 
 /// stsz (Sample Size Box) 파싱
@@ -510,6 +569,22 @@ pub fn parse_co64(data: &[u8]) -> io::Result<Vec<u64>> {
     Ok(offsets)
 }
 
+/// stss (Sync Sample Box) 파싱 - 1부터 시작하는 샘플 번호
+pub fn parse_stss(data: &[u8]) -> io::Result<Vec<u32>> {
+    let mut reader = Mp4Reader::new(data);
+    let (_version, _flags) = read_full_box_header(data)?;
+    reader.seek(4);
+
+    let entry_count = reader.read_u32()?;
+    let mut samples = Vec::with_capacity(entry_count as usize);
+
+    for _ in 0..entry_count {
+        samples.push(reader.read_u32()?);
+    }
+
+    Ok(samples)
+}
+
 /// stsc (Sample to Chunk Box) 엔트리
 #[derive(Debug, Clone)]
 pub struct StscEntry {
@@ -624,24 +699,297 @@ pub fn extract_track_info(trak_data: &[u8]) -> io::Result<TrackInfo> {
     })
 }
 
-/// 박스를 출력 버퍼에 쓰기 (크기 자동 처리)
-fn write_box(output: &mut Vec<u8>, box_type: &[u8; 4], data: &[u8]) {
-    let total_size = 8 + data.len();
+/// 일반 MP4 트랙의 샘플 테이블
+///
+/// `samples()`가 stsz/stsc/stco/stts/ctts/stss를 함께 풀어 샘플마다 파일 오프셋, 크기, DTS,
+/// CTS, duration, sync 여부를 돌려준다. Fragmented MP4의 moov에는 샘플이 없으므로 비어 있다.
+#[derive(Debug, Clone)]
+pub struct TrackSamples {
+    pub track_id: u32,
+    pub timescale: u32,
+    pub media_type: MediaType,
+    sizes: Vec<u32>,
+    chunk_offsets: Vec<u64>,
+    stsc: Vec<StscEntry>,
+    stts: Vec<SttsEntry>,
+    ctts: Vec<CttsEntry>,
+    sync_samples: Option<Vec<u32>>, // None이면 모든 샘플이 sync
+}
+
+impl TrackSamples {
+    pub fn sample_count(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// 디코딩 순서의 샘플 이터레이터
+    pub fn samples(&self) -> SampleIter<'_> {
+        SampleIter {
+            track: self,
+            index: 0,
+            next_chunk: 0,
+            stsc_index: 0,
+            left_in_chunk: 0,
+            offset: 0,
+            dts: 0,
+            stts_index: 0,
+            stts_left: self.stts.first().map_or(0, |e| e.sample_count),
+            ctts_index: 0,
+            ctts_left: self.ctts.first().map_or(0, |e| e.sample_count),
+            sync_index: 0,
+        }
+    }
+
+    /// stsc 엔트리의 청크당 샘플 수
+    fn samples_in_chunk(&self, stsc_index: usize) -> u32 {
+        self.stsc
+            .get(stsc_index)
+            .map_or(0, |entry| entry.samples_per_chunk)
+    }
+}
+
+/// `TrackSamples::samples()`의 이터레이터
+pub struct SampleIter<'a> {
+    track: &'a TrackSamples,
+    index: usize,
+    next_chunk: usize, // 0부터 시작하는 다음 청크 번호
+    stsc_index: usize,
+    left_in_chunk: u32,
+    offset: u64,
+    dts: u64,
+    stts_index: usize,
+    stts_left: u32,
+    ctts_index: usize,
+    ctts_left: u32,
+    sync_index: usize,
+}
+
+impl Iterator for SampleIter<'_> {
+    type Item = SampleInfo;
+
+    fn next(&mut self) -> Option<SampleInfo> {
+        let track = self.track;
+        let size = *track.sizes.get(self.index)?;
+
+        // 현재 청크를 다 썼으면 다음 비어 있지 않은 청크로 이동
+        while self.left_in_chunk == 0 {
+            let chunk_number = self.next_chunk as u32 + 1;
+            while track
+                .stsc
+                .get(self.stsc_index + 1)
+                .is_some_and(|next| next.first_chunk <= chunk_number)
+            {
+                self.stsc_index += 1;
+            }
+            self.offset = *track.chunk_offsets.get(self.next_chunk)?;
+            self.next_chunk += 1;
+            self.left_in_chunk = track.samples_in_chunk(self.stsc_index);
+        }
+
+        // stts가 샘플 수보다 짧으면 마지막 delta를 계속 사용
+        while self.stts_left == 0 && self.stts_index + 1 < track.stts.len() {
+            self.stts_index += 1;
+            self.stts_left = track.stts[self.stts_index].sample_count;
+        }
+        let duration = track
+            .stts
+            .get(self.stts_index)
+            .map_or(0, |e| e.sample_delta);
+
+        while self.ctts_left == 0 && self.ctts_index + 1 < track.ctts.len() {
+            self.ctts_index += 1;
+            self.ctts_left = track.ctts[self.ctts_index].sample_count;
+        }
+        let composition_offset = if self.ctts_left > 0 {
+            track.ctts[self.ctts_index].sample_offset
+        } else {
+            0
+        };
+
+        let sample_number = self.index as u32 + 1;
+        let is_sync = match &track.sync_samples {
+            None => true,
+            Some(sync) => {
+                while sync
+                    .get(self.sync_index)
+                    .is_some_and(|&n| n < sample_number)
+                {
+                    self.sync_index += 1;
+                }
+                sync.get(self.sync_index) == Some(&sample_number)
+            }
+        };
+
+        let sample = SampleInfo {
+            track_id: track.track_id,
+            index: self.index as u32,
+            size,
+            offset: self.offset,
+            dts: self.dts,
+            duration,
+            composition_offset,
+            is_sync,
+            sample_description_index: track.stsc[self.stsc_index].sample_description_index,
+        };
+
+        self.index += 1;
+        self.left_in_chunk -= 1;
+        self.offset += size as u64;
+        self.dts += duration as u64;
+        self.stts_left = self.stts_left.saturating_sub(1);
+        self.ctts_left = self.ctts_left.saturating_sub(1);
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.track.sizes.len() - self.index;
+        (left, Some(left))
+    }
+}
+
+/// trak 박스에서 샘플 테이블 읽기
+pub fn read_track_samples(trak_data: &[u8]) -> io::Result<TrackSamples> {
+    let info = extract_track_info(trak_data)?;
+    let stbl = find_box_path(trak_data, &[b"mdia", b"minf", b"stbl"])?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "stbl box not found"))?;
+    let stbl_boxes = parse_container_box(&stbl.data)?;
 
-    if total_size <= u32::MAX as usize {
-        // 32-bit size
-        output.extend_from_slice(&(total_size as u32).to_be_bytes());
-        output.extend_from_slice(box_type);
-        output.extend_from_slice(data);
+    let sizes = match find_box(&stbl_boxes, b"stsz") {
+        Some(stsz) => parse_stsz(&stsz.data)?,
+        None => Vec::new(),
+    };
+    let chunk_offsets = if let Some(stco) = find_box(&stbl_boxes, b"stco") {
+        parse_stco(&stco.data)?
+    } else if let Some(co64) = find_box(&stbl_boxes, b"co64") {
+        parse_co64(&co64.data)?
     } else {
-        // 64-bit size
-        output.extend_from_slice(&1u32.to_be_bytes()); // size = 1
-        output.extend_from_slice(box_type);
-        output.extend_from_slice(&(total_size as u64).to_be_bytes());
-        output.extend_from_slice(data);
+        Vec::new()
+    };
+    let stsc = match find_box(&stbl_boxes, b"stsc") {
+        Some(stsc) => parse_stsc(&stsc.data)?,
+        None => Vec::new(),
+    };
+    let stts = match find_box(&stbl_boxes, b"stts") {
+        Some(stts) => parse_stts(&stts.data)?,
+        None => Vec::new(),
+    };
+    let ctts = match find_box(&stbl_boxes, b"ctts") {
+        Some(ctts) => parse_ctts(&ctts.data)?,
+        None => Vec::new(),
+    };
+    let sync_samples = match find_box(&stbl_boxes, b"stss") {
+        Some(stss) => Some(parse_stss(&stss.data)?),
+        None => None,
+    };
+
+    // 청크들이 담을 수 있는 샘플 수가 stsz보다 적으면 일부 샘플의 위치를 알 수 없음
+    let mut capacity = 0u64;
+    for (i, entry) in stsc.iter().enumerate() {
+        if entry.first_chunk == 0
+            || stsc
+                .get(i + 1)
+                .is_some_and(|n| n.first_chunk <= entry.first_chunk)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "stsc first_chunk values are not increasing",
+            ));
+        }
+        let last_chunk = stsc
+            .get(i + 1)
+            .map_or(chunk_offsets.len() as u64, |next| {
+                next.first_chunk as u64 - 1
+            })
+            .min(chunk_offsets.len() as u64);
+        let chunks = last_chunk.saturating_sub(entry.first_chunk as u64 - 1);
+        capacity += chunks * entry.samples_per_chunk as u64;
+    }
+    if capacity < sizes.len() as u64 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Track {}: sample tables place only {} of {} samples",
+                info.track_id,
+                capacity,
+                sizes.len()
+            ),
+        ));
+    }
+
+    Ok(TrackSamples {
+        track_id: info.track_id,
+        timescale: info.timescale,
+        media_type: info.media_type,
+        sizes,
+        chunk_offsets,
+        stsc,
+        stts,
+        ctts,
+        sync_samples,
+    })
+}
+
+/// MP4 파일의 모든 트랙 샘플 테이블 읽기 (trak 순서)
+pub fn read_mp4_tracks(data: &[u8]) -> io::Result<Vec<TrackSamples>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    parse_container_box(&moov.data)?
+        .iter()
+        .filter(|b| &b.box_type == b"trak")
+        .map(|trak| read_track_samples(&trak.data))
+        .collect()
+}
+
+/// 여러 트랙의 샘플을 디코딩 시각 순서로 합치는 이터레이터
+///
+/// 트랙마다 timescale이 다르므로 DTS를 초 단위로 비교한다. 같은 시각이면 앞 트랙이 먼저.
+pub struct DecodeOrderSamples<'a> {
+    tracks: Vec<(u32, std::iter::Peekable<SampleIter<'a>>)>,
+}
+
+impl Iterator for DecodeOrderSamples<'_> {
+    type Item = SampleInfo;
+
+    fn next(&mut self) -> Option<SampleInfo> {
+        let mut earliest: Option<(usize, u64, u32)> = None;
+        for (i, (timescale, samples)) in self.tracks.iter_mut().enumerate() {
+            let Some(sample) = samples.peek() else {
+                continue;
+            };
+            // dts / timescale 비교를 곱셈으로
+            let is_earlier = earliest.is_none_or(|(_, dts, best_timescale)| {
+                (sample.dts as u128) * (best_timescale as u128)
+                    < (dts as u128) * (*timescale as u128)
+            });
+            if is_earlier {
+                earliest = Some((i, sample.dts, *timescale));
+            }
+        }
+
+        let (i, _, _) = earliest?;
+        self.tracks[i].1.next()
     }
 }
 
+/// 트랙들의 샘플을 디코딩 순서로 합치기
+pub fn samples_in_decode_order(tracks: &[TrackSamples]) -> DecodeOrderSamples<'_> {
+    DecodeOrderSamples {
+        tracks: tracks
+            .iter()
+            .map(|track| (track.timescale.max(1), track.samples().peekable()))
+            .collect(),
+    }
+}
+
+/// 박스를 출력 버퍼에 쓰기 (크기 자동 처리)
+fn write_box(output: &mut Vec<u8>, box_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&box_header(box_type, data.len() as u64));
+    output.extend_from_slice(data);
+}
+
 /// MP4 파일에서 타임스탬프를 리셋하여 새로운 MP4 생성
 pub fn reset_mp4_timestamps(data: &[u8]) -> io::Result<Vec<u8>> {
     let mp4 = parse_mp4(data)?;
@@ -653,47 +1001,147 @@ pub fn reset_mp4_timestamps(data: &[u8]) -> io::Result<Vec<u8>> {
     let total_durations =
         calculate_fragment_durations(&mp4.all_boxes_in_order, &first_tfdt_offsets)?;
 
-    // moov 박스 수정 (total duration 전달)
-    let new_moov = if let Some(moov) = &mp4.moov {
-        reset_moov_timestamps_with_duration(&moov.data, &total_durations)?
+    // moov 박스 수정 (fragmented이면 fragment에서 계산한 duration 전달, 일반 MP4는 기존 duration 유지)
+    let fragmented = !first_tfdt_offsets.is_empty();
+    let reset_moov = if let Some(moov) = &mp4.moov {
+        reset_moov_timestamps_with_duration(&moov.data, fragmented.then_some(&total_durations[..]))?
     } else {
         return Err(io::Error::new(ErrorKind::InvalidData, "moov box not found"));
     };
 
+    let new_moov = relocate_moov(&mp4.all_boxes_in_order, &reset_moov)?;
+
     // 새 MP4 파일 구성 - 원본 순서대로
+    // (Fragmented MP4의 fragment 타임스탬프는 상대적으로 조정)
+    rebuild_mp4(data, &mp4.all_boxes_in_order, &new_moov, |moof| {
+        reset_moof_timestamps_relative(moof, &first_tfdt_offsets)
+    })
+}
+
+/// 박스를 원본 순서대로 다시 씀: moov는 `moov_data`로 한 번만, moof는 `rewrite_moof`로
+/// 변환하고, mdat는 원본 데이터에서 직접 복사
+fn rebuild_mp4(
+    data: &[u8],
+    boxes: &[BoxInfo],
+    moov_data: &[u8],
+    mut rewrite_moof: impl FnMut(&[u8]) -> io::Result<Vec<u8>>,
+) -> io::Result<Vec<u8>> {
     let mut output = Vec::new();
     let mut moov_written = false;
 
-    for box_info in &mp4.all_boxes_in_order {
+    for box_info in boxes {
         match box_info {
-            BoxInfo::Small(mp4_box) => {
-                match &mp4_box.box_type {
-                    b"ftyp" => {
-                        write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+            BoxInfo::Small(mp4_box) => match &mp4_box.box_type {
+                b"moov" => {
+                    if !moov_written {
+                        write_box(&mut output, b"moov", moov_data);
+                        moov_written = true;
                     }
-                    b"moov" => {
-                        // moov는 리셋된 버전 사용
-                        if !moov_written {
-                            write_box(&mut output, b"moov", &new_moov);
-                            moov_written = true;
-                        }
-                    }
-                    b"moof" => {
-                        // Fragmented MP4의 fragment 타임스탬프를 상대적으로 조정
-                        let new_moof =
-                            reset_moof_timestamps_relative(&mp4_box.data, &first_tfdt_offsets)?;
-                        write_box(&mut output, b"moof", &new_moof);
-                    }
-                    _ => {
-                        // styp, emsg 등은 그대로 복사
-                        write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+                }
+                b"moof" => {
+                    let new_moof = rewrite_moof(&mp4_box.data)?;
+                    write_box(&mut output, b"moof", &new_moof);
+                }
+                _ => {
+                    // ftyp, styp, emsg 등은 그대로 복사
+                    write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+                }
+            },
+            BoxInfo::Mdat(mdat) => {
+                let mdat_data = &data[mdat.data_offset..mdat.data_offset + mdat.data_size];
+                output.extend_from_slice(&box_header(b"mdat", mdat.data_size as u64));
+                output.extend_from_slice(mdat_data);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// 새 moov 내용으로 파일을 다시 쓸 때 chunk offset을 옮긴 moov 내용을 반환
+///
+/// moov 크기가 바뀌면 뒤따르는 mdat 위치도 바뀌므로 chunk offset을 옮깁니다.
+/// (stco가 co64로 바뀌면 moov 크기가 다시 달라지므로 크기가 고정될 때까지 반복)
+fn relocate_moov(boxes: &[BoxInfo], moov_data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut new_moov = moov_data.to_vec();
+    loop {
+        let moves = plan_mdat_moves(boxes, new_moov.len());
+        let relocated = relocate_chunk_offsets(moov_data, &moves)?;
+        if relocated.len() == new_moov.len() {
+            return Ok(relocated);
+        }
+        new_moov = relocated;
+    }
+}
+
+/// 박스를 원래 순서대로 다시 쓸 때 각 mdat payload가 옮겨지는 위치
+/// (원본 시작, 원본 끝, 새 시작) - moov는 `moov_len` 크기로 한 번만 쓴다고 가정
+fn plan_mdat_moves(boxes: &[BoxInfo], moov_len: usize) -> Vec<(u64, u64, u64)> {
+    let mut moves = Vec::new();
+    let mut position = 0u64;
+    let mut moov_written = false;
+
+    for box_info in boxes {
+        match box_info {
+            BoxInfo::Small(mp4_box) => {
+                if &mp4_box.box_type == b"moov" {
+                    if !moov_written {
+                        position += 8 + moov_len as u64;
+                        moov_written = true;
                     }
+                } else {
+                    position += 8 + mp4_box.data.len() as u64;
                 }
             }
             BoxInfo::Mdat(mdat) => {
-                // mdat는 원본 데이터에서 직접 복사
-                let mdat_data = &data[mdat.data_offset..mdat.data_offset + mdat.data_size];
-                write_box(&mut output, b"mdat", mdat_data);
+                let payload_size = mdat.data_size as u64;
+                let header_size = if payload_size + 8 > u32::MAX as u64 {
+                    16
+                } else {
+                    8
+                };
+                let start = mdat.data_offset as u64;
+                moves.push((start, start + payload_size, position + header_size));
+                position += header_size + payload_size;
+            }
+        }
+    }
+
+    moves
+}
+
+/// moov 안의 모든 stco/co64 offset을 `moves`에 따라 옮김
+fn relocate_chunk_offsets(container_data: &[u8], moves: &[(u64, u64, u64)]) -> io::Result<Vec<u8>> {
+    let boxes = parse_container_box(container_data)?;
+    let mut output = Vec::new();
+
+    for mp4_box in &boxes {
+        match &mp4_box.box_type {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                let new_data = relocate_chunk_offsets(&mp4_box.data, moves)?;
+                write_box(&mut output, &mp4_box.box_type, &new_data);
+            }
+            b"stco" | b"co64" => {
+                let offsets = if &mp4_box.box_type == b"stco" {
+                    parse_stco(&mp4_box.data)?
+                } else {
+                    parse_co64(&mp4_box.data)?
+                };
+                let chunks: Vec<(u64, u32)> = offsets
+                    .iter()
+                    .map(|&offset| {
+                        let moved = moves
+                            .iter()
+                            .find(|&&(start, end, _)| offset >= start && offset < end)
+                            .map_or(offset, |&(start, _, new_start)| offset - start + new_start);
+                        (moved, 0)
+                    })
+                    .collect();
+                let (box_type, new_data) = build_chunk_offsets(&chunks)?;
+                write_box(&mut output, box_type, &new_data);
+            }
+            _ => {
+                write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
             }
         }
     }
@@ -702,173 +1150,319 @@ pub fn reset_mp4_timestamps(data: &[u8]) -> io::Result<Vec<u8>> {
 }
 
 /// moov 박스의 타임스탬프 리셋 (duration 포함)
+///
+/// `track_durations`가 None이면 (일반 MP4) 기존 duration을 유지합니다.
+/// 모든 트랙 앞에 공통으로 있는 empty edit(원본 시작 시간)은 제거하고,
+/// 트랙 간 상대적인 시작 차이는 elst에 그대로 남깁니다.
 fn reset_moov_timestamps_with_duration(
     moov_data: &[u8],
-    track_durations: &[u64],
+    track_durations: Option<&[u64]>,
 ) -> io::Result<Vec<u8>> {
-    let moov_boxes = parse_container_box(moov_data)?;
-    let mut output = Vec::new();
+    let mut moov = parse_box_tree(moov_data)?;
+
+    // 모든 트랙에 공통인 앞쪽 empty edit 길이 (movie timescale)
+    let mut lead_trim: Option<u64> = None;
+    for trak in moov.iter().filter(|b| &b.box_type == b"trak") {
+        let lead = trak_edits(trak)?
+            .map(|edits| leading_empty_duration(&edits))
+            .unwrap_or(0);
+        lead_trim = Some(lead_trim.map_or(lead, |trim| trim.min(lead)));
+    }
+    let lead_trim = lead_trim.unwrap_or(0);
+
+    // fragmented: fragment에서 계산한 길이는 트랙 timescale이므로 tkhd/mvhd용으로 변환
+    let movie_timescale = match moov.iter().find(|b| &b.box_type == b"mvhd") {
+        Some(mvhd) => typed_mvhd(mvhd)?.timescale,
+        None => 0,
+    };
+    let mut trak_durations = Vec::new(); // (mdhd, tkhd)
+    if let Some(durations) = track_durations {
+        for (index, trak) in moov.iter().filter(|b| &b.box_type == b"trak").enumerate() {
+            let media_timescale = match trak.find(&[b"mdia", b"mdhd"]) {
+                Some(mdhd) => typed_mdhd(mdhd)?.timescale,
+                None => movie_timescale,
+            };
+            let duration = durations.get(index).copied().unwrap_or(0);
+            trak_durations.push((
+                duration,
+                to_movie_timescale(duration, media_timescale, movie_timescale),
+            ));
+        }
+    }
 
-    // 비디오 트랙(track 0)의 duration을 movie duration으로 사용
-    // (각 트랙의 timescale이 다르므로 단순 max는 부적절)
-    let movie_duration = track_durations.first().copied().unwrap_or(0);
     let mut track_index = 0;
 
-    for mp4_box in &moov_boxes {
-        match &mp4_box.box_type {
+    for node in &mut moov {
+        match &node.box_type {
             b"mvhd" => {
                 // mvhd 타임스탬프 리셋 및 duration 설정
-                let new_mvhd = reset_mvhd_with_duration(&mp4_box.data, movie_duration)?;
-                write_box(&mut output, b"mvhd", &new_mvhd);
+                // fragmented: movie timescale로 변환한 트랙 길이 중 최댓값
+                let movie_duration = match track_durations {
+                    Some(_) => trak_durations
+                        .iter()
+                        .map(|&(_, tkhd)| tkhd)
+                        .max()
+                        .unwrap_or(0),
+                    None => typed_mvhd(node)?.duration.saturating_sub(lead_trim),
+                };
+                rewrite_header_duration(node, movie_duration)?;
             }
             b"trak" => {
                 // trak 내부 재귀 처리 (해당 트랙의 duration 전달)
-                let trak_duration = if track_index < track_durations.len() {
-                    track_durations[track_index]
-                } else {
-                    0
-                };
-                let new_trak = reset_trak_timestamps_with_duration(&mp4_box.data, trak_duration)?;
-                write_box(&mut output, b"trak", &new_trak);
+                let trak_duration = track_durations
+                    .map(|_| trak_durations.get(track_index).copied().unwrap_or((0, 0)));
+                reset_trak_timestamps_with_duration(node, trak_duration, lead_trim)?;
                 track_index += 1;
             }
             _ => {
-                // 다른 박스는 그대로 복사
-                write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+                // 다른 박스는 그대로 유지
             }
         }
     }
 
-    Ok(output)
+    Ok(write_box_tree(&moov))
 }
 
-/// mvhd 박스 타임스탬프 리셋 및 duration 설정
-fn reset_mvhd_with_duration(data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let mut output = data.to_vec();
-    let (version, _) = read_full_box_header(data)?;
-
-    if version == 1 {
-        // 64-bit version
-        output[4..12].fill(0); // creation_time = 0
-        output[12..20].fill(0); // modification_time = 0
-
-        // timescale at offset 20 (4 bytes)
-        let timescale = u32::from_be_bytes([output[20], output[21], output[22], output[23]]);
-
-        // duration at offset 24 (8 bytes) - 이미 해당 timescale 기준
-        output[24..32].copy_from_slice(&duration.to_be_bytes());
-    } else {
-        // 32-bit version
-        output[4..8].fill(0); // creation_time = 0
-        output[8..12].fill(0); // modification_time = 0
-
-        // timescale at offset 12 (4 bytes)
-        let timescale = u32::from_be_bytes([output[12], output[13], output[14], output[15]]);
+/// trak 박스 타임스탬프 리셋 및 duration 설정
+/// (`durations`는 (mdhd, tkhd) 길이, None이면 기존 값 유지하고 tkhd는 제거된 empty edit만큼 줄임)
+fn reset_trak_timestamps_with_duration(
+    trak: &mut BoxNode,
+    durations: Option<(u64, u64)>,
+    lead_trim: u64,
+) -> io::Result<()> {
+    let children = trak.children_mut().ok_or_else(|| malformed_box(b"trak"))?;
 
-        // duration at offset 16 (4 bytes)
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
-        } else {
-            duration as u32
+    for node in std::mem::take(children) {
+        if &node.box_type != b"edts" {
+            children.push(node);
+            continue;
+        }
+        // 공통 empty edit 제거 후 다시 작성 (elst가 없거나 남는 엔트리가 없으면 edts 제거)
+        let Some(edits) = node.find(&[b"elst"]).map(elst_entries).transpose()? else {
+            continue;
         };
-        output[16..20].copy_from_slice(&duration_32.to_be_bytes());
+        let edits = trim_leading_empty_edits(&edits, lead_trim);
+        if !edits.is_empty() {
+            children.push(edts_node(&edits)?);
+        }
     }
 
-    Ok(output)
-}
-
-/// trak 박스 타임스탬프 리셋 및 duration 설정
-fn reset_trak_timestamps_with_duration(trak_data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let trak_boxes = parse_container_box(trak_data)?;
-    let mut output = Vec::new();
-
-    for mp4_box in &trak_boxes {
-        match &mp4_box.box_type {
+    for node in children.iter_mut() {
+        match &node.box_type {
             b"tkhd" => {
-                let new_tkhd = reset_tkhd_with_duration(&mp4_box.data, duration)?;
-                write_box(&mut output, b"tkhd", &new_tkhd);
+                let tkhd_duration = match durations {
+                    Some((_, tkhd)) => tkhd,
+                    None => typed_tkhd(node)?.duration.saturating_sub(lead_trim),
+                };
+                rewrite_header_duration(node, tkhd_duration)?;
             }
             b"mdia" => {
-                let new_mdia = reset_mdia_timestamps_with_duration(&mp4_box.data, duration)?;
-                write_box(&mut output, b"mdia", &new_mdia);
-            }
-            _ => {
-                write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+                let mdia = node.children_mut().ok_or_else(|| malformed_box(b"mdia"))?;
+                for mdhd in mdia.iter_mut().filter(|b| &b.box_type == b"mdhd") {
+                    let mdhd_duration = match durations {
+                        Some((mdhd, _)) => mdhd,
+                        None => typed_mdhd(mdhd)?.duration,
+                    };
+                    rewrite_header_duration(mdhd, mdhd_duration)?;
+                }
             }
+            _ => {}
         }
     }
 
-    Ok(output)
+    Ok(())
 }
 
-/// tkhd 박스 타임스탬프 리셋 및 duration 설정
-fn reset_tkhd_with_duration(data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let mut output = data.to_vec();
-    let (version, _) = read_full_box_header(data)?;
+/// mvhd/tkhd/mdhd 노드 재작성: creation/modification time = 0, duration 설정
+///
+/// 시간 필드는 version 1에서 64비트이므로 duration이 u32를 넘으면 version 1로,
+/// 아니면 version 0으로 씁니다.
+fn rewrite_header_duration(node: &mut BoxNode, duration: u64) -> io::Result<()> {
+    let BoxBody::Full {
+        version, content, ..
+    } = &mut node.body
+    else {
+        return Err(malformed_box(&node.box_type));
+    };
+    let (creation_time, modification_time, header_duration) = match content {
+        FullBox::Mvhd(mvhd) => (
+            &mut mvhd.creation_time,
+            &mut mvhd.modification_time,
+            &mut mvhd.duration,
+        ),
+        FullBox::Tkhd(tkhd) => (
+            &mut tkhd.creation_time,
+            &mut tkhd.modification_time,
+            &mut tkhd.duration,
+        ),
+        FullBox::Mdhd(mdhd) => (
+            &mut mdhd.creation_time,
+            &mut mdhd.modification_time,
+            &mut mdhd.duration,
+        ),
+        _ => return Err(malformed_box(&node.box_type)),
+    };
+    *creation_time = 0;
+    *modification_time = 0;
+    *header_duration = duration;
+    *version = if duration > u32::MAX as u64 { 1 } else { 0 };
+    Ok(())
+}
 
-    if version == 1 {
-        output[4..12].fill(0); // creation_time = 0
-        output[12..20].fill(0); // modification_time = 0
-                                // duration at offset 24 (after track_id and reserved)
-        output[24..32].copy_from_slice(&duration.to_be_bytes());
-    } else {
-        output[4..8].fill(0); // creation_time = 0
-        output[8..12].fill(0); // modification_time = 0
-                               // duration at offset 16
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
-        } else {
-            duration as u32
-        };
-        output[16..20].copy_from_slice(&duration_32.to_be_bytes());
+fn typed_mvhd(node: &BoxNode) -> io::Result<&Mvhd> {
+    match node.full_box() {
+        Some(FullBox::Mvhd(mvhd)) => Ok(mvhd),
+        _ => Err(malformed_box(b"mvhd")),
     }
-
-    Ok(output)
 }
 
-/// mdia 박스 타임스탬프 리셋 및 duration 설정
-fn reset_mdia_timestamps_with_duration(mdia_data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let mdia_boxes = parse_container_box(mdia_data)?;
-    let mut output = Vec::new();
+fn typed_tkhd(node: &BoxNode) -> io::Result<&Tkhd> {
+    match node.full_box() {
+        Some(FullBox::Tkhd(tkhd)) => Ok(tkhd),
+        _ => Err(malformed_box(b"tkhd")),
+    }
+}
 
-    for mp4_box in &mdia_boxes {
-        match &mp4_box.box_type {
-            b"mdhd" => {
-                let new_mdhd = reset_mdhd_with_duration(&mp4_box.data, duration)?;
-                write_box(&mut output, b"mdhd", &new_mdhd);
-            }
-            _ => {
-                write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
-            }
-        }
+fn typed_mdhd(node: &BoxNode) -> io::Result<&Mdhd> {
+    match node.full_box() {
+        Some(FullBox::Mdhd(mdhd)) => Ok(mdhd),
+        _ => Err(malformed_box(b"mdhd")),
     }
+}
 
-    Ok(output)
+fn malformed_box(box_type: &[u8; 4]) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Malformed {} box", String::from_utf8_lossy(box_type)),
+    )
 }
 
-/// mdhd 박스 타임스탬프 리셋 및 duration 설정
-fn reset_mdhd_with_duration(data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let mut output = data.to_vec();
-    let (version, _) = read_full_box_header(data)?;
+/// trak 노드의 edts/elst 읽기 (없으면 None)
+fn trak_edits(trak: &BoxNode) -> io::Result<Option<Vec<EditListEntry>>> {
+    trak.find(&[b"edts", b"elst"]).map(elst_entries).transpose()
+}
 
-    if version == 1 {
-        output[4..12].fill(0); // creation_time = 0
-        output[12..20].fill(0); // modification_time = 0
-                                // duration at offset 24 (after timescale)
-        output[24..32].copy_from_slice(&duration.to_be_bytes());
+/// elst 노드의 엔트리 (typed로 읽지 못한 elst는 원본 데이터에서 파싱)
+fn elst_entries(elst: &BoxNode) -> io::Result<Vec<EditListEntry>> {
+    match &elst.body {
+        BoxBody::Full {
+            content: FullBox::Elst(edits),
+            ..
+        } => Ok(edits.clone()),
+        BoxBody::Raw(data) => parse_elst(data),
+        _ => Err(malformed_box(b"elst")),
+    }
+}
+
+/// `edits`로 새 edts 노드 생성
+fn edts_node(edits: &[EditListEntry]) -> io::Result<BoxNode> {
+    Ok(BoxNode::new(
+        b"edts",
+        BoxBody::Container(parse_box_tree(&build_edts(edits))?),
+    ))
+}
+
+/// trak의 edts/elst 읽기 (없으면 None)
+pub(crate) fn read_trak_edits(trak_data: &[u8]) -> io::Result<Option<Vec<EditListEntry>>> {
+    match find_box_path(trak_data, &[b"edts", b"elst"])? {
+        Some(elst) => Ok(Some(parse_elst(&elst.data)?)),
+        None => Ok(None),
+    }
+}
+
+/// 앞쪽 empty edit(media_time = -1) 길이의 합
+pub(crate) fn leading_empty_duration(edits: &[EditListEntry]) -> u64 {
+    edits
+        .iter()
+        .take_while(|e| e.media_time == -1)
+        .map(|e| e.segment_duration)
+        .sum()
+}
+
+/// 앞쪽 empty edit에서 `trim`만큼 잘라냄 (길이가 0이 된 엔트리는 제거)
+fn trim_leading_empty_edits(edits: &[EditListEntry], trim: u64) -> Vec<EditListEntry> {
+    let mut remaining = trim;
+    let mut output = Vec::with_capacity(edits.len());
+
+    for edit in edits {
+        if remaining > 0 && edit.media_time == -1 {
+            let cut = remaining.min(edit.segment_duration);
+            remaining -= cut;
+            if edit.segment_duration > cut {
+                output.push(EditListEntry {
+                    segment_duration: edit.segment_duration - cut,
+                    ..*edit
+                });
+            }
+        } else {
+            remaining = 0;
+            output.push(*edit);
+        }
+    }
+
+    output
+}
+
+/// track(media) timescale의 길이를 movie timescale로 변환 (내림)
+fn to_movie_timescale(duration: u64, media_timescale: u32, movie_timescale: u32) -> u64 {
+    if media_timescale == 0 || media_timescale == movie_timescale {
+        return duration;
+    }
+    (duration as u128 * movie_timescale as u128 / media_timescale as u128) as u64
+}
+
+/// 길이가 0인 media edit(fragmented에서 "끝까지")을 실제 길이로 채움
+fn fill_open_edits(
+    edits: &[EditListEntry],
+    media_duration: u64,
+    media_timescale: u32,
+    movie_timescale: u32,
+) -> Vec<EditListEntry> {
+    edits
+        .iter()
+        .map(|edit| {
+            if edit.segment_duration == 0 && edit.media_time >= 0 {
+                let remaining = media_duration.saturating_sub(edit.media_time as u64);
+                let segment_duration =
+                    to_movie_timescale(remaining, media_timescale, movie_timescale);
+                EditListEntry {
+                    segment_duration,
+                    ..*edit
+                }
+            } else {
+                *edit
+            }
+        })
+        .collect()
+}
+
+/// edts 박스 데이터 생성 (값이 32비트를 넘으면 elst version 1)
+fn build_edts(edits: &[EditListEntry]) -> Vec<u8> {
+    let version = if edits
+        .iter()
+        .any(|e| e.segment_duration > u32::MAX as u64 || i32::try_from(e.media_time).is_err())
+    {
+        1
     } else {
-        output[4..8].fill(0); // creation_time = 0
-        output[8..12].fill(0); // modification_time = 0
-                               // duration at offset 16
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
+        0
+    };
+
+    let mut elst = vec![version, 0, 0, 0];
+    elst.extend_from_slice(&(edits.len() as u32).to_be_bytes());
+    for edit in edits {
+        if version == 1 {
+            elst.extend_from_slice(&edit.segment_duration.to_be_bytes());
+            elst.extend_from_slice(&edit.media_time.to_be_bytes());
         } else {
-            duration as u32
-        };
-        output[16..20].copy_from_slice(&duration_32.to_be_bytes());
+            elst.extend_from_slice(&(edit.segment_duration as u32).to_be_bytes());
+            elst.extend_from_slice(&(edit.media_time as i32).to_be_bytes());
+        }
+        elst.extend_from_slice(&edit.media_rate.to_be_bytes());
     }
 
-    Ok(output)
+    let mut edts = Vec::new();
+    write_box(&mut edts, b"elst", &elst);
+    edts
 }
 
 /// moov 박스의 타임스탬프 리셋
@@ -1046,9 +1640,7 @@ fn extract_first_tfdt_values(boxes: &[BoxInfo]) -> io::Result<Vec<u64>> {
                                     traf_child.data[6],
                                     traf_child.data[7],
                                 ]) as usize;
-                                if track_index > 0 {
-                                    track_index -= 1; // 1-based to 0-based
-                                }
+                                track_index = track_index.saturating_sub(1); // 1-based to 0-based
                                 break;
                             }
                         }
@@ -1104,9 +1696,7 @@ fn calculate_fragment_durations(
                                     traf_child.data[6],
                                     traf_child.data[7],
                                 ]) as usize;
-                                if track_index > 0 {
-                                    track_index -= 1;
-                                }
+                                track_index = track_index.saturating_sub(1);
                                 break;
                             }
                         }
@@ -1319,9 +1909,7 @@ fn reset_traf_timestamps_relative(
                 mp4_box.data[6],
                 mp4_box.data[7],
             ]) as usize;
-            if track_index > 0 {
-                track_index -= 1;
-            }
+            track_index = track_index.saturating_sub(1);
             break;
         }
     }
@@ -1405,6 +1993,12 @@ fn reset_tfdt(data: &[u8]) -> io::Result<Vec<u8>> {
 
 /// Fragmented MP4를 일반 MP4로 변환 (defragment)
 pub fn defragment_mp4(data: &[u8]) -> io::Result<Vec<u8>> {
+    defragment_mp4_interleaved(data, DEFAULT_INTERLEAVE_MS)
+}
+
+/// Fragmented MP4를 일반 MP4로 변환 - 트랙별 sample을 `interleave_ms` 단위 chunk로 교차 배치
+/// (`interleave_ms` = 0 이면 트랙마다 하나의 chunk)
+pub fn defragment_mp4_interleaved(data: &[u8], interleave_ms: u32) -> io::Result<Vec<u8>> {
     let mp4 = parse_mp4(data)?;
 
     // Fragmented MP4인지 확인
@@ -1432,7 +2026,7 @@ pub fn defragment_mp4(data: &[u8]) -> io::Result<Vec<u8>> {
     collect_fragment_data(data, &mp4.all_boxes_in_order, &mut track_fragments)?;
 
     // 일반 MP4 생성
-    build_regular_mp4(data, &mp4, track_fragments)
+    build_regular_mp4(data, &mp4, track_fragments, interleave_ms)
 }
 
 /// moov에서 track 정보 추출
@@ -1520,7 +2114,6 @@ fn collect_fragment_data(
     tracks: &mut [TrackFragments],
 ) -> io::Result<()> {
     let mut current_moof: Option<(&Mp4Box, usize)> = None; // (moof box, file offset)
-    let mut current_mdat: Option<&[u8]> = None;
 
     // 파일 offset 계산
     let mut file_offset = 0usize;
@@ -1543,7 +2136,7 @@ fn collect_fragment_data(
                 current_moof = Some((mp4_box, *offset));
             }
             BoxInfo::Mdat(mdat) => {
-                current_mdat =
+                let current_mdat =
                     Some(&file_data[mdat.data_offset..mdat.data_offset + mdat.data_size]);
 
                 // moof와 mdat 쌍 처리
@@ -1795,473 +2388,319 @@ fn build_regular_mp4(
     _original_data: &[u8],
     mp4: &Mp4File,
     track_fragments: Vec<TrackFragments>,
+    interleave_ms: u32,
 ) -> io::Result<Vec<u8>> {
     let mut output = Vec::new();
 
-    // ftyp 박스 생성 (QuickTime 호환성을 위해 isom 기반으로)
-    let ftyp_data = {
-        let mut data = Vec::new();
-        data.extend_from_slice(b"isom"); // major_brand
-        data.extend_from_slice(&[0, 0, 0, 2]); // minor_version
-                                               // compatible_brands: isom, iso2, avc1, mp41
-        data.extend_from_slice(b"isom");
-        data.extend_from_slice(b"iso2");
-        data.extend_from_slice(b"avc1");
-        data.extend_from_slice(b"mp41");
-        data
-    };
-    write_box(&mut output, b"ftyp", &ftyp_data);
-
-    // moov의 크기를 먼저 계산해야 mdat offset을 알 수 있음
-    // 임시로 moov를 생성해서 크기 계산
-    let temp_moov = build_regular_moov(
-        &mp4.moov.as_ref().unwrap().data,
-        &track_fragments,
-        &Vec::new(),
-    )?;
-    let moov_size = temp_moov.len() + 8; // box header 포함
+    // ftyp 박스 생성 (TS 변환과 같은 isom, iso2, avc1, mp41)
+    output.extend_from_slice(&build_ftyp(&FileBrands::default()));
 
-    // 각 트랙의 mdat 시작 offset 계산
-    let ftyp_size = 32; // 고정된 ftyp 크기 (isom + 4 brands)
+    let original_moov = &mp4.moov.as_ref().unwrap().data;
 
-    let mut track_mdat_offsets = Vec::new();
-    let mut current_offset = ftyp_size + moov_size + 8; // ftyp + moov + mdat header
+    // mdat이 4GB를 넘으면 64-bit largesize 헤더(16바이트) 사용
+    let mdat_payload_size: usize = track_fragments.iter().map(|t| t.mdat_data.len()).sum();
+    let mdat_header = box_header(b"mdat", mdat_payload_size as u64);
 
-    for track in &track_fragments {
-        track_mdat_offsets.push(current_offset);
-        current_offset += track.mdat_data.len();
-    }
+    // mdat 안의 chunk 순서 (decode time 기준 interleave)
+    let chunk_plan = plan_interleaved_chunks(&track_fragments, interleave_ms);
 
-    // 실제 moov 생성 (offset 정보 포함)
-    let moov_data = build_regular_moov(
-        &mp4.moov.as_ref().unwrap().data,
-        &track_fragments,
-        &track_mdat_offsets,
-    )?;
-    write_box(&mut output, b"moov", &moov_data);
+    // moov의 크기를 먼저 알아야 mdat offset을 계산할 수 있음
+    // offset이 커져 stco가 co64로 바뀌면 moov 크기도 달라지므로 크기가 고정될 때까지 반복
+    let mut moov_data = build_regular_moov(original_moov, &track_fragments, &[])?;
+    loop {
+        let mdat_start = output.len() + moov_data.len() + 8 + mdat_header.len(); // ftyp + moov + mdat header
+        let track_chunks = layout_chunks(&track_fragments, &chunk_plan, mdat_start as u64);
 
-    // 단일 mdat에 모든 트랙 데이터 저장 (트랙 순서대로)
-    let mut mdat_data = Vec::new();
-    for track in &track_fragments {
-        mdat_data.extend_from_slice(&track.mdat_data);
+        // 실제 moov 생성 (offset 정보 포함)
+        let new_moov = build_regular_moov(original_moov, &track_fragments, &track_chunks)?;
+        let stable = new_moov.len() == moov_data.len();
+        moov_data = new_moov;
+        if stable {
+            break;
+        }
     }
-    write_box(&mut output, b"mdat", &mdat_data);
+    write_box(&mut output, b"moov", &moov_data);
+
+    // 단일 mdat에 모든 트랙 데이터를 chunk 순서대로 저장
+    let sample_starts: Vec<Vec<usize>> = track_fragments
+        .iter()
+        .map(|track| {
+            let mut start = 0;
+            let mut starts = Vec::with_capacity(track.samples.len() + 1);
+            starts.push(0);
+            for sample in &track.samples {
+                start += sample.size as usize;
+                starts.push(start);
+            }
+            starts
+        })
+        .collect();
+
+    let mut mdat_data = Vec::with_capacity(mdat_payload_size);
+    for (track_index, samples) in &chunk_plan {
+        let starts = &sample_starts[*track_index];
+        let chunk = track_fragments[*track_index]
+            .mdat_data
+            .get(starts[samples.start]..starts[samples.end])
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "Fragment sample data out of range")
+            })?;
+        mdat_data.extend_from_slice(chunk);
+    }
+    output.extend_from_slice(&mdat_header);
+    output.extend_from_slice(&mdat_data);
 
     Ok(output)
 }
 
-/// 일반 MP4용 moov 박스 생성
-fn build_regular_moov(
-    original_moov_data: &[u8],
-    track_fragments: &[TrackFragments],
-    track_mdat_offsets: &[usize],
-) -> io::Result<Vec<u8>> {
-    let moov_boxes = parse_container_box(original_moov_data)?;
-    let mut output = Vec::new();
-
-    // mvhd에서 movie timescale 추출
-    let movie_timescale = if let Some(mvhd_box) = moov_boxes.iter().find(|b| &b.box_type == b"mvhd")
+/// mdat에 기록할 chunk 순서 계산: (track index, sample 범위)
+/// 모든 트랙을 decode time 기준 `interleave_ms` 구간마다 번갈아 배치
+/// `interleave_ms` = 0 이면 트랙 순서대로 트랙당 하나의 chunk
+fn plan_interleaved_chunks(
+    tracks: &[TrackFragments],
+    interleave_ms: u32,
+) -> Vec<(usize, Range<usize>)> {
+    if interleave_ms == 0 {
+        return tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| !track.samples.is_empty())
+            .map(|(index, track)| (index, 0..track.samples.len()))
+            .collect();
+    }
+
+    let mut next_sample = vec![0usize; tracks.len()];
+    let mut decode_time = vec![0u64; tracks.len()]; // 트랙 timescale 기준
+    let mut plan = Vec::new();
+    let mut window = 1u64;
+
+    while tracks
+        .iter()
+        .zip(&next_sample)
+        .any(|(track, &next)| next < track.samples.len())
     {
-        if mvhd_box.data.len() >= 16 {
-            let version = mvhd_box.data[0];
-            if version == 1 && mvhd_box.data.len() >= 24 {
-                u32::from_be_bytes([
-                    mvhd_box.data[20],
-                    mvhd_box.data[21],
-                    mvhd_box.data[22],
-                    mvhd_box.data[23],
-                ])
-            } else if mvhd_box.data.len() >= 16 {
-                u32::from_be_bytes([
-                    mvhd_box.data[12],
-                    mvhd_box.data[13],
-                    mvhd_box.data[14],
-                    mvhd_box.data[15],
-                ])
-            } else {
-                1000 // fallback
+        for (index, track) in tracks.iter().enumerate() {
+            // 현재 구간의 끝 (트랙 timescale로 변환)
+            let window_end = window * interleave_ms as u64 * track.timescale.max(1) as u64 / 1000;
+            let first = next_sample[index];
+
+            while next_sample[index] < track.samples.len() && decode_time[index] < window_end {
+                decode_time[index] += track.samples[next_sample[index]].duration as u64;
+                next_sample[index] += 1;
             }
-        } else {
-            1000 // fallback
-        }
-    } else {
-        1000 // fallback
-    };
 
-    for moov_box in &moov_boxes {
-        match &moov_box.box_type {
-            b"mvhd" => {
-                // mvhd duration: track duration을 movie timescale로 변환
-                // 가장 긴 트랙의 duration을 사용 (일반적으로 비디오 트랙)
-                let max_duration_in_movie_ts = track_fragments
-                    .iter()
-                    .map(|t| {
-                        let track_duration: u64 = t.samples.iter().map(|s| s.duration as u64).sum();
-                        // track timescale -> movie timescale로 변환
-                        if t.timescale != 0 && t.timescale != movie_timescale {
-                            (track_duration * movie_timescale as u64) / t.timescale as u64
-                        } else {
-                            track_duration
-                        }
-                    })
-                    .max()
-                    .unwrap_or(0);
-
-                let new_mvhd = update_mvhd_duration(&moov_box.data, max_duration_in_movie_ts)?;
-                write_box(&mut output, b"mvhd", &new_mvhd);
-            }
-            b"trak" => {
-                // 원본 trak에서 track_id 추출
-                let trak_boxes = parse_container_box(&moov_box.data)?;
-                let mut trak_track_id = 0u32;
-
-                for trak_box in &trak_boxes {
-                    if &trak_box.box_type == b"tkhd" && trak_box.data.len() >= 12 {
-                        let version = trak_box.data[0];
-                        let offset = if version == 1 { 20 } else { 12 };
-                        if trak_box.data.len() >= offset + 4 {
-                            trak_track_id = u32::from_be_bytes([
-                                trak_box.data[offset],
-                                trak_box.data[offset + 1],
-                                trak_box.data[offset + 2],
-                                trak_box.data[offset + 3],
-                            ]);
-                        }
-                        break;
-                    }
-                }
-
-                // track_id로 해당하는 fragment 찾기
-                if let Some(track_frag_index) = track_fragments
-                    .iter()
-                    .position(|t| t.track_id == trak_track_id)
-                {
-                    let mdat_offset = if track_frag_index < track_mdat_offsets.len() {
-                        track_mdat_offsets[track_frag_index]
-                    } else {
-                        8000 // fallback
-                    };
-
-                    let new_trak = build_regular_trak(
-                        &moov_box.data,
-                        &track_fragments[track_frag_index],
-                        mdat_offset,
-                    )?;
-                    write_box(&mut output, b"trak", &new_trak);
-                } else {
-                    // fragment가 없으면 원본 그대로
-                    write_box(&mut output, b"trak", &moov_box.data);
-                }
-            }
-            _ => {
-                // 다른 박스는 그대로
-                write_box(&mut output, &moov_box.box_type, &moov_box.data);
+            if next_sample[index] > first {
+                plan.push((index, first..next_sample[index]));
             }
         }
+        window += 1;
     }
 
-    Ok(output)
+    plan
 }
 
-/// mvhd duration 업데이트 (version 0으로 다운그레이드)
-fn update_mvhd_duration(mvhd_data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let old_version = mvhd_data[0];
-
-    if old_version == 1 && mvhd_data.len() >= 32 {
-        // version 1 -> version 0으로 다운그레이드
-        let mut output = vec![0u8; 108]; // mvhd version 0의 표준 크기
+/// chunk 계획을 트랙별 (파일 offset, sample 개수) 목록으로 변환
+fn layout_chunks(
+    tracks: &[TrackFragments],
+    chunk_plan: &[(usize, Range<usize>)],
+    mdat_start: u64,
+) -> Vec<Vec<(u64, u32)>> {
+    let mut track_chunks = vec![Vec::new(); tracks.len()];
+    let mut offset = mdat_start;
 
-        // version = 0, flags = 0
-        output[0..4].copy_from_slice(&[0, 0, 0, 0]);
+    for (track_index, samples) in chunk_plan {
+        track_chunks[*track_index].push((offset, samples.len() as u32));
+        offset += tracks[*track_index].samples[samples.clone()]
+            .iter()
+            .map(|s| s.size as u64)
+            .sum::<u64>();
+    }
 
-        // creation_time = 0 (4바이트)
-        output[4..8].copy_from_slice(&[0, 0, 0, 0]);
+    track_chunks
+}
 
-        // modification_time = 0 (4바이트)
-        output[8..12].copy_from_slice(&[0, 0, 0, 0]);
+/// 일반 MP4용 moov 박스 생성
+fn build_regular_moov(
+    original_moov_data: &[u8],
+    track_fragments: &[TrackFragments],
+    track_chunks: &[Vec<(u64, u32)>],
+) -> io::Result<Vec<u8>> {
+    let mut moov = parse_box_tree(original_moov_data)?;
 
-        // timescale (4바이트) - 원본에서 복사
-        output[12..16].copy_from_slice(&mvhd_data[20..24]);
+    // mvhd에서 movie timescale 추출
+    let movie_timescale = match moov.iter().find(|b| &b.box_type == b"mvhd") {
+        Some(mvhd) => typed_mvhd(mvhd)?.timescale,
+        None => 1000, // fallback
+    };
 
-        // duration (4바이트) - 새 값 설정
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
-        } else {
-            duration as u32
+    // mvhd duration: 가장 긴 트랙의 재생 길이 (edit 포함, movie timescale)
+    let mut max_duration_in_movie_ts = 0u64;
+    for trak in moov.iter_mut().filter(|b| &b.box_type == b"trak") {
+        // 원본 trak의 track_id로 해당하는 fragment 찾기 (fragment가 없으면 원본 그대로)
+        let track_id = match trak.find(&[b"tkhd"]).and_then(BoxNode::full_box) {
+            Some(FullBox::Tkhd(tkhd)) => tkhd.track_id,
+            _ => 0,
+        };
+        let Some(track_frag_index) = track_fragments.iter().position(|t| t.track_id == track_id)
+        else {
+            continue;
         };
-        output[16..20].copy_from_slice(&duration_32.to_be_bytes());
-
-        // rate (4바이트) - 0x00010000 (1.0)
-        output[20..24].copy_from_slice(&[0x00, 0x01, 0x00, 0x00]);
-
-        // volume (2바이트) - 0x0100 (1.0)
-        output[24..26].copy_from_slice(&[0x01, 0x00]);
-
-        // reserved (10바이트)
-        output[26..36].fill(0);
-
-        // matrix (36바이트) - 단위 행렬
-        output[36..40].copy_from_slice(&[0x00, 0x01, 0x00, 0x00]);
-        output[40..44].fill(0);
-        output[44..48].fill(0);
-        output[48..52].fill(0);
-        output[52..56].copy_from_slice(&[0x00, 0x01, 0x00, 0x00]);
-        output[56..60].fill(0);
-        output[60..64].fill(0);
-        output[64..68].fill(0);
-        output[68..72].copy_from_slice(&[0x40, 0x00, 0x00, 0x00]);
-
-        // pre_defined (24바이트)
-        output[72..96].fill(0);
-
-        // next_track_ID - 원본 또는 3
-        if mvhd_data.len() >= 112 {
-            output[96..100].copy_from_slice(&mvhd_data[108..112]);
-        } else {
-            output[96..100].copy_from_slice(&[0, 0, 0, 3]);
-        }
 
-        // 나머지는 0
-        output[100..108].fill(0);
+        // chunk 배치가 아직 없으면 (moov 크기 계산용) 빈 목록
+        let chunks = track_chunks
+            .get(track_frag_index)
+            .map(|c| c.as_slice())
+            .unwrap_or(&[]);
 
-        return Ok(output);
+        let duration = build_regular_trak(
+            trak,
+            &track_fragments[track_frag_index],
+            chunks,
+            movie_timescale,
+        )?;
+        max_duration_in_movie_ts = max_duration_in_movie_ts.max(duration);
     }
 
-    // 이미 version 0이면 creation_time, modification_time, duration만 업데이트
-    let mut output = mvhd_data.to_vec();
-
-    if output.len() >= 20 {
-        // creation_time = 0
-        output[4..8].fill(0);
-        // modification_time = 0
-        output[8..12].fill(0);
-        // duration 설정
-        let bytes = (duration as u32).to_be_bytes();
-        output[16..20].copy_from_slice(&bytes);
+    for mvhd in moov.iter_mut().filter(|b| &b.box_type == b"mvhd") {
+        rewrite_header_duration(mvhd, max_duration_in_movie_ts)?;
     }
 
-    Ok(output)
+    Ok(write_box_tree(&moov))
 }
 
-/// 일반 MP4용 trak 박스 생성
+/// 일반 MP4용 trak 노드 재구성 (tkhd duration을 반환)
 fn build_regular_trak(
-    original_trak_data: &[u8],
+    trak: &mut BoxNode,
     track_fragment: &TrackFragments,
-    mdat_offset: usize,
-) -> io::Result<Vec<u8>> {
-    let trak_boxes = parse_container_box(original_trak_data)?;
-    let mut output = Vec::new();
-
-    // track의 총 duration 계산
-    let total_duration: u64 = track_fragment
-        .samples
-        .iter()
-        .map(|s| s.duration as u64)
-        .sum();
-
-    for trak_box in &trak_boxes {
-        match &trak_box.box_type {
+    chunks: &[(u64, u32)],
+    movie_timescale: u32,
+) -> io::Result<u64> {
+    let (edits, tkhd_duration) = regular_trak_edits(trak, track_fragment, movie_timescale)?;
+
+    let children = trak.children_mut().ok_or_else(|| malformed_box(b"trak"))?;
+    for node in children.iter_mut() {
+        match &node.box_type {
             b"tkhd" => {
-                // tkhd duration 업데이트
-                let new_tkhd = update_tkhd_duration(&trak_box.data, total_duration)?;
-                write_box(&mut output, b"tkhd", &new_tkhd);
+                // tkhd duration 업데이트 (movie timescale)
+                rewrite_header_duration(node, tkhd_duration)?;
             }
             b"mdia" => {
                 // mdia 재구성
-                let new_mdia = build_regular_mdia(&trak_box.data, track_fragment, mdat_offset)?;
-                write_box(&mut output, b"mdia", &new_mdia);
+                build_regular_mdia(node, track_fragment, chunks)?;
             }
             b"edts" => {
                 // edts는 유지 (QuickTime Player 호환성)
-                write_box(&mut output, &trak_box.box_type, &trak_box.data);
-            }
-            _ => {
-                write_box(&mut output, &trak_box.box_type, &trak_box.data);
+                if let Some(edits) = &edits {
+                    *node = edts_node(edits)?;
+                }
             }
+            _ => {}
         }
     }
 
-    Ok(output)
+    Ok(tkhd_duration)
 }
 
-/// tkhd duration 업데이트 (version 0으로 다운그레이드)
-fn update_tkhd_duration(tkhd_data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let old_version = tkhd_data[0];
-
-    if old_version == 1 && tkhd_data.len() >= 36 {
-        // version 1 -> version 0으로 다운그레이드
-        let mut output = vec![0u8; 92]; // tkhd version 0의 표준 크기
-
-        // version = 0, flags (원본 flags 복사)
-        output[0] = 0;
-        output[1..4].copy_from_slice(&tkhd_data[1..4]);
-
-        // creation_time = 0 (4바이트)
-        output[4..8].fill(0);
-
-        // modification_time = 0 (4바이트)
-        output[8..12].fill(0);
-
-        // track_id (4바이트) - 원본에서 복사
-        output[12..16].copy_from_slice(&tkhd_data[20..24]);
-
-        // reserved (4바이트)
-        output[16..20].fill(0);
-
-        // duration (4바이트) - 새 값 설정
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
-        } else {
-            duration as u32
-        };
-        output[20..24].copy_from_slice(&duration_32.to_be_bytes());
-
-        // 나머지는 원본에서 복사 (layer, alternate_group, volume, reserved, matrix, width, height)
-        if tkhd_data.len() >= 104 {
-            // version 1의 36바이트 이후 데이터를 version 0의 24바이트 이후로 복사
-            output[24..92].copy_from_slice(&tkhd_data[36..104]);
-        }
+/// 일반 MP4로 바꿀 trak의 edit list와 재생 길이 (movie timescale)
+///
+/// fragmented의 열린 edit(길이 0)은 실제 길이로 채우고, 재생 길이는 edit이 있으면
+/// edit 길이의 합, 없으면 샘플 길이의 합을 movie timescale로 변환한 값입니다.
+fn regular_trak_edits(
+    trak: &BoxNode,
+    track_fragment: &TrackFragments,
+    movie_timescale: u32,
+) -> io::Result<(Option<Vec<EditListEntry>>, u64)> {
+    let total_duration: u64 = track_fragment
+        .samples
+        .iter()
+        .map(|s| s.duration as u64)
+        .sum();
 
-        return Ok(output);
-    }
+    let edits = trak_edits(trak)?.map(|edits| {
+        fill_open_edits(
+            &edits,
+            total_duration,
+            track_fragment.timescale,
+            movie_timescale,
+        )
+    });
+    let duration = match &edits {
+        Some(edits) => edits.iter().map(|e| e.segment_duration).sum(),
+        None => to_movie_timescale(total_duration, track_fragment.timescale, movie_timescale),
+    };
 
-    // 이미 version 0이면 creation_time, modification_time, duration만 업데이트
-    let mut output = tkhd_data.to_vec();
+    Ok((edits, duration))
+}
 
-    if output.len() >= 24 {
-        // creation_time = 0
-        output[4..8].fill(0);
-        // modification_time = 0
-        output[8..12].fill(0);
-        // duration 설정
-        let bytes = (duration as u32).to_be_bytes();
-        output[20..24].copy_from_slice(&bytes);
-    }
+/// trak의 tkhd에서 track_id 읽기 (없으면 0)
+fn read_tkhd_track_id(trak_data: &[u8]) -> io::Result<u32> {
+    let trak_boxes = parse_container_box(trak_data)?;
+    let Some(tkhd) = find_box(&trak_boxes, b"tkhd") else {
+        return Ok(0);
+    };
 
-    Ok(output)
+    let offset = if tkhd.data.first() == Some(&1) {
+        20
+    } else {
+        12
+    };
+    Ok(tkhd
+        .data
+        .get(offset..offset + 4)
+        .map_or(0, |id| u32::from_be_bytes([id[0], id[1], id[2], id[3]])))
 }
 
-/// 일반 MP4용 mdia 박스 생성/// 일반 MP4용 mdia 박스 생성
+/// 일반 MP4용 mdia 노드 재구성
 fn build_regular_mdia(
-    original_mdia_data: &[u8],
+    mdia: &mut BoxNode,
     track_fragment: &TrackFragments,
-    mdat_offset: usize,
-) -> io::Result<Vec<u8>> {
-    let mdia_boxes = parse_container_box(original_mdia_data)?;
-    let mut output = Vec::new();
-
+    chunks: &[(u64, u32)],
+) -> io::Result<()> {
     let total_duration: u64 = track_fragment
         .samples
         .iter()
         .map(|s| s.duration as u64)
         .sum();
 
-    for mdia_box in &mdia_boxes {
-        match &mdia_box.box_type {
+    let children = mdia.children_mut().ok_or_else(|| malformed_box(b"mdia"))?;
+    for node in children.iter_mut() {
+        match &node.box_type {
             b"mdhd" => {
                 // mdhd duration 업데이트
-                let new_mdhd = update_mdhd_duration(&mdia_box.data, total_duration)?;
-                write_box(&mut output, b"mdhd", &new_mdhd);
+                rewrite_header_duration(node, total_duration)?;
             }
             b"minf" => {
                 // minf 재구성 (stbl 업데이트)
-                let new_minf = build_regular_minf(&mdia_box.data, track_fragment, mdat_offset)?;
-                write_box(&mut output, b"minf", &new_minf);
-            }
-            _ => {
-                write_box(&mut output, &mdia_box.box_type, &mdia_box.data);
+                build_regular_minf(node, track_fragment, chunks)?;
             }
+            _ => {}
         }
     }
 
-    Ok(output)
-}
-
-/// mdhd duration 업데이트 (version 0으로 다운그레이드)
-fn update_mdhd_duration(mdhd_data: &[u8], duration: u64) -> io::Result<Vec<u8>> {
-    let old_version = mdhd_data[0];
-
-    if old_version == 1 && mdhd_data.len() >= 32 {
-        // version 1 -> version 0으로 다운그레이드
-        let mut output = vec![0u8; 32]; // mdhd version 0의 표준 크기
-
-        // version = 0, flags (원본 flags 복사)
-        output[0] = 0;
-        output[1..4].copy_from_slice(&mdhd_data[1..4]);
-
-        // creation_time = 0 (4바이트)
-        output[4..8].fill(0);
-
-        // modification_time = 0 (4바이트)
-        output[8..12].fill(0);
-
-        // timescale (4바이트) - 원본에서 복사
-        output[12..16].copy_from_slice(&mdhd_data[20..24]);
-
-        // duration (4바이트) - 새 값 설정
-        let duration_32 = if duration > u32::MAX as u64 {
-            u32::MAX
-        } else {
-            duration as u32
-        };
-        output[16..20].copy_from_slice(&duration_32.to_be_bytes());
-
-        // language + pre_defined (4바이트) - 원본에서 복사
-        if mdhd_data.len() >= 36 {
-            output[20..24].copy_from_slice(&mdhd_data[32..36]);
-        }
-
-        // 나머지는 원본에서 복사
-        if mdhd_data.len() >= 44 {
-            output[24..32].copy_from_slice(&mdhd_data[36..44]);
-        }
-
-        return Ok(output);
-    }
-
-    // 이미 version 0이면 creation_time, modification_time, duration만 업데이트
-    let mut output = mdhd_data.to_vec();
-
-    if output.len() >= 20 {
-        // creation_time = 0
-        output[4..8].fill(0);
-        // modification_time = 0
-        output[8..12].fill(0);
-        // duration 설정
-        let bytes = (duration as u32).to_be_bytes();
-        output[16..20].copy_from_slice(&bytes);
-    }
-
-    Ok(output)
+    Ok(())
 }
 
-/// 일반 MP4용 minf 박스 생성
+/// 일반 MP4용 minf 노드 재구성
 fn build_regular_minf(
-    original_minf_data: &[u8],
+    minf: &mut BoxNode,
     track_fragment: &TrackFragments,
-    mdat_offset: usize,
-) -> io::Result<Vec<u8>> {
-    let minf_boxes = parse_container_box(original_minf_data)?;
-    let mut output = Vec::new();
-
-    for minf_box in &minf_boxes {
-        match &minf_box.box_type {
-            b"stbl" => {
-                // stbl 재구성 (sample tables 생성)
-                let new_stbl = build_sample_tables(track_fragment, mdat_offset)?;
-                write_box(&mut output, b"stbl", &new_stbl);
-            }
-            _ => {
-                write_box(&mut output, &minf_box.box_type, &minf_box.data);
-            }
-        }
+    chunks: &[(u64, u32)],
+) -> io::Result<()> {
+    let children = minf.children_mut().ok_or_else(|| malformed_box(b"minf"))?;
+    for node in children.iter_mut().filter(|b| &b.box_type == b"stbl") {
+        // stbl 재구성 (sample tables 생성)
+        let stbl = build_sample_tables(track_fragment, chunks)?;
+        *node = BoxNode::new(b"stbl", BoxBody::Container(parse_box_tree(&stbl)?));
     }
 
-    Ok(output)
+    Ok(())
 }
 
 /// Sample Tables 생성 (stts, stsz, stsc, stco, ctts)
-fn build_sample_tables(track_fragment: &TrackFragments, mdat_offset: usize) -> io::Result<Vec<u8>> {
+fn build_sample_tables(
+    track_fragment: &TrackFragments,
+    chunks: &[(u64, u32)],
+) -> io::Result<Vec<u8>> {
     let mut output = Vec::new();
 
     // stsd (Sample Description) - 원본 codec 정보 사용
@@ -2273,17 +2712,17 @@ fn build_sample_tables(track_fragment: &TrackFragments, mdat_offset: usize) -> i
     let stts = build_stts(&track_fragment.samples)?;
     write_box(&mut output, b"stts", &stts);
 
-    // stsc (Sample To Chunk) - 각 sample을 별도의 chunk로
-    let stsc = build_stsc_multi_chunk(track_fragment.samples.len())?;
+    // stsc (Sample To Chunk) - chunk별 sample 개수
+    let stsc = build_stsc(chunks)?;
     write_box(&mut output, b"stsc", &stsc);
 
     // stsz (Sample Size)
     let stsz = build_stsz(&track_fragment.samples)?;
     write_box(&mut output, b"stsz", &stsz);
 
-    // stco (Chunk Offset) - 각 sample의 offset 계산
-    let stco = build_stco_multi_chunk(&track_fragment.samples, mdat_offset)?;
-    write_box(&mut output, b"stco", &stco);
+    // stco (Chunk Offset) - 4GB를 넘으면 co64
+    let (offset_box_type, stco) = build_chunk_offsets(chunks)?;
+    write_box(&mut output, offset_box_type, &stco);
 
     // ctts (Composition Time To Sample) - composition offset이 있으면
     if track_fragment
@@ -2346,24 +2785,29 @@ fn build_stts(samples: &[FragmentSampleInfo]) -> io::Result<Vec<u8>> {
     Ok(output)
 }
 
-/// stsc (Sample To Chunk) 생성 - 각 sample을 별도의 chunk로
-fn build_stsc_multi_chunk(_sample_count: usize) -> io::Result<Vec<u8>> {
+/// stsc (Sample To Chunk) 생성 - sample 개수가 같은 연속 chunk는 하나의 entry로
+fn build_stsc(chunks: &[(u64, u32)]) -> io::Result<Vec<u8>> {
+    let mut entries: Vec<(u32, u32)> = Vec::new(); // (first_chunk, samples_per_chunk)
+    for (index, &(_, sample_count)) in chunks.iter().enumerate() {
+        if entries.last().map(|&(_, count)| count) != Some(sample_count) {
+            entries.push((index as u32 + 1, sample_count));
+        }
+    }
+
     let mut output = Vec::new();
 
     // version (1) + flags (3)
     output.extend_from_slice(&[0, 0, 0, 0]);
 
-    // entry_count = 1 (모든 chunk가 1개의 sample을 가짐)
-    output.extend_from_slice(&[0, 0, 0, 1]);
-
-    // first_chunk = 1
-    output.extend_from_slice(&[0, 0, 0, 1]);
-
-    // samples_per_chunk = 1
-    output.extend_from_slice(&[0, 0, 0, 1]);
+    // entry_count
+    output.extend_from_slice(&(entries.len() as u32).to_be_bytes());
 
-    // sample_description_index = 1
-    output.extend_from_slice(&[0, 0, 0, 1]);
+    for (first_chunk, samples_per_chunk) in entries {
+        output.extend_from_slice(&first_chunk.to_be_bytes());
+        output.extend_from_slice(&samples_per_chunk.to_be_bytes());
+        // sample_description_index = 1
+        output.extend_from_slice(&[0, 0, 0, 1]);
+    }
 
     Ok(output)
 }
@@ -2389,27 +2833,29 @@ fn build_stsz(samples: &[FragmentSampleInfo]) -> io::Result<Vec<u8>> {
     Ok(output)
 }
 
-/// stco (Chunk Offset) 생성 - 각 sample마다 offset 계산
-fn build_stco_multi_chunk(
-    samples: &[FragmentSampleInfo],
-    mdat_start_offset: usize,
-) -> io::Result<Vec<u8>> {
+/// stco (Chunk Offset) 생성
+/// offset 중 하나라도 32비트를 넘으면 co64로 생성하며, 박스 타입을 함께 반환
+fn build_chunk_offsets(chunks: &[(u64, u32)]) -> io::Result<(&'static [u8; 4], Vec<u8>)> {
+    let use_co64 = chunks.iter().any(|&(offset, _)| offset > u32::MAX as u64);
+
     let mut output = Vec::new();
 
     // version (1) + flags (3)
     output.extend_from_slice(&[0, 0, 0, 0]);
 
-    // entry_count = sample 개수 (각 sample이 하나의 chunk)
-    output.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    // entry_count
+    output.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
 
-    // 각 sample의 offset 계산
-    let mut current_offset = mdat_start_offset;
-    for sample in samples {
-        output.extend_from_slice(&(current_offset as u32).to_be_bytes());
-        current_offset += sample.size as usize;
+    for &(offset, _) in chunks {
+        if use_co64 {
+            output.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            output.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
     }
 
-    Ok(output)
+    let box_type = if use_co64 { b"co64" } else { b"stco" };
+    Ok((box_type, output))
 }
 
 /// ctts (Composition Time To Sample) 생성
@@ -2486,6 +2932,599 @@ fn build_stss(samples: &[FragmentSampleInfo]) -> io::Result<Vec<u8>> {
     Ok(output)
 }
 
+/// 첫 비디오 트랙의 tkhd matrix에서 회전/반전 읽기
+/// (비디오 트랙이 없거나 90도 단위 회전/반전으로 표현되지 않는 matrix면 None)
+pub fn read_mp4_transform(data: &[u8]) -> io::Result<Option<DisplayTransform>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    for trak in parse_container_box(&moov.data)? {
+        if &trak.box_type != b"trak" || !is_video_trak(&trak.data)? {
+            continue;
+        }
+        if find_box(&parse_container_box(&trak.data)?, b"tkhd").is_some() {
+            return read_trak_transform(&trak.data);
+        }
+    }
+    Ok(None)
+}
+
+/// trak의 tkhd matrix에서 회전/반전 읽기 (tkhd가 없거나 표현되지 않는 matrix면 None)
+///
+/// tkhd의 width/height와 샘플 엔트리는 코딩된 크기 그대로이고, 90/270도 회전은 matrix로만
+/// 표시되므로 화면에 보이는 크기는 `DisplayTransform::display_size`로 구한다
+pub(crate) fn read_trak_transform(trak_data: &[u8]) -> io::Result<Option<DisplayTransform>> {
+    let Some(tkhd) = find_box(&parse_container_box(trak_data)?, b"tkhd").cloned() else {
+        return Ok(None);
+    };
+    let offset = tkhd_matrix_offset(&tkhd.data)?;
+    let mut reader = Mp4Reader::new(&tkhd.data);
+    reader.seek(offset);
+    let mut matrix = [0u32; 9];
+    for value in &mut matrix {
+        *value = reader.read_u32()?;
+    }
+    Ok(DisplayTransform::from_matrix(&matrix))
+}
+
+/// 비디오 트랙의 tkhd matrix를 `transform`으로 교체
+/// (tkhd 크기는 그대로라 샘플 위치는 바뀌지 않음)
+pub fn write_mp4_transform(data: &[u8], transform: DisplayTransform) -> io::Result<Vec<u8>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .as_ref()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    // 박스 트리에서 비디오 트랙의 tkhd만 수정
+    let mut moov_tree = parse_box_tree(&moov.data)?;
+    for trak in moov_tree.iter_mut().filter(|b| &b.box_type == b"trak") {
+        let is_video = matches!(
+            trak.find(&[b"mdia", b"hdlr"]).and_then(BoxNode::full_box),
+            Some(FullBox::Hdlr(hdlr)) if &hdlr.handler_type == b"vide"
+        );
+        let tkhd = trak.find_mut(&[b"tkhd"]).and_then(BoxNode::full_box_mut);
+        if let (true, Some(FullBox::Tkhd(tkhd))) = (is_video, tkhd) {
+            // width/height는 16.16 고정소수점
+            tkhd.matrix = transform.matrix((tkhd.width >> 16) as u16, (tkhd.height >> 16) as u16);
+        }
+    }
+
+    let new_moov = relocate_moov(&mp4.all_boxes_in_order, &write_box_tree(&moov_tree))?;
+    rebuild_mp4(data, &mp4.all_boxes_in_order, &new_moov, |moof| {
+        Ok(moof.to_vec())
+    })
+}
+
+/// trak의 mdia/hdlr가 비디오인지 확인
+fn is_video_trak(trak_data: &[u8]) -> io::Result<bool> {
+    match find_box_path(trak_data, &[b"mdia", b"hdlr"])? {
+        Some(hdlr) => Ok(matches!(parse_hdlr(&hdlr.data)?, MediaType::Video)),
+        None => Ok(false),
+    }
+}
+
+/// tkhd 안의 matrix 위치 (version 1은 시간 필드가 64비트)
+fn tkhd_matrix_offset(tkhd_data: &[u8]) -> io::Result<usize> {
+    let (version, _flags) = read_full_box_header(tkhd_data)?;
+    let offset = if version == 1 { 52 } else { 40 };
+    if tkhd_data.len() < offset + 44 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "tkhd box too short"));
+    }
+    Ok(offset)
+}
+
+/// moov/udta/meta/ilst에서 iTunes 스타일 태그 읽기 (태그가 없으면 빈 값)
+pub fn read_mp4_tags(data: &[u8]) -> io::Result<Mp4Tags> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    let mut tags = Mp4Tags::default();
+    let Some(udta) = find_box(&parse_container_box(&moov.data)?, b"udta").cloned() else {
+        return Ok(tags);
+    };
+    let Some(meta) = find_box(&parse_container_box(&udta.data)?, b"meta").cloned() else {
+        return Ok(tags);
+    };
+    let Some(ilst) = find_box(&parse_meta_children(&meta.data)?, b"ilst").cloned() else {
+        return Ok(tags);
+    };
+
+    for item in parse_container_box(&ilst.data)? {
+        let children = parse_container_box(&item.data)?;
+        let Some((data_type, value)) = find_box(&children, b"data").and_then(read_data_atom) else {
+            continue;
+        };
+        let text = || String::from_utf8_lossy(value).into_owned();
+
+        match item.box_type {
+            metadata::TITLE => tags.title = Some(text()),
+            metadata::DATE => tags.date = Some(text()),
+            metadata::DESCRIPTION => tags.description = Some(text()),
+            metadata::COMMENT => tags.comment = Some(text()),
+            metadata::COVER => {
+                let format = if data_type == metadata::DATA_TYPE_PNG {
+                    CoverFormat::Png
+                } else {
+                    CoverFormat::Jpeg
+                };
+                tags.cover = Some(CoverArt {
+                    format,
+                    data: value.to_vec(),
+                });
+            }
+            metadata::FREEFORM => {
+                if let Some((mean, name)) = read_freeform_key(&children) {
+                    tags.freeform.push(FreeformTag {
+                        mean,
+                        name,
+                        value: text(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(tags)
+}
+
+/// `tags`에 설정된 항목만 교체하고 나머지 태그는 유지한 새 MP4 생성
+pub fn write_mp4_tags(data: &[u8], tags: &Mp4Tags) -> io::Result<Vec<u8>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .as_ref()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    let tagged_moov = update_moov_tags(&moov.data, tags)?;
+    let new_moov = relocate_moov(&mp4.all_boxes_in_order, &tagged_moov)?;
+    rebuild_mp4(data, &mp4.all_boxes_in_order, &new_moov, |moof| {
+        Ok(moof.to_vec())
+    })
+}
+
+/// ftyp의 brand 교체 (ftyp가 없으면 맨 앞에 추가)
+/// 샘플 데이터는 그대로 두고 chunk offset만 ftyp 크기 변화에 맞게 조정
+pub fn write_mp4_brands(data: &[u8], brands: &FileBrands) -> io::Result<Vec<u8>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .as_ref()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    let ftyp_box = build_ftyp(brands);
+    let ftyp = Mp4Box {
+        box_type: *b"ftyp",
+        size: ftyp_box.len() as u64,
+        offset: 0,
+        data: ftyp_box[8..].to_vec(),
+    };
+
+    let mut boxes = Vec::with_capacity(mp4.all_boxes_in_order.len() + 1);
+    boxes.push(BoxInfo::Small(ftyp));
+    boxes.extend(
+        mp4.all_boxes_in_order
+            .iter()
+            .filter(|box_info| !matches!(box_info, BoxInfo::Small(b) if &b.box_type == b"ftyp"))
+            .cloned(),
+    );
+
+    let new_moov = relocate_moov(&boxes, &moov.data)?;
+    rebuild_mp4(data, &boxes, &new_moov, |moof| Ok(moof.to_vec()))
+}
+
+/// moov의 udta를 태그가 반영된 버전으로 교체 (없으면 끝에 추가)
+fn update_moov_tags(moov_data: &[u8], tags: &Mp4Tags) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut udta_written = false;
+
+    for mp4_box in parse_container_box(moov_data)? {
+        if &mp4_box.box_type == b"udta" && !udta_written {
+            write_box(
+                &mut output,
+                b"udta",
+                &update_udta_tags(&mp4_box.data, tags)?,
+            );
+            udta_written = true;
+        } else {
+            write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+        }
+    }
+
+    if !udta_written && !tags.is_empty() {
+        write_box(&mut output, b"udta", &update_udta_tags(&[], tags)?);
+    }
+
+    Ok(output)
+}
+
+/// udta 안의 meta/ilst 갱신 (chpl 등 다른 박스는 유지)
+fn update_udta_tags(udta_data: &[u8], tags: &Mp4Tags) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut meta_written = false;
+
+    for mp4_box in parse_container_box(udta_data)? {
+        if &mp4_box.box_type != b"meta" || meta_written {
+            write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+            continue;
+        }
+
+        let children = parse_meta_children(&mp4_box.data)?;
+        let mut items = Vec::new();
+        if let Some(ilst) = find_box(&children, b"ilst") {
+            // 교체되지 않는 기존 항목 유지
+            for item in parse_container_box(&ilst.data)? {
+                let key = read_freeform_key(&parse_container_box(&item.data)?);
+                let key = key
+                    .as_ref()
+                    .map(|(mean, name)| (mean.as_str(), name.as_str()));
+                if !tags.replaces(&item.box_type, key) {
+                    write_box(&mut items, &item.box_type, &item.data);
+                }
+            }
+        }
+        items.extend_from_slice(&build_ilst_items(tags));
+
+        if find_box(&children, b"hdlr").is_some() {
+            // 기존 hdlr 등은 유지하고 ilst만 교체 (version + flags가 있는 ISO 형식으로 저장)
+            let mut meta = vec![0u8; 4];
+            for child in &children {
+                if &child.box_type != b"ilst" {
+                    write_box(&mut meta, &child.box_type, &child.data);
+                }
+            }
+            write_box(&mut meta, b"ilst", &items);
+            write_box(&mut output, b"meta", &meta);
+        } else {
+            write_box(&mut output, b"meta", &build_meta_content(&items));
+        }
+        meta_written = true;
+    }
+
+    if !meta_written {
+        write_box(
+            &mut output,
+            b"meta",
+            &build_meta_content(&build_ilst_items(tags)),
+        );
+    }
+
+    Ok(output)
+}
+
+/// meta의 자식 박스 파싱
+/// (ISO 형식은 version + flags로 시작하고, QuickTime 형식은 바로 hdlr가 나옴)
+fn parse_meta_children(meta_data: &[u8]) -> io::Result<Vec<Mp4Box>> {
+    if meta_data.get(4..8) == Some(b"hdlr") {
+        parse_container_box(meta_data)
+    } else {
+        parse_container_box(meta_data.get(4..).unwrap_or_default())
+    }
+}
+
+/// data 아톰에서 (타입, 값) 읽기
+fn read_data_atom(data_box: &Mp4Box) -> Option<(u32, &[u8])> {
+    let header = data_box.data.get(..8)?;
+    let data_type = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+    Some((data_type, &data_box.data[8..]))
+}
+
+/// freeform(----) 항목의 mean, name 읽기
+fn read_freeform_key(children: &[Mp4Box]) -> Option<(String, String)> {
+    let text = |box_type: &[u8; 4]| {
+        find_box(children, box_type)
+            .and_then(|b| b.data.get(4..))
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    };
+    Some((text(b"mean")?, text(b"name")?))
+}
+
+/// 챕터 읽기: tref/chap이 가리키는 QuickTime 텍스트 트랙을 우선 사용하고, 없으면 Nero chpl
+pub fn read_mp4_chapters(data: &[u8]) -> io::Result<Vec<Chapter>> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+    let moov_boxes = parse_container_box(&moov.data)?;
+
+    let traks: Vec<&Mp4Box> = moov_boxes
+        .iter()
+        .filter(|b| &b.box_type == b"trak")
+        .collect();
+    if let Some(chapter_track) = find_chapter_track_id(&traks)? {
+        let movie_timescale = match find_box(&moov_boxes, b"mvhd") {
+            Some(mvhd) => parse_mvhd(&mvhd.data)?.timescale,
+            None => 0,
+        };
+        for trak in &traks {
+            if read_tkhd_track_id(&trak.data)? == chapter_track {
+                return read_text_track_chapters(data, &trak.data, movie_timescale);
+            }
+        }
+    }
+
+    if let Some(chpl) = find_box_path(&moov.data, &[b"udta", b"chpl"])? {
+        return parse_chpl(&chpl.data);
+    }
+    Ok(Vec::new())
+}
+
+/// tref/chap으로 참조된 챕터 트랙 ID
+fn find_chapter_track_id(traks: &[&Mp4Box]) -> io::Result<Option<u32>> {
+    for trak in traks {
+        if let Some(chap) = find_box_path(&trak.data, &[b"tref", b"chap"])? {
+            if let Some(id) = chap.data.get(..4) {
+                return Ok(Some(u32::from_be_bytes([id[0], id[1], id[2], id[3]])));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// 텍스트 트랙의 샘플을 챕터로 변환 (앞쪽 empty edit은 시작 시간에 더함)
+fn read_text_track_chapters(
+    data: &[u8],
+    trak_data: &[u8],
+    movie_timescale: u32,
+) -> io::Result<Vec<Chapter>> {
+    let missing =
+        |name: &str| io::Error::new(ErrorKind::InvalidData, format!("{} not found", name));
+
+    let mdhd = find_box_path(trak_data, &[b"mdia", b"mdhd"])?.ok_or_else(|| missing("mdhd"))?;
+    let timescale = parse_mdhd(&mdhd.data)?.timescale;
+    let stbl =
+        find_box_path(trak_data, &[b"mdia", b"minf", b"stbl"])?.ok_or_else(|| missing("stbl"))?;
+    let stbl_boxes = parse_container_box(&stbl.data)?;
+
+    let stts = parse_stts(
+        &find_box(&stbl_boxes, b"stts")
+            .ok_or_else(|| missing("stts"))?
+            .data,
+    )?;
+    let sizes = parse_stsz(
+        &find_box(&stbl_boxes, b"stsz")
+            .ok_or_else(|| missing("stsz"))?
+            .data,
+    )?;
+    let stsc = parse_stsc(
+        &find_box(&stbl_boxes, b"stsc")
+            .ok_or_else(|| missing("stsc"))?
+            .data,
+    )?;
+    let chunk_offsets = match (
+        find_box(&stbl_boxes, b"stco"),
+        find_box(&stbl_boxes, b"co64"),
+    ) {
+        (Some(stco), _) => parse_stco(&stco.data)?,
+        (None, Some(co64)) => parse_co64(&co64.data)?,
+        (None, None) => return Err(missing("stco")),
+    };
+
+    let delay = match read_trak_edits(trak_data)? {
+        Some(edits) => to_movie_timescale(
+            leading_empty_duration(&edits),
+            movie_timescale,
+            CHAPTER_TIMESCALE,
+        ),
+        None => 0,
+    };
+
+    let offsets = sample_offsets(&stsc, &chunk_offsets, &sizes);
+    let durations = stts
+        .iter()
+        .flat_map(|e| std::iter::repeat_n(e.sample_delta, e.sample_count as usize));
+
+    let mut chapters = Vec::with_capacity(offsets.len());
+    let mut time = 0u64;
+    for ((offset, size), duration) in offsets.iter().zip(&sizes).zip(durations) {
+        let sample = usize::try_from(*offset)
+            .ok()
+            .and_then(|start| data.get(start..start.checked_add(*size as usize)?))
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "Chapter sample outside the file")
+            })?;
+        chapters.push(Chapter {
+            start_ms: delay + to_movie_timescale(time, timescale, CHAPTER_TIMESCALE),
+            title: parse_text_sample(sample),
+        });
+        time += duration as u64;
+    }
+
+    Ok(chapters)
+}
+
+/// stsc와 청크 오프셋으로 각 샘플의 파일 오프셋 계산
+fn sample_offsets(stsc: &[StscEntry], chunk_offsets: &[u64], sizes: &[u32]) -> Vec<u64> {
+    let mut offsets = Vec::with_capacity(sizes.len());
+
+    for (index, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk_number = index as u32 + 1;
+        let samples_per_chunk = stsc
+            .iter()
+            .take_while(|e| e.first_chunk <= chunk_number)
+            .last()
+            .map_or(0, |e| e.samples_per_chunk);
+
+        let mut offset = chunk_offset;
+        for _ in 0..samples_per_chunk {
+            let Some(&size) = sizes.get(offsets.len()) else {
+                return offsets;
+            };
+            offsets.push(offset);
+            offset += size as u64;
+        }
+    }
+
+    offsets
+}
+
+/// 챕터를 QuickTime 텍스트 트랙(tref/chap)과 Nero chpl로 쓴 새 MP4 생성
+/// 기존 챕터는 교체되고, 빈 목록이면 챕터를 제거함.
+/// Fragmented MP4는 전체 길이를 moov에서 알 수 없으므로 chpl만 씀
+pub fn write_mp4_chapters(data: &[u8], chapters: &[Chapter]) -> io::Result<Vec<u8>> {
+    let mut mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .take()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+    let moov_boxes = parse_container_box(&moov.data)?;
+    let mvhd = find_box(&moov_boxes, b"mvhd")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "mvhd box not found"))?;
+    let movie = parse_mvhd(&mvhd.data)?;
+    let fragmented = find_box(&moov_boxes, b"mvex").is_some();
+
+    let traks: Vec<&Mp4Box> = moov_boxes
+        .iter()
+        .filter(|b| &b.box_type == b"trak")
+        .collect();
+    let old_chapter_track = find_chapter_track_id(&traks)?;
+    let mut track_ids = Vec::with_capacity(traks.len());
+    for trak in &traks {
+        track_ids.push(read_tkhd_track_id(&trak.data)?);
+    }
+    let next_track_id = mvhd
+        .data
+        .len()
+        .checked_sub(4)
+        .map_or(0, |pos| {
+            u32::from_be_bytes([
+                mvhd.data[pos],
+                mvhd.data[pos + 1],
+                mvhd.data[pos + 2],
+                mvhd.data[pos + 3],
+            ])
+        })
+        .max(track_ids.iter().max().map_or(1, |id| id + 1));
+
+    // 텍스트 샘플은 파일 끝의 새 mdat에 저장. 청크 오프셋은 원본 끝(data.len())을 기준으로
+    // 만들고 relocate_moov에서 실제 위치로 옮김
+    let duration_ms = to_movie_timescale(movie.duration, movie.timescale, CHAPTER_TIMESCALE);
+    let chapter_trak = if fragmented {
+        None
+    } else {
+        build_chapter_trak(
+            next_track_id,
+            chapters,
+            duration_ms,
+            movie.timescale,
+            data.len() as u64,
+        )
+    };
+    let chapter_track = chapter_trak.as_ref().map(|_| next_track_id);
+
+    let last_trak = moov_boxes.iter().rposition(|b| &b.box_type == b"trak");
+    let mut new_moov = Vec::new();
+    let mut udta_written = false;
+    for (index, mp4_box) in moov_boxes.iter().enumerate() {
+        match &mp4_box.box_type {
+            b"mvhd" if chapter_track.is_some() => {
+                let mut mvhd_data = mp4_box.data.clone();
+                let pos = mvhd_data.len().saturating_sub(4);
+                mvhd_data[pos..].copy_from_slice(&(next_track_id + 1).to_be_bytes());
+                write_box(&mut new_moov, b"mvhd", &mvhd_data);
+            }
+            b"trak" => {
+                let track_id = read_tkhd_track_id(&mp4_box.data)?;
+                if old_chapter_track != Some(track_id) {
+                    let trak = set_chapter_reference(&mp4_box.data, chapter_track)?;
+                    write_box(&mut new_moov, b"trak", &trak);
+                }
+            }
+            b"udta" if !udta_written => {
+                let udta = update_udta_chapters(&mp4_box.data, chapters)?;
+                write_box(&mut new_moov, b"udta", &udta);
+                udta_written = true;
+            }
+            _ => write_box(&mut new_moov, &mp4_box.box_type, &mp4_box.data),
+        }
+
+        if Some(index) == last_trak {
+            if let Some((trak, _)) = &chapter_trak {
+                new_moov.extend_from_slice(trak);
+            }
+        }
+    }
+    if !udta_written && !chapters.is_empty() {
+        write_box(
+            &mut new_moov,
+            b"udta",
+            &update_udta_chapters(&[], chapters)?,
+        );
+    }
+
+    let mut extended = data.to_vec();
+    if let Some((_, payload)) = &chapter_trak {
+        extended.extend_from_slice(payload);
+        mp4.all_boxes_in_order.push(BoxInfo::Mdat(MdatBox {
+            offset: data.len(),
+            size: payload.len() as u64 + 8,
+            data_offset: data.len(),
+            data_size: payload.len(),
+        }));
+    }
+
+    let new_moov = relocate_moov(&mp4.all_boxes_in_order, &new_moov)?;
+    rebuild_mp4(&extended, &mp4.all_boxes_in_order, &new_moov, |moof| {
+        Ok(moof.to_vec())
+    })
+}
+
+/// trak의 tref/chap을 `chapter_track`으로 교체 (None이면 제거, 다른 참조는 유지)
+fn set_chapter_reference(trak_data: &[u8], chapter_track: Option<u32>) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut tref_written = false;
+
+    for child in parse_container_box(trak_data)? {
+        match &child.box_type {
+            b"tref" => {
+                let mut tref = Vec::new();
+                for reference in parse_container_box(&child.data)? {
+                    if &reference.box_type != b"chap" {
+                        write_box(&mut tref, &reference.box_type, &reference.data);
+                    }
+                }
+                if let Some(id) = chapter_track {
+                    write_box(&mut tref, b"chap", &id.to_be_bytes());
+                }
+                if !tref.is_empty() {
+                    write_box(&mut output, b"tref", &tref);
+                }
+                tref_written = true;
+            }
+            b"mdia" if !tref_written => {
+                // tref는 mdia 앞에 위치
+                if let Some(id) = chapter_track {
+                    output.extend_from_slice(&build_chapter_tref(id));
+                }
+                tref_written = true;
+                write_box(&mut output, b"mdia", &child.data);
+            }
+            _ => write_box(&mut output, &child.box_type, &child.data),
+        }
+    }
+
+    Ok(output)
+}
+
+/// udta의 chpl 교체 (meta 등 다른 박스는 유지)
+fn update_udta_chapters(udta_data: &[u8], chapters: &[Chapter]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    for mp4_box in parse_container_box(udta_data)? {
+        if &mp4_box.box_type != b"chpl" {
+            write_box(&mut output, &mp4_box.box_type, &mp4_box.data);
+        }
+    }
+    if !chapters.is_empty() {
+        output.extend_from_slice(&build_chpl(chapters));
+    }
+    Ok(output)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2507,4 +3546,239 @@ mod tests {
         assert_eq!(version, 1);
         assert_eq!(flags, 3);
     }
+
+    #[test]
+    fn test_mdhd_duration_upgrade() {
+        // mdhd version 0: timescale 90000, duration 0, language und
+        let mut mdhd = vec![0u8; 24];
+        mdhd[12..16].copy_from_slice(&90000u32.to_be_bytes());
+        mdhd[20..22].copy_from_slice(&[0x55, 0xC4]);
+
+        let mut box_data = Vec::new();
+        write_box(&mut box_data, b"mdhd", &mdhd);
+        let mut node = parse_box_tree(&box_data).unwrap().remove(0);
+
+        rewrite_header_duration(&mut node, u32::MAX as u64 + 1).unwrap();
+        let large = write_box_tree(std::slice::from_ref(&node))[8..].to_vec();
+        let info = parse_mdhd(&large).unwrap();
+        assert_eq!(large[0], 1);
+        assert_eq!(info.timescale, 90000);
+        assert_eq!(info.duration, u32::MAX as u64 + 1);
+        assert_eq!(&large[large.len() - 4..large.len() - 2], &[0x55, 0xC4]);
+
+        rewrite_header_duration(&mut node, 1000).unwrap();
+        let small = write_box_tree(std::slice::from_ref(&node))[8..].to_vec();
+        assert_eq!(small.len(), mdhd.len());
+        assert_eq!(parse_mdhd(&small).unwrap().duration, 1000);
+    }
+
+    #[test]
+    fn test_trim_leading_empty_edits() {
+        let edit = |segment_duration: u64, media_time: i64| EditListEntry {
+            segment_duration,
+            media_time,
+            media_rate: 0x00010000,
+        };
+        let audio = vec![edit(126000, -1), edit(176640, 1920)];
+        let video = vec![edit(120000, -1), edit(180000, 0)];
+
+        // elst 재작성 후 다시 파싱해도 같은 값
+        let edts = build_edts(&audio);
+        assert_eq!(parse_elst(&edts[8..]).unwrap(), audio);
+
+        // 공통 empty edit만 제거되고 트랙 간 차이는 유지
+        let trim = leading_empty_duration(&audio).min(leading_empty_duration(&video));
+        assert_eq!(
+            trim_leading_empty_edits(&audio, trim),
+            vec![edit(6000, -1), edit(176640, 1920)]
+        );
+        assert_eq!(
+            trim_leading_empty_edits(&video, trim),
+            vec![edit(180000, 0)]
+        );
+
+        // fragmented의 열린 edit은 남은 media 길이로 채움
+        assert_eq!(
+            fill_open_edits(&[edit(0, 1024)], 48000, 48000, 90000),
+            vec![edit(88080, 1024)]
+        );
+    }
+
+    #[test]
+    fn test_plan_interleaved_chunks() {
+        let track = |timescale: u32, duration: u32, count: usize| TrackFragments {
+            track_id: 0,
+            samples: vec![
+                FragmentSampleInfo {
+                    duration,
+                    size: 1,
+                    flags: 0,
+                    composition_time_offset: 0,
+                };
+                count
+            ],
+            mdat_data: vec![0; count],
+            timescale,
+            codec_info: Vec::new(),
+        };
+        // 1초 분량: 비디오 30fps, 오디오 1024 samples @ 48kHz
+        let tracks = vec![track(90000, 3000, 30), track(48000, 1024, 47)];
+
+        let plan = plan_interleaved_chunks(&tracks, 500);
+        assert_eq!(plan, vec![(0, 0..15), (1, 0..24), (0, 15..30), (1, 24..47)]);
+
+        let chunks = layout_chunks(&tracks, &plan, 100);
+        assert_eq!(chunks[1], vec![(115, 24), (154, 23)]);
+    }
+
+    #[test]
+    fn test_write_mp4_tags_keeps_other_items() {
+        // 기존 태그: ©nam + 다른 도구가 쓴 aART
+        let mut ilst = build_ilst_items(&Mp4Tags {
+            title: Some("Old".to_string()),
+            ..Default::default()
+        });
+        let mut artist = Vec::new();
+        write_box(&mut artist, b"data", &[0, 0, 0, 1, 0, 0, 0, 0, b'A']);
+        write_box(&mut ilst, b"aART", &artist);
+        let mut udta = Vec::new();
+        write_box(&mut udta, b"meta", &build_meta_content(&ilst));
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"udta", &udta);
+
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", b"isom\0\0\0\0");
+        write_box(&mut file, b"moov", &moov);
+        write_box(&mut file, b"mdat", &[1, 2, 3]);
+
+        let tags = Mp4Tags {
+            title: Some("New".to_string()),
+            cover: Some(CoverArt::from_image(vec![0x89, b'P', b'N', b'G']).unwrap()),
+            freeform: vec![FreeformTag::new("SOURCE", "ts")],
+            ..Default::default()
+        };
+        let tagged = write_mp4_tags(&file, &tags).unwrap();
+        assert_eq!(read_mp4_tags(&tagged).unwrap(), tags);
+        assert!(tagged.windows(4).any(|w| w == b"aART"));
+        assert!(tagged.ends_with(&[1, 2, 3]));
+
+        // 다시 쓰면 항목이 중복되지 않음
+        let retagged = write_mp4_tags(&tagged, &tags).unwrap();
+        assert_eq!(retagged.len(), tagged.len());
+    }
+
+    #[test]
+    fn test_display_transform_round_trip() {
+        use crate::mp4_writer::test_support::tiny_writer;
+        use crate::mp4_writer::Rotation;
+
+        let mut writer = tiny_writer(1);
+        writer.set_transform(0, DisplayTransform::new(Rotation::Rotate90, false));
+        let data = writer.finish_with_faststart().unwrap().into_inner();
+
+        // 90도: (x, y) -> (240 - y, x), 표시 크기는 240x320
+        let rotated = read_mp4_transform(&data).unwrap().unwrap();
+        assert_eq!(rotated.rotation, Rotation::Rotate90);
+        assert_eq!(rotated.display_size(320, 240), (240, 320));
+        let tkhd = find_box_path(
+            &parse_mp4(&data).unwrap().moov.unwrap().data,
+            &[b"trak", b"tkhd"],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(&tkhd.data[40..52], &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&tkhd.data[64..68], &(240u32 << 16).to_be_bytes());
+
+        // 모든 회전/반전 조합이 matrix를 거쳐 그대로 읽힘
+        for degrees in [0, 90, 180, 270] {
+            for flip_horizontal in [false, true] {
+                let transform = DisplayTransform::new(
+                    Rotation::from_degrees(degrees).unwrap(),
+                    flip_horizontal,
+                );
+                let rewritten = write_mp4_transform(&data, transform).unwrap();
+                assert_eq!(rewritten.len(), data.len());
+                assert_eq!(read_mp4_transform(&rewritten).unwrap(), Some(transform));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_tables_and_decode_order() {
+        use crate::mp4_writer::test_support::tiny_writer;
+        use crate::ts_parser::AacConfig;
+
+        // 비디오 90kHz, 오디오 48kHz, 청크가 트랙 사이를 오가도록 기록
+        let mut writer = tiny_writer(0);
+        let video = 0;
+        let audio = writer.add_audio_track(AacConfig {
+            object_type: 2,
+            sample_rate_index: 3,
+            channels: 2,
+        });
+        writer
+            .write_sample(video, &[0xA0; 7], 3000, 0, true)
+            .unwrap();
+        writer
+            .write_sample(video, &[0xA1; 5], 3000, 3000, false)
+            .unwrap();
+        for i in 0..3u8 {
+            writer
+                .write_sample(audio, &[0xB0 + i; 4], 1024, 0, true)
+                .unwrap();
+        }
+        writer
+            .write_sample(video, &[0xA2; 6], 3000, 0, false)
+            .unwrap();
+        let data = writer.finish_with_faststart().unwrap().into_inner();
+
+        let tracks = read_mp4_tracks(&data).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].media_type, MediaType::Video);
+        assert_eq!(tracks[1].timescale, 48000);
+
+        let samples: Vec<SampleInfo> = tracks[0].samples().collect();
+        assert_eq!(samples.len(), 3);
+        for (i, sample) in samples.iter().enumerate() {
+            let start = sample.offset as usize;
+            assert!(data[start..start + sample.size as usize]
+                .iter()
+                .all(|&b| b == 0xA0 + i as u8));
+        }
+        assert_eq!(samples[1].dts, 3000);
+        assert_eq!(samples[1].cts(), 6000);
+        assert_eq!(samples[1].duration, 3000);
+        assert_eq!(
+            samples.iter().map(|s| s.is_sync).collect::<Vec<_>>(),
+            [true, false, false]
+        );
+
+        // 같은 시각이면 앞 트랙이 먼저
+        let order: Vec<(u32, u32)> = samples_in_decode_order(&tracks)
+            .map(|s| (s.track_id, s.index))
+            .collect();
+        assert_eq!(order, [(1, 0), (2, 0), (2, 1), (1, 1), (2, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn test_chapter_track_round_trip() {
+        use crate::mp4_writer::test_support::tiny_writer;
+
+        // 1초 길이 비디오, 첫 챕터는 0.2초에 시작 (텍스트 트랙 앞에 empty edit)
+        let mut writer = tiny_writer(30);
+        let chapters = vec![Chapter::new(200, "Intro"), Chapter::new(600, "본론")];
+        writer.set_chapters([chapters.clone(), vec![Chapter::new(5000, "Late")]].concat());
+        let data = writer.finish_with_faststart().unwrap().into_inner();
+
+        // 텍스트 트랙이 우선, 영상 길이를 넘는 챕터는 텍스트 트랙에서 빠짐
+        assert_eq!(read_mp4_chapters(&data).unwrap(), chapters);
+
+        // 교체 후 다시 읽기, 제거하면 chpl과 텍스트 트랙 모두 사라짐
+        let replaced = vec![Chapter::new(0, "A"), Chapter::new(500, "B")];
+        let rewritten = write_mp4_chapters(&data, &replaced).unwrap();
+        assert_eq!(read_mp4_chapters(&rewritten).unwrap(), replaced);
+        let removed = write_mp4_chapters(&rewritten, &[]).unwrap();
+        assert!(read_mp4_chapters(&removed).unwrap().is_empty());
+        assert!(!removed.windows(4).any(|w| w == b"tref"));
+    }
 }
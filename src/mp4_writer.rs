@@ -1,511 +1,1712 @@
-use crate::ts_parser::MediaData;
-use std::io::{self, ErrorKind};
+use crate::chapters::{build_text_sample, chapter_durations, Chapter, CHAPTER_TIMESCALE};
+use crate::fmp4_writer::{FragmentOptions, FragmentedMp4Writer};
+use crate::metadata::{build_udta, Mp4Tags};
+use crate::ts_parser::{parse_sps, AacConfig, TsDemuxer, TsPacketReader};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+// Movie timescale, also used by video tracks. Audio tracks use their sample rate,
+// and tkhd/mvhd/elst durations are converted to the movie timescale (see docs/en/NOTE.md)
+pub(crate) const TIMESCALE: u32 = 90000;
+const VIDEO_SAMPLE_DURATION: u32 = 3000; // 30fps in 90kHz timebase
+const AAC_FRAME_SAMPLES: u32 = 1024; // one AAC frame in the audio track timescale
+
+// Largest audio gap filled with silence; longer PTS jumps are treated as discontinuities
+const MAX_AUDIO_GAP_SECONDS: u64 = 10;
+
+// PES timestamps read ahead to find the first presented frame (B-frame reordering)
+const VIDEO_LOOKAHEAD_FRAMES: usize = 32;
+
+// Longest stretch of one track held in memory while waiting for the other track, and longest
+// stretch of video read ahead while waiting for the first audio frame
+const MAX_BUFFERED_SECONDS: u64 = 10;
+
+// Raw AAC-LC frames that decode to silence
+const SILENT_AAC_FRAME_MONO: [u8; 6] = [0x00, 0xC8, 0x00, 0x80, 0x23, 0x80];
+const SILENT_AAC_FRAME_STEREO: [u8; 9] = [0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80];
+
+// Buffer size used when moving data forward (faststart, sidx)
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Default duration of one interleaved audio/video chunk
+pub const DEFAULT_INTERLEAVE_MS: u32 = 500;
+
+/// AAC encoder delay trimmed from the start of the audio track by default (audio samples)
+pub const DEFAULT_AAC_PRIMING_SAMPLES: u32 = 1024;
+
+/// Container flavour of regular (non-fragmented) output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// ISO base media file (MP4)
+    #[default]
+    Mp4,
+    /// QuickTime movie: `wide` atom in front of mdat, mhlr/dhlr handlers and alias data
+    /// references
+    Mov,
+}
+
+impl OutputFormat {
+    /// Brands written to ftyp unless others are given
+    pub fn default_brands(self) -> FileBrands {
+        match self {
+            OutputFormat::Mp4 => FileBrands {
+                major_brand: *b"isom",
+                minor_version: 0x200,
+                compatible_brands: vec![*b"isom", *b"iso2", *b"avc1", *b"mp41"],
+            },
+            OutputFormat::Mov => FileBrands {
+                major_brand: *b"qt  ",
+                minor_version: 0x200,
+                compatible_brands: vec![*b"qt  "],
+            },
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mp4" | "m4v" | "m4a" => Ok(OutputFormat::Mp4),
+            "mov" | "qt" => Ok(OutputFormat::Mov),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown output format '{}' (expected mp4 or mov)", s),
+            )),
+        }
+    }
+}
+
+/// Major brand, minor version and compatible brands written to the ftyp box
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileBrands {
+    pub major_brand: [u8; 4],
+    pub minor_version: u32,
+    pub compatible_brands: Vec<[u8; 4]>,
+}
+
+impl Default for FileBrands {
+    fn default() -> Self {
+        OutputFormat::Mp4.default_brands()
+    }
+}
+
+impl FromStr for FileBrands {
+    type Err = io::Error;
+
+    /// Parses a comma-separated brand list such as "M4V,M4A,mp42,isom". The first brand is
+    /// the major brand and every brand is listed as compatible. Brands shorter than four
+    /// characters are padded with spaces ("qt" = "qt  ").
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let brands = s
+            .split(',')
+            .map(|brand| {
+                let brand = brand.trim_start();
+                if brand.is_empty() || brand.len() > 4 || !brand.is_ascii() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid brand '{}' (1-4 ASCII characters)", brand),
+                    ));
+                }
+                let mut code = *b"    ";
+                code[..brand.len()].copy_from_slice(brand.as_bytes());
+                Ok(code)
+            })
+            .collect::<io::Result<Vec<[u8; 4]>>>()?;
+
+        Ok(FileBrands {
+            major_brand: brands[0],
+            minor_version: 0,
+            compatible_brands: brands,
+        })
+    }
+}
+
+/// Builds the ftyp box for `brands`
+pub(crate) fn build_ftyp(brands: &FileBrands) -> Vec<u8> {
+    let mut ftyp = Vec::new();
+    ftyp.extend_from_slice(&brands.major_brand);
+    ftyp.extend_from_slice(&brands.minor_version.to_be_bytes());
+    for brand in &brands.compatible_brands {
+        ftyp.extend_from_slice(brand);
+    }
+
+    let mut result = Vec::new();
+    write_box(&mut result, b"ftyp", &ftyp);
+    result
+}
+
+/// Clockwise display rotation of a video track
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn degrees(self) -> u16 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Rotate90 => 90,
+            Rotation::Rotate180 => 180,
+            Rotation::Rotate270 => 270,
+        }
+    }
+
+    /// Rotation for a multiple of 90 degrees (negative values turn counter-clockwise)
+    pub fn from_degrees(degrees: i32) -> Option<Self> {
+        match degrees.rem_euclid(360) {
+            0 => Some(Rotation::None),
+            90 => Some(Rotation::Rotate90),
+            180 => Some(Rotation::Rotate180),
+            270 => Some(Rotation::Rotate270),
+            _ => None,
+        }
+    }
+}
 
-pub fn create_mp4(media_data: MediaData) -> io::Result<Vec<u8>> {
-    create_mp4_with_options(media_data, false)
+impl FromStr for Rotation {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .parse::<i32>()
+            .ok()
+            .and_then(Rotation::from_degrees)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid rotation '{}' (expected 0, 90, 180 or 270)", s),
+                )
+            })
+    }
 }
 
-pub fn create_mp4_with_options(
-    media_data: MediaData,
-    _reset_timestamps: bool,
-) -> io::Result<Vec<u8>> {
-    // For now, reset_timestamps is ignored and we always use v0.1.2 behavior
-    // This parameter is kept for API compatibility with v0.3.0
-    if media_data.video_stream.is_empty() {
+/// Display transformation of a video track, written to the tkhd matrix.
+/// The flip mirrors the picture horizontally before it is rotated; a vertical flip is a
+/// horizontal flip rotated by 180 degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DisplayTransform {
+    pub rotation: Rotation,
+    pub flip_horizontal: bool,
+}
+
+impl DisplayTransform {
+    /// Every rotation and flip combination
+    const ALL: [DisplayTransform; 8] = [
+        DisplayTransform::new(Rotation::None, false),
+        DisplayTransform::new(Rotation::Rotate90, false),
+        DisplayTransform::new(Rotation::Rotate180, false),
+        DisplayTransform::new(Rotation::Rotate270, false),
+        DisplayTransform::new(Rotation::None, true),
+        DisplayTransform::new(Rotation::Rotate90, true),
+        DisplayTransform::new(Rotation::Rotate180, true),
+        DisplayTransform::new(Rotation::Rotate270, true),
+    ];
+
+    pub const fn new(rotation: Rotation, flip_horizontal: bool) -> Self {
+        DisplayTransform {
+            rotation,
+            flip_horizontal,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == DisplayTransform::default()
+    }
+
+    /// Size of a `width` x `height` picture once displayed (swapped by 90 and 270 degrees)
+    pub fn display_size(&self, width: u16, height: u16) -> (u16, u16) {
+        match self.rotation {
+            Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+            Rotation::None | Rotation::Rotate180 => (width, height),
+        }
+    }
+
+    /// Linear part (a, b, c, d) of the matrix, mapping (x, y) to (a*x + c*y, b*x + d*y)
+    fn linear(&self) -> [i32; 4] {
+        let flip = if self.flip_horizontal { -1 } else { 1 };
+        // Rotations are clockwise on screen, where y grows downwards
+        match self.rotation {
+            Rotation::None => [flip, 0, 0, 1],
+            Rotation::Rotate90 => [0, flip, -1, 0],
+            Rotation::Rotate180 => [-flip, 0, 0, -1],
+            Rotation::Rotate270 => [0, -flip, 1, 0],
+        }
+    }
+
+    /// tkhd/mvhd matrix for a `width` x `height` track. The translation moves the transformed
+    /// picture back to the origin.
+    pub(crate) fn matrix(&self, width: u16, height: u16) -> [u32; 9] {
+        let [a, b, c, d] = self.linear();
+        let (width, height) = (width as i32, height as i32);
+        let corners = [(0, 0), (width, 0), (0, height), (width, height)];
+        let tx = -corners
+            .iter()
+            .map(|&(x, y)| a * x + c * y)
+            .min()
+            .unwrap_or(0);
+        let ty = -corners
+            .iter()
+            .map(|&(x, y)| b * x + d * y)
+            .min()
+            .unwrap_or(0);
+
+        let fixed = |value: i32| (value << 16) as u32; // 16.16
+        [
+            fixed(a),
+            fixed(b),
+            0,
+            fixed(c),
+            fixed(d),
+            0,
+            fixed(tx),
+            fixed(ty),
+            0x40000000, // 1.0 in 2.30
+        ]
+    }
+
+    /// Rotation and flip of a tkhd matrix, ignoring the translation.
+    /// `None` if the matrix scales, shears or rotates by other angles.
+    pub(crate) fn from_matrix(matrix: &[u32; 9]) -> Option<Self> {
+        if matrix[2] != 0 || matrix[5] != 0 {
+            return None;
+        }
+        let unit = |value: u32| match value as i32 {
+            0 => Some(0),
+            0x10000 => Some(1),
+            -0x10000 => Some(-1),
+            _ => None,
+        };
+        let linear = [
+            unit(matrix[0])?,
+            unit(matrix[1])?,
+            unit(matrix[3])?,
+            unit(matrix[4])?,
+        ];
+        DisplayTransform::ALL
+            .into_iter()
+            .find(|transform| transform.linear() == linear)
+    }
+}
+
+/// Options controlling how TS input is written as MP4
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Keep the earliest source PTS of TS input as the start time, through an empty edit (or
+    /// the first `tfdt` of fragmented output), to line several recordings up against each other.
+    /// Without it the output starts from 0.
+    pub preserve_timestamps: bool,
+    /// Move the moov box in front of mdat once all samples are written
+    pub faststart: bool,
+    /// Duration of each interleaved chunk in milliseconds (0 = one chunk per track)
+    pub interleave_ms: u32,
+    /// Write a fragmented MP4 (init segment + moof/mdat fragments) instead of moov + mdat
+    pub fragment: Option<FragmentOptions>,
+    /// Number of leading AAC samples (encoder priming) hidden by the audio edit list
+    pub aac_priming_samples: u32,
+    /// Metadata written to moov/udta
+    pub tags: Mp4Tags,
+    /// Chapter markers, written as a QuickTime chapter track and a Nero chpl box
+    /// (fragmented output only gets chpl)
+    pub chapters: Vec<Chapter>,
+    /// MP4 or QuickTime MOV (regular output only)
+    pub format: OutputFormat,
+    /// ftyp brands, `None` for the defaults of `format` (or of fragmented output)
+    pub brands: Option<FileBrands>,
+    /// Rotation and flip of the video track, `None` keeps the source orientation (identity for
+    /// TS input)
+    pub transform: Option<DisplayTransform>,
+}
+
+/// How the source timestamps were placed on the output timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+    /// The output timeline starts at zero
+    Reset,
+    /// The earliest source PTS is kept as the start time (90kHz ticks), through an empty
+    /// edit in regular output or the first `tfdt` in fragmented output
+    Preserved { start_offset: u64 },
+}
+
+/// Changes made to keep the audio track on its source PTS
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioRepairReport {
+    /// Silent frames inserted into gaps (e.g. dropped audio packets)
+    pub silent_frames: u32,
+    /// Frames dropped because they overlapped the frames before them
+    pub dropped_frames: u32,
+    /// Frames whose stts duration was stretched or shortened to absorb sub-frame drift
+    pub adjusted_durations: u32,
+    /// PTS jumps too large to repair, left as they are
+    pub discontinuities: u32,
+    /// Frames of an audio stream that only started after the first seconds of video, left out
+    /// because the tracks were already set up
+    pub late_frames: u32,
+}
+
+/// What a TS to MP4 conversion did with the source
+#[derive(Debug, Clone)]
+pub struct ConversionReport {
+    pub timestamps: TimestampMode,
+    pub audio: AudioRepairReport,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            preserve_timestamps: false,
+            faststart: true,
+            interleave_ms: DEFAULT_INTERLEAVE_MS,
+            fragment: None,
+            aac_priming_samples: DEFAULT_AAC_PRIMING_SAMPLES,
+            tags: Mp4Tags::default(),
+            chapters: Vec::new(),
+            format: OutputFormat::Mp4,
+            brands: None,
+            transform: None,
+        }
+    }
+}
+
+/// Finishing passes that read back what was written (faststart and sidx), only available when
+/// the sink can be read
+pub(crate) struct ReadBack<W: Write + Seek> {
+    faststart: fn(StreamingMp4Writer<W>) -> io::Result<W>,
+    sidx: fn(FragmentedMp4Writer<W>) -> io::Result<W>,
+}
+
+impl<W: Read + Write + Seek> ReadBack<W> {
+    pub(crate) fn new() -> Self {
+        ReadBack {
+            faststart: StreamingMp4Writer::finish_with_faststart,
+            sidx: FragmentedMp4Writer::finish_with_sidx,
+        }
+    }
+}
+
+/// Streams the TS read from `source` into `sink` and returns it once the file is complete.
+/// Packets are demuxed and written as they are read: only the first seconds of the stream
+/// (while the tracks are set up) and the samples of the current interleave window are held in
+/// memory. With `options.faststart` the moov box is moved in front of mdat after all samples
+/// are written, which needs `read_back`, as does a fragmented sidx.
+pub(crate) fn write_mp4_with_options<R: Read, W: Write + Seek>(
+    source: R,
+    sink: W,
+    options: &ConvertOptions,
+    read_back: Option<ReadBack<W>>,
+) -> io::Result<(W, ConversionReport)> {
+    let needs_read_back = match &options.fragment {
+        Some(fragment) => fragment.sidx,
+        None => options.faststart,
+    };
+    if needs_read_back && read_back.is_none() {
         return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "No video data found",
+            ErrorKind::InvalidInput,
+            "Faststart and sidx read the written file back and need a readable sink",
         ));
     }
+    let input = TsInput::open(source)?;
+
+    if let Some(fragment) = &options.fragment {
+        if options.format == OutputFormat::Mov {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "MOV output cannot be fragmented",
+            ));
+        }
+
+        // Samples are pushed one video frame at a time so fragments cut cleanly by decode time
+        let mut writer = FragmentedMp4Writer::new(sink, fragment.clone());
+        if let Some(brands) = &options.brands {
+            writer.set_brands(brands.clone());
+        }
+        writer.set_tags(options.tags.clone());
+        writer.set_chapters(options.chapters.clone());
+        let report = write_media_data(&mut writer, input, VIDEO_SAMPLE_DURATION as u64, options)?;
+
+        let sink = match read_back {
+            Some(read_back) if fragment.sidx => (read_back.sidx)(writer)?,
+            _ => writer.finish()?,
+        };
+        return Ok((sink, report));
+    }
+
+    let brands = options
+        .brands
+        .clone()
+        .unwrap_or_else(|| options.format.default_brands());
+    let mut writer = StreamingMp4Writer::with_format(sink, options.format, &brands)?;
+    writer.set_tags(options.tags.clone());
+    writer.set_chapters(options.chapters.clone());
+    let interleave = options.interleave_ms as u64 * TIMESCALE as u64 / 1000;
+    let report = write_media_data(&mut writer, input, interleave, options)?;
+
+    let sink = match read_back {
+        Some(read_back) if options.faststart => (read_back.faststart)(writer)?,
+        _ => writer.finish()?,
+    };
+    Ok((sink, report))
+}
+
+/// Destination of converted samples, implemented by the regular and the fragmented writer
+pub(crate) trait SampleWriter {
+    fn add_video_track(&mut self, width: u16, height: u16, sps: &[u8], pps: &[u8]) -> usize;
+    fn add_audio_track(&mut self, config: AacConfig) -> usize;
+    fn set_edit(&mut self, track: usize, delay: u64, media_start: u64);
+    fn set_transform(&mut self, track: usize, transform: DisplayTransform);
+    /// Moves the start of `track` to `offset` (movie timescale) on the output timeline
+    fn set_start_offset(&mut self, track: usize, offset: u64);
+    fn write_sample(
+        &mut self,
+        track: usize,
+        data: &[u8],
+        duration: u32,
+        composition_offset: i32,
+        is_sync: bool,
+    ) -> io::Result<()>;
+}
+
+/// Feeds the video and audio samples of `input` into `writer`, alternating
+/// between the tracks every `interleave` ticks of decode time (0 = all video, then all audio).
+///
+/// Each track gets an edit that starts its first presented sample at its source PTS relative
+/// to the earliest track, with the AAC priming samples hidden. With
+/// `options.preserve_timestamps`, every track is moved to the earliest source PTS.
+/// The tracks and edits are taken from the packets read ahead by `TsInput::open`.
+fn write_media_data<R: Read>(
+    writer: &mut impl SampleWriter,
+    mut input: TsInput<R>,
+    interleave: u64,
+    options: &ConvertOptions,
+) -> io::Result<ConversionReport> {
+    let media_data = &input.demuxer.media;
+    let video_min_pts = media_data
+        .frame_timestamps
+        .iter()
+        .filter_map(|(pts, _)| *pts)
+        .min();
+
+    let video_track = writer.add_video_track(
+        media_data.width,
+        media_data.height,
+        media_data.sps.as_deref().unwrap_or_default(),
+        media_data.pps.as_deref().unwrap_or_default(),
+    );
+    writer.set_transform(video_track, options.transform.unwrap_or_default());
+    let audio_config = media_data.audio_config.unwrap_or_default();
+    let audio_rate = audio_config.sample_rate() as u64;
+    let audio_track = if media_data.audio_frames.is_empty() {
+        None
+    } else {
+        Some(writer.add_audio_track(audio_config))
+    };
+
+    // Composition offsets are relative to the first frame
+    let first_offset = media_data
+        .frame_timestamps
+        .first()
+        .map_or(0, |&timestamps| pes_composition_offset(timestamps));
+
+    // Media time of the first presented frame (B-frames may present before the first decoded one)
+    let video_media_start = media_data
+        .frame_timestamps
+        .iter()
+        .enumerate()
+        .map(|(i, &timestamps)| {
+            i as i64 * VIDEO_SAMPLE_DURATION as i64
+                + (pes_composition_offset(timestamps) - first_offset) as i64
+        })
+        .min()
+        .unwrap_or(0)
+        .max(0) as u64;
+    let video_start = media_data
+        .frame_timestamps
+        .first()
+        .and_then(|(pts, _)| *pts)
+        .or(video_min_pts)
+        .map(|pts| pts + video_media_start);
+
+    // Audio is presented from the end of the encoder priming (media time in samples)
+    let audio_media_start = options.aac_priming_samples as u64;
+    let audio_start = audio_track
+        .and(media_data.audio_timestamps.first().copied().flatten())
+        .map(|pts| pts + audio_media_start * TIMESCALE as u64 / audio_rate);
+
+    // Edits are relative to the track that starts first
+    let timeline_start = match (video_start, audio_start) {
+        (Some(v), Some(a)) => v.min(a),
+        (Some(v), None) => v,
+        (None, Some(a)) => a,
+        (None, None) => 0,
+    };
+
+    writer.set_edit(
+        video_track,
+        video_start.map_or(0, |start| start - timeline_start),
+        video_media_start,
+    );
+    if let Some(audio_track) = audio_track {
+        writer.set_edit(
+            audio_track,
+            audio_start.map_or(0, |start| start - timeline_start),
+            audio_media_start,
+        );
+    }
+
+    let timestamps = if options.preserve_timestamps {
+        writer.set_start_offset(video_track, timeline_start);
+        if let Some(audio_track) = audio_track {
+            writer.set_start_offset(audio_track, timeline_start);
+        }
+        TimestampMode::Preserved {
+            start_offset: timeline_start,
+        }
+    } else {
+        TimestampMode::Reset
+    };
+
+    // With interleaving, neither track waits for the other beyond MAX_BUFFERED_SECONDS
+    input.start(
+        audio_track.map(|_| audio_config),
+        first_offset,
+        interleave > 0,
+    );
+
+    // Video decode time in 90kHz ticks, audio decode time in samples
+    let mut video_dts = 0u64;
+    let mut audio_dts = 0u64;
+
+    // Each pass writes one chunk per track covering the next interleave window
+    let mut window_end = interleave;
+    loop {
+        while interleave == 0 || video_dts < window_end {
+            let Some((sample_data, composition_offset)) = input.next_video_sample()? else {
+                break;
+            };
+            writer.write_sample(
+                video_track,
+                &sample_data,
+                VIDEO_SAMPLE_DURATION,
+                composition_offset,
+                is_sync_sample(&sample_data),
+            )?;
+            video_dts += VIDEO_SAMPLE_DURATION as u64;
+        }
+
+        if let Some(audio_track) = audio_track {
+            while interleave == 0 || audio_dts * (TIMESCALE as u64) < window_end * audio_rate {
+                let Some((frame, duration)) = input.next_audio_sample()? else {
+                    break;
+                };
+                writer.write_sample(audio_track, &frame, duration, 0, true)?;
+                audio_dts += duration as u64;
+            }
+        }
+
+        if input.is_exhausted() {
+            break;
+        }
+        window_end += interleave;
+    }
+
+    Ok(ConversionReport {
+        timestamps,
+        audio: input.audio_report(),
+    })
+}
+
+/// PTS - DTS of a video PES packet, 0 when it carries no DTS
+fn pes_composition_offset(timestamps: (Option<u64>, Option<u64>)) -> i32 {
+    match timestamps {
+        (Some(pts), Some(dts)) => (pts as i64 - dts as i64) as i32,
+        _ => 0,
+    }
+}
+
+/// TS input demuxed while it is written. `open` reads ahead until the tracks can be set up;
+/// after `start`, complete video frames and planned audio samples wait in queues until the
+/// interleave loop takes them.
+struct TsInput<R: Read> {
+    packets: TsPacketReader<R>,
+    demuxer: TsDemuxer,
+    eof: bool,
+    started: bool,
+    bounded: bool, // stop waiting for one track once the other has piled up
+    first_offset: i32,
+    timestamps: VecDeque<(Option<u64>, Option<u64>)>, // PES timestamps not yet matched to a sample
+    video_samples: VecDeque<(Vec<u8>, i32)>,          // AVCC samples with composition offsets
+    audio: Option<AudioPlanner>,
+    audio_samples: VecDeque<(Vec<u8>, u32)>, // raw AAC samples with durations
+    max_video_samples: usize,
+    max_audio_samples: usize,
+    late_audio_frames: u32,
+}
+
+impl<R: Read> TsInput<R> {
+    /// Reads ahead until the SPS/PPS, the first audio frame and VIDEO_LOOKAHEAD_FRAMES PES
+    /// timestamps are known, or MAX_BUFFERED_SECONDS of video have been read
+    fn open(source: R) -> io::Result<Self> {
+        let mut input = TsInput {
+            packets: TsPacketReader::new(source),
+            demuxer: TsDemuxer::new(),
+            eof: false,
+            started: false,
+            bounded: false,
+            first_offset: 0,
+            timestamps: VecDeque::new(),
+            video_samples: VecDeque::new(),
+            audio: None,
+            audio_samples: VecDeque::new(),
+            max_video_samples: (MAX_BUFFERED_SECONDS * TIMESCALE as u64
+                / VIDEO_SAMPLE_DURATION as u64) as usize,
+            max_audio_samples: 0,
+            late_audio_frames: 0,
+        };
+
+        while !input.eof {
+            let media_data = &input.demuxer.media;
+            let frames = media_data.frame_timestamps.len();
+            let ready = media_data.sps.is_some()
+                && media_data.pps.is_some()
+                && !media_data.audio_frames.is_empty()
+                && frames >= VIDEO_LOOKAHEAD_FRAMES;
+            if ready || frames >= input.max_video_samples {
+                break;
+            }
+            input.read_packet()?;
+        }
+
+        if input.demuxer.media.video_stream.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "No video data found",
+            ));
+        }
+        Ok(input)
+    }
+
+    /// Starts queueing samples: audio frames go through a planner for `audio_config`, or are
+    /// counted as late when no audio track was set up
+    fn start(&mut self, audio_config: Option<AacConfig>, first_offset: i32, bounded: bool) {
+        self.audio = audio_config.map(AudioPlanner::new);
+        self.max_audio_samples = audio_config.map_or(0, |config| {
+            (MAX_BUFFERED_SECONDS * config.sample_rate() as u64 / AAC_FRAME_SAMPLES as u64) as usize
+        });
+        self.first_offset = first_offset;
+        self.bounded = bounded;
+        self.started = true;
+        self.queue_samples();
+    }
+
+    fn read_packet(&mut self) -> io::Result<()> {
+        match self.packets.next_packet()? {
+            Some(packet) => self.demuxer.push_packet(packet),
+            None => {
+                self.demuxer.finish();
+                self.eof = true;
+            }
+        }
+        if self.started {
+            self.queue_samples();
+        }
+        Ok(())
+    }
+
+    /// Moves the frames completed by the packets read so far into the sample queues
+    fn queue_samples(&mut self) {
+        let media_data = &mut self.demuxer.media;
+
+        // A frame is complete once the next one starts, i.e. with the next video PES
+        if !media_data.frame_timestamps.is_empty() || self.eof {
+            self.timestamps
+                .extend(media_data.frame_timestamps.drain(..));
+
+            let frames = split_into_frames(&media_data.video_stream);
+            // Bytes kept for the next pass: the last, still growing frame
+            let (complete, pending) = match frames.last() {
+                _ if self.eof => (frames.len(), 0),
+                Some(last) => (frames.len() - 1, last.len()),
+                None => (0, media_data.video_stream.len()),
+            };
+            for frame in &frames[..complete] {
+                let sample = convert_annexb_to_avcc(frame);
+                if sample.is_empty() {
+                    continue;
+                }
+                // Samples take the PES timestamps in order; extra samples get no offset
+                let composition_offset = self.timestamps.pop_front().map_or(0, |timestamps| {
+                    pes_composition_offset(timestamps) - self.first_offset
+                });
+                self.video_samples.push_back((sample, composition_offset));
+            }
+
+            let consumed = media_data.video_stream.len() - pending;
+            media_data.video_stream.drain(..consumed);
+        }
+
+        let frames = media_data.audio_frames.drain(..);
+        let timestamps = media_data.audio_timestamps.drain(..);
+        match &mut self.audio {
+            Some(planner) => {
+                for (frame, pts) in frames.zip(timestamps) {
+                    planner.push(frame, pts, &mut self.audio_samples);
+                }
+                if self.eof {
+                    planner.finish(&mut self.audio_samples);
+                }
+            }
+            None => self.late_audio_frames += frames.len() as u32,
+        }
+    }
+
+    /// Next video sample with its composition offset; None once the input is exhausted, or
+    /// for now when MAX_BUFFERED_SECONDS of audio piled up waiting for it
+    fn next_video_sample(&mut self) -> io::Result<Option<(Vec<u8>, i32)>> {
+        while self.video_samples.is_empty()
+            && !self.eof
+            && !(self.bounded && self.audio_samples.len() >= self.max_audio_samples)
+        {
+            self.read_packet()?;
+        }
+        Ok(self.video_samples.pop_front())
+    }
+
+    /// Next audio sample with its duration; None once the input is exhausted, or for now when
+    /// MAX_BUFFERED_SECONDS of video piled up waiting for it
+    fn next_audio_sample(&mut self) -> io::Result<Option<(Vec<u8>, u32)>> {
+        while self.audio_samples.is_empty()
+            && !self.eof
+            && !(self.bounded && self.video_samples.len() >= self.max_video_samples)
+        {
+            self.read_packet()?;
+        }
+        Ok(self.audio_samples.pop_front())
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.eof && self.video_samples.is_empty() && self.audio_samples.is_empty()
+    }
+
+    fn audio_report(&self) -> AudioRepairReport {
+        let mut report = self
+            .audio
+            .as_ref()
+            .map(|planner| planner.report.clone())
+            .unwrap_or_default();
+        report.late_frames = self.late_audio_frames;
+        report
+    }
+}
+
+/// Lays the AAC frames out on their PTS, in samples: gaps get silent frames and the sub-frame
+/// remainder stretches the frame before the gap; overlaps of half a frame or more drop the frame,
+/// smaller ones shorten the frame before it. Drift below 1ms is left to accumulate until it
+/// crosses that threshold. The last sample is held back until the next frame settles its
+/// duration.
+struct AudioPlanner {
+    sample_rate: u64,
+    tolerance: u64,
+    max_gap: u64,
+    silent_frame: &'static [u8],
+    next: Option<u64>,            // source time at which the next sample starts
+    last: Option<(Vec<u8>, u32)>, // sample whose duration may still change
+    report: AudioRepairReport,
+}
+
+impl AudioPlanner {
+    fn new(config: AacConfig) -> Self {
+        let sample_rate = config.sample_rate() as u64;
+        AudioPlanner {
+            sample_rate,
+            tolerance: sample_rate / 1000,
+            max_gap: MAX_AUDIO_GAP_SECONDS * sample_rate,
+            silent_frame: if config.channels == 1 {
+                &SILENT_AAC_FRAME_MONO
+            } else {
+                &SILENT_AAC_FRAME_STEREO
+            },
+            next: None,
+            last: None,
+            report: AudioRepairReport::default(),
+        }
+    }
+
+    /// Plans `frame` at `pts` (90kHz), queueing the samples whose duration is settled
+    fn push(&mut self, frame: Vec<u8>, pts: Option<u64>, samples: &mut VecDeque<(Vec<u8>, u32)>) {
+        let frame_samples = AAC_FRAME_SAMPLES as u64;
+        let pts = pts.map(|pts| (pts * self.sample_rate + TIMESCALE as u64 / 2) / TIMESCALE as u64);
+
+        // The frame stays right after the previous one unless it is moved onto its PTS
+        let mut position = self.next.or(pts);
+
+        if let (Some(pts), Some(expected)) = (pts, self.next) {
+            if pts > expected + self.tolerance {
+                let gap = pts - expected;
+                if gap > self.max_gap {
+                    self.report.discontinuities += 1;
+                } else {
+                    let remainder = (gap % frame_samples) as u32;
+                    if remainder > 0 {
+                        if let Some(last) = &mut self.last {
+                            last.1 += remainder;
+                            self.report.adjusted_durations += 1;
+                        }
+                    }
+
+                    let silent = (gap / frame_samples) as u32;
+                    if silent > 0 {
+                        samples.extend(self.last.take());
+                        for _ in 0..silent {
+                            samples.push_back((self.silent_frame.to_vec(), AAC_FRAME_SAMPLES));
+                        }
+                    }
+                    self.report.silent_frames += silent;
+                }
+                position = Some(pts);
+            } else if pts + self.tolerance < expected {
+                let overlap = expected - pts;
+                if overlap > self.max_gap {
+                    self.report.discontinuities += 1;
+                } else if overlap * 2 >= frame_samples {
+                    self.report.dropped_frames += 1;
+                    return;
+                } else if let Some(last) = &mut self.last {
+                    last.1 -= overlap as u32;
+                    self.report.adjusted_durations += 1;
+                }
+                position = Some(pts);
+            }
+        }
+
+        samples.extend(self.last.replace((frame, AAC_FRAME_SAMPLES)));
+        self.next = position.map(|position| position + frame_samples);
+    }
+
+    /// Queues the held-back last sample
+    fn finish(&mut self, samples: &mut VecDeque<(Vec<u8>, u32)>) {
+        samples.extend(self.last.take());
+    }
+}
+
+/// Bitrate of a track as written to btrt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bitrate {
+    buffer_size: u32, // largest sample, in bytes
+    max: u32,         // bits per second
+    average: u32,     // bits per second
+}
+
+/// Edit list (elst) entry
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EditEntry {
+    pub segment_duration: u64, // in movie timescale
+    pub media_time: i64,       // in media timescale, -1 = empty edit
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TrackMedia {
+    Video {
+        width: u16,
+        height: u16,
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+    },
+    Audio {
+        config: AacConfig,
+    },
+    /// QuickTime text track holding chapter titles
+    Text,
+}
+
+/// Sample tables collected for one track while its samples are streamed
+#[derive(Debug)]
+pub(crate) struct TrackState {
+    pub track_id: u32,
+    pub media: TrackMedia,
+    pub timescale: u32,              // 90kHz for video, the sample rate for audio
+    pub movie_timescale: u32,        // timescale of tkhd and elst durations
+    pub language: [u8; 3],           // ISO 639-2/T code written to mdhd
+    pub transform: DisplayTransform, // tkhd matrix of video tracks
+    pub chapter_track: Option<u32>,  // track ID referenced by tref/chap
+    sample_sizes: Vec<u32>,
+    time_to_sample: Vec<(u32, u32)>, // (sample_count, sample_delta)
+    composition_offsets: Vec<(u32, i32)>, // (sample_count, sample_offset)
+    sync_samples: Vec<u32>,          // 1-based sample numbers
+    chunk_offsets: Vec<u64>,         // relative to the start of the file
+    samples_per_chunk: Vec<u32>,
+    duration: u64,
+    pub delay: u64,        // empty edit in front of the media, in movie timescale
+    pub media_start: u64,  // media time of the first presented sample
+    pub start_offset: u64, // source start time kept in front of the delay, in movie timescale
+}
+
+impl TrackState {
+    pub(crate) fn new(track_id: u32, media: TrackMedia) -> Self {
+        let timescale = match &media {
+            TrackMedia::Video { .. } => TIMESCALE,
+            TrackMedia::Audio { config } => config.sample_rate(),
+            TrackMedia::Text => CHAPTER_TIMESCALE,
+        };
+
+        TrackState {
+            track_id,
+            media,
+            timescale,
+            movie_timescale: TIMESCALE,
+            language: *b"und",
+            chapter_track: None,
+            transform: DisplayTransform::default(),
+            sample_sizes: Vec::new(),
+            time_to_sample: Vec::new(),
+            composition_offsets: Vec::new(),
+            sync_samples: Vec::new(),
+            chunk_offsets: Vec::new(),
+            samples_per_chunk: Vec::new(),
+            duration: 0,
+            delay: 0,
+            media_start: 0,
+            start_offset: 0,
+        }
+    }
+
+    fn add_sample(&mut self, size: u32, duration: u32, composition_offset: i32, is_sync: bool) {
+        self.sample_sizes.push(size);
+        self.duration += duration as u64;
+
+        match self.time_to_sample.last_mut() {
+            Some((count, delta)) if *delta == duration => *count += 1,
+            _ => self.time_to_sample.push((1, duration)),
+        }
+
+        match self.composition_offsets.last_mut() {
+            Some((count, offset)) if *offset == composition_offset => *count += 1,
+            _ => self.composition_offsets.push((1, composition_offset)),
+        }
+
+        if is_sync {
+            self.sync_samples.push(self.sample_sizes.len() as u32);
+        }
+    }
+}
+
+/// MP4 writer that streams sample data straight into `mdat`.
+///
+/// Sample payloads are written to the sink as soon as they are pushed; only the
+/// sample tables are kept in memory. `finish` appends `moov` after `mdat`, while
+/// `finish_with_faststart` moves it in front of `mdat` for progressive playback.
+pub struct StreamingMp4Writer<W: Write + Seek> {
+    sink: W,
+    tracks: Vec<TrackState>,
+    format: OutputFormat,
+    start: u64,         // sink position of the ftyp box
+    header_offset: u64, // reserved free (wide) box followed by the mdat header, relative to start
+    position: u64,      // relative to start
+    current_track: Option<usize>,
+    tags: Mp4Tags,
+    chapters: Vec<Chapter>,
+}
+
+impl<W: Write + Seek> StreamingMp4Writer<W> {
+    /// Writes ftyp and the mdat header of an MP4 with the default brands to `sink`
+    pub fn new(sink: W) -> io::Result<Self> {
+        Self::with_format(sink, OutputFormat::Mp4, &FileBrands::default())
+    }
+
+    /// Writes ftyp with `brands` and the mdat header to `sink`
+    pub fn with_format(mut sink: W, format: OutputFormat, brands: &FileBrands) -> io::Result<Self> {
+        let start = sink.stream_position()?;
+
+        let ftyp = build_ftyp(brands);
+        sink.write_all(&ftyp)?;
+
+        // Reserved free box (QuickTime: wide), turned into the 64-bit mdat size if mdat
+        // outgrows 4 GB
+        let reserved_type = match format {
+            OutputFormat::Mp4 => b"free",
+            OutputFormat::Mov => b"wide",
+        };
+        sink.write_all(&[0x00, 0x00, 0x00, 0x08])?;
+        sink.write_all(reserved_type)?;
+
+        // mdat header (size is patched when the writer is finished)
+        sink.write_all(&[0x00, 0x00, 0x00, 0x00, b'm', b'd', b'a', b't'])?;
+
+        Ok(StreamingMp4Writer {
+            sink,
+            tracks: Vec::new(),
+            format,
+            start,
+            header_offset: ftyp.len() as u64,
+            position: ftyp.len() as u64 + 16,
+            current_track: None,
+            tags: Mp4Tags::default(),
+            chapters: Vec::new(),
+        })
+    }
+
+    /// Adds an H.264 video track and returns its index
+    pub fn add_video_track(&mut self, width: u16, height: u16, sps: &[u8], pps: &[u8]) -> usize {
+        self.add_track(TrackMedia::Video {
+            width,
+            height,
+            sps: sps.to_vec(),
+            pps: pps.to_vec(),
+        })
+    }
+
+    /// Adds an AAC audio track using the sample rate of `config` as its timescale
+    /// and returns its index
+    pub fn add_audio_track(&mut self, config: AacConfig) -> usize {
+        self.add_track(TrackMedia::Audio { config })
+    }
+
+    fn add_track(&mut self, media: TrackMedia) -> usize {
+        let track_id = self.tracks.len() as u32 + 1;
+        self.tracks.push(TrackState::new(track_id, media));
+        self.tracks.len() - 1
+    }
+
+    pub(crate) fn track_mut(&mut self, track: usize) -> &mut TrackState {
+        &mut self.tracks[track]
+    }
+
+    /// Presents `track` from media time `media_start` (track timescale) after an
+    /// empty edit of `delay` (movie timescale).
+    pub fn set_edit(&mut self, track: usize, delay: u64, media_start: u64) {
+        self.tracks[track].delay = delay;
+        self.tracks[track].media_start = media_start;
+    }
+
+    /// Sets the rotation and flip written to the tkhd matrix of a video track
+    pub fn set_transform(&mut self, track: usize, transform: DisplayTransform) {
+        self.tracks[track].transform = transform;
+    }
+
+    /// Sets the metadata written to moov/udta when the file is finished
+    pub fn set_tags(&mut self, tags: Mp4Tags) {
+        self.tags = tags;
+    }
+
+    /// Sets the chapters written as a text track and a chpl box when the file is finished.
+    /// Chapters starting after the last sample are dropped.
+    pub fn set_chapters(&mut self, chapters: Vec<Chapter>) {
+        self.chapters = chapters;
+    }
+
+    /// Delays `track` by `offset` (90kHz) on the movie timeline with an empty edit
+    pub fn set_start_offset(&mut self, track: usize, offset: u64) {
+        self.tracks[track].start_offset = offset;
+    }
+
+    /// Appends one sample to mdat. Consecutive samples of the same track share a chunk.
+    /// `duration` and `composition_offset` are in the track timescale.
+    pub fn write_sample(
+        &mut self,
+        track: usize,
+        data: &[u8],
+        duration: u32,
+        composition_offset: i32,
+        is_sync: bool,
+    ) -> io::Result<()> {
+        if track >= self.tracks.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown track index {}", track),
+            ));
+        }
+
+        self.sink.write_all(data)?;
+
+        let state = &mut self.tracks[track];
+        if self.current_track != Some(track) {
+            state.chunk_offsets.push(self.position);
+            state.samples_per_chunk.push(0);
+            self.current_track = Some(track);
+        }
+        if let Some(count) = state.samples_per_chunk.last_mut() {
+            *count += 1;
+        }
+        state.add_sample(data.len() as u32, duration, composition_offset, is_sync);
+
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Completes the file with moov placed after mdat
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chapter_track()?;
+        self.patch_mdat_header()?;
+
+        let moov = self.build_moov(0);
+        self.sink
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        self.sink.write_all(&moov)?;
+        self.sink.flush()?;
+
+        Ok(self.sink)
+    }
+
+    /// Appends the chapter titles as the samples of a text track referenced by every other
+    /// track through tref/chap
+    fn write_chapter_track(&mut self) -> io::Result<()> {
+        let duration_ms = rescale(self.movie_duration(), TIMESCALE, CHAPTER_TIMESCALE);
+        let samples = chapter_samples(&self.chapters, duration_ms);
+        let Some(first) = samples.first() else {
+            return Ok(());
+        };
+
+        let delay = rescale(first.0, CHAPTER_TIMESCALE, TIMESCALE);
+        let track = self.add_track(TrackMedia::Text);
+        let track_id = self.tracks[track].track_id;
+        self.tracks[track].delay = delay;
+        for state in &mut self.tracks[..track] {
+            state.chapter_track = Some(track_id);
+        }
+
+        for (_, data, duration) in samples {
+            self.write_sample(track, &data, duration, 0, true)?;
+        }
+        Ok(())
+    }
+
+    /// Duration of the longest track in the movie timescale
+    fn movie_duration(&self) -> u64 {
+        self.tracks
+            .iter()
+            .map(|t| t.presentation_duration())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Writes the final mdat size and returns the offset at which the mdat box starts.
+    /// An mdat larger than 4 GB takes over the reserved free box for its largesize header.
+    fn patch_mdat_header(&mut self) -> io::Result<u64> {
+        let payload_size = self.position - (self.header_offset + 16);
+
+        // A 32-bit header leaves the first 8 reserved bytes as a free box
+        let header = box_header(b"mdat", payload_size);
+        let mdat_offset = self.header_offset + 16 - header.len() as u64;
+        self.sink.seek(SeekFrom::Start(self.start + mdat_offset))?;
+        self.sink.write_all(&header)?;
+        Ok(mdat_offset)
+    }
+
+    fn build_moov(&self, offset_shift: u64) -> Vec<u8> {
+        let mut moov = Vec::new();
+
+        // mvhd - the longest track defines the movie duration
+        let duration = self.movie_duration();
+        moov.extend_from_slice(&build_mvhd(duration, self.tracks.len() as u32 + 1));
+
+        for track in &self.tracks {
+            moov.extend_from_slice(&build_trak(track, offset_shift, self.format));
+        }
+        moov.extend_from_slice(&build_udta(&self.tags, &self.chapters));
+
+        let mut result = Vec::new();
+        write_box(&mut result, b"moov", &moov);
+        result
+    }
+}
+
+impl<W: Read + Write + Seek> StreamingMp4Writer<W> {
+    /// Completes the file with moov placed in front of mdat (faststart).
+    /// The mdat payload is moved in place, so memory use stays bounded.
+    pub fn finish_with_faststart(mut self) -> io::Result<W> {
+        self.write_chapter_track()?;
+        let mdat_offset = self.patch_mdat_header()?;
+
+        // moov replaces the unused part of the reserved header, so chunk offsets grow by
+        // the size of moov minus that gap. The size can change when stco becomes co64.
+        let gap = mdat_offset - self.header_offset;
+        let mut moov = self.build_moov(0);
+        loop {
+            let shifted = self.build_moov(moov.len() as u64 - gap);
+            if shifted.len() == moov.len() {
+                moov = shifted;
+                break;
+            }
+            moov = shifted;
+        }
+
+        shift_forward(
+            &mut self.sink,
+            self.start + mdat_offset,
+            self.start + self.position,
+            moov.len() as u64 - gap,
+        )?;
+
+        self.sink
+            .seek(SeekFrom::Start(self.start + self.header_offset))?;
+        self.sink.write_all(&moov)?;
+        self.sink.flush()?;
+
+        Ok(self.sink)
+    }
+}
+
+impl<W: Write + Seek> SampleWriter for StreamingMp4Writer<W> {
+    fn add_video_track(&mut self, width: u16, height: u16, sps: &[u8], pps: &[u8]) -> usize {
+        StreamingMp4Writer::add_video_track(self, width, height, sps, pps)
+    }
+
+    fn add_audio_track(&mut self, config: AacConfig) -> usize {
+        StreamingMp4Writer::add_audio_track(self, config)
+    }
+
+    fn set_edit(&mut self, track: usize, delay: u64, media_start: u64) {
+        StreamingMp4Writer::set_edit(self, track, delay, media_start)
+    }
+
+    fn set_transform(&mut self, track: usize, transform: DisplayTransform) {
+        StreamingMp4Writer::set_transform(self, track, transform)
+    }
+
+    fn set_start_offset(&mut self, track: usize, offset: u64) {
+        StreamingMp4Writer::set_start_offset(self, track, offset)
+    }
+
+    fn write_sample(
+        &mut self,
+        track: usize,
+        data: &[u8],
+        duration: u32,
+        composition_offset: i32,
+        is_sync: bool,
+    ) -> io::Result<()> {
+        StreamingMp4Writer::write_sample(self, track, data, duration, composition_offset, is_sync)
+    }
+}
+
+/// Moves the bytes between the absolute sink positions `from` and `end` forward by
+/// `distance`. Copies from the back so that no data is overwritten before it is moved.
+pub(crate) fn shift_forward<W: Read + Write + Seek>(
+    sink: &mut W,
+    from: u64,
+    end: u64,
+    distance: u64,
+) -> io::Result<()> {
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut end = end;
+
+    while end > from {
+        let len = (end - from).min(COPY_BUFFER_SIZE as u64) as usize;
+        let chunk_start = end - len as u64;
+
+        sink.seek(SeekFrom::Start(chunk_start))?;
+        sink.read_exact(&mut buffer[..len])?;
+        sink.seek(SeekFrom::Start(chunk_start + distance))?;
+        sink.write_all(&buffer[..len])?;
+
+        end = chunk_start;
+    }
+
+    Ok(())
+}
+
+impl TrackState {
+    /// Edit list as written to elst: an empty edit for the start offset and delay,
+    /// then the media from `media_start` to the end. Empty if neither is needed.
+    /// In fragmented output the duration is unknown and the media edit is left open (0).
+    fn edits(&self) -> Vec<EditEntry> {
+        let empty = self.start_offset + self.delay;
+        if empty == 0 && self.media_start == 0 {
+            return Vec::new();
+        }
+
+        let mut edits = Vec::new();
+        if empty > 0 {
+            edits.push(EditEntry {
+                segment_duration: empty,
+                media_time: -1,
+            });
+        }
+        edits.push(EditEntry {
+            segment_duration: self.to_movie_time(self.duration.saturating_sub(self.media_start)),
+            media_time: self.media_start as i64,
+        });
+        edits
+    }
+
+    /// Converts a duration from the track timescale to the movie timescale
+    pub(crate) fn to_movie_time(&self, duration: u64) -> u64 {
+        rescale(duration, self.timescale, self.movie_timescale)
+    }
+
+    /// Decoder buffer size, maximum bitrate over one-second windows of decode time and average
+    /// bitrate, as written to btrt. `None` until the track has samples (fragmented init segment).
+    fn bitrate(&self) -> Option<Bitrate> {
+        if self.sample_sizes.is_empty() || self.duration == 0 || self.timescale == 0 {
+            return None;
+        }
+
+        let mut sizes = self.sample_sizes.iter();
+        let mut window_bytes = 0u64;
+        let mut max_window_bytes = 0u64;
+        let mut window = 0u64;
+        let mut dts = 0u64;
+        for &(count, delta) in &self.time_to_sample {
+            for size in sizes.by_ref().take(count as usize) {
+                if dts / self.timescale as u64 != window {
+                    window = dts / self.timescale as u64;
+                    window_bytes = 0;
+                }
+                window_bytes += *size as u64;
+                max_window_bytes = max_window_bytes.max(window_bytes);
+                dts += delta as u64;
+            }
+        }
+
+        let total_bytes: u64 = self.sample_sizes.iter().map(|&size| size as u64).sum();
+        let average = total_bytes as u128 * 8 * self.timescale as u128 / self.duration as u128;
+        Some(Bitrate {
+            buffer_size: self.sample_sizes.iter().copied().max().unwrap_or(0),
+            max: (max_window_bytes * 8).min(u32::MAX as u64) as u32,
+            average: average.min(u32::MAX as u128) as u32,
+        })
+    }
+
+    /// Track duration in the movie timescale, taking the edit list into account
+    fn presentation_duration(&self) -> u64 {
+        let edits = self.edits();
+        if edits.is_empty() {
+            self.to_movie_time(self.duration)
+        } else {
+            edits.iter().map(|e| e.segment_duration).sum()
+        }
+    }
+}
+
+/// Text samples for the chapters that start within `duration_ms`:
+/// (start, sample data, duration), with start and duration in `CHAPTER_TIMESCALE`
+fn chapter_samples(chapters: &[Chapter], duration_ms: u64) -> Vec<(u64, Vec<u8>, u32)> {
+    chapter_durations(chapters, duration_ms)
+        .into_iter()
+        .map(|(chapter, duration)| {
+            (
+                chapter.start_ms,
+                build_text_sample(&chapter.title),
+                duration,
+            )
+        })
+        .collect()
+}
+
+/// Builds the chapter text track of an existing movie, with all samples in one chunk at
+/// `chunk_offset`. Returns the trak box and the sample data to store at that offset, or
+/// `None` if no chapter starts within `duration_ms`.
+pub(crate) fn build_chapter_trak(
+    track_id: u32,
+    chapters: &[Chapter],
+    duration_ms: u64,
+    movie_timescale: u32,
+    chunk_offset: u64,
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let samples = chapter_samples(chapters, duration_ms);
+    let first_start = samples.first()?.0;
+
+    let mut track = TrackState::new(track_id, TrackMedia::Text);
+    track.movie_timescale = movie_timescale;
+    track.delay = rescale(first_start, CHAPTER_TIMESCALE, movie_timescale);
+    track.chunk_offsets.push(chunk_offset);
+    track.samples_per_chunk.push(samples.len() as u32);
+
+    let mut payload = Vec::new();
+    for (_, data, duration) in samples {
+        track.add_sample(data.len() as u32, duration, 0, true);
+        payload.extend_from_slice(&data);
+    }
+    Some((build_trak(&track, 0, OutputFormat::Mp4), payload))
+}
+
+/// Converts `value` from timescale `from` to timescale `to`, rounding down
+pub(crate) fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    if from == to || from == 0 {
+        return value;
+    }
+    (value as u128 * to as u128 / from as u128) as u64
+}
+
+/// Header of a box holding `payload_size` bytes: a 32-bit size, or size 1 followed by a
+/// 64-bit largesize when the box does not fit in 32 bits (e.g. an mdat over 4GB)
+pub(crate) fn box_header(box_type: &[u8; 4], payload_size: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    if payload_size + 8 > u32::MAX as u64 {
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(box_type);
+        header.extend_from_slice(&(payload_size + 16).to_be_bytes());
+    } else {
+        header.extend_from_slice(&((payload_size + 8) as u32).to_be_bytes());
+        header.extend_from_slice(box_type);
+    }
+    header
+}
 
-    // Step 1: Prepare video data
-    let frames = split_into_frames(&media_data.video_stream);
-    let mut video_samples = Vec::new();
+/// Writes a box with a 32-bit size header
+pub(crate) fn write_box(output: &mut Vec<u8>, box_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&((8 + data.len()) as u32).to_be_bytes());
+    output.extend_from_slice(box_type);
+    output.extend_from_slice(data);
+}
 
-    for frame in frames.iter() {
-        let sample_data = convert_annexb_to_avcc(frame);
-        if !sample_data.is_empty() {
-            video_samples.push(sample_data);
-        }
-    }
+/// Writes a full box (version + flags followed by `data`)
+pub(crate) fn write_full_box(
+    output: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    version: u8,
+    flags: u32,
+    data: &[u8],
+) {
+    let mut content = Vec::with_capacity(4 + data.len());
+    content.push(version);
+    content.extend_from_slice(&flags.to_be_bytes()[1..]);
+    content.extend_from_slice(data);
+    write_box(output, box_type, &content);
+}
 
-    // Step 2: Prepare audio data (already in correct format)
-    let audio_samples = &media_data.audio_frames;
+// Unity transformation matrix used by mvhd and tkhd
+const UNITY_MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
 
-    // Step 3: Build mdat
-    let mut mdat_data = Vec::new();
-    for sample in &video_samples {
-        mdat_data.extend_from_slice(sample);
+/// Full box version needed to store `value` (1 = 64-bit times and durations)
+fn time_version(value: u64) -> u8 {
+    if value > u32::MAX as u64 {
+        1
+    } else {
+        0
     }
-    let video_data_end = mdat_data.len();
+}
 
-    for sample in audio_samples {
-        mdat_data.extend_from_slice(sample);
+/// Appends a time or duration field, 64-bit wide in version 1 boxes
+fn push_time_field(output: &mut Vec<u8>, version: u8, value: u64) {
+    if version == 1 {
+        output.extend_from_slice(&value.to_be_bytes());
+    } else {
+        output.extend_from_slice(&(value as u32).to_be_bytes());
     }
+}
 
-    // Step 4: Calculate offsets
-    let ftyp_size = 28;
-    let mdat_header_size = 8;
-
-    // Build moov to calculate its size
-    let moov_box = build_moov(
-        &media_data,
-        &video_samples,
-        audio_samples,
-        ftyp_size,
-        0, // moov_size placeholder
-        mdat_header_size,
-        video_data_end,
-    )?;
+pub(crate) fn build_mvhd(duration: u64, next_track_id: u32) -> Vec<u8> {
+    let version = time_version(duration);
+
+    let mut mvhd = Vec::new();
+    push_time_field(&mut mvhd, version, 0); // creation time
+    push_time_field(&mut mvhd, version, 0); // modification time
+    mvhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+    push_time_field(&mut mvhd, version, duration);
+    mvhd.extend_from_slice(&0x00010000u32.to_be_bytes()); // rate = 1.0
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume = 1.0
+    mvhd.extend_from_slice(&[0u8; 10]); // reserved
+    for value in UNITY_MATRIX {
+        mvhd.extend_from_slice(&value.to_be_bytes());
+    }
+    mvhd.extend_from_slice(&[0u8; 24]); // pre-defined
+    mvhd.extend_from_slice(&next_track_id.to_be_bytes());
 
-    let moov_size = moov_box.len();
+    let mut result = Vec::new();
+    write_full_box(&mut result, b"mvhd", version, 0, &mvhd);
+    result
+}
 
-    // Rebuild moov with correct offsets
-    let moov_box = build_moov(
-        &media_data,
-        &video_samples,
-        audio_samples,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-        video_data_end,
-    )?;
+pub(crate) fn build_trak(track: &TrackState, offset_shift: u64, format: OutputFormat) -> Vec<u8> {
+    let mut trak = Vec::new();
 
-    // Step 5: Write MP4 file
-    let mut mp4_buffer = Vec::new();
+    trak.extend_from_slice(&build_tkhd(track));
 
-    // ftyp
-    mp4_buffer.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x1C, // size
-        b'f', b't', b'y', b'p', b'i', b's', b'o', b'm', 0x00, 0x00, 0x02, 0x00, b'i', b's', b'o',
-        b'm', b'i', b's', b'o', b'2', b'm', b'p', b'4', b'1',
-    ]);
+    let edits = track.edits();
+    if !edits.is_empty() {
+        trak.extend_from_slice(&build_edts(&edits));
+    }
 
-    // moov
-    mp4_buffer.extend_from_slice(&moov_box);
+    if let Some(chapter_track) = track.chapter_track {
+        trak.extend_from_slice(&build_chapter_tref(chapter_track));
+    }
 
-    // mdat
-    let mdat_size = 8 + mdat_data.len();
-    mp4_buffer.extend_from_slice(&(mdat_size as u32).to_be_bytes());
-    mp4_buffer.extend_from_slice(b"mdat");
-    mp4_buffer.extend_from_slice(&mdat_data);
+    // mdia
+    let mut mdia = Vec::new();
+    mdia.extend_from_slice(&build_mdhd(track.duration, track.timescale, track.language));
+    mdia.extend_from_slice(&build_hdlr(&track.media, format));
+    mdia.extend_from_slice(&build_minf(track, offset_shift, format));
+    write_box(&mut trak, b"mdia", &mdia);
 
-    Ok(mp4_buffer)
+    let mut result = Vec::new();
+    write_box(&mut result, b"trak", &trak);
+    result
 }
 
-fn build_moov(
-    media_data: &MediaData,
-    video_samples: &[Vec<u8>],
-    audio_samples: &[Vec<u8>],
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-    video_data_end: usize,
-) -> io::Result<Vec<u8>> {
-    let mut moov = Vec::new();
-
-    // Calculate global minimum PTS across all streams for proper synchronization
-    let video_min_pts = media_data
-        .frame_timestamps
-        .iter()
-        .filter_map(|(pts, _)| *pts)
-        .min();
+/// tref with a chap reference to the chapter text track
+pub(crate) fn build_chapter_tref(chapter_track: u32) -> Vec<u8> {
+    let mut tref = Vec::new();
+    write_box(&mut tref, b"chap", &chapter_track.to_be_bytes());
 
-    let audio_min_pts = media_data
-        .audio_timestamps
-        .iter()
-        .filter_map(|&pts| pts)
-        .min();
+    let mut result = Vec::new();
+    write_box(&mut result, b"tref", &tref);
+    result
+}
 
-    let global_min_pts = match (video_min_pts, audio_min_pts) {
-        (Some(v), Some(a)) => v.min(a),
-        (Some(v), None) => v,
-        (None, Some(a)) => a,
-        (None, None) => 0,
+fn build_tkhd(track: &TrackState) -> Vec<u8> {
+    let (volume, width, height) = match &track.media {
+        TrackMedia::Video { width, height, .. } => (0u16, *width, *height),
+        TrackMedia::Audio { .. } => (0x0100u16, 0, 0),
+        TrackMedia::Text => (0, 0, 0),
+    };
+    // The chapter track is disabled so players do not show it as subtitles
+    let flags = match track.media {
+        TrackMedia::Text => 0,
+        _ => 0x000007, // enabled | in movie | in preview
     };
 
-    // mvhd
-    let duration = video_samples.len() as u32 * 3000; // 90000 timescale, 30fps
-    moov.extend_from_slice(&build_mvhd(duration, !audio_samples.is_empty()));
-
-    // video trak
-    moov.extend_from_slice(&build_video_trak(
-        media_data,
-        video_samples,
-        &calculate_composition_offsets(&media_data.frame_timestamps, global_min_pts),
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-    )?);
-
-    // audio trak (if present)
-    if !audio_samples.is_empty() {
-        moov.extend_from_slice(&build_audio_trak(
-            media_data,
-            audio_samples,
-            global_min_pts,
-            ftyp_size,
-            moov_size,
-            mdat_header_size,
-            video_data_end,
-        )?);
-    }
-
-    // Add moov header
-    let total_size = 8 + moov.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"moov");
-    result.extend_from_slice(&moov);
+    let duration = track.presentation_duration();
+    let version = time_version(duration);
+
+    let mut tkhd = Vec::new();
+    push_time_field(&mut tkhd, version, 0); // creation time
+    push_time_field(&mut tkhd, version, 0); // modification time
+    tkhd.extend_from_slice(&track.track_id.to_be_bytes());
+    tkhd.extend_from_slice(&0u32.to_be_bytes()); // reserved
+    push_time_field(&mut tkhd, version, duration);
+    tkhd.extend_from_slice(&[0u8; 8]); // reserved
+    tkhd.extend_from_slice(&0u16.to_be_bytes()); // layer
+    tkhd.extend_from_slice(&0u16.to_be_bytes()); // alternate group
+    tkhd.extend_from_slice(&volume.to_be_bytes());
+    tkhd.extend_from_slice(&0u16.to_be_bytes()); // reserved
+    for value in track.transform.matrix(width, height) {
+        tkhd.extend_from_slice(&value.to_be_bytes());
+    }
+    tkhd.extend_from_slice(&((width as u32) << 16).to_be_bytes());
+    tkhd.extend_from_slice(&((height as u32) << 16).to_be_bytes());
 
-    Ok(result)
+    let mut result = Vec::new();
+    write_full_box(&mut result, b"tkhd", version, flags, &tkhd);
+    result
 }
 
-fn build_mvhd(duration: u32, has_audio: bool) -> Vec<u8> {
-    let next_track_id = if has_audio { 3 } else { 2 };
+fn build_edts(edit_list: &[EditEntry]) -> Vec<u8> {
+    let version = if edit_list
+        .iter()
+        .any(|e| e.segment_duration > u32::MAX as u64 || i32::try_from(e.media_time).is_err())
+    {
+        1
+    } else {
+        0
+    };
 
-    vec![
-        0x00,
-        0x00,
-        0x00,
-        0x6C, // size
-        b'm',
-        b'v',
-        b'h',
-        b'd',
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // creation time
-        0x00,
-        0x00,
-        0x00,
-        0x00, // modification time
-        0x00,
-        0x01,
-        0x5F,
-        0x90, // timescale = 90000
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8,
-        0x00,
-        0x01,
-        0x00,
-        0x00, // rate
-        0x01,
-        0x00, // volume
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x40,
-        0x00,
-        0x00,
-        0x00, // matrix
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00, // pre-defined
-        (next_track_id >> 24) as u8,
-        (next_track_id >> 16) as u8,
-        (next_track_id >> 8) as u8,
-        next_track_id as u8, // next track ID
-    ]
-}
-
-fn build_video_trak(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    composition_offsets: &[i32],
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-) -> io::Result<Vec<u8>> {
-    let mut trak = Vec::new();
+    let mut elst = Vec::new();
+    elst.extend_from_slice(&(edit_list.len() as u32).to_be_bytes());
+    for entry in edit_list {
+        push_time_field(&mut elst, version, entry.segment_duration);
+        if version == 1 {
+            elst.extend_from_slice(&entry.media_time.to_be_bytes());
+        } else {
+            elst.extend_from_slice(&(entry.media_time as i32).to_be_bytes());
+        }
+        elst.extend_from_slice(&0x00010000u32.to_be_bytes()); // media rate = 1.0
+    }
 
-    // tkhd
-    trak.extend_from_slice(&build_tkhd(
-        1,
-        samples.len(),
-        media_data.width,
-        media_data.height,
-    ));
+    let mut edts = Vec::new();
+    write_full_box(&mut edts, b"elst", version, 0, &elst);
 
-    // mdia
-    trak.extend_from_slice(&build_video_mdia(
-        media_data,
-        samples,
-        composition_offsets,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-    )?);
-
-    let total_size = 8 + trak.len();
     let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"trak");
-    result.extend_from_slice(&trak);
-
-    Ok(result)
+    write_box(&mut result, b"edts", &edts);
+    result
 }
 
-fn build_tkhd(track_id: u32, sample_count: usize, width: u16, height: u16) -> Vec<u8> {
-    let duration = sample_count as u32 * 3000;
-    let width_fixed = (width as u32) << 16;
-    let height_fixed = (height as u32) << 16;
+fn build_mdhd(duration: u64, timescale: u32, language: [u8; 3]) -> Vec<u8> {
+    let version = time_version(duration);
+
+    let mut mdhd = Vec::new();
+    push_time_field(&mut mdhd, version, 0); // creation time
+    push_time_field(&mut mdhd, version, 0); // modification time
+    mdhd.extend_from_slice(&timescale.to_be_bytes());
+    push_time_field(&mut mdhd, version, duration);
+    // language: three 5-bit letters offset from 0x60 ("und" = 0x55C4)
+    let packed = language.iter().fold(0u16, |packed, &c| {
+        packed << 5 | (c.wrapping_sub(0x60) as u16 & 0x1F)
+    });
+    mdhd.extend_from_slice(&packed.to_be_bytes());
+    mdhd.extend_from_slice(&[0x00, 0x00]); // pre-defined
 
-    vec![
-        0x00,
-        0x00,
-        0x00,
-        0x5C, // size
-        b't',
-        b'k',
-        b'h',
-        b'd',
-        0x00,
-        0x00,
-        0x00,
-        0x07, // version + flags (enabled)
-        0x00,
-        0x00,
-        0x00,
-        0x00, // creation time
-        0x00,
-        0x00,
-        0x00,
-        0x00, // modification time
-        (track_id >> 24) as u8,
-        (track_id >> 16) as u8,
-        (track_id >> 8) as u8,
-        track_id as u8,
-        0x00,
-        0x00,
-        0x00,
-        0x00, // reserved
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x00, // layer
-        0x00,
-        0x00, // alternate group
-        0x00,
-        0x00, // volume
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x40,
-        0x00,
-        0x00,
-        0x00, // matrix
-        (width_fixed >> 24) as u8,
-        (width_fixed >> 16) as u8,
-        (width_fixed >> 8) as u8,
-        width_fixed as u8,
-        (height_fixed >> 24) as u8,
-        (height_fixed >> 16) as u8,
-        (height_fixed >> 8) as u8,
-        height_fixed as u8,
-    ]
-}
-
-fn build_video_mdia(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    composition_offsets: &[i32],
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-) -> io::Result<Vec<u8>> {
-    let mut mdia = Vec::new();
+    let mut result = Vec::new();
+    write_full_box(&mut result, b"mdhd", version, 0, &mdhd);
+    result
+}
 
-    // mdhd
-    let duration = samples.len() as u32 * 3000;
-    mdia.extend_from_slice(&[
-        0x00,
-        0x00,
-        0x00,
-        0x20, // size
-        b'm',
-        b'd',
-        b'h',
-        b'd',
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // creation time
-        0x00,
-        0x00,
-        0x00,
-        0x00, // modification time
-        0x00,
-        0x01,
-        0x5F,
-        0x90, // timescale = 90000
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8,
-        0x55,
-        0xC4, // language
-        0x00,
-        0x00, // pre-defined
-    ]);
+fn build_hdlr(media: &TrackMedia, format: OutputFormat) -> Vec<u8> {
+    let (handler_type, name) = match media {
+        TrackMedia::Video { .. } => (b"vide", "VideoHandler"),
+        TrackMedia::Audio { .. } => (b"soun", "SoundHandler"),
+        TrackMedia::Text => (b"text", "TextHandler"),
+    };
 
-    // hdlr
-    mdia.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x21, // size
-        b'h', b'd', b'l', b'r', 0x00, 0x00, 0x00, 0x00, // version + flags
-        0x00, 0x00, 0x00, 0x00, // pre-defined
-        b'v', b'i', b'd', b'e', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, // name
-    ]);
+    match format {
+        OutputFormat::Mp4 => {
+            let mut hdlr = Vec::new();
+            hdlr.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // pre-defined
+            hdlr.extend_from_slice(handler_type);
+            hdlr.extend_from_slice(&[0u8; 12]); // reserved
+            hdlr.push(0x00); // name
+
+            let mut result = Vec::new();
+            write_full_box(&mut result, b"hdlr", 0, 0, &hdlr);
+            result
+        }
+        OutputFormat::Mov => build_quicktime_hdlr(b"mhlr", handler_type, name),
+    }
+}
 
-    // minf
-    mdia.extend_from_slice(&build_video_minf(
-        media_data,
-        samples,
-        composition_offsets,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-    )?);
-
-    let total_size = 8 + mdia.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"mdia");
-    result.extend_from_slice(&mdia);
+/// QuickTime handler reference: component type (mhlr = media, dhlr = data), subtype and a
+/// Pascal string name
+fn build_quicktime_hdlr(component_type: &[u8; 4], subtype: &[u8; 4], name: &str) -> Vec<u8> {
+    let mut hdlr = Vec::new();
+    hdlr.extend_from_slice(component_type);
+    hdlr.extend_from_slice(subtype);
+    hdlr.extend_from_slice(&[0u8; 12]); // manufacturer, flags, flags mask
+    hdlr.push(name.len() as u8);
+    hdlr.extend_from_slice(name.as_bytes());
 
-    Ok(result)
+    let mut result = Vec::new();
+    write_full_box(&mut result, b"hdlr", 0, 0, &hdlr);
+    result
 }
 
-fn build_video_minf(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    composition_offsets: &[i32],
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-) -> io::Result<Vec<u8>> {
+fn build_minf(track: &TrackState, offset_shift: u64, format: OutputFormat) -> Vec<u8> {
     let mut minf = Vec::new();
 
-    // vmhd
-    minf.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x14, // size = 20
-        b'v', b'm', b'h', b'd', 0x00, 0x00, 0x00, 0x01, // version + flags
-        0x00, 0x00, // graphics mode
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // opcolor (RGB)
-    ]);
+    match &track.media {
+        TrackMedia::Video { .. } => {
+            // vmhd
+            minf.extend_from_slice(&[
+                0x00, 0x00, 0x00, 0x14, // size = 20
+                b'v', b'm', b'h', b'd', 0x00, 0x00, 0x00, 0x01, // version + flags
+                0x00, 0x00, // graphics mode
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // opcolor (RGB)
+            ]);
+        }
+        TrackMedia::Audio { .. } => {
+            // smhd
+            minf.extend_from_slice(&[
+                0x00, 0x00, 0x00, 0x10, // size
+                b's', b'm', b'h', b'd', 0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, // balance
+                0x00, 0x00, // reserved
+            ]);
+        }
+        TrackMedia::Text => minf.extend_from_slice(&build_text_gmhd()),
+    }
+
+    // QuickTime names the data handler of the media, which resolves an alias reference
+    let data_reference = match format {
+        OutputFormat::Mp4 => b"url ",
+        OutputFormat::Mov => {
+            minf.extend_from_slice(&build_quicktime_hdlr(b"dhlr", b"alis", "DataHandler"));
+            b"alis"
+        }
+    };
 
     // dinf
     minf.extend_from_slice(&[
@@ -513,183 +1714,184 @@ fn build_video_minf(
         b'd', b'i', b'n', b'f', 0x00, 0x00, 0x00, 0x1C, // dref size
         b'd', b'r', b'e', b'f', 0x00, 0x00, 0x00, 0x00, // version + flags
         0x00, 0x00, 0x00, 0x01, // entry count
-        0x00, 0x00, 0x00, 0x0C, // url size
-        b'u', b'r', b'l', b' ', 0x00, 0x00, 0x00, 0x01, // version + flags (self-reference)
+        0x00, 0x00, 0x00, 0x0C, // url (alis) size
     ]);
+    minf.extend_from_slice(data_reference);
+    minf.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); // version + flags (self-reference)
 
-    // stbl
-    minf.extend_from_slice(&build_video_stbl(
-        media_data,
-        samples,
-        composition_offsets,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-    )?);
-
-    let total_size = 8 + minf.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"minf");
-    result.extend_from_slice(&minf);
+    minf.extend_from_slice(&build_stbl(track, offset_shift, format));
 
-    Ok(result)
+    let mut result = Vec::new();
+    write_box(&mut result, b"minf", &minf);
+    result
 }
 
-fn build_video_stbl(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    composition_offsets: &[i32],
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-) -> io::Result<Vec<u8>> {
+fn build_stbl(track: &TrackState, offset_shift: u64, format: OutputFormat) -> Vec<u8> {
     let mut stbl = Vec::new();
+    let sample_count = track.sample_sizes.len() as u32;
 
     // stsd
-    stbl.extend_from_slice(&build_video_stsd(media_data)?);
+    match &track.media {
+        TrackMedia::Video {
+            width,
+            height,
+            sps,
+            pps,
+        } => stbl.extend_from_slice(&build_video_stsd(
+            *width,
+            *height,
+            sps,
+            pps,
+            track.bitrate(),
+            format,
+        )),
+        TrackMedia::Audio { config } => stbl.extend_from_slice(&build_audio_stsd(config)),
+        TrackMedia::Text => stbl.extend_from_slice(&build_text_stsd()),
+    }
 
     // stts
-    let sample_count = samples.len() as u32;
-    let stts = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x01, // entry count
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8,
-        0x00,
-        0x00,
-        0x0B,
-        0xB8, // sample delta = 3000
-    ];
-    let stts_size = 8 + stts.len();
-    let mut stts_box = Vec::new();
-    stts_box.extend_from_slice(&(stts_size as u32).to_be_bytes());
-    stts_box.extend_from_slice(b"stts");
-    stts_box.extend_from_slice(&stts);
-    stbl.extend_from_slice(&stts_box);
-
-    // stsc - Put all video samples in a single chunk
-    stbl.extend_from_slice(&[
-        0x00,
-        0x00,
-        0x00,
-        0x1C, // size
-        b's',
-        b't',
-        b's',
-        b'c',
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x01, // entry count
-        0x00,
-        0x00,
-        0x00,
-        0x01, // first chunk
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8, // samples per chunk = all samples
-        0x00,
-        0x00,
-        0x00,
-        0x01, // sample description index
-    ]);
+    let mut stts = Vec::new();
+    stts.extend_from_slice(&(track.time_to_sample.len() as u32).to_be_bytes());
+    for (count, delta) in &track.time_to_sample {
+        stts.extend_from_slice(&count.to_be_bytes());
+        stts.extend_from_slice(&delta.to_be_bytes());
+    }
+    write_full_box(&mut stbl, b"stts", 0, 0, &stts);
+
+    // ctts (composition time offsets) - version 1 allows negative offsets
+    if track.composition_offsets.iter().any(|&(_, o)| o != 0) {
+        let version = if track.composition_offsets.iter().any(|&(_, o)| o < 0) {
+            1
+        } else {
+            0
+        };
+        let mut ctts = Vec::new();
+        ctts.extend_from_slice(&(track.composition_offsets.len() as u32).to_be_bytes());
+        for (count, offset) in &track.composition_offsets {
+            ctts.extend_from_slice(&count.to_be_bytes());
+            ctts.extend_from_slice(&offset.to_be_bytes());
+        }
+        write_full_box(&mut stbl, b"ctts", version, 0, &ctts);
+    }
+
+    // stss - omitted when every sample is a sync sample
+    if track.sync_samples.len() as u32 != sample_count {
+        let mut stss = Vec::new();
+        stss.extend_from_slice(&(track.sync_samples.len() as u32).to_be_bytes());
+        for sample_number in &track.sync_samples {
+            stss.extend_from_slice(&sample_number.to_be_bytes());
+        }
+        write_full_box(&mut stbl, b"stss", 0, 0, &stss);
+    }
+
+    // stsc - one entry per run of chunks with the same sample count
+    let mut stsc_entries: Vec<(u32, u32)> = Vec::new();
+    for (index, &count) in track.samples_per_chunk.iter().enumerate() {
+        if stsc_entries.last().map(|&(_, c)| c) != Some(count) {
+            stsc_entries.push((index as u32 + 1, count));
+        }
+    }
+    let mut stsc = Vec::new();
+    stsc.extend_from_slice(&(stsc_entries.len() as u32).to_be_bytes());
+    for (first_chunk, samples_per_chunk) in stsc_entries {
+        stsc.extend_from_slice(&first_chunk.to_be_bytes());
+        stsc.extend_from_slice(&samples_per_chunk.to_be_bytes());
+        stsc.extend_from_slice(&1u32.to_be_bytes()); // sample description index
+    }
+    write_full_box(&mut stbl, b"stsc", 0, 0, &stsc);
 
     // stsz
-    let mut stsz = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // sample size (0 = variable)
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8,
-    ];
-    for sample in samples {
-        let size = sample.len() as u32;
+    let mut stsz = Vec::new();
+    stsz.extend_from_slice(&0u32.to_be_bytes()); // sample size (0 = variable)
+    stsz.extend_from_slice(&sample_count.to_be_bytes());
+    for size in &track.sample_sizes {
         stsz.extend_from_slice(&size.to_be_bytes());
     }
-    let stsz_size = 8 + stsz.len();
-    let mut stsz_box = Vec::new();
-    stsz_box.extend_from_slice(&(stsz_size as u32).to_be_bytes());
-    stsz_box.extend_from_slice(b"stsz");
-    stsz_box.extend_from_slice(&stsz);
-    stbl.extend_from_slice(&stsz_box);
-
-    // stco - Only one chunk containing all video samples
-    let base_offset = ftyp_size + moov_size + mdat_header_size;
-    let chunk_count = 1u32;
-    let mut stco = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        (chunk_count >> 24) as u8,
-        (chunk_count >> 16) as u8,
-        (chunk_count >> 8) as u8,
-        chunk_count as u8,
-    ];
-    // Single chunk offset pointing to start of video data
-    stco.extend_from_slice(&(base_offset as u32).to_be_bytes());
-    let stco_size = 8 + stco.len();
-    let mut stco_box = Vec::new();
-    stco_box.extend_from_slice(&(stco_size as u32).to_be_bytes());
-    stco_box.extend_from_slice(b"stco");
-    stco_box.extend_from_slice(&stco);
-    stbl.extend_from_slice(&stco_box);
-
-    // ctts (composition time offsets)
-    if !composition_offsets.is_empty() && composition_offsets.iter().any(|&o| o != 0) {
-        let mut ctts = vec![
-            0x00,
-            0x00,
-            0x00,
-            0x00, // version + flags
-            (sample_count >> 24) as u8,
-            (sample_count >> 16) as u8,
-            (sample_count >> 8) as u8,
-            sample_count as u8,
-        ];
-        for &offset in composition_offsets {
-            ctts.extend_from_slice(&1u32.to_be_bytes()); // sample count
-            ctts.extend_from_slice(&(offset as u32).to_be_bytes()); // sample offset
+    write_full_box(&mut stbl, b"stsz", 0, 0, &stsz);
+
+    // stco, or co64 once any chunk lies beyond 4 GB
+    let use_co64 = track
+        .chunk_offsets
+        .last()
+        .is_some_and(|offset| offset + offset_shift > u32::MAX as u64);
+    let mut stco = Vec::new();
+    stco.extend_from_slice(&(track.chunk_offsets.len() as u32).to_be_bytes());
+    for offset in &track.chunk_offsets {
+        if use_co64 {
+            stco.extend_from_slice(&(offset + offset_shift).to_be_bytes());
+        } else {
+            stco.extend_from_slice(&((offset + offset_shift) as u32).to_be_bytes());
         }
-        let ctts_size = 8 + ctts.len();
-        let mut ctts_box = Vec::new();
-        ctts_box.extend_from_slice(&(ctts_size as u32).to_be_bytes());
-        ctts_box.extend_from_slice(b"ctts");
-        ctts_box.extend_from_slice(&ctts);
-        stbl.extend_from_slice(&ctts_box);
     }
+    let box_type = if use_co64 { b"co64" } else { b"stco" };
+    write_full_box(&mut stbl, box_type, 0, 0, &stco);
+
+    let mut result = Vec::new();
+    write_box(&mut result, b"stbl", &stbl);
+    result
+}
+
+/// QuickTime base media header (gmhd) of a text track: gmin and the text media info
+fn build_text_gmhd() -> Vec<u8> {
+    let mut gmhd = Vec::new();
+
+    let mut gmin = Vec::new();
+    gmin.extend_from_slice(&0x0040u16.to_be_bytes()); // graphics mode (dither copy)
+    for _ in 0..3 {
+        gmin.extend_from_slice(&0x8000u16.to_be_bytes()); // opcolor
+    }
+    gmin.extend_from_slice(&0u16.to_be_bytes()); // balance
+    gmin.extend_from_slice(&0u16.to_be_bytes()); // reserved
+    write_full_box(&mut gmhd, b"gmin", 0, 0, &gmin);
+
+    // text: display matrix
+    let mut text = Vec::new();
+    for value in UNITY_MATRIX {
+        text.extend_from_slice(&value.to_be_bytes());
+    }
+    write_box(&mut gmhd, b"text", &text);
 
-    let total_size = 8 + stbl.len();
     let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"stbl");
-    result.extend_from_slice(&stbl);
+    write_box(&mut result, b"gmhd", &gmhd);
+    result
+}
+
+/// QuickTime text sample description with default display settings
+fn build_text_stsd() -> Vec<u8> {
+    let mut stsd = vec![
+        0x00, 0x00, 0x00, 0x00, // version + flags
+        0x00, 0x00, 0x00, 0x01, // entry count
+    ];
 
-    Ok(result)
+    let mut text = Vec::new();
+    text.extend_from_slice(&[0u8; 6]); // reserved
+    text.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    text.extend_from_slice(&0u32.to_be_bytes()); // display flags
+    text.extend_from_slice(&1u32.to_be_bytes()); // text justification (center)
+    text.extend_from_slice(&[0u8; 6]); // background color
+    text.extend_from_slice(&[0u8; 8]); // default text box
+    text.extend_from_slice(&[0u8; 8]); // reserved
+    text.extend_from_slice(&0u16.to_be_bytes()); // font number
+    text.extend_from_slice(&0u16.to_be_bytes()); // font face
+    text.push(0); // reserved
+    text.extend_from_slice(&0u16.to_be_bytes()); // reserved
+    text.extend_from_slice(&[0u8; 6]); // foreground color
+    text.push(0); // text name (empty Pascal string)
+    write_box(&mut stsd, b"text", &text);
+
+    let mut result = Vec::new();
+    write_box(&mut result, b"stsd", &stsd);
+    result
 }
 
-fn build_video_stsd(media_data: &MediaData) -> io::Result<Vec<u8>> {
+fn build_video_stsd(
+    width: u16,
+    height: u16,
+    sps: &[u8],
+    pps: &[u8],
+    bitrate: Option<Bitrate>,
+    format: OutputFormat,
+) -> Vec<u8> {
     let mut stsd = vec![
         0x00, 0x00, 0x00, 0x00, // version + flags
         0x00, 0x00, 0x00, 0x01, // entry count
@@ -721,10 +1923,10 @@ fn build_video_stsd(media_data: &MediaData) -> io::Result<Vec<u8>> {
         0x00,
         0x00,
         0x00, // pre-defined
-        (media_data.width >> 8) as u8,
-        (media_data.width & 0xFF) as u8,
-        (media_data.height >> 8) as u8,
-        (media_data.height & 0xFF) as u8,
+        (width >> 8) as u8,
+        (width & 0xFF) as u8,
+        (height >> 8) as u8,
+        (height & 0xFF) as u8,
         0x00,
         0x48,
         0x00,
@@ -778,7 +1980,7 @@ fn build_video_stsd(media_data: &MediaData) -> io::Result<Vec<u8>> {
     ];
 
     // avcC
-    if let (Some(sps), Some(pps)) = (&media_data.sps, &media_data.pps) {
+    if !sps.is_empty() && !pps.is_empty() {
         let mut avcc = vec![
             0x01, // configuration version
         ];
@@ -810,6 +2012,50 @@ fn build_video_stsd(media_data: &MediaData) -> io::Result<Vec<u8>> {
         avc1.extend_from_slice(&avcc_box);
     }
 
+    if let Some(bitrate) = bitrate {
+        let mut btrt = Vec::new();
+        btrt.extend_from_slice(&bitrate.buffer_size.to_be_bytes());
+        btrt.extend_from_slice(&bitrate.max.to_be_bytes());
+        btrt.extend_from_slice(&bitrate.average.to_be_bytes());
+        write_box(&mut avc1, b"btrt", &btrt);
+    }
+
+    if let Some(info) = parse_sps(sps) {
+        // The entry already carries the cropped size, so the clean aperture spans all of it
+        // centred on the picture
+        if info.cropped {
+            let mut clap = Vec::new();
+            for numerator in [width as u32, height as u32, 0, 0] {
+                clap.extend_from_slice(&numerator.to_be_bytes());
+                clap.extend_from_slice(&1u32.to_be_bytes()); // denominator
+            }
+            write_box(&mut avc1, b"clap", &clap);
+        }
+
+        if let Some((h_spacing, v_spacing)) = info.sample_aspect_ratio {
+            let mut pasp = Vec::new();
+            pasp.extend_from_slice(&(h_spacing as u32).to_be_bytes());
+            pasp.extend_from_slice(&(v_spacing as u32).to_be_bytes());
+            write_box(&mut avc1, b"pasp", &pasp);
+        }
+
+        // QuickTime reads nclc, which has no range flag
+        if let Some(colour) = info.colour {
+            let mut colr = Vec::new();
+            colr.extend_from_slice(match format {
+                OutputFormat::Mp4 => b"nclx",
+                OutputFormat::Mov => b"nclc",
+            });
+            colr.extend_from_slice(&(colour.primaries as u16).to_be_bytes());
+            colr.extend_from_slice(&(colour.transfer as u16).to_be_bytes());
+            colr.extend_from_slice(&(colour.matrix as u16).to_be_bytes());
+            if format == OutputFormat::Mp4 {
+                colr.push(if colour.full_range { 0x80 } else { 0x00 });
+            }
+            write_box(&mut avc1, b"colr", &colr);
+        }
+    }
+
     let avc1_size = 8 + avc1.len();
     let mut avc1_box = Vec::new();
     avc1_box.extend_from_slice(&(avc1_size as u32).to_be_bytes());
@@ -824,468 +2070,36 @@ fn build_video_stsd(media_data: &MediaData) -> io::Result<Vec<u8>> {
     result.extend_from_slice(b"stsd");
     result.extend_from_slice(&stsd);
 
-    Ok(result)
-}
-
-fn build_audio_trak(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    global_min_pts: u64,
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-    video_data_end: usize,
-) -> io::Result<Vec<u8>> {
-    let mut trak = Vec::new();
-
-    // tkhd
-    let duration = samples.len() as u32 * 1920; // Duration in movie timescale (90kHz): 1024 samples @ 48kHz = 1920 in 90kHz
-    trak.extend_from_slice(&[
-        0x00,
-        0x00,
-        0x00,
-        0x5C, // size
-        b't',
-        b'k',
-        b'h',
-        b'd',
-        0x00,
-        0x00,
-        0x00,
-        0x07, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // creation time
-        0x00,
-        0x00,
-        0x00,
-        0x00, // modification time
-        0x00,
-        0x00,
-        0x00,
-        0x02, // track ID = 2
-        0x00,
-        0x00,
-        0x00,
-        0x00, // reserved
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x00, // layer
-        0x00,
-        0x00, // alternate group
-        0x01,
-        0x00, // volume = 1.0
-        0x00,
-        0x00, // reserved
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x40,
-        0x00,
-        0x00,
-        0x00, // matrix
-        0x00,
-        0x00,
-        0x00,
-        0x00, // width
-        0x00,
-        0x00,
-        0x00,
-        0x00, // height
-    ]);
-
-    // mdia
-    trak.extend_from_slice(&build_audio_mdia(
-        media_data,
-        samples,
-        global_min_pts,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-        video_data_end,
-    )?);
-
-    // Add Edit List if audio doesn't start at global minimum PTS
-    if let Some(Some(first_audio_pts)) = media_data.audio_timestamps.first() {
-        if *first_audio_pts > global_min_pts {
-            // Calculate delay in 90kHz timeline
-            let delay_90khz = *first_audio_pts - global_min_pts;
-            // Convert to 48kHz for audio track
-            let delay_48khz = ((delay_90khz as i64 * 48000) / 90000) as i32;
-
-            // Create edit list with empty edit followed by media edit
-            let media_duration_48khz = samples.len() as u32 * 1024;
-            let segment_duration_90khz = media_duration_48khz as u64 * 90000 / 48000;
-
-            let elst_size = 36u32; // 8 (box header) + 28 (elst content)
-            let edts = vec![
-                // elst box
-                (elst_size >> 24) as u8,
-                (elst_size >> 16) as u8,
-                (elst_size >> 8) as u8,
-                elst_size as u8,
-                b'e',
-                b'l',
-                b's',
-                b't',
-                0x00,
-                0x00,
-                0x00,
-                0x00, // version + flags
-                0x00,
-                0x00,
-                0x00,
-                0x01, // entry count = 1
-                // Entry: segment duration (in movie timescale = 90000)
-                ((segment_duration_90khz >> 24) as u8),
-                ((segment_duration_90khz >> 16) as u8),
-                ((segment_duration_90khz >> 8) as u8),
-                (segment_duration_90khz as u8),
-                // Media time: where to start in media timeline (in media timescale = 48000)
-                (delay_48khz >> 24) as u8,
-                (delay_48khz >> 16) as u8,
-                (delay_48khz >> 8) as u8,
-                delay_48khz as u8,
-                0x00,
-                0x01,
-                0x00,
-                0x00, // media rate = 1.0
-            ];
-
-            let edts_size = 8 + edts.len();
-            let mut edts_box = Vec::new();
-            edts_box.extend_from_slice(&(edts_size as u32).to_be_bytes());
-            edts_box.extend_from_slice(b"edts");
-            edts_box.extend_from_slice(&edts);
-
-            trak.extend_from_slice(&edts_box);
-        }
-    }
-
-    let total_size = 8 + trak.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"trak");
-    result.extend_from_slice(&trak);
-
-    Ok(result)
-}
-
-fn build_audio_mdia(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    global_min_pts: u64,
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-    video_data_end: usize,
-) -> io::Result<Vec<u8>> {
-    let mut mdia = Vec::new();
-
-    // mdhd
-    let duration = samples.len() as u32 * 1920; // 1920 = 1024 samples @ 48kHz in 90kHz timebase
-    mdia.extend_from_slice(&[
-        0x00,
-        0x00,
-        0x00,
-        0x20, // size
-        b'm',
-        b'd',
-        b'h',
-        b'd',
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // creation time
-        0x00,
-        0x00,
-        0x00,
-        0x00, // modification time
-        0x00,
-        0x01,
-        0x5F,
-        0x90, // timescale = 90000 (same as video for consistency)
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8,
-        0x55,
-        0xC4, // language
-        0x00,
-        0x00, // pre-defined
-    ]);
-
-    // hdlr
-    mdia.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x21, // size
-        b'h', b'd', b'l', b'r', 0x00, 0x00, 0x00, 0x00, // version + flags
-        0x00, 0x00, 0x00, 0x00, // pre-defined
-        b's', b'o', b'u', b'n', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, // name
-    ]);
-
-    // minf
-    mdia.extend_from_slice(&build_audio_minf(
-        media_data,
-        samples,
-        global_min_pts,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-        video_data_end,
-    )?);
-
-    let total_size = 8 + mdia.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"mdia");
-    result.extend_from_slice(&mdia);
-
-    Ok(result)
-}
-
-fn build_audio_minf(
-    media_data: &MediaData,
-    samples: &[Vec<u8>],
-    global_min_pts: u64,
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-    video_data_end: usize,
-) -> io::Result<Vec<u8>> {
-    let mut minf = Vec::new();
-
-    // smhd
-    minf.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x10, // size
-        b's', b'm', b'h', b'd', 0x00, 0x00, 0x00, 0x00, // version + flags
-        0x00, 0x00, // balance
-        0x00, 0x00, // reserved
-    ]);
-
-    // dinf
-    minf.extend_from_slice(&[
-        0x00, 0x00, 0x00, 0x24, // size
-        b'd', b'i', b'n', b'f', 0x00, 0x00, 0x00, 0x1C, // dref size
-        b'd', b'r', b'e', b'f', 0x00, 0x00, 0x00, 0x00, // version + flags
-        0x00, 0x00, 0x00, 0x01, // entry count
-        0x00, 0x00, 0x00, 0x0C, // url size
-        b'u', b'r', b'l', b' ', 0x00, 0x00, 0x00, 0x01, // version + flags (self-reference)
-    ]);
-
-    // stbl
-    minf.extend_from_slice(&build_audio_stbl(
-        media_data,
-        samples,
-        global_min_pts,
-        ftyp_size,
-        moov_size,
-        mdat_header_size,
-        video_data_end,
-    )?);
-
-    let total_size = 8 + minf.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"minf");
-    result.extend_from_slice(&minf);
-
-    Ok(result)
-}
-
-fn build_audio_stbl(
-    _media_data: &MediaData,
-    samples: &[Vec<u8>],
-    _global_min_pts: u64,
-    ftyp_size: usize,
-    moov_size: usize,
-    mdat_header_size: usize,
-    video_data_end: usize,
-) -> io::Result<Vec<u8>> {
-    let mut stbl = Vec::new();
-
-    // stsd
-    stbl.extend_from_slice(&build_audio_stsd()?);
-
-    // stts
-    let sample_count = samples.len() as u32;
-    let stts = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x01, // entry count
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8,
-        0x00,
-        0x00,
-        0x07,
-        0x80, // sample delta = 1920 (1024 samples @ 48kHz = 0.021333s in 90kHz timebase)
-    ];
-    let stts_size = 8 + stts.len();
-    let mut stts_box = Vec::new();
-    stts_box.extend_from_slice(&(stts_size as u32).to_be_bytes());
-    stts_box.extend_from_slice(b"stts");
-    stts_box.extend_from_slice(&stts);
-    stbl.extend_from_slice(&stts_box);
-
-    // stsc - Put all audio samples in a single chunk for better compatibility
-    stbl.extend_from_slice(&[
-        0x00,
-        0x00,
-        0x00,
-        0x1C, // size
-        b's',
-        b't',
-        b's',
-        b'c',
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x01, // entry count = 1
-        0x00,
-        0x00,
-        0x00,
-        0x01, // first chunk = 1
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8, // samples per chunk = all samples
-        0x00,
-        0x00,
-        0x00,
-        0x01, // sample description index
-    ]);
-
-    // stsz
-    let mut stsz = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        0x00,
-        0x00,
-        0x00,
-        0x00, // sample size (0 = variable)
-        (sample_count >> 24) as u8,
-        (sample_count >> 16) as u8,
-        (sample_count >> 8) as u8,
-        sample_count as u8,
-    ];
-    for sample in samples {
-        let size = sample.len() as u32;
-        stsz.extend_from_slice(&size.to_be_bytes());
-    }
-    let stsz_size = 8 + stsz.len();
-    let mut stsz_box = Vec::new();
-    stsz_box.extend_from_slice(&(stsz_size as u32).to_be_bytes());
-    stsz_box.extend_from_slice(b"stsz");
-    stsz_box.extend_from_slice(&stsz);
-    stbl.extend_from_slice(&stsz_box);
-
-    // stco - Only one chunk containing all audio samples
-    let base_offset = ftyp_size + moov_size + mdat_header_size + video_data_end;
-    let chunk_count = 1u32; // Single chunk containing all samples
-    let mut stco = vec![
-        0x00,
-        0x00,
-        0x00,
-        0x00, // version + flags
-        (chunk_count >> 24) as u8,
-        (chunk_count >> 16) as u8,
-        (chunk_count >> 8) as u8,
-        chunk_count as u8,
-    ];
-    // Add the single chunk offset (start of audio data in mdat)
-    stco.extend_from_slice(&(base_offset as u32).to_be_bytes());
-
-    let stco_size = 8 + stco.len();
-    let mut stco_box = Vec::new();
-    stco_box.extend_from_slice(&(stco_size as u32).to_be_bytes());
-    stco_box.extend_from_slice(b"stco");
-    stco_box.extend_from_slice(&stco);
-    stbl.extend_from_slice(&stco_box);
-
-    let total_size = 8 + stbl.len();
-    let mut result = Vec::new();
-    result.extend_from_slice(&(total_size as u32).to_be_bytes());
-    result.extend_from_slice(b"stbl");
-    result.extend_from_slice(&stbl);
-
-    Ok(result)
+    result
 }
 
-fn build_audio_stsd() -> io::Result<Vec<u8>> {
+fn build_audio_stsd(config: &AacConfig) -> Vec<u8> {
     let mut stsd = vec![
         0x00, 0x00, 0x00, 0x00, // version + flags
         0x00, 0x00, 0x00, 0x01, // entry count
     ];
 
+    // The 16.16 sample rate field only fits rates below 65536; higher rates are left to the ASC
+    let sample_rate = config.sample_rate();
+    let rate_field = if sample_rate <= u16::MAX as u32 {
+        sample_rate << 16
+    } else {
+        0
+    };
+
     // mp4a sample entry
     let mut mp4a = vec![
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reserved
         0x00, 0x01, // data reference index
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reserved (version 0)
-        0x00, 0x02, // channel count = 2
+    ];
+    mp4a.extend_from_slice(&(config.channels.max(1) as u16).to_be_bytes()); // channel count
+    mp4a.extend_from_slice(&[
         0x00, 0x10, // sample size = 16
         0x00, 0x00, // pre-defined
         0x00, 0x00, // reserved
-        0xBB, 0x80, 0x00, 0x00, // sample rate = 48000 << 16
-    ];
+    ]);
+    mp4a.extend_from_slice(&rate_field.to_be_bytes());
 
     // esds
     let mut esds_content = vec![
@@ -1310,23 +2124,7 @@ fn build_audio_stsd() -> io::Result<Vec<u8>> {
     // DecoderSpecificInfo
     esds_content.push(0x05); // DecoderSpecificInfoTag
     esds_content.push(0x02); // length
-                             // AudioSpecificConfig: AAC-LC (2), 48kHz (3), stereo (2)
-                             // 5 bits: audioObjectType = 2 (AAC-LC)
-                             // 4 bits: samplingFrequencyIndex = 3 (48000 Hz)
-                             // 4 bits: channelConfiguration = 2 (stereo)
-                             // 3 bits: frameLengthFlag, dependsOnCoreCoder, extensionFlag = 0
-                             // Byte 1: 00010 011 = 0x13 (but should be 0001 0011 = 0x11)
-                             // Wait, let me recalculate:
-                             // audioObjectType = 2: 00010
-                             // samplingFrequencyIndex = 3: 0011
-                             // First byte = 00010 011 = 0x13
-                             // Wait, that's wrong. Let's do it properly:
-                             // First 5 bits: audioObjectType = 2 = 00010
-                             // Next 4 bits: samplingFrequencyIndex = 3 = 0011
-                             // Next 4 bits: channelConfiguration = 2 = 0010
-                             // Next 3 bits: other flags = 000
-                             // Total: 00010 0011 0010 000 = 0001 0001 1001 0000 = 0x11 0x90
-    esds_content.extend_from_slice(&[0x11, 0x90]);
+    esds_content.extend_from_slice(&config.audio_specific_config());
 
     // SLConfigDescriptor
     esds_content.push(0x06); // SLConfigDescrTag
@@ -1355,12 +2153,12 @@ fn build_audio_stsd() -> io::Result<Vec<u8>> {
     result.extend_from_slice(b"stsd");
     result.extend_from_slice(&stsd);
 
-    Ok(result)
+    result
 }
 
-fn split_into_frames(video_stream: &[u8]) -> Vec<Vec<u8>> {
+fn split_into_frames(video_stream: &[u8]) -> Vec<&[u8]> {
     let mut frames = Vec::new();
-    let mut current_frame = Vec::new();
+    let mut frame_start: Option<usize> = None;
     let mut i = 0;
 
     while i < video_stream.len() {
@@ -1391,9 +2189,11 @@ fn split_into_frames(video_stream: &[u8]) -> Vec<Vec<u8>> {
         let nal_type = video_stream[nal_start] & 0x1F;
 
         // AUD (9) marks new frame - save previous frame
-        if nal_type == 9 && !current_frame.is_empty() {
-            frames.push(current_frame.clone());
-            current_frame.clear();
+        if nal_type == 9 {
+            if let Some(start) = frame_start {
+                frames.push(&video_stream[start..i]);
+            }
+            frame_start = Some(i);
         }
 
         // Find end of this NAL unit (next start code)
@@ -1416,19 +2216,19 @@ fn split_into_frames(video_stream: &[u8]) -> Vec<Vec<u8>> {
             nal_end = video_stream.len();
         }
 
-        // Add this NAL to current frame (with start code)
-        current_frame.extend_from_slice(&video_stream[i..nal_end]);
+        // This NAL (with start code) belongs to the current frame
+        frame_start.get_or_insert(i);
         i = nal_end;
     }
 
-    if !current_frame.is_empty() {
-        frames.push(current_frame);
+    if let Some(start) = frame_start {
+        frames.push(&video_stream[start..]);
     }
 
     frames
 }
 
-fn convert_annexb_to_avcc(data: &[u8]) -> Vec<u8> {
+pub(crate) fn convert_annexb_to_avcc(data: &[u8]) -> Vec<u8> {
     let mut output = Vec::new();
     let mut i = 0;
 
@@ -1496,37 +2296,344 @@ fn convert_annexb_to_avcc(data: &[u8]) -> Vec<u8> {
     output
 }
 
-fn calculate_composition_offsets(
-    timestamps: &[(Option<u64>, Option<u64>)],
-    global_min_pts: u64,
-) -> Vec<i32> {
-    if timestamps.is_empty() {
-        return Vec::new();
+/// Returns true if the AVCC sample contains an IDR slice
+pub(crate) fn is_sync_sample(avcc_data: &[u8]) -> bool {
+    let mut offset = 0;
+
+    while offset + 4 < avcc_data.len() {
+        let nal_size = u32::from_be_bytes([
+            avcc_data[offset],
+            avcc_data[offset + 1],
+            avcc_data[offset + 2],
+            avcc_data[offset + 3],
+        ]) as usize;
+
+        if avcc_data[offset + 4] & 0x1F == 5 {
+            return true;
+        }
+
+        offset += 4 + nal_size;
     }
 
-    // Use global minimum PTS for synchronization across all streams
-    let min_dts = global_min_pts;
+    false
+}
 
-    let mut offsets = Vec::new();
+/// Small MP4 files shared by the tests of the crate
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::{SampleWriter, StreamingMp4Writer};
+    use crate::ts_parser::AacConfig;
+    use std::io::Cursor;
 
-    for (pts, dts) in timestamps {
-        let offset = match (pts, dts) {
-            (Some(p), Some(d)) => {
-                let normalized_pts = (*p as i64 - min_dts as i64) as i32;
-                let normalized_dts = (*d as i64 - min_dts as i64) as i32;
-                normalized_pts - normalized_dts
-            }
-            _ => 0,
-        };
-        offsets.push(offset);
+    /// Adds a 320x240 H.264 track with a placeholder SPS/PPS
+    pub(crate) fn tiny_video_track(writer: &mut impl SampleWriter) -> usize {
+        writer.add_video_track(320, 240, &[0x67, 0x42, 0x00, 0x1E], &[0x68])
+    }
+
+    /// Writes `frames` 6-byte frames of 3000 ticks with a keyframe every `gop` frames.
+    /// The last byte of a frame is its number.
+    pub(crate) fn write_tiny_frames(
+        writer: &mut impl SampleWriter,
+        track: usize,
+        frames: u8,
+        gop: u8,
+    ) {
+        for i in 0..frames {
+            let sync = i % gop == 0;
+            let nal = if sync { 0x65 } else { 0x41 };
+            writer
+                .write_sample(track, &[0, 0, 0, 2, nal, i], 3000, 0, sync)
+                .unwrap();
+        }
     }
 
-    if let Some(&first_offset) = offsets.first() {
-        let adjustment = first_offset;
-        for offset in &mut offsets {
-            *offset -= adjustment;
+    /// Writer holding `frames` frames on a tiny video track (track 0), only the first a keyframe
+    pub(crate) fn tiny_writer(frames: u8) -> StreamingMp4Writer<Cursor<Vec<u8>>> {
+        let mut writer = StreamingMp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        let video = tiny_video_track(&mut writer);
+        write_tiny_frames(&mut writer, video, frames, frames.max(1));
+        writer
+    }
+
+    /// Faststart MP4 of `tiny_writer`
+    pub(crate) fn tiny_mp4(frames: u8) -> Vec<u8> {
+        tiny_writer(frames)
+            .finish_with_faststart()
+            .unwrap()
+            .into_inner()
+    }
+
+    /// Faststart MP4 with a real 1280x720 SPS: `frames` video frames of 3000 ticks with a
+    /// keyframe every `gop`, each followed by an AAC frame of 1920 samples. Frame `i` is filled
+    /// with `first_byte + i` after its NAL header.
+    pub(crate) fn sample_mp4(
+        frames: u8,
+        gop: u8,
+        audio_config: AacConfig,
+        first_byte: u8,
+    ) -> Vec<u8> {
+        let sps = [
+            0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00,
+            0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+        ];
+        let pps = [0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+        let mut writer = StreamingMp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        let video = writer.add_video_track(1280, 720, &sps, &pps);
+        let audio = writer.add_audio_track(audio_config);
+        for i in 0..frames {
+            let keyframe = i % gop == 0;
+            let byte = first_byte + i;
+            let mut frame = vec![0, 0, 0, 20, if keyframe { 0x65 } else { 0x41 }];
+            frame.resize(24, byte);
+            writer
+                .write_sample(video, &frame, 3000, 0, keyframe)
+                .unwrap();
+            writer
+                .write_sample(audio, &[0x21, byte, 0x40], 1920, 0, true)
+                .unwrap();
+        }
+        writer.finish_with_faststart().unwrap().into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{tiny_mp4, tiny_video_track, write_tiny_frames};
+    use super::*;
+    use crate::mp4_parser::{
+        find_box_path, parse_co64, parse_container_box, parse_mp4, parse_stco,
+    };
+    use std::io::Cursor;
+
+    #[test]
+    fn test_streaming_writer_faststart() {
+        let data = tiny_mp4(2);
+        let mp4 = parse_mp4(&data).unwrap();
+
+        // ftyp, moov, mdat
+        let moov = mp4.moov.unwrap();
+        let mdat = mp4.mdat.unwrap();
+        assert!(moov.offset < mdat.offset);
+
+        // The first chunk offset points at the first sample
+        let stco = find_box_path(&moov.data, &[b"trak", b"mdia", b"minf", b"stbl", b"stco"])
+            .unwrap()
+            .unwrap();
+        let offsets = parse_stco(&stco.data).unwrap();
+        assert_eq!(offsets, vec![mdat.data_offset as u64]);
+        assert_eq!(data[offsets[0] as usize + 4], 0x65);
+    }
+
+    #[test]
+    fn test_box_header_at_u32_boundary() {
+        // The largest box with a 32-bit size
+        let fits = u32::MAX as u64 - 8;
+        assert_eq!(
+            box_header(b"mdat", fits),
+            [0xFF, 0xFF, 0xFF, 0xFF, b'm', b'd', b'a', b't']
+        );
+
+        // One more byte needs size 1 and a largesize that counts the 16-byte header
+        let header = box_header(b"mdat", fits + 1);
+        assert_eq!(&header[..8], &[0, 0, 0, 1, b'm', b'd', b'a', b't']);
+        assert_eq!(&header[8..], &(fits + 1 + 16).to_be_bytes());
+        assert_eq!(fits + 1 + 16, 0x1_0000_0008);
+    }
+
+    #[test]
+    fn test_mov_output() {
+        let brands: FileBrands = "qt,isom".parse().unwrap();
+        assert_eq!(brands.major_brand, *b"qt  ");
+        assert_eq!(brands.compatible_brands, vec![*b"qt  ", *b"isom"]);
+        assert!("mp4,toolong".parse::<FileBrands>().is_err());
+
+        let sink = Cursor::new(Vec::new());
+        let mut writer = StreamingMp4Writer::with_format(sink, OutputFormat::Mov, &brands).unwrap();
+        let video = tiny_video_track(&mut writer);
+        write_tiny_frames(&mut writer, video, 1, 1);
+        let data = writer.finish().unwrap().into_inner();
+
+        // ftyp, wide, mdat, moov
+        let mp4 = parse_mp4(&data).unwrap();
+        assert_eq!(&mp4.ftyp.unwrap().data[..4], b"qt  ");
+        assert_eq!(&data[24..32], &[0, 0, 0, 8, b'w', b'i', b'd', b'e']);
+
+        let moov = mp4.moov.unwrap();
+        let hdlr = find_box_path(&moov.data, &[b"trak", b"mdia", b"hdlr"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(&hdlr.data[4..12], b"mhlrvide");
+        let data_hdlr = find_box_path(&moov.data, &[b"trak", b"mdia", b"minf", b"hdlr"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data_hdlr.data[4..12], b"dhlralis");
+        let dref = find_box_path(&moov.data, &[b"trak", b"mdia", b"minf", b"dinf", b"dref"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(&dref.data[12..16], b"alis");
+    }
+
+    #[test]
+    fn test_video_sample_entry_vui_boxes() {
+        // 720x576 baseline, SAR 16:11, BT.601 PAL colour
+        let sd_sps = [
+            0x67, 0x42, 0x00, 0x1E, 0xDA, 0x02, 0xD0, 0x49, 0xBF, 0xF0, 0x01, 0x00, 0x00, 0xB6,
+            0xA0, 0xA0, 0xC0, 0xA0, 0x40,
+        ];
+        let info = parse_sps(&sd_sps).unwrap();
+        assert_eq!((info.width, info.height, info.cropped), (720, 576, false));
+        assert_eq!(info.sample_aspect_ratio, Some((16, 11)));
+        assert_eq!(
+            info.colour.map(|c| (c.primaries, c.transfer, c.matrix)),
+            Some((5, 6, 5))
+        );
+
+        // 1920x1088 high profile cropped to 1080, square pixels, BT.709
+        let hd_sps = [
+            0x67, 0x64, 0x00, 0x28, 0xAC, 0xB4, 0x03, 0xC0, 0x11, 0x3F, 0x2E, 0x02, 0xD4, 0x04,
+            0x04, 0x04, 0x08,
+        ];
+        let mut writer = StreamingMp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        let video = writer.add_video_track(1920, 1080, &hd_sps, &[0x68]);
+        for i in 0..50 {
+            writer
+                .write_sample(video, &vec![0u8; 1000], 3600, 0, i == 0)
+                .unwrap();
+        }
+        let data = writer.finish().unwrap().into_inner();
+        let moov = parse_mp4(&data).unwrap().moov.unwrap();
+        let stsd = find_box_path(&moov.data, &[b"trak", b"mdia", b"minf", b"stbl", b"stsd"])
+            .unwrap()
+            .unwrap();
+
+        // avc1 children follow the 78-byte visual sample entry
+        let avc1 = &stsd.data[16..];
+        let children = parse_container_box(&avc1[78..]).unwrap();
+        let types: Vec<&[u8; 4]> = children.iter().map(|b| &b.box_type).collect();
+        assert_eq!(types, [b"avcC", b"btrt", b"clap", b"pasp", b"colr"]);
+
+        // 50 x 1000 bytes over 2 s, at most 25 samples per second
+        assert_eq!(
+            children[1].data,
+            [1000u32, 200_000, 200_000].map(u32::to_be_bytes).concat()
+        );
+        assert_eq!(&children[2].data[..8], &[0, 0, 7, 0x80, 0, 0, 0, 1]);
+        assert_eq!(children[3].data, [0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(children[4].data, *b"nclx\0\x01\0\x01\0\x01\0");
+    }
+
+    #[test]
+    fn test_large_offsets_and_durations() {
+        let mut track = TrackState::new(
+            1,
+            TrackMedia::Audio {
+                config: AacConfig::default(),
+            },
+        );
+        track.add_sample(16, u32::MAX, 0, true);
+        track.add_sample(16, u32::MAX, 0, true);
+        track.chunk_offsets.push(u32::MAX as u64 + 1);
+        track.samples_per_chunk.push(2);
+
+        let trak = build_trak(&track, 0, OutputFormat::Mp4);
+        let tkhd = find_box_path(&trak[8..], &[b"tkhd"]).unwrap().unwrap();
+        let mdhd = find_box_path(&trak[8..], &[b"mdia", b"mdhd"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(tkhd.data[0], 1);
+        assert_eq!(mdhd.data[0], 1);
+
+        let co64 = find_box_path(&trak[8..], &[b"mdia", b"minf", b"stbl", b"co64"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(parse_co64(&co64.data).unwrap(), vec![u32::MAX as u64 + 1]);
+    }
+
+    #[test]
+    fn test_start_offset_empty_edit() {
+        let mut track = TrackState::new(
+            1,
+            TrackMedia::Audio {
+                config: AacConfig::default(),
+            },
+        );
+        track.add_sample(16, 1024, 0, true);
+        track.start_offset = 126000;
+
+        let trak = build_trak(&track, 0, OutputFormat::Mp4);
+        let elst = find_box_path(&trak[8..], &[b"edts", b"elst"])
+            .unwrap()
+            .unwrap();
+        // entry_count = 2: empty edit for the offset, then the media from time 0
+        assert_eq!(&elst.data[4..8], &2u32.to_be_bytes());
+        assert_eq!(&elst.data[8..12], &126000u32.to_be_bytes());
+        assert_eq!(&elst.data[12..16], &(-1i32).to_be_bytes());
+        assert_eq!(&elst.data[20..24], &1920u32.to_be_bytes());
+        assert_eq!(&elst.data[24..28], &0i32.to_be_bytes());
+        assert_eq!(track.presentation_duration(), 126000 + 1920);
+    }
+
+    #[test]
+    fn test_native_audio_timescale() {
+        // 44.1kHz mono: index 4, channel configuration 1
+        let config = AacConfig {
+            object_type: 2,
+            sample_rate_index: 4,
+            channels: 1,
+        };
+        let mut track = TrackState::new(1, TrackMedia::Audio { config });
+        for _ in 0..441 {
+            track.add_sample(16, 1024, 0, true);
         }
+
+        let trak = build_trak(&track, 0, OutputFormat::Mp4);
+        let mdhd = find_box_path(&trak[8..], &[b"mdia", b"mdhd"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(&mdhd.data[12..16], &44100u32.to_be_bytes());
+        assert_eq!(&mdhd.data[16..20], &(441u32 * 1024).to_be_bytes());
+
+        // tkhd is in the 90kHz movie timescale: 441 * 1024 samples = 10.24s
+        let tkhd = find_box_path(&trak[8..], &[b"tkhd"]).unwrap().unwrap();
+        assert_eq!(&tkhd.data[20..24], &921600u32.to_be_bytes());
+
+        let stsd = find_box_path(&trak[8..], &[b"mdia", b"minf", b"stbl", b"stsd"])
+            .unwrap()
+            .unwrap();
+        // mp4a entry: channel count and 16.16 sample rate
+        assert_eq!(&stsd.data[32..34], &1u16.to_be_bytes());
+        assert_eq!(&stsd.data[40..44], &(44100u32 << 16).to_be_bytes());
     }
 
-    offsets
+    #[test]
+    fn test_audio_planner_repairs() {
+        let frames: Vec<Vec<u8>> = (0..5).map(|i| vec![i]).collect();
+        let timestamps = vec![
+            Some(0),
+            Some(1920),
+            Some(1920 * 4 + 1875), // two frames missing, plus 1000 samples of drift
+            Some(1920 * 4 + 1875), // duplicate of the previous frame
+            Some(11287),           // 100 samples early
+        ];
+
+        let mut planner = AudioPlanner::new(AacConfig::default());
+        let mut samples = VecDeque::new();
+        for (frame, pts) in frames.into_iter().zip(timestamps) {
+            planner.push(frame, pts, &mut samples);
+        }
+        planner.finish(&mut samples);
+        let durations: Vec<u32> = samples.iter().map(|s| s.1).collect();
+        assert_eq!(durations, vec![1024, 2024, 1024, 1024, 924, 1024]);
+        assert_eq!(samples[2].0, SILENT_AAC_FRAME_STEREO);
+        assert_eq!(
+            planner.report,
+            AudioRepairReport {
+                silent_frames: 2,
+                dropped_frames: 1,
+                adjusted_durations: 2,
+                discontinuities: 0,
+                late_frames: 0,
+            }
+        );
+    }
 }
@@ -0,0 +1,461 @@
+use crate::fmp4_writer::{FragmentOptions, FragmentedMp4Writer};
+use crate::metadata::Mp4Tags;
+use crate::mp4_writer::{
+    rescale, FileBrands, OutputFormat, StreamingMp4Writer, TrackState, TIMESCALE,
+};
+use crate::ts_parser::AacConfig;
+use std::io::{self, ErrorKind, Read, Seek, Write};
+
+/// Codec of a track added to `Mp4Writer`
+#[derive(Debug, Clone)]
+pub enum TrackCodec {
+    /// H.264 with its SPS and PPS (without start codes); samples are AVCC access units
+    H264 {
+        width: u16,
+        height: u16,
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+    },
+    /// AAC; samples are raw frames without ADTS headers
+    Aac(AacConfig),
+}
+
+/// Configuration of one `Mp4Writer` track
+#[derive(Debug, Clone)]
+pub struct TrackConfig {
+    pub codec: TrackCodec,
+    /// Units per second of the sample timestamps and durations
+    pub timescale: u32,
+    /// ISO 639-2/T language code (three lowercase letters)
+    pub language: String,
+}
+
+impl TrackConfig {
+    /// H.264 track in the 90kHz timescale
+    pub fn h264(width: u16, height: u16, sps: &[u8], pps: &[u8]) -> Self {
+        TrackConfig {
+            codec: TrackCodec::H264 {
+                width,
+                height,
+                sps: sps.to_vec(),
+                pps: pps.to_vec(),
+            },
+            timescale: TIMESCALE,
+            language: "und".to_string(),
+        }
+    }
+
+    /// AAC track in the timescale of its sample rate
+    pub fn aac(config: AacConfig) -> Self {
+        TrackConfig {
+            codec: TrackCodec::Aac(config),
+            timescale: config.sample_rate(),
+            language: "und".to_string(),
+        }
+    }
+
+    pub fn with_timescale(mut self, timescale: u32) -> Self {
+        self.timescale = timescale;
+        self
+    }
+
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = language.to_string();
+        self
+    }
+}
+
+/// One access unit pushed to `Mp4Writer`. Times are in the track timescale.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample<'a> {
+    pub data: &'a [u8],
+    /// Decode timestamp
+    pub dts: u64,
+    /// Composition (presentation) timestamp
+    pub cts: u64,
+    /// Duration, used for the last sample of the track; earlier samples last until the
+    /// DTS of the next one
+    pub duration: u32,
+    /// Random access point (IDR frame)
+    pub is_sync: bool,
+}
+
+/// Options for `Mp4Writer`
+#[derive(Debug, Clone)]
+pub struct Mp4WriterOptions {
+    /// Move the moov box in front of mdat when the file is finished (regular output)
+    pub faststart: bool,
+    /// Write a fragmented MP4 instead of moov + mdat
+    pub fragment: Option<FragmentOptions>,
+    /// Metadata written to moov/udta
+    pub tags: Mp4Tags,
+    /// MP4 or QuickTime MOV (regular output only)
+    pub format: OutputFormat,
+    /// ftyp brands, `None` for the defaults of `format` (or of fragmented output)
+    pub brands: Option<FileBrands>,
+}
+
+impl Default for Mp4WriterOptions {
+    fn default() -> Self {
+        Mp4WriterOptions {
+            faststart: true,
+            fragment: None,
+            tags: Mp4Tags::default(),
+            format: OutputFormat::Mp4,
+            brands: None,
+        }
+    }
+}
+
+enum Output<W: Write + Seek> {
+    Regular(StreamingMp4Writer<W>),
+    Fragmented(FragmentedMp4Writer<W>),
+}
+
+/// A sample held back until the DTS of the next sample of its track gives its duration
+struct HeldSample {
+    data: Vec<u8>,
+    dts: u64,
+    composition_offset: i32,
+    duration: u32,
+    is_sync: bool,
+}
+
+struct MuxTrack {
+    timescale: u32,
+    first_dts: Option<u64>,
+    first_presentation: Option<u64>, // smallest CTS
+    held: Option<HeldSample>,
+}
+
+/// MP4 muxer for H.264 and AAC samples from any source.
+///
+/// Tracks are described with `TrackConfig` and samples are pushed in decode order with their
+/// timestamps. Regular output places each track on the timeline with an edit list relative to
+/// the track that starts first; fragmented output carries the first DTS in `tfdt`.
+pub struct Mp4Writer<W: Write + Seek> {
+    output: Output<W>,
+    options: Mp4WriterOptions,
+    tracks: Vec<MuxTrack>,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// Starts the file in `sink`. Regular output writes ftyp and the mdat header right away.
+    pub fn new(sink: W, options: Mp4WriterOptions) -> io::Result<Self> {
+        let output = match &options.fragment {
+            Some(_) if options.format == OutputFormat::Mov => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "MOV output cannot be fragmented",
+                ));
+            }
+            Some(fragment) => {
+                let mut writer = FragmentedMp4Writer::new(sink, fragment.clone());
+                if let Some(brands) = &options.brands {
+                    writer.set_brands(brands.clone());
+                }
+                writer.set_tags(options.tags.clone());
+                Output::Fragmented(writer)
+            }
+            None => {
+                let brands = options
+                    .brands
+                    .clone()
+                    .unwrap_or_else(|| options.format.default_brands());
+                let mut writer = StreamingMp4Writer::with_format(sink, options.format, &brands)?;
+                writer.set_tags(options.tags.clone());
+                Output::Regular(writer)
+            }
+        };
+
+        Ok(Mp4Writer {
+            output,
+            options,
+            tracks: Vec::new(),
+        })
+    }
+
+    /// Adds a track and returns its index.
+    /// In fragmented output all tracks must be added before the first sample is written.
+    pub fn add_track(&mut self, config: TrackConfig) -> io::Result<usize> {
+        if config.timescale == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Track timescale must not be 0",
+            ));
+        }
+        let language: [u8; 3] = config
+            .language
+            .as_bytes()
+            .try_into()
+            .ok()
+            .filter(|code: &[u8; 3]| code.iter().all(u8::is_ascii_lowercase))
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid ISO 639-2 language code '{}'", config.language),
+                )
+            })?;
+
+        let track = match (&mut self.output, &config.codec) {
+            (
+                Output::Regular(writer),
+                TrackCodec::H264 {
+                    width,
+                    height,
+                    sps,
+                    pps,
+                },
+            ) => writer.add_video_track(*width, *height, sps, pps),
+            (Output::Regular(writer), TrackCodec::Aac(aac)) => writer.add_audio_track(*aac),
+            (
+                Output::Fragmented(writer),
+                TrackCodec::H264 {
+                    width,
+                    height,
+                    sps,
+                    pps,
+                },
+            ) => writer.add_video_track(*width, *height, sps, pps),
+            (Output::Fragmented(writer), TrackCodec::Aac(aac)) => writer.add_audio_track(*aac),
+        };
+
+        let state = self.track_state(track);
+        state.timescale = config.timescale;
+        state.language = language;
+
+        self.tracks.push(MuxTrack {
+            timescale: config.timescale,
+            first_dts: None,
+            first_presentation: None,
+            held: None,
+        });
+        Ok(track)
+    }
+
+    /// Pushes one sample of `track`. Samples of a track must have increasing DTS.
+    pub fn write_sample(&mut self, track: usize, sample: &Sample) -> io::Result<()> {
+        let Some(state) = self.tracks.get_mut(track) else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown track index {}", track),
+            ));
+        };
+
+        let composition_offset =
+            i32::try_from(sample.cts as i64 - sample.dts as i64).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Composition offset of track {} does not fit in 32 bits (dts {}, cts {})",
+                        track, sample.dts, sample.cts
+                    ),
+                )
+            })?;
+
+        if let Some(held) = &state.held {
+            if sample.dts <= held.dts || sample.dts - held.dts > u32::MAX as u64 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Track {} DTS must increase by at most 32 bits (got {} after {})",
+                        track, sample.dts, held.dts
+                    ),
+                ));
+            }
+        }
+
+        let previous = match state.held.take() {
+            Some(mut held) => {
+                held.duration = (sample.dts - held.dts) as u32;
+                Some(held)
+            }
+            None => {
+                state.first_dts = Some(sample.dts);
+                if let Output::Fragmented(writer) = &mut self.output {
+                    writer.set_base_decode_time(track, sample.dts);
+                }
+                None
+            }
+        };
+
+        state.first_presentation = Some(
+            state
+                .first_presentation
+                .map_or(sample.cts, |first| first.min(sample.cts)),
+        );
+        state.held = Some(HeldSample {
+            data: sample.data.to_vec(),
+            dts: sample.dts,
+            composition_offset,
+            duration: sample.duration,
+            is_sync: sample.is_sync,
+        });
+
+        match previous {
+            Some(held) => self.emit(track, held),
+            None => Ok(()),
+        }
+    }
+
+    fn emit(&mut self, track: usize, sample: HeldSample) -> io::Result<()> {
+        match &mut self.output {
+            Output::Regular(writer) => writer.write_sample(
+                track,
+                &sample.data,
+                sample.duration,
+                sample.composition_offset,
+                sample.is_sync,
+            ),
+            Output::Fragmented(writer) => writer.write_sample(
+                track,
+                &sample.data,
+                sample.duration,
+                sample.composition_offset,
+                sample.is_sync,
+            ),
+        }
+    }
+
+    fn track_state(&mut self, track: usize) -> &mut TrackState {
+        match &mut self.output {
+            Output::Regular(writer) => writer.track_mut(track),
+            Output::Fragmented(writer) => writer.track_mut(track),
+        }
+    }
+
+    /// Writes the held back samples and, in regular output, the edit lists that place every
+    /// track relative to the one that starts first
+    fn flush_tracks(&mut self) -> io::Result<()> {
+        for track in 0..self.tracks.len() {
+            if let Some(held) = self.tracks[track].held.take() {
+                self.emit(track, held)?;
+            }
+        }
+
+        if let Output::Regular(writer) = &mut self.output {
+            let timeline_start = self
+                .tracks
+                .iter()
+                .filter_map(|t| {
+                    t.first_presentation
+                        .map(|pts| rescale(pts, t.timescale, TIMESCALE))
+                })
+                .min()
+                .unwrap_or(0);
+
+            for (index, track) in self.tracks.iter().enumerate() {
+                if let (Some(first_dts), Some(first_pts)) =
+                    (track.first_dts, track.first_presentation)
+                {
+                    let delay = rescale(first_pts, track.timescale, TIMESCALE) - timeline_start;
+                    writer.set_edit(index, delay, first_pts.saturating_sub(first_dts));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Read + Write + Seek> Mp4Writer<W> {
+    /// Completes the file: moov (moved in front of mdat with `faststart`) for regular output,
+    /// the last fragment plus the requested sidx/mfra for fragmented output
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_tracks()?;
+
+        match self.output {
+            Output::Regular(writer) if self.options.faststart => writer.finish_with_faststart(),
+            Output::Regular(writer) => writer.finish(),
+            Output::Fragmented(writer) => {
+                if self.options.fragment.as_ref().is_some_and(|f| f.sidx) {
+                    writer.finish_with_sidx()
+                } else {
+                    writer.finish()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::{find_box_path, parse_elst, parse_mdhd, parse_mp4};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_mp4_writer_timestamps() {
+        let mut writer =
+            Mp4Writer::new(Cursor::new(Vec::new()), Mp4WriterOptions::default()).unwrap();
+        let video = writer
+            .add_track(TrackConfig::h264(64, 64, &[0x67, 0x42], &[0x68]).with_timescale(30))
+            .unwrap();
+        let audio = writer
+            .add_track(TrackConfig::aac(AacConfig::default()).with_language("eng"))
+            .unwrap();
+
+        // Video starts 0.5s after audio, with one frame of reordering delay
+        for (dts, is_sync) in [(15u64, true), (16, false), (17, false)] {
+            let sample = Sample {
+                data: &[0, 0, 0, 1, 0x65],
+                dts,
+                cts: dts + 1,
+                duration: 1,
+                is_sync,
+            };
+            writer.write_sample(video, &sample).unwrap();
+        }
+        for dts in [0u64, 1024, 4096] {
+            let sample = Sample {
+                data: &[0x21],
+                dts,
+                cts: dts,
+                duration: 1024,
+                is_sync: true,
+            };
+            writer.write_sample(audio, &sample).unwrap();
+        }
+        assert!(writer
+            .write_sample(
+                audio,
+                &Sample {
+                    data: &[0x21],
+                    dts: 4096,
+                    cts: 4096,
+                    duration: 1024,
+                    is_sync: true
+                }
+            )
+            .is_err());
+
+        let data = writer.finish().unwrap().into_inner();
+        let mp4 = parse_mp4(&data).unwrap();
+        let moov = mp4.moov.unwrap();
+
+        let traks: Vec<_> = crate::mp4_parser::parse_container_box(&moov.data)
+            .unwrap()
+            .into_iter()
+            .filter(|b| &b.box_type == b"trak")
+            .collect();
+        let elst = |trak: &[u8]| {
+            let elst = find_box_path(trak, &[b"edts", b"elst"]).unwrap().unwrap();
+            parse_elst(&elst.data)
+                .unwrap()
+                .iter()
+                .map(|e| (e.segment_duration, e.media_time))
+                .collect::<Vec<_>>()
+        };
+        // Video: 16/30s after the audio start, presented from its first CTS
+        assert_eq!(elst(&traks[0].data), vec![(48000, -1), (6000, 1)]);
+        assert!(find_box_path(&traks[1].data, &[b"edts"]).unwrap().is_none());
+
+        // Audio durations come from the DTS deltas, the last one from the sample
+        let mdhd = find_box_path(&traks[1].data, &[b"mdia", b"mdhd"])
+            .unwrap()
+            .unwrap();
+        let info = parse_mdhd(&mdhd.data).unwrap();
+        assert_eq!((info.timescale, info.duration), (48000, 5120));
+        assert_eq!(&mdhd.data[20..22], &[0x15, 0xC7]); // "eng"
+    }
+}
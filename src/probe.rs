@@ -0,0 +1,843 @@
+use crate::box_tree::{parse_box_tree, BoxNode, FullBox};
+use crate::inspect::write_json_string;
+use crate::mp4_parser::{
+    extract_track_info, find_box_path, parse_container_box, parse_mp4, parse_mvhd,
+    read_track_samples, read_trak_transform, BoxInfo, MediaType,
+};
+use crate::ts_parser::{
+    extract_h264_params, extract_pes_timestamps, parse_adts_config, parse_pat_programs,
+    parse_pmt_streams, parse_sps, MediaData, PmtStream, SYNC_BYTE, TS_PACKET_SIZE,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// Size of an M2TS (Blu-ray) packet: a 4-byte arrival timestamp followed by a TS packet
+const M2TS_PACKET_SIZE: usize = 192;
+
+/// Stop buffering a video stream for its SPS after this many bytes
+const MAX_PARAMETER_SCAN: usize = 1 << 20;
+
+/// PTS values are 33 bits and wrap around
+const PTS_WRAP: u64 = 1 << 33;
+
+/// Container of a probed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerKind {
+    Ts,
+    M2ts,
+    Mp4,
+    FragmentedMp4,
+}
+
+impl ContainerKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ContainerKind::Ts => "mpegts",
+            ContainerKind::M2ts => "m2ts",
+            ContainerKind::Mp4 => "mp4",
+            ContainerKind::FragmentedMp4 => "fmp4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+}
+
+impl StreamKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            StreamKind::Video => "video",
+            StreamKind::Audio => "audio",
+            StreamKind::Subtitle => "subtitle",
+            StreamKind::Data => "data",
+        }
+    }
+}
+
+/// Program of a transport stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramInfo {
+    pub number: u16,
+    pub pmt_pid: u16,
+}
+
+/// One elementary stream (TS PID or MP4 track)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    /// PID for TS, track ID for MP4
+    pub id: u32,
+    /// Program number (TS only)
+    pub program: Option<u16>,
+    pub kind: StreamKind,
+    pub codec: String,
+    /// Displayed size: MP4 tracks rotated by 90 or 270 degrees have their coded size swapped
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Seconds
+    pub duration: Option<f64>,
+    /// Bits per second
+    pub bitrate: Option<u64>,
+    /// Samples for MP4, PES packets for TS
+    pub sample_count: u64,
+}
+
+/// What `probe` found in a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeReport {
+    pub container: ContainerKind,
+    pub size: u64,
+    /// Seconds
+    pub duration: Option<f64>,
+    /// Bits per second over the whole file
+    pub bitrate: Option<u64>,
+    pub programs: Vec<ProgramInfo>,
+    pub streams: Vec<StreamInfo>,
+}
+
+/// Reports the streams of a TS, M2TS, regular MP4 or fragmented MP4 file
+pub fn probe(data: &[u8]) -> io::Result<ProbeReport> {
+    let mut report = if data.len() >= 8
+        && matches!(&data[4..8], b"ftyp" | b"styp" | b"moov" | b"free" | b"wide")
+    {
+        probe_mp4(data)?
+    } else if let Some((container, start, stride)) = packet_layout(data) {
+        probe_ts(data, container, start, stride)
+    } else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Unknown file format. Expected TS, M2TS or MP4 file.",
+        ));
+    };
+
+    report.size = data.len() as u64;
+    if report.duration.is_none() {
+        report.duration = report
+            .streams
+            .iter()
+            .filter_map(|stream| stream.duration)
+            .reduce(f64::max);
+    }
+    report.bitrate = report
+        .duration
+        .filter(|&duration| duration > 0.0)
+        .map(|duration| (report.size as f64 * 8.0 / duration) as u64);
+    Ok(report)
+}
+
+/// Common name of a channel count
+fn channel_layout(channels: u8) -> String {
+    match channels {
+        1 => "mono".to_string(),
+        2 => "stereo".to_string(),
+        3 => "3.0".to_string(),
+        6 => "5.1".to_string(),
+        8 => "7.1".to_string(),
+        n => format!("{} channels", n),
+    }
+}
+
+/// Finds the packet size and first sync byte of a TS or M2TS file
+fn packet_layout(data: &[u8]) -> Option<(ContainerKind, usize, usize)> {
+    let synced = |start: usize, stride: usize| {
+        data.get(start) == Some(&SYNC_BYTE)
+            && (start + stride >= data.len() || data[start + stride] == SYNC_BYTE)
+    };
+
+    if data.len() >= M2TS_PACKET_SIZE && synced(4, M2TS_PACKET_SIZE) && !synced(0, TS_PACKET_SIZE) {
+        return Some((ContainerKind::M2ts, 4, M2TS_PACKET_SIZE));
+    }
+    let start = (0..data.len().min(TS_PACKET_SIZE)).find(|&i| synced(i, TS_PACKET_SIZE))?;
+    Some((ContainerKind::Ts, start, TS_PACKET_SIZE))
+}
+
+/// Per-PID counters collected while scanning a transport stream
+#[derive(Default)]
+struct PidStats {
+    payload_bytes: u64,
+    pes_count: u64,
+    first_pts: Option<u64>,
+    last_raw_pts: u64,
+    pts_base: u64,
+    min_pts: u64,
+    max_pts: u64,
+    head: Vec<u8>, // start of the elementary stream, for SPS / ADTS parameters
+    width: Option<u32>,
+    height: Option<u32>,
+    sample_rate: Option<u32>,
+    channels: Option<u8>,
+}
+
+impl PidStats {
+    fn add_pts(&mut self, raw: u64) {
+        // Unwrap the 33-bit PTS (a large backwards jump means it wrapped)
+        if self.first_pts.is_some() && raw + PTS_WRAP / 2 < self.last_raw_pts {
+            self.pts_base += PTS_WRAP;
+        }
+        self.last_raw_pts = raw;
+
+        let pts = self.pts_base + raw;
+        if self.first_pts.is_none() {
+            self.first_pts = Some(pts);
+            self.min_pts = pts;
+            self.max_pts = pts;
+        }
+        self.min_pts = self.min_pts.min(pts);
+        self.max_pts = self.max_pts.max(pts);
+    }
+}
+
+fn probe_ts(data: &[u8], container: ContainerKind, start: usize, stride: usize) -> ProbeReport {
+    let mut programs: Vec<ProgramInfo> = Vec::new();
+    let mut streams: BTreeMap<u16, (u16, PmtStream)> = BTreeMap::new();
+    let mut stats: BTreeMap<u16, PidStats> = BTreeMap::new();
+
+    let mut offset = start;
+    while offset + TS_PACKET_SIZE <= data.len() {
+        let packet = &data[offset..offset + TS_PACKET_SIZE];
+        if packet[0] != SYNC_BYTE {
+            // Resync on the next sync byte
+            offset += 1;
+            while offset < data.len() && data[offset] != SYNC_BYTE {
+                offset += 1;
+            }
+            continue;
+        }
+        offset += stride;
+
+        let pid = ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16;
+        let payload_start = (packet[1] & 0x40) != 0;
+        let mut payload_offset = 4;
+        if packet[3] & 0x20 != 0 {
+            payload_offset += 1 + packet[4] as usize;
+        }
+        if packet[3] & 0x10 == 0 || payload_offset >= TS_PACKET_SIZE {
+            continue;
+        }
+        let payload = &packet[payload_offset..];
+
+        if pid == 0 {
+            for (number, pmt_pid) in parse_pat_programs(payload, payload_start) {
+                if !programs.iter().any(|p| p.number == number) {
+                    programs.push(ProgramInfo { number, pmt_pid });
+                }
+            }
+            continue;
+        }
+        if let Some(program) = programs.iter().find(|p| p.pmt_pid == pid) {
+            for stream in parse_pmt_streams(payload, payload_start).unwrap_or_default() {
+                streams
+                    .entry(stream.pid)
+                    .or_insert((program.number, stream));
+            }
+            continue;
+        }
+        let Some((_, stream)) = streams.get(&pid) else {
+            continue;
+        };
+
+        let pid_stats = stats.entry(pid).or_default();
+        pid_stats.payload_bytes += payload.len() as u64;
+        let mut es_data = payload;
+        if payload_start {
+            pid_stats.pes_count += 1;
+            if let (Some(pts), _) = extract_pes_timestamps(payload) {
+                pid_stats.add_pts(pts);
+            }
+            let header_end = payload
+                .get(8)
+                .map_or(payload.len(), |&len| 9 + len as usize);
+            es_data = payload.get(header_end..).unwrap_or_default();
+        }
+        collect_parameters(pid_stats, stream.stream_type, es_data);
+    }
+
+    let streams = streams
+        .into_iter()
+        .map(|(pid, (program, stream))| {
+            let stats = stats.remove(&pid).unwrap_or_default();
+            let (kind, codec) = ts_stream_codec(&stream);
+            let span = stats.max_pts - stats.min_pts;
+
+            let frame_rate = (kind == StreamKind::Video && stats.pes_count > 1 && span > 0)
+                .then(|| (stats.pes_count - 1) as f64 * 90000.0 / span as f64);
+            // Each video PES is one frame, so add the last frame's duration
+            let duration = stats.first_pts.map(|_| match frame_rate {
+                Some(rate) => span as f64 / 90000.0 + 1.0 / rate,
+                None => span as f64 / 90000.0,
+            });
+
+            StreamInfo {
+                id: pid as u32,
+                program: Some(program),
+                kind,
+                codec: codec.to_string(),
+                width: stats.width,
+                height: stats.height,
+                frame_rate,
+                sample_rate: stats.sample_rate,
+                channels: stats.channels,
+                duration,
+                bitrate: bitrate(stats.payload_bytes, duration),
+                sample_count: stats.pes_count,
+            }
+        })
+        .collect();
+
+    ProbeReport {
+        container,
+        size: 0,
+        duration: None,
+        bitrate: None,
+        programs,
+        streams,
+    }
+}
+
+/// Reads resolution from the first H.264 SPS and the audio layout from the first ADTS header
+fn collect_parameters(stats: &mut PidStats, stream_type: u8, es_data: &[u8]) {
+    match stream_type {
+        0x1B if stats.width.is_none() && stats.head.len() < MAX_PARAMETER_SCAN => {
+            stats.head.extend_from_slice(es_data);
+            let mut params = MediaData::new();
+            extract_h264_params(&mut params, &stats.head);
+            // The SPS is complete once the PPS behind it has been seen
+            if let (Some(sps), Some(_)) = (&params.sps, &params.pps) {
+                if let Some(sps) = parse_sps(sps) {
+                    stats.width = Some(sps.width as u32);
+                    stats.height = Some(sps.height as u32);
+                }
+                stats.head = Vec::new();
+            }
+        }
+        0x0F if stats.sample_rate.is_none() && stats.head.len() < MAX_PARAMETER_SCAN => {
+            stats.head.extend_from_slice(es_data);
+            if let Some(config) = parse_adts_config(&stats.head) {
+                stats.sample_rate = Some(config.sample_rate());
+                stats.channels = Some(config.channels);
+                stats.head = Vec::new();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Stream kind and codec name of a PMT entry
+fn ts_stream_codec(stream: &PmtStream) -> (StreamKind, &'static str) {
+    match stream.stream_type {
+        0x01 => (StreamKind::Video, "mpeg1video"),
+        0x02 => (StreamKind::Video, "mpeg2video"),
+        0x10 => (StreamKind::Video, "mpeg4"),
+        0x1B => (StreamKind::Video, "h264"),
+        0x24 => (StreamKind::Video, "hevc"),
+        0x03 => (StreamKind::Audio, "mp3"),
+        0x04 => (StreamKind::Audio, "mp2"),
+        0x0F => (StreamKind::Audio, "aac"),
+        0x11 => (StreamKind::Audio, "aac_latm"),
+        0x81 => (StreamKind::Audio, "ac3"),
+        0x87 => (StreamKind::Audio, "eac3"),
+        0x15 => (StreamKind::Data, "timed_id3"),
+        0x86 => (StreamKind::Data, "scte_35"),
+        // PES private data is identified by its descriptors
+        0x06 => {
+            let mut offset = 0;
+            while offset + 2 <= stream.descriptors.len() {
+                match stream.descriptors[offset] {
+                    0x6A => return (StreamKind::Audio, "ac3"),
+                    0x7A => return (StreamKind::Audio, "eac3"),
+                    0x59 => return (StreamKind::Subtitle, "dvb_subtitle"),
+                    0x56 => return (StreamKind::Subtitle, "dvb_teletext"),
+                    _ => offset += 2 + stream.descriptors[offset + 1] as usize,
+                }
+            }
+            (StreamKind::Data, "private")
+        }
+        _ => (StreamKind::Data, "unknown"),
+    }
+}
+
+fn probe_mp4(data: &[u8]) -> io::Result<ProbeReport> {
+    let mp4 = parse_mp4(data)?;
+    let moov = mp4
+        .moov
+        .as_ref()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+    let moov_boxes = parse_container_box(&moov.data)?;
+
+    let fragments: Vec<&[u8]> = mp4
+        .all_boxes_in_order
+        .iter()
+        .filter_map(|b| match b {
+            BoxInfo::Small(b) if &b.box_type == b"moof" => Some(&b.data[..]),
+            _ => None,
+        })
+        .collect();
+    let fragmented = !fragments.is_empty() || moov_boxes.iter().any(|b| &b.box_type == b"mvex");
+
+    // (sample_count, total size, total duration) per track from the trun boxes
+    let mut fragment_totals: BTreeMap<u32, (u64, u64, u64)> = BTreeMap::new();
+    if fragmented {
+        let defaults = match moov_boxes.iter().find(|b| &b.box_type == b"mvex") {
+            Some(mvex) => parse_box_tree(&mvex.data)?,
+            None => Vec::new(),
+        };
+        for moof in fragments {
+            for traf in parse_box_tree(moof)?
+                .iter()
+                .filter(|n| &n.box_type == b"traf")
+            {
+                add_fragment_totals(traf, &defaults, &mut fragment_totals);
+            }
+        }
+    }
+
+    let movie_duration = match moov_boxes.iter().find(|b| &b.box_type == b"mvhd") {
+        Some(mvhd) => {
+            let info = parse_mvhd(&mvhd.data)?;
+            (info.timescale > 0 && info.duration > 0)
+                .then(|| info.duration as f64 / info.timescale as f64)
+        }
+        None => None,
+    };
+
+    let mut streams = Vec::new();
+    for trak in moov_boxes.iter().filter(|b| &b.box_type == b"trak") {
+        let info = extract_track_info(&trak.data)?;
+        let (sample_count, bytes, media_duration) = if fragmented {
+            fragment_totals
+                .get(&info.track_id)
+                .copied()
+                .unwrap_or_default()
+        } else {
+            read_track_samples(&trak.data)?.samples().fold(
+                (0, 0, 0),
+                |(count, bytes, duration), sample| {
+                    (
+                        count + 1,
+                        bytes + sample.size as u64,
+                        duration + sample.duration as u64,
+                    )
+                },
+            )
+        };
+
+        let duration = (info.timescale > 0 && sample_count > 0)
+            .then(|| media_duration as f64 / info.timescale as f64);
+        let mut entry = sample_entry(&trak.data)?;
+        // Sample entries keep the coded size; a 90 or 270 degree tkhd rotation swaps it on screen
+        if let (Some(width), Some(height)) = (entry.width, entry.height) {
+            if let Some(transform) = read_trak_transform(&trak.data)? {
+                let (width, height) = transform.display_size(width as u16, height as u16);
+                entry.width = Some(width as u32);
+                entry.height = Some(height as u32);
+            }
+        }
+        let kind = match info.media_type {
+            MediaType::Video => StreamKind::Video,
+            MediaType::Audio => StreamKind::Audio,
+            MediaType::Unknown if entry.subtitle => StreamKind::Subtitle,
+            MediaType::Unknown => StreamKind::Data,
+        };
+
+        streams.push(StreamInfo {
+            id: info.track_id,
+            program: None,
+            kind,
+            codec: entry.codec,
+            width: entry.width,
+            height: entry.height,
+            frame_rate: duration
+                .filter(|&d| kind == StreamKind::Video && d > 0.0)
+                .map(|d| sample_count as f64 / d),
+            sample_rate: entry.sample_rate,
+            channels: entry.channels,
+            duration,
+            bitrate: bitrate(bytes, duration),
+            sample_count,
+        });
+    }
+
+    Ok(ProbeReport {
+        container: if fragmented {
+            ContainerKind::FragmentedMp4
+        } else {
+            ContainerKind::Mp4
+        },
+        size: 0,
+        duration: movie_duration,
+        bitrate: None,
+        programs: Vec::new(),
+        streams,
+    })
+}
+
+/// Adds the samples of one traf, falling back to the tfhd and trex defaults
+fn add_fragment_totals(
+    traf: &BoxNode,
+    mvex: &[BoxNode],
+    totals: &mut BTreeMap<u32, (u64, u64, u64)>,
+) {
+    let Some(FullBox::Tfhd(tfhd)) = traf.find(&[b"tfhd"]).and_then(BoxNode::full_box) else {
+        return;
+    };
+    let trex = mvex.iter().find_map(|node| match node.full_box() {
+        Some(FullBox::Trex(trex)) if trex.track_id == tfhd.track_id => Some(trex),
+        _ => None,
+    });
+    let default_duration = tfhd
+        .default_sample_duration
+        .or(trex.map(|t| t.default_sample_duration))
+        .unwrap_or(0);
+    let default_size = tfhd
+        .default_sample_size
+        .or(trex.map(|t| t.default_sample_size))
+        .unwrap_or(0);
+
+    let total = totals.entry(tfhd.track_id).or_default();
+    for node in traf.children() {
+        if let Some(FullBox::Trun(trun)) = node.full_box() {
+            for sample in &trun.samples {
+                total.0 += 1;
+                total.1 += sample.size.unwrap_or(default_size) as u64;
+                total.2 += sample.duration.unwrap_or(default_duration) as u64;
+            }
+        }
+    }
+}
+
+/// Fields of the first stsd sample entry
+#[derive(Default)]
+struct SampleEntry {
+    codec: String,
+    subtitle: bool,
+    width: Option<u32>,
+    height: Option<u32>,
+    sample_rate: Option<u32>,
+    channels: Option<u8>,
+}
+
+fn sample_entry(trak_data: &[u8]) -> io::Result<SampleEntry> {
+    let Some(stsd) = find_box_path(trak_data, &[b"mdia", b"minf", b"stbl", b"stsd"])? else {
+        return Ok(SampleEntry {
+            codec: "unknown".to_string(),
+            ..Default::default()
+        });
+    };
+    // version/flags, entry_count, then the entry's size and format
+    let Some(format) = stsd.data.get(12..16) else {
+        return Ok(SampleEntry {
+            codec: "unknown".to_string(),
+            ..Default::default()
+        });
+    };
+    let entry = &stsd.data[16..];
+    let read_u16 = |at: usize| {
+        entry
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    let mut result = SampleEntry {
+        codec: match format {
+            b"avc1" | b"avc3" => "h264".to_string(),
+            b"hvc1" | b"hev1" => "hevc".to_string(),
+            b"mp4a" => "aac".to_string(),
+            b"ac-3" => "ac3".to_string(),
+            b"ec-3" => "eac3".to_string(),
+            b"Opus" => "opus".to_string(),
+            b"text" | b"tx3g" => "mov_text".to_string(),
+            b"wvtt" => "webvtt".to_string(),
+            other => String::from_utf8_lossy(other).trim().to_string(),
+        },
+        subtitle: matches!(format, b"text" | b"tx3g" | b"wvtt" | b"stpp"),
+        ..Default::default()
+    };
+    match format {
+        b"avc1" | b"avc3" | b"hvc1" | b"hev1" | b"mp4v" => {
+            result.width = read_u16(24).map(u32::from);
+            result.height = read_u16(26).map(u32::from);
+        }
+        b"mp4a" | b"ac-3" | b"ec-3" | b"Opus" => {
+            result.channels = read_u16(16).map(|c| c as u8);
+            result.sample_rate = read_u16(24).map(u32::from);
+        }
+        _ => {}
+    }
+    Ok(result)
+}
+
+fn json_string(value: &str) -> String {
+    let mut output = String::new();
+    write_json_string(&mut output, value);
+    output
+}
+
+fn bitrate(bytes: u64, duration: Option<f64>) -> Option<u64> {
+    duration
+        .filter(|&duration| duration > 0.0)
+        .map(|duration| (bytes as f64 * 8.0 / duration) as u64)
+}
+
+/// Rounds to milliseconds (or thousandths of a frame) for printing
+fn round3(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+fn format_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind.name(), self.codec)?;
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            write!(f, ", {}x{}", width, height)?;
+        }
+        if let Some(rate) = self.frame_rate {
+            write!(f, ", {} fps", round3(rate))?;
+        }
+        if let Some(rate) = self.sample_rate {
+            write!(f, ", {} Hz", rate)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, ", {}", channel_layout(channels))?;
+        }
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", {} kb/s", bitrate / 1000)?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, ", {}", format_time(duration))?;
+        }
+        write!(f, ", {} samples", self.sample_count)
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Container: {}", self.container.name())?;
+        writeln!(f, "Size: {} bytes", self.size)?;
+        if let Some(duration) = self.duration {
+            writeln!(f, "Duration: {}", format_time(duration))?;
+        }
+        if let Some(bitrate) = self.bitrate {
+            writeln!(f, "Bitrate: {} kb/s", bitrate / 1000)?;
+        }
+
+        let id_label = if self.programs.is_empty() {
+            "track"
+        } else {
+            "PID"
+        };
+        let mut programs: Vec<Option<&ProgramInfo>> = self.programs.iter().map(Some).collect();
+        if programs.is_empty() {
+            programs.push(None);
+        }
+        for program in programs {
+            let indent = if let Some(program) = program {
+                writeln!(
+                    f,
+                    "Program {} (PMT PID {})",
+                    program.number, program.pmt_pid
+                )?;
+                "  "
+            } else {
+                ""
+            };
+            let streams = self
+                .streams
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.program == program.map(|p| p.number));
+            for (index, stream) in streams {
+                writeln!(
+                    f,
+                    "{}Stream #{} ({} {}): {}",
+                    indent, index, id_label, stream.id, stream
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProbeReport {
+    /// The report as a JSON object, one line per stream
+    pub fn to_json(&self) -> String {
+        fn number<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "null".to_string(), |v| v.to_string())
+        }
+
+        let programs: Vec<String> = self
+            .programs
+            .iter()
+            .map(|p| format!("{{\"number\": {}, \"pmt_pid\": {}}}", p.number, p.pmt_pid))
+            .collect();
+        let streams: Vec<String> = self
+            .streams
+            .iter()
+            .enumerate()
+            .map(|(index, s)| {
+                format!(
+                    "{{\"index\": {}, \"id\": {}, \"program\": {}, \"kind\": \"{}\", \"codec\": {}, \
+                     \"width\": {}, \"height\": {}, \"frame_rate\": {}, \"sample_rate\": {}, \
+                     \"channels\": {}, \"duration\": {}, \"bitrate\": {}, \"sample_count\": {}}}",
+                    index,
+                    s.id,
+                    number(s.program),
+                    s.kind.name(),
+                    json_string(&s.codec),
+                    number(s.width),
+                    number(s.height),
+                    number(s.frame_rate.map(round3)),
+                    number(s.sample_rate),
+                    number(s.channels),
+                    number(s.duration.map(round3)),
+                    number(s.bitrate),
+                    s.sample_count
+                )
+            })
+            .collect();
+
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "[]".to_string()
+            } else {
+                format!("[\n    {}\n  ]", items.join(",\n    "))
+            }
+        };
+        format!(
+            "{{\n  \"container\": \"{}\",\n  \"size\": {},\n  \"duration\": {},\n  \"bitrate\": {},\n  \"programs\": {},\n  \"streams\": {}\n}}\n",
+            self.container.name(),
+            self.size,
+            number(self.duration.map(round3)),
+            number(self.bitrate),
+            list(&programs),
+            list(&streams)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts_packet(pid: u16, payload_start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            SYNC_BYTE,
+            ((payload_start as u8) << 6) | (pid >> 8) as u8,
+            pid as u8,
+            0x10,
+        ];
+        packet.extend_from_slice(payload);
+        packet.resize(TS_PACKET_SIZE, 0xFF);
+        packet
+    }
+
+    fn pes(stream_id: u8, pts: u64, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0x00, 0x00, 0x01, stream_id, 0x00, 0x00, 0x80, 0x80, 0x05];
+        pes.extend_from_slice(&[
+            0x21 | ((pts >> 29) as u8 & 0x0E),
+            (pts >> 22) as u8,
+            ((pts >> 14) as u8 & 0xFE) | 1,
+            (pts >> 7) as u8,
+            ((pts << 1) as u8 & 0xFE) | 1,
+        ]);
+        pes.extend_from_slice(data);
+        pes
+    }
+
+    #[test]
+    fn test_probe_ts_and_m2ts() {
+        let pat = [
+            0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xF0, 0x00, 0, 0, 0,
+            0,
+        ];
+        let pmt = [
+            0x00, 0x02, 0xB0, 0x17, 0x00, 0x01, 0xC1, 0x00, 0x00, 0xE1, 0x00, 0xF0, 0x00, 0x1B,
+            0xE1, 0x00, 0xF0, 0x00, 0x0F, 0xE1, 0x01, 0xF0, 0x00, 0, 0, 0, 0,
+        ];
+        let sps = [
+            0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00,
+            0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60,
+        ];
+        let mut keyframe = vec![0, 0, 0, 1];
+        keyframe.extend_from_slice(&sps);
+        keyframe.extend_from_slice(&[0, 0, 0, 1, 0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0]);
+        keyframe.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88]);
+        let adts = [0xFF, 0xF1, 0x4C, 0x80, 0x01, 0xFF, 0xFC];
+
+        let mut ts = ts_packet(0, true, &pat);
+        ts.extend(ts_packet(0x1000, true, &pmt));
+        ts.extend(ts_packet(0x100, true, &pes(0xE0, 90000, &keyframe)));
+        ts.extend(ts_packet(0x101, true, &pes(0xC0, 90000, &adts)));
+        ts.extend(ts_packet(0x100, true, &pes(0xE0, 93000, &[0, 0, 1, 0x41])));
+        ts.extend(ts_packet(0x101, true, &pes(0xC0, 91920, &adts)));
+        ts.extend(ts_packet(0x100, true, &pes(0xE0, 96000, &[0, 0, 1, 0x41])));
+
+        let report = probe(&ts).unwrap();
+        assert_eq!(report.container, ContainerKind::Ts);
+        assert_eq!(
+            report.programs,
+            [ProgramInfo {
+                number: 1,
+                pmt_pid: 0x1000
+            }]
+        );
+        let video = &report.streams[0];
+        assert_eq!(
+            (video.id, video.kind, &video.codec[..]),
+            (0x100, StreamKind::Video, "h264")
+        );
+        assert_eq!((video.width, video.height), (Some(1280), Some(720)));
+        assert_eq!(video.frame_rate, Some(30.0));
+        assert_eq!(video.sample_count, 3);
+        let audio = &report.streams[1];
+        assert_eq!((audio.sample_rate, audio.channels), (Some(48000), Some(2)));
+        assert_eq!(report.duration.map(round3), Some(0.1));
+        assert!(report
+            .to_string()
+            .contains("Stream #1 (PID 257): audio aac, 48000 Hz, stereo"));
+        assert!(report
+            .to_json()
+            .contains("\"codec\": \"h264\", \"width\": 1280"));
+
+        // The same packets with 4-byte M2TS timestamps
+        let m2ts: Vec<u8> = ts
+            .chunks(TS_PACKET_SIZE)
+            .flat_map(|packet| [&[0u8; 4][..], packet].concat())
+            .collect();
+        let m2ts_report = probe(&m2ts).unwrap();
+        assert_eq!(m2ts_report.container, ContainerKind::M2ts);
+        assert_eq!(m2ts_report.streams, report.streams);
+    }
+
+    #[test]
+    fn test_probe_mp4_reports_displayed_size() {
+        use crate::mp4_writer::test_support::tiny_writer;
+        use crate::mp4_writer::{DisplayTransform, Rotation};
+
+        let mut writer = tiny_writer(1);
+        writer.set_transform(0, DisplayTransform::new(Rotation::Rotate270, false));
+        let data = writer.finish_with_faststart().unwrap().into_inner();
+
+        // The coded 320x240 picture is shown portrait
+        let report = probe(&data).unwrap();
+        let video = &report.streams[0];
+        assert_eq!((video.width, video.height), (Some(240), Some(320)));
+    }
+}
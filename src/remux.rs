@@ -0,0 +1,683 @@
+use crate::box_tree::{parse_box_tree, write_box_tree, BoxBody, BoxNode, FullBox, HeaderForm};
+use crate::mp4_writer::box_header;
+use crate::ts_writer::regular_mp4;
+use std::collections::HashSet;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+/// One entry of a track filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackMatch {
+    /// 1-based position among the tracks of the kind, in file order
+    Index(usize),
+    /// ISO 639-2/T language code of the mdhd box ("eng", "kor", "und")
+    Language(String),
+}
+
+/// Which tracks of one kind (video or audio) to keep
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TrackFilter {
+    /// Keep every track of the kind
+    #[default]
+    All,
+    /// Drop every track of the kind
+    None,
+    /// Keep the tracks matching any entry; every entry has to match at least one track
+    Only(Vec<TrackMatch>),
+}
+
+impl FromStr for TrackFilter {
+    type Err = io::Error;
+
+    /// Parses "all", "none" or a comma-separated list of positions and language codes ("1,kor")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => return Ok(TrackFilter::All),
+            "none" => return Ok(TrackFilter::None),
+            _ => {}
+        }
+
+        let entries = s
+            .split(',')
+            .map(|entry| {
+                let entry = entry.trim();
+                match entry.parse::<usize>() {
+                    Ok(index) if index > 0 => Ok(TrackMatch::Index(index)),
+                    Err(_) if entry.len() == 3 && entry.chars().all(|c| c.is_ascii_alphabetic()) => {
+                        Ok(TrackMatch::Language(entry.to_ascii_lowercase()))
+                    }
+                    _ => Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid track filter entry '{}' (expected all, none, a 1-based position or a 3-letter language code)",
+                            entry
+                        ),
+                    )),
+                }
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(TrackFilter::Only(entries))
+    }
+}
+
+/// Tracks to keep when remuxing an MP4
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackSelection {
+    pub video: TrackFilter,
+    pub audio: TrackFilter,
+    /// Track IDs to keep, in output order. When not empty only these tracks are kept and
+    /// `video` and `audio` are ignored; otherwise tracks keep their file order and tracks
+    /// that are neither video nor audio (chapters, subtitles) are kept.
+    pub track_ids: Vec<u32>,
+}
+
+impl TrackSelection {
+    /// Whether the selection keeps every track as it is
+    pub fn is_all(&self) -> bool {
+        *self == TrackSelection::default()
+    }
+}
+
+/// Track of the source moov with what the selection looks at
+struct TrackInfo {
+    id: u32,
+    handler: [u8; 4],
+    language: String,
+}
+
+/// Removes, keeps or reorders the tracks of an MP4 (regular or fragmented, which is
+/// defragmented first). The media data is rebuilt from the chunks of the kept tracks only,
+/// so nothing of a dropped track is left in mdat. Track IDs are kept as they are.
+pub fn select_mp4_tracks(data: &[u8], selection: &TrackSelection) -> io::Result<Vec<u8>> {
+    let data = regular_mp4(data)?;
+    let output = Vec::with_capacity(data.len());
+    write_selected_mp4_tracks(Cursor::new(&data[..]), output, selection)
+}
+
+/// Like `select_mp4_tracks`, reading a regular MP4 from `source` (e.g. a `BufReader<File>`)
+/// and writing the result to `sink`. Only the boxes other than mdat are held in memory; the
+/// chunks of the kept tracks are copied from `source` one at a time.
+pub fn write_selected_mp4_tracks<R: Read + Seek, W: Write>(
+    mut source: R,
+    mut sink: W,
+    selection: &TrackSelection,
+) -> io::Result<W> {
+    let boxes = read_top_level_boxes(&mut source)?;
+    if boxes.iter().any(|top| &top.box_type == b"moof") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Fragmented MP4 has to be defragmented before its tracks are selected",
+        ));
+    }
+    let moov = boxes
+        .iter()
+        .find(|top| &top.box_type == b"moov")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+    let mut moov = parse_box_tree(&read_range(&mut source, moov.offset, moov.size)?)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+
+    // Pull the traks out of moov and put the kept ones back where the first trak was
+    let children = moov
+        .children_mut()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov is not a container"))?;
+    let trak_position = children
+        .iter()
+        .position(|child| &child.box_type == b"trak")
+        .unwrap_or(children.len());
+    let (traks, others): (Vec<BoxNode>, Vec<BoxNode>) = children
+        .drain(..)
+        .partition(|child| &child.box_type == b"trak");
+    *children = others;
+    // The output has no fragments, so mvex (with the trex of dropped tracks) goes too
+    children.retain(|child| &child.box_type != b"mvex");
+
+    let infos = traks
+        .iter()
+        .map(track_info)
+        .collect::<io::Result<Vec<_>>>()?;
+    let order = selected_tracks(&infos, selection)?;
+    if order.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "No tracks left after the track selection",
+        ));
+    }
+    let kept_ids: HashSet<u32> = order.iter().map(|&index| infos[index].id).collect();
+    let mut traks: Vec<Option<BoxNode>> = traks.into_iter().map(Some).collect();
+    let mut kept: Vec<BoxNode> = order
+        .iter()
+        .filter_map(|&index| traks[index].take())
+        .collect();
+
+    for trak in &mut kept {
+        drop_track_references(trak, &kept_ids);
+    }
+    update_mvhd(&mut moov, &kept)?;
+
+    // Copy the chunks of the kept tracks in their original file order
+    let chunks = kept
+        .iter()
+        .map(chunk_ranges)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut copies: Vec<(u64, u64, usize, usize)> = chunks
+        .iter()
+        .enumerate()
+        .flat_map(|(track, ranges)| {
+            ranges
+                .iter()
+                .enumerate()
+                .map(move |(chunk, &(offset, size))| (offset, size, track, chunk))
+        })
+        .collect();
+    copies.sort_by_key(|&(offset, _, _, _)| offset);
+
+    let file_size = source.seek(SeekFrom::End(0))?;
+    let mut payload_size = 0;
+    let mut relative: Vec<Vec<u64>> = chunks.iter().map(|ranges| vec![0; ranges.len()]).collect();
+    for &(offset, size, track, chunk) in &copies {
+        if offset.checked_add(size).is_none_or(|end| end > file_size) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Chunk lies outside of the file",
+            ));
+        }
+        relative[track][chunk] = payload_size;
+        payload_size += size;
+    }
+    let mdat_header = box_header(b"mdat", payload_size);
+
+    // The new mdat takes the place of the first mdat. The moov size only depends on whether
+    // the chunk offsets are 32 or 64 bits wide, so it is measured with placeholder offsets.
+    let mut wide = false;
+    let payload_start = loop {
+        for (trak, offsets) in kept.iter_mut().zip(&relative) {
+            set_chunk_offsets(trak, vec![0; offsets.len()], wide)?;
+        }
+        let moov_size = moov.size() + kept.iter().map(BoxNode::size).sum::<u64>();
+        let payload_start = payload_position(&boxes, moov_size) + mdat_header.len() as u64;
+        if wide || payload_start + payload_size <= u32::MAX as u64 {
+            break payload_start;
+        }
+        wide = true;
+    };
+    for (trak, offsets) in kept.iter_mut().zip(&relative) {
+        let absolute = offsets
+            .iter()
+            .map(|offset| offset + payload_start)
+            .collect();
+        set_chunk_offsets(trak, absolute, wide)?;
+    }
+    moov.children_mut()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov is not a container"))?
+        .splice(trak_position..trak_position, kept);
+    let moov_bytes = write_box_tree(std::slice::from_ref(&moov));
+
+    let write_mdat = |source: &mut R, sink: &mut W| -> io::Result<()> {
+        sink.write_all(&mdat_header)?;
+        for &(offset, size, _, _) in &copies {
+            copy_range(source, sink, offset, size)?;
+        }
+        Ok(())
+    };
+    let mut moov_written = false;
+    let mut mdat_written = false;
+    for top in &boxes {
+        match &top.box_type {
+            b"moov" => {
+                if !moov_written {
+                    sink.write_all(&moov_bytes)?;
+                    moov_written = true;
+                }
+            }
+            b"mdat" => {
+                if !mdat_written {
+                    write_mdat(&mut source, &mut sink)?;
+                    mdat_written = true;
+                }
+            }
+            _ => copy_range(&mut source, &mut sink, top.offset, top.size)?,
+        }
+    }
+    if !mdat_written {
+        write_mdat(&mut source, &mut sink)?;
+    }
+
+    Ok(sink)
+}
+
+/// Top-level box of a file read through `Read + Seek`
+struct TopBox {
+    box_type: [u8; 4],
+    offset: u64,
+    size: u64, // with the header
+}
+
+/// Reads the headers of the top-level boxes, skipping over their content
+fn read_top_level_boxes<R: Read + Seek>(source: &mut R) -> io::Result<Vec<TopBox>> {
+    let file_size = source.seek(SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while file_size - offset >= 8 {
+        source.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        source.read_exact(&mut header)?;
+        let box_type = [header[4], header[5], header[6], header[7]];
+        let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => file_size - offset, // to the end of the file
+            1 => {
+                let mut largesize = [0u8; 8];
+                source.read_exact(&mut largesize)?;
+                u64::from_be_bytes(largesize)
+            }
+            size => size as u64,
+        };
+        if size < 8 || size > file_size - offset {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Box size {} exceeds available data", size),
+            ));
+        }
+        boxes.push(TopBox {
+            box_type,
+            offset,
+            size,
+        });
+        offset += size;
+    }
+    Ok(boxes)
+}
+
+fn read_range<R: Read + Seek>(source: &mut R, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    source.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    source.take(size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn copy_range<R: Read + Seek, W: Write>(
+    source: &mut R,
+    sink: &mut W,
+    offset: u64,
+    size: u64,
+) -> io::Result<()> {
+    source.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut source.take(size), sink)?;
+    if copied != size {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "File ended inside a box",
+        ));
+    }
+    Ok(())
+}
+
+fn track_info(trak: &BoxNode) -> io::Result<TrackInfo> {
+    let id = match trak.find(&[b"tkhd"]).and_then(BoxNode::full_box) {
+        Some(FullBox::Tkhd(tkhd)) => tkhd.track_id,
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Track without a tkhd box",
+            ))
+        }
+    };
+    let handler = match trak.find(&[b"mdia", b"hdlr"]).and_then(BoxNode::full_box) {
+        Some(FullBox::Hdlr(hdlr)) => hdlr.handler_type,
+        _ => [0; 4],
+    };
+    let language = match trak.find(&[b"mdia", b"mdhd"]).and_then(BoxNode::full_box) {
+        Some(FullBox::Mdhd(mdhd)) => language_code(mdhd.language),
+        _ => "und".to_string(),
+    };
+    Ok(TrackInfo {
+        id,
+        handler,
+        language,
+    })
+}
+
+/// Packed ISO 639-2/T code of mdhd (three 5-bit letters)
+fn language_code(code: u16) -> String {
+    [(code >> 10) & 0x1F, (code >> 5) & 0x1F, code & 0x1F]
+        .iter()
+        .map(|&c| (c as u8 + 0x60) as char)
+        .collect()
+}
+
+/// Positions of the kept tracks in output order
+fn selected_tracks(infos: &[TrackInfo], selection: &TrackSelection) -> io::Result<Vec<usize>> {
+    if !selection.track_ids.is_empty() {
+        let mut order = Vec::with_capacity(selection.track_ids.len());
+        for &id in &selection.track_ids {
+            let index = infos.iter().position(|info| info.id == id).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Track ID {} not found", id),
+                )
+            })?;
+            if !order.contains(&index) {
+                order.push(index);
+            }
+        }
+        return Ok(order);
+    }
+
+    let mut keep = vec![true; infos.len()];
+    for (handler, kind, filter) in [
+        (b"vide", "video", &selection.video),
+        (b"soun", "audio", &selection.audio),
+    ] {
+        let tracks: Vec<usize> = (0..infos.len())
+            .filter(|&index| &infos[index].handler == handler)
+            .collect();
+        let entries = match filter {
+            TrackFilter::All => continue,
+            TrackFilter::None => &[][..],
+            TrackFilter::Only(entries) => &entries[..],
+        };
+        let mut matched = vec![false; tracks.len()];
+        for entry in entries {
+            let found = match entry {
+                TrackMatch::Index(index) => match matched.get_mut(index - 1) {
+                    Some(slot) => {
+                        *slot = true;
+                        true
+                    }
+                    None => false,
+                },
+                TrackMatch::Language(language) => {
+                    let mut found = false;
+                    for (slot, &track) in matched.iter_mut().zip(&tracks) {
+                        if infos[track].language.eq_ignore_ascii_case(language) {
+                            *slot = true;
+                            found = true;
+                        }
+                    }
+                    found
+                }
+            };
+            if !found {
+                let description = match entry {
+                    TrackMatch::Index(index) => format!("{} track {}", kind, index),
+                    TrackMatch::Language(language) => {
+                        format!("{} track with language '{}'", kind, language)
+                    }
+                };
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("No {} in the input", description),
+                ));
+            }
+        }
+        for (&track, matched) in tracks.iter().zip(matched) {
+            keep[track] = matched;
+        }
+    }
+
+    Ok((0..infos.len()).filter(|&index| keep[index]).collect())
+}
+
+/// Removes references to dropped tracks from the tref box (and tref itself when it empties)
+fn drop_track_references(trak: &mut BoxNode, kept_ids: &HashSet<u32>) {
+    let Some(tref) = trak.find_mut(&[b"tref"]) else {
+        return;
+    };
+    if let Some(references) = tref.children_mut() {
+        references.retain_mut(|reference| {
+            let BoxBody::Raw(payload) = &mut reference.body else {
+                return true;
+            };
+            *payload = payload
+                .chunks_exact(4)
+                .filter(|id| kept_ids.contains(&u32::from_be_bytes([id[0], id[1], id[2], id[3]])))
+                .flatten()
+                .copied()
+                .collect();
+            !payload.is_empty()
+        });
+    }
+    if tref.children().is_empty() {
+        if let Some(children) = trak.children_mut() {
+            children.retain(|child| &child.box_type != b"tref");
+        }
+    }
+}
+
+/// Points next_track_id past the kept tracks and shortens the movie duration to them
+fn update_mvhd(moov: &mut BoxNode, kept: &[BoxNode]) -> io::Result<()> {
+    let mut last_id = 0;
+    let mut duration = 0;
+    for trak in kept {
+        if let Some(FullBox::Tkhd(tkhd)) = trak.find(&[b"tkhd"]).and_then(BoxNode::full_box) {
+            last_id = last_id.max(tkhd.track_id);
+            duration = duration.max(tkhd.duration);
+        }
+    }
+    match moov.find_mut(&[b"mvhd"]).and_then(BoxNode::full_box_mut) {
+        Some(FullBox::Mvhd(mvhd)) => {
+            mvhd.next_track_id = last_id + 1;
+            // Leave the duration alone when the tkhd boxes do not carry one
+            if duration > 0 {
+                mvhd.duration = duration;
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "mvhd box not found")),
+    }
+}
+
+/// Byte ranges (offset, size) of the chunks of a track, from stco/co64, stsc and stsz
+fn chunk_ranges(trak: &BoxNode) -> io::Result<Vec<(u64, u64)>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    let stbl = trak
+        .find(&[b"mdia", b"minf", b"stbl"])
+        .ok_or_else(|| invalid("Track without a sample table"))?;
+    let offsets: Vec<u64> = match stbl
+        .find(&[b"stco"])
+        .or_else(|| stbl.find(&[b"co64"]))
+        .and_then(BoxNode::full_box)
+    {
+        Some(FullBox::Stco(offsets)) => offsets.iter().map(|&offset| offset as u64).collect(),
+        Some(FullBox::Co64(offsets)) => offsets.clone(),
+        _ => return Err(invalid("Track without a chunk offset table")),
+    };
+    let stsc = match stbl.find(&[b"stsc"]).and_then(BoxNode::full_box) {
+        Some(FullBox::Stsc(entries)) => entries,
+        _ => return Err(invalid("Track without a sample-to-chunk table")),
+    };
+    let stsz = match stbl.find(&[b"stsz"]).and_then(BoxNode::full_box) {
+        Some(FullBox::Stsz(stsz)) => stsz,
+        _ => return Err(invalid("Track without a sample size table")),
+    };
+
+    let mut ranges = Vec::with_capacity(offsets.len());
+    let mut entry = 0;
+    let mut sample = 0usize;
+    for (chunk, &offset) in offsets.iter().enumerate() {
+        let number = chunk as u32 + 1;
+        while entry + 1 < stsc.len() && stsc[entry + 1].first_chunk <= number {
+            entry += 1;
+        }
+        let samples = stsc.get(entry).map_or(0, |e| e.samples_per_chunk) as usize;
+        let size: u64 = if stsz.sample_size != 0 {
+            stsz.sample_size as u64 * samples as u64
+        } else {
+            stsz.entry_sizes
+                .get(sample..sample + samples)
+                .ok_or_else(|| invalid("Sample-to-chunk table runs past the sample sizes"))?
+                .iter()
+                .map(|&size| size as u64)
+                .sum()
+        };
+        sample += samples;
+        ranges.push((offset, size));
+    }
+    Ok(ranges)
+}
+
+/// Replaces the chunk offset table of a track with stco, or co64 when `wide`
+fn set_chunk_offsets(trak: &mut BoxNode, offsets: Vec<u64>, wide: bool) -> io::Result<()> {
+    let table = trak
+        .find_mut(&[b"mdia", b"minf", b"stbl"])
+        .and_then(BoxNode::children_mut)
+        .and_then(|children| {
+            children
+                .iter_mut()
+                .find(|child| &child.box_type == b"stco" || &child.box_type == b"co64")
+        })
+        .ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, "Track without a chunk offset table")
+        })?;
+    let (box_type, content) = if wide {
+        (*b"co64", FullBox::Co64(offsets))
+    } else {
+        (
+            *b"stco",
+            FullBox::Stco(offsets.iter().map(|&offset| offset as u32).collect()),
+        )
+    };
+    *table = BoxNode {
+        box_type,
+        header: HeaderForm::Compact,
+        body: BoxBody::Full {
+            version: 0,
+            flags: 0,
+            content,
+        },
+    };
+    Ok(())
+}
+
+/// Position of the first mdat when the top-level boxes are written in their original order
+/// with a moov of `moov_size` bytes
+fn payload_position(boxes: &[TopBox], moov_size: u64) -> u64 {
+    let mut position = 0;
+    let mut moov_written = false;
+    for top in boxes {
+        match &top.box_type {
+            b"moov" => {
+                if !moov_written {
+                    position += moov_size;
+                    moov_written = true;
+                }
+            }
+            b"mdat" => return position,
+            _ => position += top.size,
+        }
+    }
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::{parse_mp4, read_mp4_tracks};
+    use crate::muxer::{Mp4Writer, Mp4WriterOptions, Sample, TrackConfig};
+    use crate::ts_parser::AacConfig;
+
+    /// Video with an English and a Korean audio track, each sample filled with its track ID
+    fn sample_mp4() -> Vec<u8> {
+        let mut writer =
+            Mp4Writer::new(Cursor::new(Vec::new()), Mp4WriterOptions::default()).unwrap();
+        let tracks = [
+            TrackConfig::h264(64, 64, &[0x67, 0x42], &[0x68]).with_timescale(30),
+            TrackConfig::aac(AacConfig::default()).with_language("eng"),
+            TrackConfig::aac(AacConfig::default()).with_language("kor"),
+        ];
+        for config in tracks {
+            let track = writer.add_track(config).unwrap();
+            for index in 0..4u64 {
+                let sample = Sample {
+                    data: &[track as u8 + 1; 16],
+                    dts: index * 1024,
+                    cts: index * 1024,
+                    duration: 1024,
+                    is_sync: true,
+                };
+                writer.write_sample(track, &sample).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn next_track_id(data: &[u8]) -> u32 {
+        let moov = parse_box_tree(data)
+            .unwrap()
+            .into_iter()
+            .find(|node| &node.box_type == b"moov")
+            .unwrap();
+        match moov.find(&[b"mvhd"]).and_then(BoxNode::full_box) {
+            Some(FullBox::Mvhd(mvhd)) => mvhd.next_track_id,
+            _ => panic!("mvhd not found"),
+        }
+    }
+
+    #[test]
+    fn test_select_tracks_compacts_mdat() {
+        let data = sample_mp4();
+
+        let selection = TrackSelection {
+            audio: "kor".parse().unwrap(),
+            ..Default::default()
+        };
+        let output = select_mp4_tracks(&data, &selection).unwrap();
+        let tracks = read_mp4_tracks(&output).unwrap();
+        assert_eq!(
+            tracks.iter().map(|t| t.track_id).collect::<Vec<_>>(),
+            [1, 3]
+        );
+        assert_eq!(next_track_id(&output), 4);
+        for track in &tracks {
+            for sample in track.samples() {
+                let start = sample.offset as usize;
+                assert_eq!(output[start..start + 16], [track.track_id as u8; 16]);
+            }
+        }
+        // The English track's samples are gone from mdat
+        assert_eq!(
+            data.len() - output.len(),
+            4 * 16 + moov_size(&data) - moov_size(&output)
+        );
+
+        let selection = TrackSelection {
+            track_ids: vec![2, 1],
+            ..Default::default()
+        };
+        let output = select_mp4_tracks(&data, &selection).unwrap();
+        let tracks = read_mp4_tracks(&output).unwrap();
+        assert_eq!(
+            tracks.iter().map(|t| t.track_id).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert_eq!(next_track_id(&output), 3);
+
+        let selection = TrackSelection {
+            video: TrackFilter::None,
+            audio: "2,fra".parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(select_mp4_tracks(&data, &selection).is_err());
+    }
+
+    #[test]
+    fn test_write_selected_tracks_streams_the_same_output() {
+        let data = sample_mp4();
+        let selection = TrackSelection {
+            audio: "kor".parse().unwrap(),
+            ..Default::default()
+        };
+        let streamed =
+            write_selected_mp4_tracks(Cursor::new(&data[..]), Vec::new(), &selection).unwrap();
+        assert_eq!(streamed, select_mp4_tracks(&data, &selection).unwrap());
+
+        // A chunk offset past the end of the file is rejected instead of being copied short
+        let truncated = &data[..data.len() - 8];
+        assert!(write_selected_mp4_tracks(Cursor::new(truncated), Vec::new(), &selection).is_err());
+    }
+
+    fn moov_size(data: &[u8]) -> usize {
+        parse_mp4(data).unwrap().moov.unwrap().size as usize
+    }
+}
@@ -1,3 +1,4 @@
+use crate::mp4_parser::{read_mp4_tracks, MediaType};
 use crate::ts_parser::{parse_ts_packets, MediaData};
 use std::io::{self, ErrorKind};
 
@@ -79,15 +80,12 @@ fn extract_first_iframe(media_data: &MediaData) -> io::Result<Vec<u8>> {
                 if frame_end + 3 <= data.len()
                     && data[frame_end] == 0x00
                     && data[frame_end + 1] == 0x00
+                    && (data[frame_end + 2] == 0x01
+                        || (frame_end + 4 <= data.len()
+                            && data[frame_end + 2] == 0x00
+                            && data[frame_end + 3] == 0x01))
                 {
-                    if data[frame_end + 2] == 0x01 {
-                        break;
-                    } else if frame_end + 4 <= data.len()
-                        && data[frame_end + 2] == 0x00
-                        && data[frame_end + 3] == 0x01
-                    {
-                        break;
-                    }
+                    break;
                 }
                 frame_end += 1;
             }
@@ -107,53 +105,34 @@ fn extract_first_iframe(media_data: &MediaData) -> io::Result<Vec<u8>> {
     ))
 }
 
-/// Finds the first video sample in an MP4 file
+/// Finds the first keyframe of the first video track in an MP4 file
 fn find_first_video_sample(mp4_data: &[u8]) -> io::Result<Vec<u8>> {
-    // Find mdat box
-    let mdat_offset = find_box(mp4_data, b"mdat")?;
-    let _mdat_size = read_u32(mp4_data, mdat_offset) as usize;
-    let mdat_data_start = mdat_offset + 8;
-
-    if mdat_data_start >= mp4_data.len() {
-        return Err(io::Error::new(ErrorKind::InvalidData, "Invalid mdat box"));
-    }
-
-    // Find moov box to get sample information
-    let moov_offset = find_box(mp4_data, b"moov")?;
-
-    // Find trak box for video
-    let trak_offset = find_box_in_container(mp4_data, moov_offset, b"trak")?;
-
-    // Find stbl (sample table) box
-    let mdia_offset = find_box_in_container(mp4_data, trak_offset, b"mdia")?;
-    let minf_offset = find_box_in_container(mp4_data, mdia_offset, b"minf")?;
-    let stbl_offset = find_box_in_container(mp4_data, minf_offset, b"stbl")?;
-
-    // Find stsz (sample sizes) to get first sample size
-    let stsz_offset = find_box_in_container(mp4_data, stbl_offset, b"stsz")?;
-    let first_sample_size = read_first_sample_size(mp4_data, stsz_offset)?;
-
-    // Find stss (sync samples) to verify it's a keyframe
-    // For thumbnail, we assume the first sample is a keyframe
-
-    // Extract first sample from mdat
-    if mdat_data_start + first_sample_size > mp4_data.len() {
+    let tracks = read_mp4_tracks(mp4_data)?;
+    let video = tracks
+        .iter()
+        .find(|track| track.media_type == MediaType::Video)
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No video track found"))?;
+
+    let sample = video
+        .samples()
+        .find(|sample| sample.is_sync)
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No keyframe found in video track"))?;
+
+    let start = sample.offset as usize;
+    let end = start + sample.size as usize;
+    if end > mp4_data.len() {
         return Err(io::Error::new(
             ErrorKind::InvalidData,
-            "Sample size exceeds mdat bounds",
+            "Sample size exceeds file bounds",
         ));
     }
 
-    let sample_data = mp4_data[mdat_data_start..mdat_data_start + first_sample_size].to_vec();
-
     // Convert from AVCC format to Annex B format (add start codes)
-    let annexb_data = convert_avcc_to_annexb(&sample_data)?;
-
-    Ok(annexb_data)
+    convert_avcc_to_annexb(&mp4_data[start..end])
 }
 
 /// Converts AVCC format (length-prefixed NAL units) to Annex B format (start code prefixed)
-fn convert_avcc_to_annexb(avcc_data: &[u8]) -> io::Result<Vec<u8>> {
+pub(crate) fn convert_avcc_to_annexb(avcc_data: &[u8]) -> io::Result<Vec<u8>> {
     let mut annexb = Vec::new();
     let mut offset = 0;
 
@@ -177,98 +156,6 @@ fn convert_avcc_to_annexb(avcc_data: &[u8]) -> io::Result<Vec<u8>> {
     Ok(annexb)
 }
 
-/// Finds a box in MP4 data
-fn find_box(data: &[u8], box_type: &[u8; 4]) -> io::Result<usize> {
-    let mut offset = 0;
-
-    while offset + 8 <= data.len() {
-        let size = read_u32(data, offset) as usize;
-        if size < 8 {
-            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid box size"));
-        }
-
-        if &data[offset + 4..offset + 8] == box_type {
-            return Ok(offset);
-        }
-
-        offset += size;
-    }
-
-    Err(io::Error::new(
-        ErrorKind::NotFound,
-        format!(
-            "Box {:?} not found",
-            std::str::from_utf8(box_type).unwrap_or("unknown")
-        ),
-    ))
-}
-
-/// Finds a box within a container box
-fn find_box_in_container(
-    data: &[u8],
-    container_offset: usize,
-    box_type: &[u8; 4],
-) -> io::Result<usize> {
-    let container_size = read_u32(data, container_offset) as usize;
-    let mut offset = container_offset + 8;
-    let container_end = container_offset + container_size;
-
-    while offset + 8 <= container_end && offset + 8 <= data.len() {
-        let size = read_u32(data, offset) as usize;
-        if size < 8 {
-            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid box size"));
-        }
-
-        if &data[offset + 4..offset + 8] == box_type {
-            return Ok(offset);
-        }
-
-        offset += size;
-    }
-
-    Err(io::Error::new(
-        ErrorKind::NotFound,
-        format!(
-            "Box {:?} not found in container",
-            std::str::from_utf8(box_type).unwrap_or("unknown")
-        ),
-    ))
-}
-
-/// Reads first sample size from stsz box
-fn read_first_sample_size(data: &[u8], stsz_offset: usize) -> io::Result<usize> {
-    // stsz box structure:
-    // 4 bytes: size
-    // 4 bytes: type ('stsz')
-    // 1 byte: version
-    // 3 bytes: flags
-    // 4 bytes: sample_size (if 0, then each sample has different size)
-    // 4 bytes: sample_count
-    // [4 bytes * sample_count]: sample sizes (if sample_size == 0)
-
-    if stsz_offset + 20 > data.len() {
-        return Err(io::Error::new(ErrorKind::InvalidData, "Invalid stsz box"));
-    }
-
-    let sample_size = read_u32(data, stsz_offset + 12) as usize;
-
-    if sample_size != 0 {
-        // All samples have the same size
-        return Ok(sample_size);
-    }
-
-    // Read first sample size from the table
-    if stsz_offset + 24 > data.len() {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "Invalid stsz box - no sample sizes",
-        ));
-    }
-
-    let first_sample_size = read_u32(data, stsz_offset + 20) as usize;
-    Ok(first_sample_size)
-}
-
 /// Reads a big-endian u32 from data
 fn read_u32(data: &[u8], offset: usize) -> u32 {
     u32::from_be_bytes([
@@ -1,7 +1,77 @@
-use std::io::{self, ErrorKind};
+use std::io::{self, ErrorKind, Read};
+
+pub(crate) const TS_PACKET_SIZE: usize = 188;
+pub(crate) const SYNC_BYTE: u8 = 0x47;
+const AAC_FRAME_SAMPLES: u64 = 1024;
+const TS_READ_BUFFER_SIZE: usize = TS_PACKET_SIZE * 512;
+
+// ADTS sampling_frequency_index values
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// AAC stream parameters, taken from the first ADTS header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AacConfig {
+    pub object_type: u8,       // 2 = AAC-LC
+    pub sample_rate_index: u8, // index into the ADTS sampling frequency table
+    pub channels: u8,          // channel configuration
+}
+
+impl AacConfig {
+    pub fn sample_rate(&self) -> u32 {
+        AAC_SAMPLE_RATES
+            .get(self.sample_rate_index as usize)
+            .copied()
+            .unwrap_or(48000)
+    }
 
-const TS_PACKET_SIZE: usize = 188;
-const SYNC_BYTE: u8 = 0x47;
+    /// AudioSpecificConfig for esds: object type (5 bits), frequency index (4), channels (4)
+    pub fn audio_specific_config(&self) -> [u8; 2] {
+        let value = (self.object_type as u16 & 0x1F) << 11
+            | (self.sample_rate_index as u16 & 0x0F) << 7
+            | (self.channels as u16 & 0x0F) << 3;
+        value.to_be_bytes()
+    }
+
+    /// Reads an AudioSpecificConfig from esds (explicit frequencies are not supported)
+    pub fn from_audio_specific_config(config: &[u8]) -> Option<Self> {
+        let value = u16::from_be_bytes([*config.first()?, *config.get(1)?]);
+        let config = AacConfig {
+            object_type: (value >> 11) as u8,
+            sample_rate_index: ((value >> 7) & 0x0F) as u8,
+            channels: ((value >> 3) & 0x0F) as u8,
+        };
+        (config.object_type != 0 && (config.sample_rate_index as usize) < AAC_SAMPLE_RATES.len())
+            .then_some(config)
+    }
+
+    /// 7-byte ADTS header (no CRC) for a raw AAC frame of `payload_len` bytes
+    pub(crate) fn adts_header(&self, payload_len: usize) -> [u8; 7] {
+        let frame_len = payload_len + 7;
+        let profile = self.object_type.saturating_sub(1) & 0x03; // ADTS profile is object type - 1
+        [
+            0xFF,
+            0xF1, // MPEG-4, layer 0, no CRC
+            profile << 6 | (self.sample_rate_index & 0x0F) << 2 | (self.channels >> 2) & 0x01,
+            (self.channels & 0x03) << 6 | (frame_len >> 11) as u8 & 0x03,
+            (frame_len >> 3) as u8,
+            (frame_len as u8 & 0x07) << 5 | 0x1F, // buffer fullness 0x7FF (VBR)
+            0xFC,
+        ]
+    }
+}
+
+impl Default for AacConfig {
+    /// AAC-LC, 48kHz, stereo
+    fn default() -> Self {
+        AacConfig {
+            object_type: 2,
+            sample_rate_index: 3,
+            channels: 2,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct MediaData {
@@ -13,6 +83,8 @@ pub struct MediaData {
     pub audio_timestamps: Vec<Option<u64>>, // Audio PTS values
     pub audio_buffer: Vec<u8>,      // Temporary buffer for collecting audio PES packets
     pub current_audio_pts: Option<u64>, // PTS for the current audio PES packet being accumulated
+    pub current_audio_frames: u32,  // Frames already taken from the current audio PES packet
+    pub audio_config: Option<AacConfig>, // From the first ADTS header
     pub width: u16,
     pub height: u16,
     pub sps: Option<Vec<u8>>,
@@ -30,6 +102,8 @@ impl MediaData {
             audio_timestamps: Vec::new(),
             audio_buffer: Vec::new(),
             current_audio_pts: None,
+            current_audio_frames: 0,
+            audio_config: None,
             width: 1920,
             height: 1080,
             sps: None,
@@ -39,36 +113,144 @@ impl MediaData {
 }
 
 pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
-    let mut media_data = MediaData::new();
-    let mut offset = 0;
+    let mut packets = TsPacketReader::new(data);
+    let mut demuxer = TsDemuxer::new();
+    while let Some(packet) = packets.next_packet()? {
+        demuxer.push_packet(packet);
+    }
+    demuxer.finish();
+    let media_data = demuxer.media;
 
-    // Find first sync byte
-    while offset < data.len() && data[offset] != SYNC_BYTE {
-        offset += 1;
+    println!(
+        "Total audio frames collected: {}",
+        media_data.audio_frames.len()
+    );
+    println!(
+        "Total video frames collected: {}",
+        media_data.frame_timestamps.len()
+    );
+
+    if !media_data.audio_timestamps.is_empty() {
+        if let Some(Some(first_audio_pts)) = media_data.audio_timestamps.first() {
+            if let Some(Some(last_audio_pts)) = media_data.audio_timestamps.last() {
+                println!(
+                    "Audio PTS range: {} - {} ({:.2} - {:.2} sec)",
+                    first_audio_pts,
+                    last_audio_pts,
+                    *first_audio_pts as f64 / 90000.0,
+                    *last_audio_pts as f64 / 90000.0
+                );
+            }
+        }
     }
 
-    if offset >= data.len() {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "No valid TS sync byte found",
-        ));
+    if !media_data.frame_timestamps.is_empty() {
+        if let Some(&(Some(first_video_pts), _)) = media_data.frame_timestamps.first() {
+            if let Some(&(Some(last_video_pts), _)) = media_data.frame_timestamps.last() {
+                println!(
+                    "Video PTS range: {} - {} ({:.2} - {:.2} sec)",
+                    first_video_pts,
+                    last_video_pts,
+                    first_video_pts as f64 / 90000.0,
+                    last_video_pts as f64 / 90000.0
+                );
+            }
+        }
     }
 
-    let mut pat_pmt_parsed = false;
-    let mut pmt_pid: Option<u16> = None;
+    Ok(media_data)
+}
 
-    while offset + TS_PACKET_SIZE <= data.len() {
-        let packet = &data[offset..offset + TS_PACKET_SIZE];
+/// Reads 188-byte TS packets from `source`, resynchronizing on the next sync byte after
+/// corrupt data. Only a small read buffer is held, so any `Read` can be demuxed as it arrives.
+pub(crate) struct TsPacketReader<R: Read> {
+    source: R,
+    buffer: Vec<u8>,
+    start: usize, // first unread byte in buffer
+    end: usize,   // end of the valid data in buffer
+    found_sync: bool,
+}
 
-        if packet[0] != SYNC_BYTE {
-            // Try to resync
-            offset += 1;
-            while offset < data.len() && data[offset] != SYNC_BYTE {
-                offset += 1;
+impl<R: Read> TsPacketReader<R> {
+    pub(crate) fn new(source: R) -> Self {
+        TsPacketReader {
+            source,
+            buffer: vec![0; TS_READ_BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            found_sync: false,
+        }
+    }
+
+    /// Returns the next packet, or None once fewer than 188 bytes are left
+    pub(crate) fn next_packet(&mut self) -> io::Result<Option<&[u8]>> {
+        loop {
+            if !self.fill(TS_PACKET_SIZE)? {
+                let rest = &self.buffer[self.start..self.end];
+                if !self.found_sync && !rest.contains(&SYNC_BYTE) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "No valid TS sync byte found",
+                    ));
+                }
+                return Ok(None);
             }
-            continue;
+
+            if self.buffer[self.start] != SYNC_BYTE {
+                // Skip to the next sync byte
+                self.start += 1;
+                continue;
+            }
+
+            self.found_sync = true;
+            let packet = &self.buffer[self.start..self.start + TS_PACKET_SIZE];
+            self.start += TS_PACKET_SIZE;
+            return Ok(Some(packet));
+        }
+    }
+
+    /// Reads until at least `len` bytes are buffered; false if the source ends first
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        if self.end - self.start >= len {
+            return Ok(true);
         }
 
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        while self.end < len {
+            match self.source.read(&mut self.buffer[self.end..]) {
+                Ok(0) => return Ok(false),
+                Ok(read) => self.end += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Demuxes the video and audio PES packets of the first program into `media`, one TS packet
+/// at a time. Callers may drain the collected frames between packets to bound memory use.
+pub(crate) struct TsDemuxer {
+    pub media: MediaData,
+    pmt_pid: Option<u16>,
+    pat_pmt_parsed: bool,
+}
+
+impl TsDemuxer {
+    pub(crate) fn new() -> Self {
+        TsDemuxer {
+            media: MediaData::new(),
+            pmt_pid: None,
+            pat_pmt_parsed: false,
+        }
+    }
+
+    pub(crate) fn push_packet(&mut self, packet: &[u8]) {
+        let media_data = &mut self.media;
+
         // Parse TS header
         let pid = ((packet[1] as u16 & 0x1F) << 8) | (packet[2] as u16);
         let payload_start = (packet[1] & 0x40) != 0;
@@ -84,24 +266,23 @@ pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
         }
 
         if !has_payload || payload_offset >= TS_PACKET_SIZE {
-            offset += TS_PACKET_SIZE;
-            continue;
+            return;
         }
 
         let payload = &packet[payload_offset..];
 
         // Parse PAT (PID 0)
-        if pid == 0 && !pat_pmt_parsed {
+        if pid == 0 && !self.pat_pmt_parsed {
             if let Some(pmt) = parse_pat(payload, payload_start) {
-                pmt_pid = Some(pmt);
+                self.pmt_pid = Some(pmt);
             }
         }
         // Parse PMT
-        else if Some(pid) == pmt_pid && !pat_pmt_parsed {
+        else if Some(pid) == self.pmt_pid && !self.pat_pmt_parsed {
             if let Some((vpid, apid)) = parse_pmt(payload, payload_start) {
                 media_data.video_pid = Some(vpid);
                 media_data.audio_pid = Some(apid);
-                pat_pmt_parsed = true;
+                self.pat_pmt_parsed = true;
             }
         }
         // Collect media packets
@@ -114,7 +295,7 @@ pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
                 let pes_data = extract_pes_payload(payload);
                 if !pes_data.is_empty() {
                     // Check for SPS/PPS NAL units
-                    extract_h264_params(&mut media_data, &pes_data);
+                    extract_h264_params(media_data, &pes_data);
                     // Append to video stream
                     media_data.video_stream.extend_from_slice(&pes_data);
                 }
@@ -128,12 +309,7 @@ pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
                 if !media_data.audio_buffer.is_empty() {
                     let (aac_frames, consumed) = extract_aac_frames(&media_data.audio_buffer);
                     // Use the stored PTS from the previous PES packet
-                    for _ in 0..aac_frames.len() {
-                        media_data
-                            .audio_timestamps
-                            .push(media_data.current_audio_pts);
-                    }
-                    media_data.audio_frames.extend(aac_frames);
+                    push_audio_frames(media_data, aac_frames);
                     // Remove consumed bytes from buffer
                     media_data.audio_buffer.drain(..consumed);
                 }
@@ -141,20 +317,21 @@ pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
                 // Start of new audio PES packet - extract timestamps and data
                 let (pts, _) = extract_pes_timestamps(payload);
                 media_data.current_audio_pts = pts; // Store PTS for this PES packet
+                media_data.current_audio_frames = 0;
 
                 let pes_data = extract_pes_payload(payload);
 
                 // Store in buffer for potential continuation packets
                 media_data.audio_buffer.extend_from_slice(&pes_data);
+                if media_data.audio_config.is_none() {
+                    media_data.audio_config = parse_adts_config(&media_data.audio_buffer);
+                }
 
                 // Try to extract complete AAC frames
                 let (aac_frames, consumed) = extract_aac_frames(&media_data.audio_buffer);
                 if !aac_frames.is_empty() {
-                    // Assign PTS to all frames extracted from this PES packet
-                    for _ in 0..aac_frames.len() {
-                        media_data.audio_timestamps.push(pts);
-                    }
-                    media_data.audio_frames.extend(aac_frames);
+                    // Frames extracted from this PES packet follow its PTS
+                    push_audio_frames(media_data, aac_frames);
                     // Remove consumed bytes from buffer
                     media_data.audio_buffer.drain(..consumed);
                 }
@@ -162,75 +339,30 @@ pub fn parse_ts_packets(data: &[u8]) -> io::Result<MediaData> {
             } else if !payload_start && !payload.is_empty() {
                 // Continuation of audio PES packet
                 media_data.audio_buffer.extend_from_slice(payload);
+                if media_data.audio_config.is_none() {
+                    media_data.audio_config = parse_adts_config(&media_data.audio_buffer);
+                }
 
                 // Try to extract complete AAC frames from accumulated data
                 let (aac_frames, consumed) = extract_aac_frames(&media_data.audio_buffer);
                 if !aac_frames.is_empty() {
                     // Use the PTS stored from the PES packet start
-                    for _ in 0..aac_frames.len() {
-                        media_data
-                            .audio_timestamps
-                            .push(media_data.current_audio_pts);
-                    }
-                    media_data.audio_frames.extend(aac_frames);
+                    push_audio_frames(media_data, aac_frames);
                     // Remove consumed bytes from buffer
                     media_data.audio_buffer.drain(..consumed);
                 }
             }
         }
-
-        offset += TS_PACKET_SIZE;
     }
 
-    // Process any remaining buffered audio data
-    if !media_data.audio_buffer.is_empty() {
-        let (aac_frames, _consumed) = extract_aac_frames(&media_data.audio_buffer);
-        for _ in 0..aac_frames.len() {
-            media_data
-                .audio_timestamps
-                .push(media_data.current_audio_pts);
+    /// Takes the frames left in the audio buffer at the end of the stream
+    pub(crate) fn finish(&mut self) {
+        if !self.media.audio_buffer.is_empty() {
+            let (aac_frames, consumed) = extract_aac_frames(&self.media.audio_buffer);
+            push_audio_frames(&mut self.media, aac_frames);
+            self.media.audio_buffer.drain(..consumed);
         }
-        media_data.audio_frames.extend(aac_frames);
     }
-
-    println!(
-        "Total audio frames collected: {}",
-        media_data.audio_frames.len()
-    );
-    println!(
-        "Total video frames collected: {}",
-        media_data.frame_timestamps.len()
-    );
-
-    if !media_data.audio_timestamps.is_empty() {
-        if let Some(Some(first_audio_pts)) = media_data.audio_timestamps.first() {
-            if let Some(Some(last_audio_pts)) = media_data.audio_timestamps.last() {
-                println!(
-                    "Audio PTS range: {} - {} ({:.2} - {:.2} sec)",
-                    first_audio_pts,
-                    last_audio_pts,
-                    *first_audio_pts as f64 / 90000.0,
-                    *last_audio_pts as f64 / 90000.0
-                );
-            }
-        }
-    }
-
-    if !media_data.frame_timestamps.is_empty() {
-        if let Some(&(Some(first_video_pts), _)) = media_data.frame_timestamps.first() {
-            if let Some(&(Some(last_video_pts), _)) = media_data.frame_timestamps.last() {
-                println!(
-                    "Video PTS range: {} - {} ({:.2} - {:.2} sec)",
-                    first_video_pts,
-                    last_video_pts,
-                    first_video_pts as f64 / 90000.0,
-                    last_video_pts as f64 / 90000.0
-                );
-            }
-        }
-    }
-
-    Ok(media_data)
 }
 
 fn parse_pat(payload: &[u8], payload_start: bool) -> Option<u16> {
@@ -328,6 +460,74 @@ fn parse_pmt(payload: &[u8], payload_start: bool) -> Option<(u16, u16)> {
     None
 }
 
+/// Elementary stream entry of a PMT
+#[derive(Debug, Clone)]
+pub(crate) struct PmtStream {
+    pub stream_type: u8,
+    pub pid: u16,
+    pub descriptors: Vec<u8>, // raw ES_info descriptors
+}
+
+/// Lists every (program_number, PMT PID) in a PAT section, skipping the network PID entry
+pub(crate) fn parse_pat_programs(payload: &[u8], payload_start: bool) -> Vec<(u16, u16)> {
+    let Some(section) = psi_section(payload, payload_start, 0x00) else {
+        return Vec::new();
+    };
+
+    // 8-byte header, 4-byte entries, 4-byte CRC
+    section[8..section.len() - 4]
+        .chunks_exact(4)
+        .map(|entry| {
+            (
+                u16::from_be_bytes([entry[0], entry[1]]),
+                ((entry[2] as u16 & 0x1F) << 8) | entry[3] as u16,
+            )
+        })
+        .filter(|&(program, _)| program != 0)
+        .collect()
+}
+
+/// Lists every elementary stream of a PMT section
+pub(crate) fn parse_pmt_streams(payload: &[u8], payload_start: bool) -> Option<Vec<PmtStream>> {
+    let section = psi_section(payload, payload_start, 0x02)?;
+    let program_info_length = (((section[10] as usize) & 0x0F) << 8) | section[11] as usize;
+
+    let mut streams = Vec::new();
+    let mut offset = 12 + program_info_length;
+    let end = section.len() - 4;
+    while offset + 5 <= end {
+        let es_info_length =
+            (((section[offset + 3] as usize) & 0x0F) << 8) | section[offset + 4] as usize;
+        let descriptors_end = (offset + 5 + es_info_length).min(end);
+        streams.push(PmtStream {
+            stream_type: section[offset],
+            pid: ((section[offset + 1] as u16 & 0x1F) << 8) | section[offset + 2] as u16,
+            descriptors: section[offset + 5..descriptors_end].to_vec(),
+        });
+        offset = descriptors_end;
+    }
+
+    Some(streams)
+}
+
+/// Returns the PSI section with `table_id` that starts in this packet, up to its CRC
+fn psi_section(payload: &[u8], payload_start: bool, table_id: u8) -> Option<&[u8]> {
+    if !payload_start {
+        return None;
+    }
+
+    let section = payload.get(1 + *payload.first()? as usize..)?;
+    if section.len() < 12 || section[0] != table_id {
+        return None;
+    }
+
+    let section_length = (((section[1] as usize) & 0x0F) << 8) | section[2] as usize;
+    if section_length < 9 {
+        return None;
+    }
+    section.get(..3 + section_length)
+}
+
 fn extract_pes_payload(payload: &[u8]) -> Vec<u8> {
     if payload.len() < 9 {
         return Vec::new();
@@ -348,7 +548,7 @@ fn extract_pes_payload(payload: &[u8]) -> Vec<u8> {
     payload[payload_start..].to_vec()
 }
 
-fn extract_pes_timestamps(payload: &[u8]) -> (Option<u64>, Option<u64>) {
+pub(crate) fn extract_pes_timestamps(payload: &[u8]) -> (Option<u64>, Option<u64>) {
     if payload.len() < 9 {
         return (None, None);
     }
@@ -372,11 +572,11 @@ fn extract_pes_timestamps(payload: &[u8]) -> (Option<u64>, Option<u64>) {
     if pts_dts_flags >= 2 && pes_header_length >= 5 {
         let pts_bytes = &payload[9..14];
         pts = Some(
-            (((pts_bytes[0] as u64 & 0x0E) << 29)
+            ((pts_bytes[0] as u64 & 0x0E) << 29)
                 | ((pts_bytes[1] as u64) << 22)
                 | ((pts_bytes[2] as u64 & 0xFE) << 14)
                 | ((pts_bytes[3] as u64) << 7)
-                | ((pts_bytes[4] as u64 & 0xFE) >> 1)) as u64,
+                | ((pts_bytes[4] as u64 & 0xFE) >> 1),
         );
     }
 
@@ -384,18 +584,18 @@ fn extract_pes_timestamps(payload: &[u8]) -> (Option<u64>, Option<u64>) {
     if pts_dts_flags == 3 && pes_header_length >= 10 {
         let dts_bytes = &payload[14..19];
         dts = Some(
-            (((dts_bytes[0] as u64 & 0x0E) << 29)
+            ((dts_bytes[0] as u64 & 0x0E) << 29)
                 | ((dts_bytes[1] as u64) << 22)
                 | ((dts_bytes[2] as u64 & 0xFE) << 14)
                 | ((dts_bytes[3] as u64) << 7)
-                | ((dts_bytes[4] as u64 & 0xFE) >> 1)) as u64,
+                | ((dts_bytes[4] as u64 & 0xFE) >> 1),
         );
     }
 
     (pts, dts)
 }
 
-fn extract_h264_params(media_data: &mut MediaData, pes_data: &[u8]) {
+pub(crate) fn extract_h264_params(media_data: &mut MediaData, pes_data: &[u8]) {
     let mut i = 0;
 
     while i + 4 <= pes_data.len() {
@@ -427,15 +627,14 @@ fn extract_h264_params(media_data: &mut MediaData, pes_data: &[u8]) {
         // Find next NAL unit start code
         let mut nal_end = nal_start + 1;
         while nal_end + 2 < pes_data.len() {
-            if pes_data[nal_end] == 0x00 && pes_data[nal_end + 1] == 0x00 {
-                if nal_end + 2 < pes_data.len() && pes_data[nal_end + 2] == 0x01 {
-                    break;
-                } else if nal_end + 3 < pes_data.len()
-                    && pes_data[nal_end + 2] == 0x00
-                    && pes_data[nal_end + 3] == 0x01
-                {
-                    break;
-                }
+            if pes_data[nal_end] == 0x00
+                && pes_data[nal_end + 1] == 0x00
+                && ((nal_end + 2 < pes_data.len() && pes_data[nal_end + 2] == 0x01)
+                    || (nal_end + 3 < pes_data.len()
+                        && pes_data[nal_end + 2] == 0x00
+                        && pes_data[nal_end + 3] == 0x01))
+            {
+                break;
             }
             nal_end += 1;
         }
@@ -447,9 +646,9 @@ fn extract_h264_params(media_data: &mut MediaData, pes_data: &[u8]) {
         // SPS (Sequence Parameter Set)
         if nal_type == 7 && media_data.sps.is_none() {
             let sps_data = pes_data[nal_start..nal_end].to_vec();
-            if let Some((w, h)) = parse_sps_resolution(&sps_data) {
-                media_data.width = w;
-                media_data.height = h;
+            if let Some(info) = parse_sps(&sps_data) {
+                media_data.width = info.width;
+                media_data.height = info.height;
             }
             media_data.sps = Some(sps_data);
         }
@@ -463,7 +662,62 @@ fn extract_h264_params(media_data: &mut MediaData, pes_data: &[u8]) {
     }
 }
 
-fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
+// Sample aspect ratios for aspect_ratio_idc 1-16 (H.264 Table E-1)
+const SAMPLE_ASPECT_RATIOS: [(u16, u16); 16] = [
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+const EXTENDED_SAR: u32 = 255;
+
+/// Picture size and VUI fields of an H.264 SPS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct SpsInfo {
+    pub width: u16, // after cropping
+    pub height: u16,
+    pub cropped: bool,
+    pub sample_aspect_ratio: Option<(u16, u16)>,
+    pub colour: Option<ColourInfo>,
+}
+
+/// VUI colour description (H.264 Tables E-3 to E-5, same code points as ISO/IEC 23091-2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct ColourInfo {
+    pub primaries: u8,
+    pub transfer: u8,
+    pub matrix: u8,
+    pub full_range: bool,
+}
+
+/// Removes emulation prevention bytes (00 00 03 -> 00 00)
+fn nal_to_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+pub(crate) fn parse_sps(sps: &[u8]) -> Option<SpsInfo> {
     // SPS structure (simplified):
     // - NAL header (1 byte)
     // - profile_idc (1 byte)
@@ -478,15 +732,15 @@ fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
         return None;
     }
 
+    let sps = nal_to_rbsp(sps);
     let profile_idc = sps[1];
+    let mut chroma_format_idc = 1;
 
     // Initialize bitstream reader
     let mut bit_reader = BitReader::new(&sps[4..]); // Skip NAL header + profile + constraint + level
 
     // Read seq_parameter_set_id
-    if bit_reader.read_ue().is_none() {
-        return None;
-    }
+    bit_reader.read_ue()?;
 
     // Profile-specific fields
     if profile_idc == 100
@@ -500,7 +754,7 @@ fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
         || profile_idc == 128
     {
         // chroma_format_idc
-        let chroma_format_idc = bit_reader.read_ue()?;
+        chroma_format_idc = bit_reader.read_ue()?;
 
         if chroma_format_idc == 3 {
             // separate_colour_plane_flag
@@ -580,6 +834,7 @@ fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
     let mut frame_crop_right = 0;
     let mut frame_crop_top = 0;
     let mut frame_crop_bottom = 0;
+    let mut cropped = false;
 
     if !frame_mbs_only_flag {
         // mb_adaptive_frame_field_flag
@@ -595,15 +850,84 @@ fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
         frame_crop_right = bit_reader.read_ue()?;
         frame_crop_top = bit_reader.read_ue()?;
         frame_crop_bottom = bit_reader.read_ue()?;
+        cropped = frame_crop_left + frame_crop_right + frame_crop_top + frame_crop_bottom > 0;
     }
 
+    // Crop offsets count chroma samples: 4:2:0 crops in steps of 2 in both directions,
+    // 4:2:2 only horizontally, 4:4:4 and monochrome in single luma samples
+    let field_factor = if frame_mbs_only_flag { 1 } else { 2 };
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        1 => (2, 2 * field_factor),
+        2 => (2, field_factor),
+        _ => (1, field_factor),
+    };
+
     // Calculate actual width and height
-    let width = ((pic_width_in_mbs_minus1 + 1) * 16) - (frame_crop_left + frame_crop_right) * 2;
-    let height =
-        ((2 - if frame_mbs_only_flag { 1 } else { 0 }) * (pic_height_in_map_units_minus1 + 1) * 16)
-            - (frame_crop_top + frame_crop_bottom) * 2;
+    let width = ((pic_width_in_mbs_minus1 + 1) * 16)
+        .checked_sub((frame_crop_left + frame_crop_right) * crop_unit_x)?;
+    let height = (field_factor * (pic_height_in_map_units_minus1 + 1) * 16)
+        .checked_sub((frame_crop_top + frame_crop_bottom) * crop_unit_y)?;
+
+    let mut info = SpsInfo {
+        width: width as u16,
+        height: height as u16,
+        cropped,
+        ..Default::default()
+    };
+
+    // vui_parameters_present_flag - a truncated VUI keeps the picture size
+    if bit_reader.read_bit() == Some(true) {
+        parse_vui(&mut bit_reader, &mut info);
+    }
+
+    Some(info)
+}
+
+/// Reads the aspect ratio and colour description at the start of the VUI
+fn parse_vui(bit_reader: &mut BitReader, info: &mut SpsInfo) -> Option<()> {
+    // aspect_ratio_info_present_flag
+    if bit_reader.read_bit()? {
+        let aspect_ratio_idc = bit_reader.read_bits(8)?;
+        let sar = if aspect_ratio_idc == EXTENDED_SAR {
+            (
+                bit_reader.read_bits(16)? as u16,
+                bit_reader.read_bits(16)? as u16,
+            )
+        } else {
+            // 0 = unspecified, 17-254 reserved
+            SAMPLE_ASPECT_RATIOS
+                .get((aspect_ratio_idc as usize).wrapping_sub(1))
+                .copied()
+                .unwrap_or((0, 0))
+        };
+        if sar.0 != 0 && sar.1 != 0 {
+            info.sample_aspect_ratio = Some(sar);
+        }
+    }
+
+    // overscan_info_present_flag
+    if bit_reader.read_bit()? {
+        // overscan_appropriate_flag
+        bit_reader.read_bit()?;
+    }
+
+    // video_signal_type_present_flag
+    if bit_reader.read_bit()? {
+        // video_format
+        bit_reader.read_bits(3)?;
+        let full_range = bit_reader.read_bit()?;
+        // colour_description_present_flag
+        if bit_reader.read_bit()? {
+            info.colour = Some(ColourInfo {
+                primaries: bit_reader.read_bits(8)? as u8,
+                transfer: bit_reader.read_bits(8)? as u8,
+                matrix: bit_reader.read_bits(8)? as u8,
+                full_range,
+            });
+        }
+    }
 
-    Some((width as u16, height as u16))
+    Some(())
 }
 
 // Bitstream reader for exponential-Golomb coding
@@ -638,6 +962,15 @@ impl<'a> BitReader<'a> {
         Some(bit != 0)
     }
 
+    // Read an n-bit unsigned value (n <= 32)
+    fn read_bits(&mut self, n: u8) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..n {
+            value = (value << 1) | (if self.read_bit()? { 1 } else { 0 });
+        }
+        Some(value)
+    }
+
     // Read unsigned exponential-Golomb code
     fn read_ue(&mut self) -> Option<u32> {
         let mut leading_zeros = 0;
@@ -667,13 +1000,47 @@ impl<'a> BitReader<'a> {
         let value = if code % 2 == 0 {
             -((code / 2) as i32)
         } else {
-            ((code + 1) / 2) as i32
+            code.div_ceil(2) as i32
         };
         Some(value)
     }
 }
 
-fn extract_aac_frames(pes_payload: &[u8]) -> (Vec<Vec<u8>>, usize) {
+/// Appends AAC frames taken from the current audio PES packet. The PES PTS belongs to
+/// its first frame, the following frames are interpolated one frame duration apart.
+fn push_audio_frames(media_data: &mut MediaData, aac_frames: Vec<Vec<u8>>) {
+    let sample_rate = media_data.audio_config.unwrap_or_default().sample_rate() as u64;
+    for frame in aac_frames {
+        let elapsed = media_data.current_audio_frames as u64 * AAC_FRAME_SAMPLES;
+        let pts = media_data
+            .current_audio_pts
+            .map(|pts| pts + elapsed * 90000 / sample_rate);
+        media_data.audio_timestamps.push(pts);
+        media_data.audio_frames.push(frame);
+        media_data.current_audio_frames += 1;
+    }
+}
+
+/// Reads the stream parameters from the first ADTS header in `data`
+pub(crate) fn parse_adts_config(data: &[u8]) -> Option<AacConfig> {
+    let offset = data
+        .windows(2)
+        .position(|w| w[0] == 0xFF && (w[1] & 0xF0) == 0xF0)?;
+    let header = data.get(offset..offset + 4)?;
+
+    let sample_rate_index = (header[2] >> 2) & 0x0F;
+    if sample_rate_index as usize >= AAC_SAMPLE_RATES.len() {
+        return None;
+    }
+
+    Some(AacConfig {
+        object_type: (header[2] >> 6) + 1, // ADTS profile is object type - 1
+        sample_rate_index,
+        channels: ((header[2] & 0x01) << 2) | (header[3] >> 6),
+    })
+}
+
+pub(crate) fn extract_aac_frames(pes_payload: &[u8]) -> (Vec<Vec<u8>>, usize) {
     let mut frames = Vec::new();
     let mut offset = 0;
     let mut last_complete_offset = 0;
@@ -687,9 +1054,9 @@ fn extract_aac_frames(pes_payload: &[u8]) -> (Vec<Vec<u8>>, usize) {
 
         // Parse ADTS header
         let protection_absent = (pes_payload[offset + 1] & 0x01) == 1;
-        let frame_length = (((pes_payload[offset + 3] as usize & 0x03) << 11)
+        let frame_length = ((pes_payload[offset + 3] as usize & 0x03) << 11)
             | ((pes_payload[offset + 4] as usize) << 3)
-            | ((pes_payload[offset + 5] as usize) >> 5)) as usize;
+            | ((pes_payload[offset + 5] as usize) >> 5);
 
         if frame_length < 7 || offset + frame_length > pes_payload.len() {
             // Incomplete frame - stop here
@@ -711,3 +1078,46 @@ fn extract_aac_frames(pes_payload: &[u8]) -> (Vec<Vec<u8>>, usize) {
 
     (frames, last_complete_offset)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most 5 bytes per read, like a pipe
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(5);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_packet_reader_resyncs_across_reads() {
+        let packet = |pid: u8| {
+            let mut packet = vec![0xFF; TS_PACKET_SIZE];
+            packet[..4].copy_from_slice(&[SYNC_BYTE, 0x00, pid, 0x10]);
+            packet
+        };
+        let mut data = vec![0x00, 0x01]; // garbage before the first packet
+        data.extend(packet(1));
+        data.extend([0x12, 0x34, 0x56]); // corrupt bytes between packets
+        data.extend(packet(2));
+        data.extend(packet(3));
+        data.extend([SYNC_BYTE, 0x00]); // truncated last packet
+
+        let mut reader = TsPacketReader::new(Trickle(&data));
+        let mut pids = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            assert_eq!(packet.len(), TS_PACKET_SIZE);
+            pids.push(packet[2]);
+        }
+        assert_eq!(pids, vec![1, 2, 3]);
+
+        let mut reader = TsPacketReader::new(Trickle(&[0x00; 400]));
+        assert!(reader.next_packet().is_err());
+    }
+}
@@ -0,0 +1,686 @@
+use crate::mp4_parser::{
+    defragment_mp4, find_box_path, leading_empty_duration, parse_container_box, parse_mp4,
+    parse_mvhd, read_track_samples, read_trak_edits, samples_in_decode_order, BoxInfo,
+    TrackSamples,
+};
+use crate::thumbnail::convert_avcc_to_annexb;
+use crate::ts_parser::{AacConfig, SYNC_BYTE, TS_PACKET_SIZE};
+use std::borrow::Cow;
+use std::io::{self, ErrorKind, Write};
+
+const TS_TIMESCALE: u32 = 90000;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const FIRST_STREAM_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// First DTS of the output and how far PCR runs ahead of DTS (the same as ffmpeg's defaults)
+const START_TIME: u64 = 126000;
+const PCR_DELAY: u64 = 63000;
+
+/// PAT/PMT are repeated at least this often (90kHz), and before every video keyframe
+const PSI_INTERVAL: u64 = 9000;
+
+const PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+const ACCESS_UNIT_DELIMITER: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xF0];
+
+/// Remuxes an MP4 (regular or fragmented) into MPEG-TS
+///
+/// H.264 samples are converted to Annex B with an access unit delimiter, and SPS/PPS are
+/// repeated on keyframes. AAC frames get ADTS headers. Tracks with other codecs are skipped.
+pub fn convert_mp4_to_ts(mp4_data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    write_mp4_as_ts(mp4_data, &mut output)?;
+    Ok(output)
+}
+
+/// Remuxes an MP4 into MPEG-TS and writes the packets to `sink`
+pub fn write_mp4_as_ts<W: Write>(mp4_data: &[u8], sink: W) -> io::Result<W> {
+    let mp4_data = regular_mp4(mp4_data)?;
+    let mp4_data = mp4_data.as_ref();
+
+    let mut tables = Vec::new();
+    let mut streams = Vec::new();
+    for track in read_source_tracks(mp4_data)? {
+        let index = streams.len() as u16;
+        let video = matches!(track.codec, StreamCodec::H264 { .. });
+        let same_kind = streams
+            .iter()
+            .filter(|s: &&TsStream| s.is_video() == video)
+            .count() as u8;
+        streams.push(TsStream {
+            pid: FIRST_STREAM_PID + index,
+            stream_id: if video { 0xE0 } else { 0xC0 } + same_kind,
+            track_id: track.samples.track_id,
+            timescale: track.samples.timescale,
+            shift: track.shift,
+            codec: track.codec,
+            continuity: 0,
+        });
+        tables.push(track.samples);
+    }
+
+    let pcr_pid = streams
+        .iter()
+        .find(|s| s.is_video())
+        .unwrap_or(&streams[0])
+        .pid;
+    let mut muxer = TsMuxer {
+        sink,
+        pmt: build_pmt(&streams, pcr_pid),
+        pat_continuity: 0,
+        pmt_continuity: 0,
+        last_psi: None,
+        last_pcr: 0,
+    };
+
+    // Keep every DTS at or above START_TIME even when an edit starts a track early
+    let earliest = tables
+        .iter()
+        .zip(&streams)
+        .map(|(table, stream)| stream.shift - scale_u(first_dts(table), table.timescale))
+        .min()
+        .unwrap_or(0);
+    let base = START_TIME as i64 - earliest.min(0);
+
+    for sample in samples_in_decode_order(&tables) {
+        let stream = streams
+            .iter_mut()
+            .find(|s| s.track_id == sample.track_id)
+            .expect("sample of a muxed track");
+        let data = mp4_data
+            .get(sample.offset as usize..(sample.offset + sample.size as u64) as usize)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "Sample lies outside the file")
+            })?;
+
+        let dts = (base + stream.shift + scale_u(sample.dts, stream.timescale)) as u64;
+        let pts = (base + stream.shift + scale(sample.cts(), stream.timescale)).max(0) as u64;
+        let keyframe = stream.is_video() && sample.is_sync;
+
+        let payload = match &stream.codec {
+            StreamCodec::H264 { sps, pps } => annexb_access_unit(data, keyframe, sps, pps)?,
+            StreamCodec::Aac(config) => {
+                let mut frame = config.adts_header(data.len()).to_vec();
+                frame.extend_from_slice(data);
+                frame
+            }
+        };
+        let pcr = (stream.pid == pcr_pid).then(|| dts.saturating_sub(PCR_DELAY));
+
+        muxer.write_psi_if_due(dts, keyframe)?;
+        let pes = build_pes(stream.stream_id, pts, dts, &payload, stream.is_video());
+        muxer.write_pes(stream, &pes, pcr, keyframe)?;
+    }
+
+    muxer.sink.flush()?;
+    Ok(muxer.sink)
+}
+
+/// The input itself if it is a regular MP4, otherwise the defragmented copy
+/// (sample tables only exist in regular MP4)
+pub(crate) fn regular_mp4(mp4_data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let fragmented = parse_mp4(mp4_data)?
+        .all_boxes_in_order
+        .iter()
+        .any(|b| matches!(b, BoxInfo::Small(b) if &b.box_type == b"moof"));
+    if fragmented {
+        Ok(Cow::Owned(defragment_mp4(mp4_data)?))
+    } else {
+        Ok(Cow::Borrowed(mp4_data))
+    }
+}
+
+/// H.264 or AAC track of a regular MP4
+pub(crate) struct SourceTrack {
+    pub samples: TrackSamples,
+    pub codec: StreamCodec,
+    /// Offset of media time 0 on the presentation timeline from the edit list (90kHz)
+    pub shift: i64,
+}
+
+/// Reads the H.264 and AAC tracks of a regular MP4 in file order, skipping tracks with other
+/// codecs and tracks without samples. Fails if no track is left.
+pub(crate) fn read_source_tracks(mp4_data: &[u8]) -> io::Result<Vec<SourceTrack>> {
+    let mp4 = parse_mp4(mp4_data)?;
+    let moov = mp4
+        .moov
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "moov box not found"))?;
+    let moov_boxes = parse_container_box(&moov.data)?;
+    let movie_timescale = match moov_boxes.iter().find(|b| &b.box_type == b"mvhd") {
+        Some(mvhd) => parse_mvhd(&mvhd.data)?.timescale,
+        None => TS_TIMESCALE,
+    };
+
+    let mut tracks = Vec::new();
+    for trak in moov_boxes.iter().filter(|b| &b.box_type == b"trak") {
+        let Some(codec) = stream_codec(&trak.data)? else {
+            continue;
+        };
+        let samples = read_track_samples(&trak.data)?;
+        if samples.sample_count() == 0 {
+            continue;
+        }
+
+        // Edit list: leading empty edits delay the track, media_time skips into it
+        let mut shift = 0i64;
+        if let Some(edits) = read_trak_edits(&trak.data)? {
+            shift += scale(leading_empty_duration(&edits) as i64, movie_timescale);
+            if let Some(edit) = edits.iter().find(|e| e.media_time >= 0) {
+                shift -= scale(edit.media_time, samples.timescale);
+            }
+        }
+
+        tracks.push(SourceTrack {
+            samples,
+            codec,
+            shift,
+        });
+    }
+    if tracks.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "No H.264 or AAC track found",
+        ));
+    }
+    Ok(tracks)
+}
+
+/// Codec of a track that can be carried in TS
+#[derive(Clone, PartialEq)]
+pub(crate) enum StreamCodec {
+    H264 {
+        sps: Vec<Vec<u8>>,
+        pps: Vec<Vec<u8>>,
+    },
+    Aac(AacConfig),
+}
+
+struct TsStream {
+    pid: u16,
+    stream_id: u8,
+    track_id: u32,
+    timescale: u32,
+    shift: i64, // 90kHz
+    codec: StreamCodec,
+    continuity: u8,
+}
+
+impl TsStream {
+    fn is_video(&self) -> bool {
+        matches!(self.codec, StreamCodec::H264 { .. })
+    }
+}
+
+struct TsMuxer<W: Write> {
+    sink: W,
+    pmt: Vec<u8>,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    last_psi: Option<u64>,
+    last_pcr: u64,
+}
+
+impl<W: Write> TsMuxer<W> {
+    fn write_psi_if_due(&mut self, dts: u64, keyframe: bool) -> io::Result<()> {
+        let due = match self.last_psi {
+            None => true,
+            Some(last) => keyframe || dts >= last + PSI_INTERVAL,
+        };
+        if !due {
+            return Ok(());
+        }
+        self.last_psi = Some(dts);
+
+        let pat = build_pat();
+        write_section(&mut self.sink, PAT_PID, &mut self.pat_continuity, &pat)?;
+        write_section(&mut self.sink, PMT_PID, &mut self.pmt_continuity, &self.pmt)
+    }
+
+    /// Splits a PES packet into TS packets, with PCR and random access flag on the first one
+    fn write_pes(
+        &mut self,
+        stream: &mut TsStream,
+        pes: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) -> io::Result<()> {
+        // PCR has to increase
+        let pcr = pcr.map(|pcr| {
+            self.last_pcr = pcr.max(self.last_pcr);
+            self.last_pcr
+        });
+
+        let mut offset = 0;
+        while offset < pes.len() {
+            let first = offset == 0;
+
+            // Adaptation field content after its length byte
+            let mut adaptation = Vec::new();
+            if first && (pcr.is_some() || random_access) {
+                let mut flags = 0u8;
+                if random_access {
+                    flags |= 0x40;
+                }
+                if pcr.is_some() {
+                    flags |= 0x10;
+                }
+                adaptation.push(flags);
+                if let Some(pcr) = pcr {
+                    // 33-bit base, 6 reserved bits, 9-bit extension (0)
+                    adaptation.extend_from_slice(&[
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr as u8 & 0x01) << 7) | 0x7E,
+                        0x00,
+                    ]);
+                }
+            }
+            let mut adaptation_size = if adaptation.is_empty() {
+                0
+            } else {
+                1 + adaptation.len()
+            };
+
+            // Stuff the last packet through the adaptation field
+            let remaining = pes.len() - offset;
+            if remaining < PAYLOAD_SIZE - adaptation_size {
+                let stuffing = PAYLOAD_SIZE - adaptation_size - remaining;
+                if adaptation_size == 0 && stuffing > 1 {
+                    adaptation.push(0x00);
+                    adaptation.resize(stuffing - 1, 0xFF);
+                } else if adaptation_size > 0 {
+                    adaptation.resize(adaptation.len() + stuffing, 0xFF);
+                }
+                adaptation_size += stuffing;
+            }
+
+            let payload_len = PAYLOAD_SIZE - adaptation_size;
+            let mut packet = Vec::with_capacity(TS_PACKET_SIZE);
+            packet.extend_from_slice(&ts_header(
+                stream.pid,
+                first,
+                adaptation_size > 0,
+                stream.continuity,
+            ));
+            if adaptation_size > 0 {
+                packet.push(adaptation.len() as u8);
+                packet.extend_from_slice(&adaptation);
+            }
+            packet.extend_from_slice(&pes[offset..offset + payload_len]);
+            self.sink.write_all(&packet)?;
+
+            stream.continuity = (stream.continuity + 1) & 0x0F;
+            offset += payload_len;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a PSI section in one TS packet (pointer field 0, 0xFF stuffing)
+fn write_section<W: Write>(
+    sink: &mut W,
+    pid: u16,
+    continuity: &mut u8,
+    section: &[u8],
+) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(TS_PACKET_SIZE);
+    packet.extend_from_slice(&ts_header(pid, true, false, *continuity));
+    packet.push(0x00);
+    packet.extend_from_slice(section);
+    packet.resize(TS_PACKET_SIZE, 0xFF);
+    *continuity = (*continuity + 1) & 0x0F;
+    sink.write_all(&packet)
+}
+
+fn ts_header(pid: u16, payload_start: bool, adaptation: bool, continuity: u8) -> [u8; 4] {
+    [
+        SYNC_BYTE,
+        ((payload_start as u8) << 6) | (pid >> 8) as u8 & 0x1F,
+        pid as u8,
+        if adaptation { 0x30 } else { 0x10 } | continuity,
+    ]
+}
+
+fn build_pat() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+    body.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+    build_section(0x00, 0x0001, &body)
+}
+
+fn build_pmt(streams: &[TsStream], pcr_pid: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(0xE000 | pcr_pid).to_be_bytes());
+    body.extend_from_slice(&[0xF0, 0x00]); // program_info_length = 0
+    for stream in streams {
+        body.push(if stream.is_video() { 0x1B } else { 0x0F });
+        body.extend_from_slice(&(0xE000 | stream.pid).to_be_bytes());
+        body.extend_from_slice(&[0xF0, 0x00]); // ES_info_length = 0
+    }
+    build_section(0x02, PROGRAM_NUMBER, &body)
+}
+
+/// PSI section with the long header (version 0, current) and CRC
+fn build_section(table_id: u8, table_id_extension: u16, body: &[u8]) -> Vec<u8> {
+    let section_length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xB0 | (section_length >> 8) as u8,
+        section_length as u8,
+    ];
+    section.extend_from_slice(&table_id_extension.to_be_bytes());
+    section.extend_from_slice(&[0xC1, 0x00, 0x00]);
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// CRC-32/MPEG-2 of PSI sections
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn build_pes(stream_id: u8, pts: u64, dts: u64, payload: &[u8], video: bool) -> Vec<u8> {
+    let with_dts = dts != pts;
+    let header_data_len = if with_dts { 10 } else { 5 };
+
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+    // Video PES packets may be unbounded (length 0)
+    let length = 3 + header_data_len + payload.len();
+    let length = if video || length > u16::MAX as usize {
+        0
+    } else {
+        length as u16
+    };
+    pes.extend_from_slice(&length.to_be_bytes());
+    pes.push(0x84); // data_alignment_indicator
+    pes.push(if with_dts { 0xC0 } else { 0x80 });
+    pes.push(header_data_len as u8);
+    pes.extend_from_slice(&pes_timestamp(if with_dts { 0x30 } else { 0x20 }, pts));
+    if with_dts {
+        pes.extend_from_slice(&pes_timestamp(0x10, dts));
+    }
+    pes.extend_from_slice(payload);
+    pes
+}
+
+fn pes_timestamp(prefix: u8, value: u64) -> [u8; 5] {
+    let value = value & 0x1_FFFF_FFFF;
+    [
+        prefix | ((value >> 29) as u8 & 0x0E) | 0x01,
+        (value >> 22) as u8,
+        ((value >> 14) as u8 & 0xFE) | 0x01,
+        (value >> 7) as u8,
+        ((value << 1) as u8 & 0xFE) | 0x01,
+    ]
+}
+
+/// Annex B access unit: AUD, SPS/PPS on keyframes (unless the sample carries them), then the NALs
+pub(crate) fn annexb_access_unit(
+    sample: &[u8],
+    keyframe: bool,
+    sps: &[Vec<u8>],
+    pps: &[Vec<u8>],
+) -> io::Result<Vec<u8>> {
+    let nals = convert_avcc_to_annexb(sample)?;
+    let nal_types = annexb_nal_types(&nals);
+
+    let mut output = Vec::with_capacity(nals.len() + 64);
+    if nal_types.first() != Some(&9) {
+        output.extend_from_slice(&ACCESS_UNIT_DELIMITER);
+    }
+    if keyframe && !nal_types.contains(&7) {
+        for parameter_set in sps.iter().chain(pps) {
+            output.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            output.extend_from_slice(parameter_set);
+        }
+    }
+    output.extend_from_slice(&nals);
+    Ok(output)
+}
+
+/// NAL unit types of an Annex B buffer written by `convert_avcc_to_annexb` (4-byte start codes)
+fn annexb_nal_types(data: &[u8]) -> Vec<u8> {
+    data.windows(5)
+        .filter(|w| w[..4] == [0x00, 0x00, 0x00, 0x01])
+        .map(|w| w[4] & 0x1F)
+        .collect()
+}
+
+/// Codec of a trak from its first sample entry, None if it cannot be carried
+fn stream_codec(trak_data: &[u8]) -> io::Result<Option<StreamCodec>> {
+    let Some(stsd) = find_box_path(trak_data, &[b"mdia", b"minf", b"stbl", b"stsd"])? else {
+        return Ok(None);
+    };
+    // version/flags, entry_count, then the first entry
+    let Some(entry) = stsd.data.get(8..) else {
+        return Ok(None);
+    };
+    let Some(format) = entry.get(4..8) else {
+        return Ok(None);
+    };
+    let entry_size = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+    let entry = &entry[..entry_size.min(entry.len())];
+
+    match format {
+        // Visual sample entry: 8-byte header + 78 bytes, then child boxes
+        b"avc1" | b"avc3" => {
+            let children = entry.get(86..).unwrap_or_default();
+            let avcc = parse_container_box(children)?
+                .into_iter()
+                .find(|b| &b.box_type == b"avcC")
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "avcC box not found"))?;
+            parse_avc_config(&avcc.data).map(Some)
+        }
+        // Audio sample entry: 8-byte header + 28 bytes, then child boxes
+        b"mp4a" => {
+            let children = entry.get(36..).unwrap_or_default();
+            let config = parse_container_box(children)?
+                .into_iter()
+                .find(|b| &b.box_type == b"esds")
+                .and_then(|esds| esds_audio_config(&esds.data))
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "No AAC config found in esds")
+                })?;
+            Ok(Some(StreamCodec::Aac(config)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// SPS and PPS lists of an AVCDecoderConfigurationRecord
+fn parse_avc_config(data: &[u8]) -> io::Result<StreamCodec> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "Invalid avcC box");
+    if data.len() < 6 {
+        return Err(invalid());
+    }
+    if data[4] & 0x03 != 3 {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Only 4-byte NAL unit lengths are supported",
+        ));
+    }
+
+    let mut offset = 5;
+    let mut read_sets = |count_mask: u8| -> io::Result<Vec<Vec<u8>>> {
+        let count = (*data.get(offset).ok_or_else(invalid)? & count_mask) as usize;
+        offset += 1;
+        let mut sets = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len_bytes = data.get(offset..offset + 2).ok_or_else(invalid)?;
+            let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+            sets.push(
+                data.get(offset + 2..offset + 2 + len)
+                    .ok_or_else(invalid)?
+                    .to_vec(),
+            );
+            offset += 2 + len;
+        }
+        Ok(sets)
+    };
+    let sps = read_sets(0x1F)?;
+    let pps = read_sets(0xFF)?;
+    Ok(StreamCodec::H264 { sps, pps })
+}
+
+/// AudioSpecificConfig from the DecoderSpecificInfo of an esds box
+fn esds_audio_config(data: &[u8]) -> Option<AacConfig> {
+    // Skip version/flags, then walk ES_Descriptor > DecoderConfigDescriptor > DecSpecificInfo
+    let mut offset = 4;
+    while offset < data.len() {
+        let tag = data[offset];
+        offset += 1;
+        let mut len = 0usize;
+        for _ in 0..4 {
+            let byte = *data.get(offset)?;
+            offset += 1;
+            len = (len << 7) | (byte & 0x7F) as usize;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        match tag {
+            0x03 => {
+                // ES_ID, flags and the optional fields they announce
+                let flags = *data.get(offset + 2)?;
+                offset += 3;
+                if flags & 0x80 != 0 {
+                    offset += 2;
+                }
+                if flags & 0x40 != 0 {
+                    offset += 1 + *data.get(offset)? as usize;
+                }
+                if flags & 0x20 != 0 {
+                    offset += 2;
+                }
+            }
+            0x04 => offset += 13, // objectTypeIndication .. avgBitrate
+            0x05 => return AacConfig::from_audio_specific_config(data.get(offset..offset + len)?),
+            _ => offset += len,
+        }
+    }
+    None
+}
+
+fn first_dts(table: &TrackSamples) -> u64 {
+    table.samples().next().map_or(0, |sample| sample.dts)
+}
+
+/// Converts a signed media time to 90kHz
+pub(crate) fn scale(value: i64, timescale: u32) -> i64 {
+    if timescale == 0 {
+        return value;
+    }
+    (value as i128 * TS_TIMESCALE as i128 / timescale as i128) as i64
+}
+
+fn scale_u(value: u64, timescale: u32) -> i64 {
+    scale(value as i64, timescale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_parser::read_mp4_tracks;
+    use crate::mp4_writer::test_support::sample_mp4;
+    use crate::mp4_writer::{ConvertOptions, TimestampMode};
+    use crate::probe::{probe, StreamKind};
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    #[test]
+    fn test_mp4_to_ts_round_trip() {
+        let mp4 = sample_mp4(10, 10, AacConfig::default(), 0);
+
+        let ts = convert_mp4_to_ts(&mp4).unwrap();
+        assert_eq!(ts.len() % TS_PACKET_SIZE, 0);
+        // PAT first, with a valid CRC
+        assert_eq!(&ts[..5], &[SYNC_BYTE, 0x40, 0x00, 0x10, 0x00]);
+        assert_eq!(crc32_mpeg2(&ts[5..5 + 16]), 0);
+
+        let report = probe(&ts).unwrap();
+        assert_eq!(report.streams.len(), 2);
+        assert_eq!(report.streams[0].kind, StreamKind::Video);
+        assert_eq!(report.streams[0].width, Some(1280));
+        assert_eq!(report.streams[0].sample_count, 10);
+        assert_eq!(report.streams[1].sample_rate, Some(48000));
+
+        // Converting back gives the same samples
+        let back = crate::convert_ts_to_mp4(&ts).unwrap();
+        let original = read_mp4_tracks(&mp4).unwrap();
+        let converted = read_mp4_tracks(&back).unwrap();
+        for (a, b) in original.iter().zip(&converted) {
+            let bytes = |data: &[u8], track: &TrackSamples| -> Vec<Vec<u8>> {
+                track
+                    .samples()
+                    .map(|s| data[s.offset as usize..(s.offset + s.size as u64) as usize].to_vec())
+                    .collect()
+            };
+            assert_eq!(bytes(&mp4, a), bytes(&back, b));
+        }
+
+        // The source start time is only kept on request
+        let convert = |options: &ConvertOptions| {
+            crate::convert_ts_to_mp4_writer(ts.as_slice(), Cursor::new(Vec::new()), options)
+                .unwrap()
+                .1
+                .timestamps
+        };
+        assert_eq!(convert(&ConvertOptions::default()), TimestampMode::Reset);
+
+        // A sink that cannot be read gets the same file as long as nothing is read back
+        struct WriteOnly(Cursor<Vec<u8>>);
+        impl Write for WriteOnly {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for WriteOnly {
+            fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+                self.0.seek(position)
+            }
+        }
+        let sink = || WriteOnly(Cursor::new(Vec::new()));
+        assert!(
+            crate::convert_ts_to_mp4_write_only(ts.as_slice(), sink(), &Default::default())
+                .is_err()
+        );
+        let options = ConvertOptions {
+            faststart: false,
+            ..Default::default()
+        };
+        let (written, _) =
+            crate::convert_ts_to_mp4_write_only(ts.as_slice(), sink(), &options).unwrap();
+        let (expected, _) =
+            crate::convert_ts_to_mp4_writer(ts.as_slice(), Cursor::new(Vec::new()), &options)
+                .unwrap();
+        assert_eq!(written.0.into_inner(), expected.into_inner());
+        let options = ConvertOptions {
+            preserve_timestamps: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            convert(&options),
+            TimestampMode::Preserved {
+                start_offset: START_TIME
+            }
+        );
+    }
+}